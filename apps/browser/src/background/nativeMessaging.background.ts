@@ -248,6 +248,21 @@ export class NativeMessagingBackground {
               }
             }
             return;
+          case "messageTooLarge":
+            // The proxy dropped a response larger than the browser accepts
+            if (message.appId !== appId) {
+              return;
+            }
+            this.logService.error(
+              "[Native Messaging IPC] The Bitwarden Desktop app sent a message that is too large.",
+            );
+            if (message.messageId != null && this.callbacks.has(message.messageId)) {
+              this.callbacks.get(message.messageId)?.rejecter({
+                message: "messageTooLarge",
+              });
+              this.callbacks.delete(message.messageId);
+            }
+            return;
           default:
             // Ignore since it belongs to another device
             if (!this.platformUtilsService.isSafari() && message.appId !== appId) {
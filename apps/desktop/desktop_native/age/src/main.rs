@@ -0,0 +1,68 @@
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use desktop_core::ssh_agent::age;
+
+const USAGE: &str = "Usage: bitwarden-age [-d] [-o OUTPUT] [INPUT]";
+
+/// Bitwarden age decryption.
+///
+/// Decrypts files encrypted with `age -R ~/.ssh/id_ed25519.pub` (or an `ssh-rsa` recipient), using the SSH keys
+/// in the vault instead of a private key file. Every decryption is approved in the desktop app, which has to be
+/// running with the SSH agent and the age server enabled.
+///
+/// The file is read from INPUT, or stdin, and the plaintext is written to OUTPUT, or stdout.
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("bitwarden-age: {e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<()> {
+    let mut input = None;
+    let mut output = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Decrypting is the only mode, the flag is accepted for compatibility with `age -d`
+            "-d" | "--decrypt" => {}
+            "-o" | "--output" => {
+                output = Some(PathBuf::from(
+                    args.next().ok_or_else(|| anyhow!("{USAGE}"))?,
+                ))
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            "-" if input.is_none() => input = Some(None),
+            _ if !arg.starts_with('-') && input.is_none() => input = Some(Some(PathBuf::from(arg))),
+            _ => return Err(anyhow!("Unexpected argument {arg}\n{USAGE}")),
+        }
+    }
+
+    let data = match input.flatten() {
+        Some(path) => {
+            std::fs::read(&path).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?
+        }
+        None => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            data
+        }
+    };
+
+    let plaintext = age::client::decrypt(&data).await?;
+
+    match output {
+        Some(path) => std::fs::write(&path, plaintext)
+            .map_err(|e| anyhow!("Could not write {}: {e}", path.display()))?,
+        None => std::io::stdout().write_all(&plaintext)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use desktop_core::ssh_agent::askpass::{self, AskpassPromptType};
+
+/// Bitwarden askpass helper.
+///
+/// Implements the `SSH_ASKPASS` contract of OpenSSH by showing the prompt in the desktop app:
+///
+/// - For passphrase prompts the entered passphrase is printed to stdout.
+/// - With `SSH_ASKPASS_PROMPT=confirm`, for example for keys added with `ssh-add -c`, nothing is printed.
+/// - With `SSH_ASKPASS_PROMPT=none` the prompt is only shown, until OpenSSH terminates the helper.
+///
+/// The exit code is 0 if the user confirmed or entered a passphrase, and 1 if they canceled or the app is not running.
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let prompt = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+    let prompt_type =
+        AskpassPromptType::from_env_value(std::env::var("SSH_ASKPASS_PROMPT").ok().as_deref());
+
+    match askpass::ask(prompt_type, &prompt).await {
+        Ok(response) if response.approved => {
+            if let Some(passphrase) = response.passphrase {
+                println!("{passphrase}");
+            }
+        }
+        Ok(_) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("bitwarden-askpass: {e}");
+            std::process::exit(1);
+        }
+    }
+}
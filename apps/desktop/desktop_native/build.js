@@ -45,6 +45,51 @@ function buildProxyBin(target, release = true) {
     }
 }
 
+function buildAgeBin(target, release = true) {
+    const targetArg = target ? `--target ${target}` : "";
+    const releaseArg = release ? "--release" : "";
+    child_process.execSync(`cargo build --bin bitwarden-age ${releaseArg} ${targetArg}`, {stdio: 'inherit', cwd: path.join(__dirname, "age")});
+
+    if (target) {
+        // Copy the resulting binary to the dist folder
+        const targetFolder = release ? "release" : "debug";
+        const ext = process.platform === "win32" ? ".exe" : "";
+        const nodeArch = rustTargetsMap[target].nodeArch;
+        fs.copyFileSync(path.join(__dirname, "target", target, targetFolder, `bitwarden-age${ext}`), path.join(__dirname, "dist", `bitwarden-age.${process.platform}-${nodeArch}${ext}`));
+    }
+}
+
+function buildAskpassBin(target, release = true) {
+    const targetArg = target ? `--target ${target}` : "";
+    const releaseArg = release ? "--release" : "";
+    child_process.execSync(`cargo build --bin bitwarden-askpass ${releaseArg} ${targetArg}`, {stdio: 'inherit', cwd: path.join(__dirname, "askpass")});
+
+    if (target) {
+        // Copy the resulting binary to the dist folder
+        const targetFolder = release ? "release" : "debug";
+        const ext = process.platform === "win32" ? ".exe" : "";
+        const nodeArch = rustTargetsMap[target].nodeArch;
+        fs.copyFileSync(path.join(__dirname, "target", target, targetFolder, `bitwarden-askpass${ext}`), path.join(__dirname, "dist", `bitwarden-askpass.${process.platform}-${nodeArch}${ext}`));
+    }
+}
+
+function buildPkcs11Lib(target, release = true) {
+    const targetArg = target ? `--target ${target}` : "";
+    const releaseArg = release ? "--release" : "";
+    child_process.execSync(`cargo build --lib ${releaseArg} ${targetArg}`, {stdio: 'inherit', cwd: path.join(__dirname, "pkcs11")});
+
+    if (target) {
+        // Copy the resulting library to the dist folder
+        const targetFolder = release ? "release" : "debug";
+        const [prefix, ext] = {
+            win32: ["", ".dll"],
+            darwin: ["lib", ".dylib"],
+        }[process.platform] ?? ["lib", ".so"];
+        const nodeArch = rustTargetsMap[target].nodeArch;
+        fs.copyFileSync(path.join(__dirname, "target", target, targetFolder, `${prefix}bitwarden_pkcs11${ext}`), path.join(__dirname, "dist", `${prefix}bitwarden_pkcs11.${process.platform}-${nodeArch}${ext}`));
+    }
+}
+
 function installTarget(target) {
     child_process.execSync(`rustup target add ${target}`, { stdio: 'inherit', cwd: __dirname });
 }
@@ -53,6 +98,9 @@ if (!crossPlatform && !target) {
     console.log(`Building native modules in ${mode} mode for the native architecture`);
     buildNapiModule(false, mode === "release");
     buildProxyBin(false, mode === "release");
+    buildAgeBin(false, mode === "release");
+    buildPkcs11Lib(false, mode === "release");
+    buildAskpassBin(false, mode === "release");
     return;
 }
 
@@ -61,6 +109,9 @@ if (target) {
     installTarget(target);
     buildNapiModule(target, mode === "release");
     buildProxyBin(target, mode === "release");
+    buildAgeBin(target, mode === "release");
+    buildPkcs11Lib(target, mode === "release");
+    buildAskpassBin(target, mode === "release");
     return;
 }
 
@@ -78,4 +129,7 @@ platformTargets.forEach(([target, _]) => {
     installTarget(target);
     buildNapiModule(target);
     buildProxyBin(target);
+    buildAgeBin(target);
+    buildPkcs11Lib(target);
+    buildAskpassBin(target);
 });
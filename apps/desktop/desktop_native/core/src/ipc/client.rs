@@ -1,30 +1,337 @@
-use std::path::PathBuf;
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use futures::{SinkExt, StreamExt};
 use interprocess::local_socket::{
     tokio::{prelude::*, Stream},
     GenericFilePath, ToFsName,
 };
-use log::{error, info};
+use log::{error, info, warn};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::codec::Framed;
+
+use super::{
+    noise::{self, IpcCodec, NoiseKeys},
+    protocol::{
+        self, Command, Envelope, ErrorCode, HeartbeatOptions, Link, MIN_PROTOCOL_VERSION,
+        NEGOTIATION_TIMEOUT, PROTOCOL_VERSION,
+    },
+    MAX_MESSAGE_SIZE, MESSAGE_CHANNEL_BUFFER,
+};
 
 pub async fn connect(
     path: PathBuf,
     send: tokio::sync::mpsc::Sender<String>,
+    recv: tokio::sync::mpsc::Receiver<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    connect_with_keys(path, send, recv, None).await
+}
+
+/// Like [`connect`], but if `keys` are given, the connection is encrypted with them, see [`crate::ipc::noise`].
+pub async fn connect_with_keys(
+    path: PathBuf,
+    send: tokio::sync::mpsc::Sender<String>,
+    recv: tokio::sync::mpsc::Receiver<String>,
+    keys: Option<NoiseKeys>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    connect_to(path, None, send, recv, keys).await
+}
+
+/// Like [`connect_with_keys`], but the messages are sent on `channel`, see [`protocol::check_channel`]. The server
+/// must have a subscriber for it.
+pub async fn connect_on_channel(
+    path: PathBuf,
+    channel: &str,
+    send: tokio::sync::mpsc::Sender<String>,
+    recv: tokio::sync::mpsc::Receiver<String>,
+    keys: Option<NoiseKeys>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    protocol::check_channel(channel)?;
+    connect_to(path, Some(channel), send, recv, keys).await
+}
+
+/// Send `request` on `channel` over a new connection, and wait for its response, which is the first message that
+/// parses as a `Res` that `is_response` accepts. Fails if there is none within `timeout`.
+///
+/// This is how helpers such as the PKCS#11 module ask the app for something, usually waiting for the user to approve.
+pub async fn request_on_channel<Req, Res>(
+    path: PathBuf,
+    channel: &str,
+    request: &Req,
+    is_response: impl Fn(&Res) -> bool,
+    timeout: Duration,
+) -> anyhow::Result<Res>
+where
+    Req: Serialize,
+    Res: DeserializeOwned,
+{
+    let (to_server_send, to_server_recv) = tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+    let (from_server_send, mut from_server_recv) =
+        tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+    to_server_send.send(serde_json::to_string(request)?).await?;
+
+    let connection = connect_on_channel(path, channel, from_server_send, to_server_recv, None);
+    let response = async {
+        while let Some(message) = from_server_recv.recv().await {
+            // Skip the `connected` message of the client, and anything else that isn't the response
+            match serde_json::from_str::<Res>(&message) {
+                Ok(response) if is_response(&response) => return Ok(response),
+                _ => continue,
+            }
+        }
+        Err(anyhow!("The connection to the app was closed"))
+    };
+
+    tokio::time::timeout(timeout, async {
+        tokio::select! {
+            result = connection => match result {
+                Ok(()) => Err(anyhow!("The connection to the app was closed")),
+                Err(e) => Err(anyhow!("Could not connect to the app: {e}")),
+            },
+            response = response => response,
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("The app did not respond in time"))?
+}
+
+async fn connect_to(
+    path: PathBuf,
+    channel: Option<&str>,
+    send: tokio::sync::mpsc::Sender<String>,
     mut recv: tokio::sync::mpsc::Receiver<String>,
+    keys: Option<NoiseKeys>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut conn, version) = establish(&path, keys.as_ref(), channel).await?;
+    let link = Link::new(version, Some(HeartbeatOptions::default()), MAX_MESSAGE_SIZE);
+
+    // This `connected` and the latter `disconnected` messages are the only ones that
+    // are sent from the Rust IPC code and not just forwarded from the desktop app.
+    send.send(Command::Connected.to_json()).await?;
+    let reason = match forward(
+        &mut conn,
+        &send,
+        &mut recv,
+        &mut VecDeque::new(),
+        link,
+        channel,
+    )
+    .await
+    {
+        Ended::ChannelClosed => None,
+        Ended::ConnectionClosed(reason) => reason,
+    };
+    let _ = send.send(Command::Disconnected { reason }.to_json()).await;
+
+    Ok(())
+}
+
+/// How [`connect_reconnecting`] retries.
+pub struct ReconnectOptions {
+    /// The delay before the first retry, doubled after each failed attempt.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The number of consecutive failed attempts after which to give up, or `None` to retry forever.
+    pub max_attempts: Option<u32>,
+    /// The number of messages kept while disconnected. The oldest ones are dropped first.
+    pub queue_capacity: usize,
+    /// How a server that is gone without closing the connection is detected, or `None` to wait for the OS to notice.
+    pub heartbeat: Option<HeartbeatOptions>,
+    /// The size of the largest message accepted from the server, once its fragments are put back together.
+    pub max_message_size: usize,
+    /// The channel to send the messages on, or `None` for the default channel, see [`protocol::check_channel`].
+    /// The server must have a subscriber for it.
+    pub channel: Option<String>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+            queue_capacity: MESSAGE_CHANNEL_BUFFER,
+            heartbeat: Some(HeartbeatOptions::default()),
+            max_message_size: MAX_MESSAGE_SIZE,
+            channel: None,
+        }
+    }
+}
+
+/// Like [`connect_with_keys`], but waits for the server to start listening, and reconnects whenever the connection
+/// is lost, with exponential backoff. A `connected` and a `disconnected` message are sent on `send` for every
+/// connection, and messages received on `recv` while disconnected are sent once connected again.
+///
+/// Returns once `recv` is closed, or with an error once `options.max_attempts` attempts in a row have failed.
+pub async fn connect_reconnecting(
+    path: PathBuf,
+    send: tokio::sync::mpsc::Sender<String>,
+    mut recv: tokio::sync::mpsc::Receiver<String>,
+    keys: Option<NoiseKeys>,
+    options: ReconnectOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(channel) = &options.channel {
+        protocol::check_channel(channel)?;
+    }
+    let channel = options.channel.as_deref();
+
+    let mut queue = VecDeque::new();
+    let mut delay = options.initial_delay;
+    let mut failed_attempts = 0;
+
+    loop {
+        let attempt = establish(&path, keys.as_ref(), channel);
+        tokio::pin!(attempt);
+        let result = loop {
+            tokio::select! {
+                result = &mut attempt => break result,
+                msg = recv.recv() => match msg {
+                    Some(msg) => enqueue(&mut queue, msg, options.queue_capacity),
+                    None => return Ok(()),
+                },
+            }
+        };
+
+        match result {
+            Ok((mut conn, version)) => {
+                failed_attempts = 0;
+                delay = options.initial_delay;
+                if send.send(Command::Connected.to_json()).await.is_err() {
+                    return Ok(());
+                }
+                let link = Link::new(version, options.heartbeat, options.max_message_size);
+                let Ended::ConnectionClosed(reason) =
+                    forward(&mut conn, &send, &mut recv, &mut queue, link, channel).await
+                else {
+                    let _ = send
+                        .send(Command::Disconnected { reason: None }.to_json())
+                        .await;
+                    return Ok(());
+                };
+                if send
+                    .send(Command::Disconnected { reason }.to_json())
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                failed_attempts += 1;
+                if options
+                    .max_attempts
+                    .is_some_and(|max| failed_attempts >= max)
+                {
+                    return Err(e.into());
+                }
+                // Only the first failure is worth reporting, the server is usually just not running
+                if failed_attempts == 1 {
+                    info!("Could not connect to {}, retrying: {e}", path.display());
+                }
+            }
+        }
+
+        // Spread the retries of multiple clients, so they don't all hit the server at once
+        let jittered = rand::rng().random_range(delay / 2..=delay);
+        let wait = tokio::time::sleep(jittered);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                msg = recv.recv() => match msg {
+                    Some(msg) => enqueue(&mut queue, msg, options.queue_capacity),
+                    None => return Ok(()),
+                },
+            }
+        }
+        delay = (delay * 2).min(options.max_delay);
+    }
+}
+
+fn enqueue(queue: &mut VecDeque<String>, message: String, capacity: usize) {
+    if queue.len() >= capacity {
+        warn!("Dropping a message queued while disconnected");
+        queue.pop_front();
+    }
+    queue.push_back(message);
+}
+
+/// Connect to the server, then run the encryption handshake and the protocol negotiation. Returns the connection
+/// and the protocol version, which must support `channel` if set.
+async fn establish(
+    path: &Path,
+    keys: Option<&NoiseKeys>,
+    channel: Option<&str>,
+) -> anyhow::Result<(Framed<Stream, IpcCodec>, u32)> {
     info!("Attempting to connect to {}", path.display());
 
     let name = path.as_os_str().to_fs_name::<GenericFilePath>()?;
     let conn = Stream::connect(name).await?;
 
     let mut conn = crate::ipc::internal_ipc_codec(conn);
+    if let Some(keys) = keys {
+        noise::initiate(&mut conn, keys).await?;
+    }
+    let version = tokio::time::timeout(NEGOTIATION_TIMEOUT, negotiate(&mut conn))
+        .await
+        .map_err(|_| anyhow!("The server did not answer the protocol negotiation"))??;
 
-    info!("Connected to {}", path.display());
+    if channel.is_some() && version < protocol::CHANNEL_VERSION {
+        return Err(anyhow!(
+            "The server speaks protocol version {version}, which has no channels"
+        ));
+    }
 
-    // This `connected` and the latter `disconnected` messages are the only ones that
-    // are sent from the Rust IPC code and not just forwarded from the desktop app.
-    // As it's only two, we hardcode the JSON values to avoid pulling in a JSON library.
-    send.send("{\"command\":\"connected\"}".to_owned()).await?;
+    info!(
+        "Connected to {} with protocol version {version}",
+        path.display()
+    );
+    Ok((conn, version))
+}
+
+/// Send the frames of the message `msg`, see [`protocol::message_frames`].
+async fn send_message(
+    conn: &mut Framed<Stream, IpcCodec>,
+    msg: &str,
+    channel: Option<&str>,
+    version: u32,
+) -> std::io::Result<()> {
+    for frame in protocol::message_frames(msg, channel, version) {
+        conn.send(frame.into()).await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Ended {
+    /// The application closed its side of the channels.
+    ChannelClosed,
+    /// The connection was lost, for the given reason if it was not closed by the server.
+    ConnectionClosed(Option<String>),
+}
+
+/// Forward messages between the server and the application until either side is closed. The messages in `queue`
+/// are sent first, and a message that could not be sent is put back.
+async fn forward(
+    conn: &mut Framed<Stream, IpcCodec>,
+    send: &tokio::sync::mpsc::Sender<String>,
+    recv: &mut tokio::sync::mpsc::Receiver<String>,
+    queue: &mut VecDeque<String>,
+    mut link: Link,
+    channel: Option<&str>,
+) -> Ended {
+    while let Some(msg) = queue.pop_front() {
+        if let Err(e) = send_message(conn, &msg, channel, link.version).await {
+            error!("Error writing to IPC server: {e}");
+            queue.push_front(msg);
+            return Ended::ConnectionClosed(Some(format!("Error writing to the server: {e}")));
+        }
+    }
 
     // Listen to IPC messages
     loop {
@@ -33,36 +340,110 @@ pub async fn connect(
             msg = recv.recv() => {
                 match msg {
                     Some(msg) => {
-                        conn.send(msg.into()).await?;
+                        if let Err(e) = send_message(conn, &msg, channel, link.version).await {
+                            error!("Error writing to IPC server: {e}");
+                            queue.push_front(msg);
+                            return Ended::ConnectionClosed(Some(format!("Error writing to the server: {e}")));
+                        }
                     }
                     None => {
                         info!("Client channel closed");
-                        break;
+                        return Ended::ChannelClosed;
                     },
                 }
             },
 
+            // Check that the server is still alive when it has been quiet for a while. A server that is alive
+            // answers even while its application is busy.
+            due = link.heartbeat.due() => {
+                let result = match due {
+                    Ok(()) => conn.send(Envelope::Ping.to_json().into()).await.map_err(|e| e.to_string()),
+                    Err(reason) => Err(reason),
+                };
+                if let Err(reason) = result {
+                    error!("IPC server is gone: {reason}");
+                    return Ended::ConnectionClosed(Some(format!("The server is not responding: {reason}")));
+                }
+            },
+
             // Forward messages from the IPC server
             res = conn.next() => {
                 match res {
                     Some(Err(e)) => {
                         error!("Error reading from IPC server: {e}");
-                        break;
+                        return Ended::ConnectionClosed(Some(format!("Error reading from the server: {e}")));
                     }
-                     None => {
+                    None => {
                         info!("Connection closed");
-                        break;
+                        return Ended::ConnectionClosed(None);
                     }
                     Some(Ok(bytes)) => {
-                        let message = String::from_utf8_lossy(&bytes).to_string();
-                        send.send(message).await?;
-                    }
+                        link.heartbeat.seen();
+
+                        match serde_json::from_slice::<Envelope>(&bytes) {
+                            Ok(Envelope::Fragment { payload }) => link.reassembler.fragment(payload),
+                            Ok(Envelope::Message { payload, .. }) => match link.reassembler.message(payload) {
+                                Ok(message) => {
+                                    if send.send(message).await.is_err() {
+                                        return Ended::ChannelClosed;
+                                    }
+                                }
+                                Err(reason) => {
+                                    warn!("Dropped a message from IPC server: {reason}");
+                                    let reply = Envelope::error(ErrorCode::MessageTooLarge, reason);
+                                    if let Err(e) = conn.send(reply.to_json().into()).await {
+                                        error!("Error writing to IPC server: {e}");
+                                        return Ended::ConnectionClosed(Some(format!("Error writing to the server: {e}")));
+                                    }
+                                }
+                            },
+                            Ok(Envelope::Ping) => {
+                                if let Err(e) = conn.send(Envelope::Pong.to_json().into()).await {
+                                    error!("Error writing to IPC server: {e}");
+                                    return Ended::ConnectionClosed(Some(format!("Error writing to the server: {e}")));
+                                }
+                            }
+                            Ok(Envelope::Pong) => {}
+                            Ok(Envelope::Error { code, message }) => {
+                                error!("IPC server could not handle a message: {code:?} {message}");
+                            }
+                            Ok(envelope) => {
+                                error!("Unexpected message from IPC server: {envelope:?}");
+                            }
+                            Err(e) => {
+                                let reply = Envelope::error(ErrorCode::UnknownMessage, e.to_string());
+                                if let Err(e) = conn.send(reply.to_json().into()).await {
+                                    error!("Error writing to IPC server: {e}");
+                                    return Ended::ConnectionClosed(Some(format!("Error writing to the server: {e}")));
+                                }
+                            }
+                        }
+                    },
                 }
             }
         }
     }
+}
 
-    let _ = send.send("{\"command\":\"disconnected\"}".to_owned()).await;
-
-    Ok(())
+/// Agree on the protocol version with the server, see [`crate::ipc::protocol`].
+async fn negotiate(conn: &mut Framed<Stream, IpcCodec>) -> anyhow::Result<u32> {
+    conn.send(Envelope::hello().to_json().into()).await?;
+    let reply = conn
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Connection closed during the protocol negotiation"))??;
+    match serde_json::from_slice(&reply)? {
+        Envelope::Welcome { version }
+            if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+        {
+            Ok(version)
+        }
+        Envelope::Welcome { version } => Err(anyhow!(
+            "The server chose protocol version {version}, which is not supported"
+        )),
+        Envelope::Error { code, message } => Err(anyhow!(
+            "The server refused the connection: {code:?} {message}"
+        )),
+        envelope => Err(anyhow!("Unexpected reply to hello: {envelope:?}")),
+    }
 }
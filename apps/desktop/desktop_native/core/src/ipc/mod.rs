@@ -1,32 +1,45 @@
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::Framed;
 
 pub mod client;
+pub mod noise;
+pub mod peer;
+pub mod protocol;
+pub mod rpc;
 pub mod server;
 
 /// The maximum size of a message that can be sent over IPC.
 /// According to the documentation, the maximum size sent to the browser is 1MB.
 /// While the maximum size sent from the browser to the native messaging host is 4GB.
 ///
-/// Currently we are setting the maximum both ways to be 1MB.
+/// Currently we are setting the maximum of a frame both ways to be 1MB, larger messages are split into multiple
+/// frames, up to [`MAX_MESSAGE_SIZE`].
 ///
 /// https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/Native_messaging#app_side
 /// https://developer.chrome.com/docs/extensions/develop/concepts/native-messaging#native-messaging-host-protocol
 pub const NATIVE_MESSAGING_BUFFER_SIZE: usize = 1024 * 1024;
 
+/// The maximum size of a message that is accepted over IPC by default. Larger messages than
+/// [`NATIVE_MESSAGING_BUFFER_SIZE`] are split into multiple frames, see [`protocol`].
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
 /// The maximum number of messages that can be buffered in a channel.
 /// This number is more or less arbitrary and can be adjusted as needed,
 /// but ideally the messages should be processed as quickly as possible.
 pub const MESSAGE_CHANNEL_BUFFER: usize = 32;
 
+/// The name of the endpoint that the app listens on, see [`path`] and [`server::shared`].
+pub const APP_ENDPOINT: &str = "bitwarden";
+
+/// The channel of the browser extensions on the app's endpoint, through the native messaging proxy, and the name of
+/// the keys the proxy connects with, see [`noise::key_path`].
+pub const BROWSER_CHANNEL: &str = "browser";
+
 /// This is the codec used for communication through the UNIX socket / Windows named pipe.
 /// It's an internal implementation detail, but we want to make sure that both the client
 ///  and the server use the same one.
-fn internal_ipc_codec<T: AsyncRead + AsyncWrite>(inner: T) -> Framed<T, LengthDelimitedCodec> {
-    LengthDelimitedCodec::builder()
-        .max_frame_length(NATIVE_MESSAGING_BUFFER_SIZE)
-        .native_endian()
-        .new_framed(inner)
+fn internal_ipc_codec<T: AsyncRead + AsyncWrite>(inner: T) -> Framed<T, noise::IpcCodec> {
+    Framed::new(inner, noise::IpcCodec::new())
 }
 
 /// Resolve the path to the IPC socket.
@@ -45,30 +58,12 @@ pub fn path(name: &str) -> std::path::PathBuf {
     }
 
     #[cfg(target_os = "macos")]
-    {
-        // When running in an unsandboxed environment, path is: /Users/<user>/
-        // While running sandboxed, it's different: /Users/<user>/Library/Containers/com.bitwarden.desktop/Data
-        let mut home = dirs::home_dir().unwrap();
-
-        // Check if the app is sandboxed by looking for the Containers directory
-        let containers_position = home
-            .components()
-            .position(|c| c.as_os_str() == "Containers");
-
-        // If the app is sanboxed, we need to use the App Group directory
-        if let Some(position) = containers_position {
-            // We want to use App Groups in /Users/<user>/Library/Group Containers/LTZ2PFU5D6.com.bitwarden.desktop,
-            // so we need to remove all the components after the user. We can use the previous position to do this.
-            while home.components().count() > position - 1 {
-                home.pop();
-            }
-
-            let tmp = home.join("Library/Group Containers/LTZ2PFU5D6.com.bitwarden.desktop/tmp");
-
-            // The tmp directory might not exist, so create it
-            let _ = std::fs::create_dir_all(&tmp);
-            return tmp.join(format!("app.{name}"));
-        }
+    if let Some(group_container) = group_container() {
+        let tmp = group_container.join("tmp");
+
+        // The tmp directory might not exist, so create it
+        let _ = std::fs::create_dir_all(&tmp);
+        return tmp.join(format!("app.{name}"));
     }
 
     #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -82,3 +77,25 @@ pub fn path(name: &str) -> std::path::PathBuf {
         path_dir.join(format!("app.{name}"))
     }
 }
+
+/// The App Group directory shared with the proxy when the app is sandboxed, or `None` when it isn't.
+#[cfg(target_os = "macos")]
+fn group_container() -> Option<std::path::PathBuf> {
+    // When running in an unsandboxed environment, path is: /Users/<user>/
+    // While running sandboxed, it's different: /Users/<user>/Library/Containers/com.bitwarden.desktop/Data
+    let mut home = dirs::home_dir().unwrap();
+
+    // Check if the app is sandboxed by looking for the Containers directory
+    let position = home
+        .components()
+        .position(|c| c.as_os_str() == "Containers")?;
+
+    // If the app is sanboxed, we need to use the App Group directory
+    // We want to use App Groups in /Users/<user>/Library/Group Containers/LTZ2PFU5D6.com.bitwarden.desktop,
+    // so we need to remove all the components after the user. We can use the previous position to do this.
+    while home.components().count() > position - 1 {
+        home.pop();
+    }
+
+    Some(home.join("Library/Group Containers/LTZ2PFU5D6.com.bitwarden.desktop"))
+}
@@ -0,0 +1,662 @@
+//! Optional end-to-end encryption of IPC connections, so that other processes of the user that open the socket can
+//! neither read nor inject messages.
+//!
+//! Connections start with a `Noise_KK_25519_ChaChaPoly_SHA256` handshake: both ends hold a static X25519 key pair
+//! and the public key of the other end. Every frame of the [`LengthDelimitedCodec`] framing is then encrypted with
+//! ChaCha20-Poly1305. Unlike Noise, frames are not limited to 65535 bytes, as the framing already carries their length.
+//!
+//! The server tells the handshake apart from an unencrypted connection by its first frame, and answers it with
+//! whichever of its keys the client holds the other half of, so clients with different keys can share a server.
+//!
+//! The keys are provisioned once with [`provision_keys`] when the app installs the client of an endpoint, such as the
+//! native messaging manifests pointing to the proxy, and are never rotated afterwards. They are stored in a private
+//! directory of their own, away from the socket, in files created only readable by the user, and [`NoiseKeys::load`]
+//! refuses files that other users can access. This keeps out other users, not other processes of the same user that
+//! can read the user's files.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use curve25519_dalek::MontgomeryPoint;
+use futures::{SinkExt, StreamExt};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec};
+
+use super::NATIVE_MESSAGING_BUFFER_SIZE;
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_KK_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"bitwarden-desktop-ipc";
+const TAG_LEN: usize = 16;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which end of the connection a key file belongs to. The proxy starts the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    App,
+    Proxy,
+}
+
+impl Role {
+    fn file_name(self) -> &'static str {
+        match self {
+            Role::App => "app",
+            Role::Proxy => "proxy",
+        }
+    }
+}
+
+/// The keys of one end of the connection: its static secret key and the public key of the other end.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoiseKeys {
+    #[serde(with = "base64_key")]
+    secret_key: [u8; 32],
+    #[serde(with = "base64_key")]
+    remote_public_key: [u8; 32],
+}
+
+impl NoiseKeys {
+    fn public_key(&self) -> [u8; 32] {
+        MontgomeryPoint::mul_base_clamped(self.secret_key).to_bytes()
+    }
+
+    /// The public key of the other end, which it authenticates with.
+    pub(super) fn remote_public_key(&self) -> [u8; 32] {
+        self.remote_public_key
+    }
+
+    /// Generate the keys of both ends of a connection, returned as the keys of the app and of the proxy.
+    pub fn generate() -> (Self, Self) {
+        let mut app_secret = [0u8; 32];
+        let mut proxy_secret = [0u8; 32];
+        rand::rng().fill_bytes(&mut app_secret);
+        rand::rng().fill_bytes(&mut proxy_secret);
+        let app = NoiseKeys {
+            secret_key: app_secret,
+            remote_public_key: MontgomeryPoint::mul_base_clamped(proxy_secret).to_bytes(),
+        };
+        let proxy = NoiseKeys {
+            secret_key: proxy_secret,
+            remote_public_key: MontgomeryPoint::mul_base_clamped(app_secret).to_bytes(),
+        };
+        (app, proxy)
+    }
+
+    /// Load the keys of one end from `path`. On unix, files that other users can access are refused.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(path)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(anyhow!(
+                    "Key file {} is accessible by other users (mode {:o})",
+                    path.display(),
+                    mode & 0o777
+                ));
+            }
+        }
+
+        serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid key file {}: {e}", path.display()))
+    }
+
+    /// Write the keys to a new file only the user can read. Existing files are never overwritten.
+    fn save(&self, path: &Path) -> Result<()> {
+        use std::io::Write;
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(serde_json::to_string(self)?.as_bytes()))
+            .map_err(|e| anyhow!("Could not write {}: {e}", path.display()))
+    }
+}
+
+mod base64_key {
+    use super::*;
+
+    pub fn serialize<S: serde::Serializer>(
+        key: &[u8; 32],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(key))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; 32], D::Error> {
+        let key = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| serde::de::Error::custom("Expected a base64 encoded 32 byte key"))
+    }
+}
+
+/// The directory holding the key files of all the endpoints.
+fn key_dir() -> PathBuf {
+    // The sandboxed app shares its files with the proxy through the App Group directory
+    #[cfg(target_os = "macos")]
+    if let Some(group_container) = super::group_container() {
+        return group_container.join("keys");
+    }
+
+    #[cfg(windows)]
+    {
+        dirs::data_local_dir()
+            .unwrap()
+            .join("Bitwarden")
+            .join("ipc-keys")
+    }
+
+    #[cfg(not(windows))]
+    {
+        dirs::data_local_dir()
+            .unwrap()
+            .join("com.bitwarden.desktop")
+            .join("ipc-keys")
+    }
+}
+
+/// The path of the key file of `role` for the IPC endpoint `name`.
+pub fn key_path(name: &str, role: Role) -> PathBuf {
+    key_dir().join(key_file_name(name, role))
+}
+
+fn key_file_name(name: &str, role: Role) -> String {
+    format!("app.{name}.{}.key", role.file_name())
+}
+
+/// Generate and store the key pairs of both ends of the IPC endpoint `name`, unless valid ones already exist. This is
+/// meant to be called when installing the client of the endpoint, servers only [load](NoiseKeys::load) the keys.
+pub fn provision_keys(name: &str) -> Result<()> {
+    provision_keys_in(&key_dir(), name)
+}
+
+fn provision_keys_in(dir: &Path, name: &str) -> Result<()> {
+    create_private_dir(dir)?;
+
+    let app_path = dir.join(key_file_name(name, Role::App));
+    let proxy_path = dir.join(key_file_name(name, Role::Proxy));
+    if NoiseKeys::load(&app_path).is_ok() && NoiseKeys::load(&proxy_path).is_ok() {
+        return Ok(());
+    }
+
+    // Either half is useless without the other one, so a missing or invalid file replaces both
+    for path in [&app_path, &proxy_path] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(anyhow!("Could not remove {}: {e}", path.display()));
+            }
+            _ => {}
+        }
+    }
+
+    let (app_keys, proxy_keys) = NoiseKeys::generate();
+    proxy_keys.save(&proxy_path)?;
+    app_keys.save(&app_path)
+}
+
+/// Create `dir` if needed, and make sure only the user can list or enter it.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(dir)
+        .map_err(|e| anyhow!("Could not create {}: {e}", dir.display()))?;
+
+    // An existing directory keeps its mode when created recursively
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+            .map_err(|e| anyhow!("Could not restrict {}: {e}", dir.display()))?;
+    }
+
+    Ok(())
+}
+
+fn dh(secret: [u8; 32], public: [u8; 32]) -> Result<[u8; 32]> {
+    let shared = MontgomeryPoint(public).mul_clamped(secret).to_bytes();
+    // A low order public key gives an all zero output, which would make the key independent of our secret
+    if shared == [0u8; 32] {
+        return Err(anyhow!("Invalid public key"));
+    }
+    Ok(shared)
+}
+
+#[derive(Default)]
+struct CipherState {
+    cipher: Option<ChaCha20Poly1305>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        CipherState {
+            cipher: Some(ChaCha20Poly1305::new(&key.into())),
+            nonce: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<[u8; 12]> {
+        // The maximum nonce is reserved by Noise
+        if self.nonce == u64::MAX {
+            return Err(anyhow!("Too many messages on this connection"));
+        }
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Ok(nonce)
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        if self.cipher.is_none() {
+            return Ok(plaintext.to_vec());
+        }
+        let nonce = self.next_nonce()?;
+        let cipher = self.cipher.as_ref().expect("Cipher is set");
+        cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad: ad,
+                },
+            )
+            .map_err(|_| anyhow!("Encryption failed"))
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if self.cipher.is_none() {
+            return Ok(ciphertext.to_vec());
+        }
+        let nonce = self.next_nonce()?;
+        let cipher = self.cipher.as_ref().expect("Cipher is set");
+        cipher
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: ad,
+                },
+            )
+            .map_err(|_| anyhow!("Message could not be authenticated"))
+    }
+}
+
+struct SymmetricState {
+    cipher: CipherState,
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let mut state = SymmetricState {
+            cipher: CipherState::default(),
+            chaining_key: *PROTOCOL_NAME,
+            hash: *PROTOCOL_NAME,
+        };
+        state.mix_hash(PROLOGUE);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = Sha256::new()
+            .chain_update(self.hash)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn hkdf(&self, input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+        let mut output = [0u8; 64];
+        Hkdf::<Sha256>::new(Some(&self.chaining_key), input_key_material)
+            .expand(&[], &mut output)
+            .expect("64 bytes is a valid HKDF output length");
+        let (first, second) = output.split_at(32);
+        (
+            first.try_into().expect("Split at 32"),
+            second.try_into().expect("Split at 32"),
+        )
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (chaining_key, key) = self.hkdf(input_key_material);
+        self.chaining_key = chaining_key;
+        self.cipher = CipherState::new(key);
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let ciphertext = self.cipher.encrypt(&self.hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = self.cipher.decrypt(&self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Returns the cipher states for the messages sent by the initiator and by the responder.
+    fn split(&self) -> (CipherState, CipherState) {
+        let (initiator, responder) = self.hkdf(&[]);
+        (CipherState::new(initiator), CipherState::new(responder))
+    }
+}
+
+/// The cipher states of an established connection.
+pub(super) struct Transport {
+    send: CipherState,
+    recv: CipherState,
+}
+
+/// The [`LengthDelimitedCodec`] framing, with each frame encrypted once the handshake is done.
+pub(super) struct IpcCodec {
+    inner: LengthDelimitedCodec,
+    transport: Option<Transport>,
+}
+
+impl IpcCodec {
+    pub(super) fn new() -> Self {
+        IpcCodec {
+            inner: LengthDelimitedCodec::builder()
+                .max_frame_length(NATIVE_MESSAGING_BUFFER_SIZE)
+                .native_endian()
+                .new_codec(),
+            transport: None,
+        }
+    }
+
+    fn set_transport(&mut self, transport: Transport) {
+        // Make room for the authentication tag, so the maximum message size stays the same
+        self.inner
+            .set_max_frame_length(NATIVE_MESSAGING_BUFFER_SIZE + TAG_LEN);
+        self.transport = Some(transport);
+    }
+}
+
+impl Decoder for IpcCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let Some(frame) = self.inner.decode(src)? else {
+            return Ok(None);
+        };
+        match &mut self.transport {
+            Some(transport) => transport
+                .recv
+                .decrypt(&[], &frame)
+                .map(|plaintext| Some(BytesMut::from(plaintext.as_slice())))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            None => Ok(Some(frame)),
+        }
+    }
+}
+
+impl Encoder<Bytes> for IpcCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let item = match &mut self.transport {
+            Some(transport) => transport
+                .send
+                .encrypt(&[], &item)
+                .map(Bytes::from)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+            None => item,
+        };
+        self.inner.encode(item, dst)
+    }
+}
+
+/// Run the handshake as the proxy, which starts it, then encrypt all further frames.
+pub(super) async fn initiate<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, IpcCodec>,
+    keys: &NoiseKeys,
+) -> Result<()> {
+    let transport = tokio::time::timeout(HANDSHAKE_TIMEOUT, run_initiator(framed, keys))
+        .await
+        .map_err(|_| anyhow!("Handshake timed out"))??;
+    framed.codec_mut().set_transport(transport);
+    Ok(())
+}
+
+/// Answer the handshake started by `message`, the first frame the proxy sent, with the one of `candidates` whose
+/// other half the proxy holds, then encrypt all further frames. Returns the public key the proxy authenticated with,
+/// which is the `remote_public_key` of that candidate.
+pub(super) async fn respond<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, IpcCodec>,
+    message: &[u8],
+    candidates: &[NoiseKeys],
+) -> Result<[u8; 32]> {
+    let remote_ephemeral = read_ephemeral(message)?;
+
+    // -> e, es, ss
+    // Only the keys the proxy used can authenticate the empty payload of its first message
+    let (keys, mut state) = candidates
+        .iter()
+        .find_map(|keys| {
+            let mut state = SymmetricState::new();
+            state.mix_hash(&keys.remote_public_key);
+            state.mix_hash(&keys.public_key());
+            state.mix_hash(&remote_ephemeral);
+            state.mix_key(&dh(keys.secret_key, remote_ephemeral).ok()?);
+            state.mix_key(&dh(keys.secret_key, keys.remote_public_key).ok()?);
+            state.decrypt_and_hash(&message[32..]).ok()?;
+            Some((keys, state))
+        })
+        .ok_or_else(|| anyhow!("The client does not hold any of the expected keys"))?;
+
+    // <- e, ee, se
+    let mut ephemeral_secret = [0u8; 32];
+    rand::rng().fill_bytes(&mut ephemeral_secret);
+    let ephemeral_public = MontgomeryPoint::mul_base_clamped(ephemeral_secret).to_bytes();
+    state.mix_hash(&ephemeral_public);
+    state.mix_key(&dh(ephemeral_secret, remote_ephemeral)?);
+    state.mix_key(&dh(ephemeral_secret, keys.remote_public_key)?);
+    let mut reply = ephemeral_public.to_vec();
+    reply.extend(state.encrypt_and_hash(&[])?);
+    framed.send(Bytes::from(reply)).await?;
+
+    let (recv, send) = state.split();
+    framed.codec_mut().set_transport(Transport { send, recv });
+    Ok(keys.remote_public_key)
+}
+
+async fn run_initiator<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, IpcCodec>,
+    keys: &NoiseKeys,
+) -> Result<Transport> {
+    // The static keys of both ends are known in advance, and hashed initiator first
+    let mut state = SymmetricState::new();
+    state.mix_hash(&keys.public_key());
+    state.mix_hash(&keys.remote_public_key);
+
+    let mut ephemeral_secret = [0u8; 32];
+    rand::rng().fill_bytes(&mut ephemeral_secret);
+    let ephemeral_public = MontgomeryPoint::mul_base_clamped(ephemeral_secret).to_bytes();
+
+    // -> e, es, ss
+    state.mix_hash(&ephemeral_public);
+    state.mix_key(&dh(ephemeral_secret, keys.remote_public_key)?);
+    state.mix_key(&dh(keys.secret_key, keys.remote_public_key)?);
+    let mut message = ephemeral_public.to_vec();
+    message.extend(state.encrypt_and_hash(&[])?);
+    framed.send(Bytes::from(message)).await?;
+
+    // <- e, ee, se
+    let message = receive(framed).await?;
+    let remote_ephemeral = read_ephemeral(&message)?;
+    state.mix_hash(&remote_ephemeral);
+    state.mix_key(&dh(ephemeral_secret, remote_ephemeral)?);
+    state.mix_key(&dh(keys.secret_key, remote_ephemeral)?);
+    state.decrypt_and_hash(&message[32..])?;
+
+    let (send, recv) = state.split();
+    Ok(Transport { send, recv })
+}
+
+async fn receive<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, IpcCodec>,
+) -> Result<BytesMut> {
+    framed
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Connection closed during the handshake"))?
+        .map_err(|e| anyhow!("Could not read the handshake: {e}"))
+}
+
+fn read_ephemeral(message: &[u8]) -> Result<[u8; 32]> {
+    message
+        .get(..32)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| anyhow!("Handshake message is too short"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_pairs() -> (NoiseKeys, NoiseKeys) {
+        let (app_secret, proxy_secret) = ([1u8; 32], [2u8; 32]);
+        let app = NoiseKeys {
+            secret_key: app_secret,
+            remote_public_key: MontgomeryPoint::mul_base_clamped(proxy_secret).to_bytes(),
+        };
+        let proxy = NoiseKeys {
+            secret_key: proxy_secret,
+            remote_public_key: MontgomeryPoint::mul_base_clamped(app_secret).to_bytes(),
+        };
+        (app, proxy)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (app_keys, proxy_keys) = key_pairs();
+        let (app, proxy) = tokio::io::duplex(1024);
+        let mut app = Framed::new(app, IpcCodec::new());
+        let mut proxy = Framed::new(proxy, IpcCodec::new());
+
+        // The app tries every key it holds until one matches the proxy's
+        let (other_app_keys, _) = {
+            let mut keys = key_pairs();
+            keys.0.remote_public_key = [4u8; 32];
+            keys
+        };
+        let (app_result, proxy_result) = tokio::join!(
+            async {
+                let message = receive(&mut app).await?;
+                respond(&mut app, &message, &[other_app_keys, app_keys.clone()]).await
+            },
+            initiate(&mut proxy, &proxy_keys)
+        );
+        assert_eq!(app_result.unwrap(), app_keys.remote_public_key());
+        proxy_result.unwrap();
+
+        proxy.send(Bytes::from("hello")).await.unwrap();
+        assert_eq!(app.next().await.unwrap().unwrap(), "hello");
+        app.send(Bytes::from("world")).await.unwrap();
+        assert_eq!(proxy.next().await.unwrap().unwrap(), "world");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_unknown_key() {
+        let (app_keys, _) = key_pairs();
+        let (_, other_proxy_keys) = {
+            let mut keys = key_pairs();
+            keys.1.secret_key = [3u8; 32];
+            keys
+        };
+        let (app, proxy) = tokio::io::duplex(1024);
+        let mut app = Framed::new(app, IpcCodec::new());
+        let mut proxy = Framed::new(proxy, IpcCodec::new());
+
+        let (app_result, proxy_result) = tokio::join!(
+            // The app closes the connection once the handshake fails, so the proxy stops waiting for its reply
+            async move {
+                let message = receive(&mut app).await?;
+                respond(&mut app, &message, &[app_keys]).await
+            },
+            initiate(&mut proxy, &other_proxy_keys)
+        );
+        assert!(app_result.is_err());
+        assert!(proxy_result.is_err());
+    }
+
+    #[test]
+    fn test_provision_keys() {
+        let dir = std::env::temp_dir().join(format!("noise-keys-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let app_path = dir.join(key_file_name("test", Role::App));
+        let proxy_path = dir.join(key_file_name("test", Role::Proxy));
+
+        provision_keys_in(&dir, "test").unwrap();
+        let app = NoiseKeys::load(&app_path).unwrap();
+        let proxy = NoiseKeys::load(&proxy_path).unwrap();
+        assert_eq!(app.remote_public_key, proxy.public_key());
+        assert_eq!(proxy.remote_public_key, app.public_key());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir), 0o700);
+            assert_eq!(mode(&app_path), 0o600);
+            assert_eq!(mode(&proxy_path), 0o600);
+
+            // Readable files are refused, and replaced when provisioning again
+            std::fs::set_permissions(&app_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(NoiseKeys::load(&app_path).is_err());
+            provision_keys_in(&dir, "test").unwrap();
+            assert_eq!(mode(&app_path), 0o600);
+            assert_ne!(
+                NoiseKeys::load(&app_path).unwrap().secret_key,
+                app.secret_key
+            );
+        }
+
+        // Valid keys are never rotated
+        let app = NoiseKeys::load(&app_path).unwrap();
+        provision_keys_in(&dir, "test").unwrap();
+        assert_eq!(
+            NoiseKeys::load(&app_path).unwrap().secret_key,
+            app.secret_key
+        );
+
+        // Saving never overwrites an existing file
+        assert!(app.save(&app_path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//! Identification of the processes connecting to the IPC server, so that only known executables are accepted.
+//!
+//! An allowlist only tells which executable is on the other end, not what drives it. `desktop_proxy` relays whatever
+//! it reads on stdin to the socket, so allowing it lets every process that can start it talk to the server through
+//! it. Against other processes of the same user, allowlisting the proxy adds little on its own, and the messages it
+//! relays need to be authenticated by the app, as the browser's messages are.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use interprocess::local_socket::tokio::Stream;
+use sha2::{Digest, Sha256};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+/// The stream of an accepted connection, once its peer has been identified.
+#[cfg(unix)]
+pub(super) type PeerStream = tokio::net::UnixStream;
+#[cfg(windows)]
+pub(super) type PeerStream = Stream;
+
+/// The process on the other end of an IPC connection, as far as it could be determined.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub pid: Option<u32>,
+    /// The user the process runs as. Always `None` on Windows, where pipes are already restricted to the user.
+    pub uid: Option<u32>,
+    pub executable: Option<PathBuf>,
+}
+
+impl PeerIdentity {
+    /// Read the credentials of the peer of `stream` and resolve its executable.
+    pub(super) async fn of(stream: Stream) -> std::io::Result<(PeerStream, Self)> {
+        #[cfg(unix)]
+        let (stream, pid, uid) = {
+            let Stream::UdSocket(stream) = stream;
+            let stream = tokio::net::UnixStream::from(stream);
+            let cred = stream.peer_cred()?;
+            let (pid, uid) = (cred.pid().map(|pid| pid as u32), Some(cred.uid()));
+            (stream, pid, uid)
+        };
+
+        #[cfg(windows)]
+        let (stream, pid, uid) = {
+            use std::os::windows::io::{AsHandle, AsRawHandle};
+            use windows::Win32::{Foundation::HANDLE, System::Pipes::GetNamedPipeClientProcessId};
+
+            let Stream::NamedPipe(pipe) = &stream;
+            let mut pid = 0;
+            let handle = HANDLE(pipe.as_handle().as_raw_handle());
+            let pid = unsafe { GetNamedPipeClientProcessId(handle, &mut pid) }
+                .ok()
+                .map(|_| pid);
+            (stream, pid, None)
+        };
+
+        // Resolving the executable reads the process table
+        let executable = match pid {
+            Some(pid) => tokio::task::spawn_blocking(move || executable(pid))
+                .await
+                .ok()
+                .flatten(),
+            None => None,
+        };
+        Ok((
+            stream,
+            PeerIdentity {
+                pid,
+                uid,
+                executable,
+            },
+        ))
+    }
+}
+
+fn executable(pid: u32) -> Option<PathBuf> {
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing().with_exe(UpdateKind::Always),
+    );
+    system
+        .process(pid)
+        .and_then(|process| process.exe())
+        .map(|exe| exe.to_owned())
+}
+
+/// An executable that is allowed to connect, pinned to its contents when the allowlist was created.
+#[derive(Debug, Clone)]
+pub struct AllowedExecutable {
+    path: PathBuf,
+    sha256: [u8; 32],
+}
+
+impl AllowedExecutable {
+    /// Allow the executable at `path` as it is now. Connections from a modified or replaced executable are rejected.
+    pub fn new(path: &Path) -> Result<Self> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| anyhow!("Could not resolve {}: {e}", path.display()))?;
+        let sha256 = hash(&path)?;
+        Ok(AllowedExecutable { path, sha256 })
+    }
+
+    fn matches(&self, executable: &Path) -> bool {
+        executable
+            .canonicalize()
+            .is_ok_and(|path| path == self.path)
+            && hash(executable).is_ok_and(|sha256| sha256 == self.sha256)
+    }
+}
+
+/// When a file was last changed. A file whose stamp did not change since it was hashed still has the same contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// The inode and its change time, which unlike the modification time can't be set back.
+    #[cfg(unix)]
+    inode: (u64, i64, i64),
+}
+
+impl FileStamp {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: (metadata.ino(), metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+/// The hashes of executables, by path, with the stamp of the file when it was hashed.
+type Hashes = HashMap<PathBuf, (FileStamp, [u8; 32])>;
+
+/// The executables checked so far, so each one is only read again once it changes.
+static HASHES: LazyLock<Mutex<Hashes>> = LazyLock::new(Mutex::default);
+
+fn hash(path: &Path) -> Result<[u8; 32]> {
+    let stamp = std::fs::metadata(path)
+        .map(|metadata| FileStamp::of(&metadata))
+        .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+    if let Some((cached, sha256)) = HASHES.lock().expect("Mutex is not poisoned").get(path) {
+        if *cached == stamp {
+            return Ok(*sha256);
+        }
+    }
+
+    let contents =
+        std::fs::read(path).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+    let sha256 = Sha256::digest(contents).into();
+    // Only cache the hash if the file did not change while it was read
+    if std::fs::metadata(path).is_ok_and(|metadata| FileStamp::of(&metadata) == stamp) {
+        HASHES
+            .lock()
+            .expect("Mutex is not poisoned")
+            .insert(path.to_owned(), (stamp, sha256));
+    }
+    Ok(sha256)
+}
+
+/// Which processes the IPC server accepts connections from.
+#[derive(Debug, Clone, Default)]
+pub enum PeerPolicy {
+    /// Accept every process that can reach the socket.
+    #[default]
+    AllowAll,
+    /// Only accept processes of the current user running one of these executables. See the module documentation for
+    /// what this does not protect against.
+    Allowlist(Vec<AllowedExecutable>),
+}
+
+impl PeerPolicy {
+    /// Returns the reason `peer` must be rejected, if it must be. Executables are hashed on a blocking thread, the
+    /// first time and whenever they change.
+    pub async fn check(&self, peer: &PeerIdentity) -> Result<()> {
+        if matches!(self, PeerPolicy::AllowAll) {
+            return Ok(());
+        }
+        let (policy, peer) = (self.clone(), peer.clone());
+        tokio::task::spawn_blocking(move || policy.check_blocking(&peer))
+            .await
+            .map_err(|e| anyhow!("Could not check the peer: {e}"))?
+    }
+
+    fn check_blocking(&self, peer: &PeerIdentity) -> Result<()> {
+        let PeerPolicy::Allowlist(allowed) = self else {
+            return Ok(());
+        };
+
+        #[cfg(unix)]
+        {
+            let current_uid = unsafe { libc::geteuid() };
+            if peer.uid != Some(current_uid) {
+                return Err(anyhow!(
+                    "Peer runs as user {:?}, not {current_uid}",
+                    peer.uid
+                ));
+            }
+        }
+
+        let executable = peer
+            .executable
+            .as_ref()
+            .ok_or_else(|| anyhow!("The executable of the peer could not be resolved"))?;
+        if !allowed.iter().any(|allowed| allowed.matches(executable)) {
+            return Err(anyhow!(
+                "{} is not an allowed executable",
+                executable.display()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_allowlist() {
+        let executable = std::env::temp_dir().join(format!("ipc-peer-{}", std::process::id()));
+        std::fs::write(&executable, b"executable").unwrap();
+        let mut peer = PeerIdentity {
+            pid: Some(std::process::id()),
+            #[cfg(unix)]
+            uid: Some(unsafe { libc::geteuid() }),
+            #[cfg(windows)]
+            uid: None,
+            executable: Some(executable.clone()),
+        };
+        assert!(PeerPolicy::AllowAll
+            .check(&PeerIdentity::default())
+            .await
+            .is_ok());
+
+        let policy = PeerPolicy::Allowlist(vec![AllowedExecutable::new(&executable).unwrap()]);
+        assert!(policy.check(&peer).await.is_ok());
+        assert!(policy.check(&PeerIdentity::default()).await.is_err());
+        assert!(PeerPolicy::Allowlist(vec![]).check(&peer).await.is_err());
+
+        // The executable was replaced since the allowlist was created, even if its modification time is set back
+        let modified = std::fs::metadata(&executable).unwrap().modified().unwrap();
+        std::fs::write(&executable, b"replacement").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&executable)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(policy.check(&peer).await.is_err());
+
+        peer.executable = None;
+        assert!(policy.check(&peer).await.is_err());
+        std::fs::remove_file(executable).unwrap();
+    }
+}
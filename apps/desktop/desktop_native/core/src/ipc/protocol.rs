@@ -0,0 +1,493 @@
+//! The frames exchanged over IPC connections.
+//!
+//! Every frame is a JSON encoded [`Envelope`]. A client starts with [`Envelope::Hello`] and the range of versions it
+//! speaks, and the server answers with [`Envelope::Welcome`] and the version both ends use, or with an
+//! [`Envelope::Error`] before closing the connection if there is none. Application messages are then carried as
+//! [`Envelope::Message`] payloads, which are delivered to the other application unchanged.
+//!
+//! From version 2, both ends send [`Envelope::Ping`] when the connection has been idle, which the other end answers
+//! with [`Envelope::Pong`], see [`Heartbeat`]. Since they are answered by the IPC code and not by the applications,
+//! a connection that is still alive but whose application is slow to answer its messages is not closed.
+//!
+//! From version 3, messages that don't fit in a frame are split into [`Envelope::Fragment`]s followed by a final
+//! [`Envelope::Message`], see [`message_frames`] and [`Reassembler`].
+//!
+//! From version 4, messages can be sent on a named channel, so that different applications of a server can share
+//! its socket. Messages without a channel are on the default channel, which is the only one of older versions.
+
+use std::{future::pending, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::NATIVE_MESSAGING_BUFFER_SIZE;
+
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u32 = 4;
+/// The oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The first protocol version with [`Envelope::Ping`] and [`Envelope::Pong`].
+pub const HEARTBEAT_VERSION: u32 = 2;
+/// The first protocol version with [`Envelope::Fragment`].
+pub const FRAGMENT_VERSION: u32 = 3;
+/// The first protocol version with channels in [`Envelope::Message`].
+pub const CHANNEL_VERSION: u32 = 4;
+
+/// The longest channel name, so that it always fits in a frame along with a fragment.
+const MAX_CHANNEL_LEN: usize = 64;
+
+/// The size of the JSON encoded payload of a fragment, leaving room for the rest of the envelope in the frame.
+const FRAGMENT_SIZE: usize = NATIVE_MESSAGING_BUFFER_SIZE - 1024;
+
+/// How long either end waits for the other to send its part of the negotiation.
+pub(super) const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Envelope {
+    #[serde(rename_all = "camelCase")]
+    Hello {
+        min_version: u32,
+        max_version: u32,
+    },
+    Welcome {
+        version: u32,
+    },
+    Message {
+        payload: String,
+        /// The channel of the message, or `None` for the default channel.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+    },
+    /// The start of a message too large for a single frame. The following fragments are appended to it, up to the
+    /// [`Envelope::Message`] with its end.
+    Fragment {
+        payload: String,
+    },
+    /// Asks the other end to answer with [`Envelope::Pong`], to check that it is still alive.
+    Ping,
+    Pong,
+    /// The other end could not handle a frame. Errors during the negotiation close the connection.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The versions spoken by both ends don't overlap.
+    UnsupportedVersion,
+    /// The connection didn't start with [`Envelope::Hello`].
+    HelloExpected,
+    /// The frame is not a known [`Envelope`], possibly from a newer version.
+    UnknownMessage,
+    /// The fragments of a message add up to more than the other end accepts. The message was dropped.
+    MessageTooLarge,
+    /// Nobody listens on the channel of the message, or the client is not allowed on it. The message was dropped.
+    ChannelRefused,
+}
+
+impl Envelope {
+    /// The [`Envelope::Hello`] of this build.
+    pub fn hello() -> Self {
+        Envelope::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Envelope::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn message(payload: impl Into<String>) -> Self {
+        Envelope::Message {
+            payload: payload.into(),
+            channel: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Envelopes can be serialized")
+    }
+}
+
+/// Pick the version to use with a client that speaks `min_version` to `max_version`, as the server.
+pub fn negotiate(min_version: u32, max_version: u32) -> Result<u32, String> {
+    let version = max_version.min(PROTOCOL_VERSION);
+    if version < min_version.max(MIN_PROTOCOL_VERSION) {
+        return Err(format!(
+            "Versions {min_version} to {max_version} are not supported, expected {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+        ));
+    }
+    Ok(version)
+}
+
+/// Returns why `channel` can't be used as the name of a channel, if it can't. Names are made of at most 64 ASCII
+/// letters, digits, `.`, `_` and `-`.
+pub fn check_channel(channel: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+    if channel.is_empty() || channel.len() > MAX_CHANNEL_LEN || !channel.chars().all(valid) {
+        return Err(format!("{channel:?} is not a valid channel name"));
+    }
+    Ok(())
+}
+
+/// The frames to send for the message `payload` on `channel`, split into fragments if both ends speak a version
+/// that has them and it doesn't fit in a single frame. The channel must have been checked with [`check_channel`].
+pub(super) fn message_frames(payload: &str, channel: Option<&str>, version: u32) -> Vec<String> {
+    let end = |payload: &str| {
+        Envelope::Message {
+            payload: payload.to_owned(),
+            channel: channel.map(str::to_owned),
+        }
+        .to_json()
+    };
+    if version < FRAGMENT_VERSION || escaped_len(payload) <= FRAGMENT_SIZE {
+        return vec![end(payload)];
+    }
+
+    let mut frames = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (index, c) in payload.char_indices() {
+        let len = escaped_char_len(c);
+        if size + len > FRAGMENT_SIZE {
+            frames.push(
+                Envelope::Fragment {
+                    payload: payload[start..index].to_owned(),
+                }
+                .to_json(),
+            );
+            start = index;
+            size = 0;
+        }
+        size += len;
+    }
+    frames.push(end(&payload[start..]));
+    frames
+}
+
+/// The length of `payload` once encoded as a JSON string, without the quotes.
+fn escaped_len(payload: &str) -> usize {
+    payload.chars().map(escaped_char_len).sum()
+}
+
+fn escaped_char_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0C}' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+    }
+}
+
+/// Puts the messages split by [`message_frames`] back together.
+pub(super) struct Reassembler {
+    max_message_size: usize,
+    buffer: String,
+    too_large: bool,
+}
+
+impl Reassembler {
+    pub(super) fn new(max_message_size: usize) -> Self {
+        Reassembler {
+            max_message_size,
+            buffer: String::new(),
+            too_large: false,
+        }
+    }
+
+    /// Add the payload of an [`Envelope::Fragment`].
+    pub(super) fn fragment(&mut self, payload: String) {
+        if self.too_large || self.buffer.len() + payload.len() > self.max_message_size {
+            // The rest of the message is dropped, but its end is still expected
+            self.too_large = true;
+            self.buffer = String::new();
+            return;
+        }
+        self.buffer.push_str(&payload);
+    }
+
+    /// Complete the message with the payload of an [`Envelope::Message`]. Returns the reason if it is too large.
+    pub(super) fn message(&mut self, payload: String) -> Result<String, String> {
+        let size = self.buffer.len() + payload.len();
+        if std::mem::take(&mut self.too_large) || size > self.max_message_size {
+            self.buffer = String::new();
+            return Err(format!(
+                "The message is larger than {} bytes",
+                self.max_message_size
+            ));
+        }
+        if self.buffer.is_empty() {
+            return Ok(payload);
+        }
+        let mut message = std::mem::take(&mut self.buffer);
+        message.push_str(&payload);
+        Ok(message)
+    }
+}
+
+/// The state of an established connection, shared by the client and the server.
+pub(super) struct Link {
+    pub(super) version: u32,
+    pub(super) heartbeat: Heartbeat,
+    pub(super) reassembler: Reassembler,
+}
+
+impl Link {
+    pub(super) fn new(
+        version: u32,
+        heartbeat: Option<HeartbeatOptions>,
+        max_message_size: usize,
+    ) -> Self {
+        Link {
+            version,
+            heartbeat: Heartbeat::new(heartbeat, version),
+            reassembler: Reassembler::new(max_message_size),
+        }
+    }
+}
+
+/// How often the ends of a connection check on each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatOptions {
+    /// How long the connection can be idle before a [`Envelope::Ping`] is sent.
+    pub interval: Duration,
+    /// How long without any frame from the other end before it is considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        HeartbeatOptions {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Keeps track of when the other end of a connection was last heard from. The times are absolute, so that waiting
+/// for [`Heartbeat::due`] again, as a `select!` loop does for every frame sent, doesn't push them back.
+pub(super) struct Heartbeat {
+    options: Option<HeartbeatOptions>,
+    /// When to send the next [`Envelope::Ping`], unless a frame is received first.
+    next_ping: Instant,
+    /// When the other end is considered dead, unless a frame is received first.
+    deadline: Instant,
+}
+
+impl Heartbeat {
+    /// A heartbeat with `options`, if both ends speak a version that has one.
+    pub(super) fn new(options: Option<HeartbeatOptions>, version: u32) -> Self {
+        let options = options.filter(|_| version >= HEARTBEAT_VERSION);
+        let now = Instant::now();
+        let HeartbeatOptions { interval, timeout } = options.unwrap_or_default();
+        Heartbeat {
+            options,
+            next_ping: now + interval,
+            deadline: now + timeout,
+        }
+    }
+
+    /// Record that a frame was received from the other end.
+    pub(super) fn seen(&mut self) {
+        if let Some(options) = self.options {
+            let now = Instant::now();
+            self.next_ping = now + options.interval;
+            self.deadline = now + options.timeout;
+        }
+    }
+
+    /// Wait until a [`Envelope::Ping`] must be sent, or return the reason the other end is considered dead. Never
+    /// returns if the heartbeat is disabled. Pings are sent every interval until a frame is received.
+    pub(super) async fn due(&mut self) -> Result<(), String> {
+        let Some(options) = self.options else {
+            return pending().await;
+        };
+
+        tokio::time::sleep_until(self.next_ping.min(self.deadline)).await;
+        let now = Instant::now();
+        if now >= self.deadline {
+            let idle = now - (self.deadline - options.timeout);
+            return Err(format!("No heartbeat for {idle:?}"));
+        }
+        self.next_ping = now + options.interval;
+        Ok(())
+    }
+}
+
+/// The events the IPC client reports to the application alongside the messages from the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum Command {
+    Connected,
+    Disconnected {
+        /// Why the connection was lost, if it was not closed by the server. For example, a server that stopped
+        /// answering heartbeats is gone, while one that is only slow to answer its messages is not disconnected.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+}
+
+impl Command {
+    pub fn from_json(message: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(message)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Commands can be serialized")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        assert_eq!(
+            Envelope::hello().to_json(),
+            r#"{"type":"hello","minVersion":1,"maxVersion":4}"#
+        );
+        assert_eq!(
+            Envelope::message(r#"{"command":"unlock"}"#).to_json(),
+            r#"{"type":"message","payload":"{\"command\":\"unlock\"}"}"#
+        );
+        assert!(serde_json::from_str::<Envelope>(r#"{"type":"goodbye"}"#).is_err());
+        assert_eq!(Envelope::Ping.to_json(), r#"{"type":"ping"}"#);
+        assert_eq!(Command::Connected.to_json(), r#"{"command":"connected"}"#);
+        assert_eq!(
+            Command::Disconnected { reason: None }.to_json(),
+            r#"{"command":"disconnected"}"#
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(1, 1), Ok(1));
+        assert_eq!(negotiate(1, PROTOCOL_VERSION + 1), Ok(PROTOCOL_VERSION));
+        assert!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2).is_err());
+    }
+
+    #[test]
+    fn test_check_channel() {
+        assert!(check_channel("pkcs11").is_ok());
+        assert!(check_channel("ssh-agent.askpass").is_ok());
+        assert!(check_channel("").is_err());
+        assert!(check_channel("a\"b").is_err());
+        assert!(check_channel(&"a".repeat(MAX_CHANNEL_LEN + 1)).is_err());
+        assert_eq!(
+            message_frames("{}", Some("pkcs11"), CHANNEL_VERSION),
+            [r#"{"type":"message","payload":"{}","channel":"pkcs11"}"#]
+        );
+    }
+
+    #[test]
+    fn test_fragments() {
+        let small = "{\"command\":\"unlock\"}";
+        assert_eq!(message_frames(small, None, FRAGMENT_VERSION).len(), 1);
+
+        // Quotes take twice as much room once escaped
+        let large = "\"".repeat(NATIVE_MESSAGING_BUFFER_SIZE) + "é".repeat(1000).as_str();
+        assert_eq!(message_frames(&large, None, HEARTBEAT_VERSION).len(), 1);
+        let frames = message_frames(&large, Some("pkcs11"), FRAGMENT_VERSION);
+        assert_eq!(frames.len(), 3);
+        assert!(frames
+            .iter()
+            .all(|frame| frame.len() <= NATIVE_MESSAGING_BUFFER_SIZE));
+
+        let mut reassembler = Reassembler::new(large.len());
+        let mut message = None;
+        for frame in frames {
+            match serde_json::from_str(&frame).unwrap() {
+                Envelope::Fragment { payload } => reassembler.fragment(payload),
+                Envelope::Message { payload, channel } => {
+                    assert_eq!(channel.as_deref(), Some("pkcs11"));
+                    message = Some(reassembler.message(payload));
+                }
+                envelope => panic!("Unexpected {envelope:?}"),
+            }
+        }
+        assert_eq!(message, Some(Ok(large.clone())));
+
+        let mut reassembler = Reassembler::new(large.len() - 1);
+        for frame in message_frames(&large, None, FRAGMENT_VERSION) {
+            match serde_json::from_str(&frame).unwrap() {
+                Envelope::Fragment { payload } => reassembler.fragment(payload),
+                Envelope::Message { payload, .. } => {
+                    assert!(reassembler.message(payload).is_err())
+                }
+                envelope => panic!("Unexpected {envelope:?}"),
+            }
+        }
+        assert_eq!(reassembler.message(small.to_owned()).unwrap(), small);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let options = HeartbeatOptions {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+        };
+
+        // Version 1 has no heartbeat
+        let mut heartbeat = Heartbeat::new(Some(options), 1);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), heartbeat.due())
+                .await
+                .is_err()
+        );
+
+        let mut heartbeat = Heartbeat::new(Some(options), HEARTBEAT_VERSION);
+        let start = Instant::now();
+        assert_eq!(heartbeat.due().await, Ok(()));
+        heartbeat.seen();
+        assert_eq!(heartbeat.due().await, Ok(()));
+        while heartbeat.due().await.is_ok() {}
+        assert!(start.elapsed() >= options.timeout);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_one_way_traffic() {
+        let options = HeartbeatOptions {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+        let mut sender = Heartbeat::new(Some(options), HEARTBEAT_VERSION);
+        let mut receiver = Heartbeat::new(Some(options), HEARTBEAT_VERSION);
+        let mut traffic = tokio::time::interval(Duration::from_millis(2));
+
+        // The sender sends a frame more often than the interval and never hears back, like a `select!` loop that
+        // waits for the heartbeat again after every frame. It still pings, and gives up after the timeout.
+        let start = Instant::now();
+        let mut pings = 0;
+        let (mut receiver_pings, mut receiver_result) = (0, Ok(()));
+        let result = loop {
+            tokio::select! {
+                due = sender.due() => match due {
+                    Ok(()) => pings += 1,
+                    Err(reason) => break reason,
+                },
+                due = receiver.due() => match due {
+                    Ok(()) => receiver_pings += 1,
+                    Err(reason) => receiver_result = Err(reason),
+                },
+                _ = traffic.tick() => receiver.seen(),
+            }
+        };
+        let elapsed = start.elapsed();
+        assert!(result.contains("No heartbeat"));
+        assert!(elapsed >= options.timeout && elapsed < options.timeout * 5);
+        assert!(pings >= 3);
+
+        // The receiver hears from the sender all the time, so it has no reason to ping
+        assert_eq!(receiver_pings, 0);
+        assert_eq!(receiver_result, Ok(()));
+    }
+}
@@ -0,0 +1,265 @@
+//! Requests and their responses over an IPC connection.
+//!
+//! Every request is sent as an [`RpcMessage`] with a sequence number, and the other end answers with an
+//! [`RpcMessage`] carrying the same sequence number. [`RpcClient`] keeps track of the pending requests on the
+//! requesting end, while the answering end only needs [`RpcMessage`] to parse the requests and build the responses.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{runtime::Handle, sync::mpsc, task::AbortHandle};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum RpcError {
+    /// The other end could not handle the request.
+    #[error("{0}")]
+    Internal(String),
+    #[error("The request timed out")]
+    Timeout,
+    #[error("The request was cancelled")]
+    Cancelled,
+    /// Too many requests are waiting to be sent. Nothing was sent, so the request can be retried later.
+    #[error("Too many requests are waiting to be sent, try again later")]
+    Busy,
+}
+
+/// A request, or the response to the request with the same sequence number.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcMessage<T> {
+    pub sequence_number: u32,
+    pub value: Result<T, RpcError>,
+}
+
+impl<T: DeserializeOwned> RpcMessage<T> {
+    pub fn from_json(message: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(message)
+    }
+}
+
+impl<T: Serialize> RpcMessage<T> {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+type Callback = Box<dyn FnOnce(Result<serde_json::Value, RpcError>) + Send>;
+
+struct Pending {
+    callback: Callback,
+    started: Instant,
+    timeout: AbortHandle,
+}
+
+type PendingRequests = Arc<Mutex<HashMap<u32, Pending>>>;
+
+/// Sends requests and calls their callback once they are answered, time out or are cancelled, whichever
+/// happens first.
+pub struct RpcClient {
+    send: mpsc::Sender<String>,
+    runtime: Handle,
+    next_sequence_number: AtomicU32,
+    pending: PendingRequests,
+}
+
+impl RpcClient {
+    /// Create a client sending its requests on `send`. The responses have to be passed to [`RpcClient::dispatch`],
+    /// and the timeouts run on `runtime`.
+    pub fn new(send: mpsc::Sender<String>, runtime: Handle) -> Self {
+        RpcClient {
+            send,
+            runtime,
+            next_sequence_number: AtomicU32::new(0),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Send `request`, and call `callback` with the response, or with [`RpcError::Timeout`] if there is none after
+    /// `timeout`. Returns the sequence number of the request, which can be passed to [`RpcClient::cancel`].
+    ///
+    /// This never blocks, so it can be called from any thread, async or not. Instead of waiting for room in the
+    /// channel, the callback is called with [`RpcError::Busy`] right away if the channel is full.
+    pub fn call<Req, Res>(
+        &self,
+        request: Req,
+        timeout: Duration,
+        callback: impl FnOnce(Result<Res, RpcError>) + Send + 'static,
+    ) -> u32
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let sequence_number = self.next_sequence_number.fetch_add(1, Ordering::SeqCst);
+        let callback: Callback = Box::new(move |value| {
+            callback(value.and_then(|value| {
+                serde_json::from_value(value)
+                    .map_err(|e| RpcError::Internal(format!("Invalid response: {e}")))
+            }))
+        });
+
+        let message = match (RpcMessage {
+            sequence_number,
+            value: Ok(request),
+        })
+        .to_json()
+        {
+            Ok(message) => message,
+            Err(e) => {
+                callback(Err(RpcError::Internal(format!("Invalid request: {e}"))));
+                return sequence_number;
+            }
+        };
+
+        // Registered before sending, so that a fast response can't arrive before its request is known. The lock
+        // is held until then, so the timeout can't fire before either.
+        let mut pending = self.pending.lock().expect("Mutex is not poisoned");
+        let pending_requests = self.pending.clone();
+        let timeout = self
+            .runtime
+            .spawn(async move {
+                tokio::time::sleep(timeout).await;
+                let request = pending_requests
+                    .lock()
+                    .expect("Mutex is not poisoned")
+                    .remove(&sequence_number);
+                if let Some(request) = request {
+                    warn!("Request {sequence_number} timed out");
+                    (request.callback)(Err(RpcError::Timeout));
+                }
+            })
+            .abort_handle();
+        pending.insert(
+            sequence_number,
+            Pending {
+                callback,
+                started: Instant::now(),
+                timeout,
+            },
+        );
+        drop(pending);
+
+        let error = match self.send.try_send(message) {
+            Ok(()) => return sequence_number,
+            Err(mpsc::error::TrySendError::Full(_)) => RpcError::Busy,
+            Err(e) => RpcError::Internal(format!("Error sending request: {e}")),
+        };
+        self.complete(sequence_number, Err(error));
+        sequence_number
+    }
+
+    /// Stop waiting for the response to a request and call its callback with [`RpcError::Cancelled`]. Returns
+    /// whether the request was still pending.
+    pub fn cancel(&self, sequence_number: u32) -> bool {
+        self.complete(sequence_number, Err(RpcError::Cancelled))
+    }
+
+    /// Call the callback of the request `message` answers. Returns an error if `message` is not a response.
+    pub fn dispatch(&self, message: &str) -> Result<(), serde_json::Error> {
+        let response = RpcMessage::<serde_json::Value>::from_json(message)?;
+        if !self.complete(response.sequence_number, response.value) {
+            // Most likely the request timed out or was cancelled
+            warn!(
+                "Received a response to unknown request {}",
+                response.sequence_number
+            );
+        }
+        Ok(())
+    }
+
+    fn complete(&self, sequence_number: u32, value: Result<serde_json::Value, RpcError>) -> bool {
+        let request = self
+            .pending
+            .lock()
+            .expect("Mutex is not poisoned")
+            .remove(&sequence_number);
+        let Some(request) = request else {
+            return false;
+        };
+
+        request.timeout.abort();
+        debug!(
+            "Request {sequence_number} completed in {:?}",
+            request.started.elapsed()
+        );
+        (request.callback)(value);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Response = Result<String, RpcError>;
+
+    fn result_channel() -> (
+        impl FnOnce(Response) + Send + 'static,
+        tokio::sync::oneshot::Receiver<Response>,
+    ) {
+        let (send, recv) = tokio::sync::oneshot::channel();
+        (move |result| send.send(result).unwrap(), recv)
+    }
+
+    #[tokio::test]
+    async fn test_call() {
+        let (send, mut recv) = mpsc::channel(8);
+        let client = RpcClient::new(send, Handle::current());
+
+        let (callback, result) = result_channel();
+        client.call("ping".to_owned(), Duration::from_secs(10), callback);
+        let request = RpcMessage::<String>::from_json(&recv.recv().await.unwrap()).unwrap();
+        assert_eq!(request.value, Ok("ping".to_owned()));
+
+        let response = RpcMessage {
+            sequence_number: request.sequence_number,
+            value: Ok("pong".to_owned()),
+        };
+        client.dispatch(&response.to_json().unwrap()).unwrap();
+        assert_eq!(result.await.unwrap(), Ok("pong".to_owned()));
+
+        // Already answered
+        assert!(!client.cancel(request.sequence_number));
+        assert!(client.dispatch(r#"{"command":"connected"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_and_cancel() {
+        let (send, _recv) = mpsc::channel(8);
+        let client = RpcClient::new(send, Handle::current());
+
+        let (callback, result) = result_channel();
+        client.call("ping".to_owned(), Duration::from_millis(10), callback);
+        assert_eq!(result.await.unwrap(), Err(RpcError::Timeout));
+
+        let (callback, result) = result_channel();
+        let sequence_number = client.call("ping".to_owned(), Duration::from_secs(10), callback);
+        assert!(client.cancel(sequence_number));
+        assert_eq!(result.await.unwrap(), Err(RpcError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn test_busy() {
+        let (send, mut recv) = mpsc::channel(1);
+        let client = RpcClient::new(send, Handle::current());
+
+        let (callback, _result) = result_channel();
+        client.call("ping".to_owned(), Duration::from_secs(10), callback);
+        let (callback, result) = result_channel();
+        client.call("ping".to_owned(), Duration::from_secs(10), callback);
+        assert_eq!(result.await.unwrap(), Err(RpcError::Busy));
+
+        // There is room again once the first request is sent
+        recv.recv().await.unwrap();
+        let (callback, mut result) = result_channel();
+        client.call("ping".to_owned(), Duration::from_secs(10), callback);
+        assert!(recv.recv().await.is_some());
+        assert!(result.try_recv().is_err());
+    }
+}
@@ -1,27 +1,42 @@
 use std::{
+    collections::{HashMap, VecDeque},
     error::Error,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
 };
 
-use futures::{SinkExt, StreamExt, TryFutureExt};
+use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use interprocess::local_socket::{tokio::prelude::*, GenericFilePath, ListenerOptions};
-use log::{error, info};
+use log::{error, info, warn};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::{broadcast, mpsc},
+    sync::{mpsc, Notify},
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{codec::Framed, sync::CancellationToken};
 
-use super::MESSAGE_CHANNEL_BUFFER;
+use super::{
+    noise::{self, IpcCodec, NoiseKeys},
+    peer::{PeerIdentity, PeerPolicy},
+    protocol::{self, Envelope, ErrorCode, HeartbeatOptions, Link, NEGOTIATION_TIMEOUT},
+    MAX_MESSAGE_SIZE, MESSAGE_CHANNEL_BUFFER,
+};
 
 #[derive(Debug)]
 pub struct Message {
     pub client_id: u32,
     pub kind: MessageType,
-    // This value should be Some for MessageType::Message and None for the rest
+    // This value should be Some for MessageType::Message, MessageType::Disconnected and MessageType::Rejected and None
+    // for the rest. For the last two, it contains the reason.
     pub message: Option<String>,
+    // This value should be Some for MessageType::Connected and MessageType::Rejected and None for the rest
+    pub peer: Option<PeerIdentity>,
+    /// The channel of the message, or `None` for the default channel, see [`Server::subscribe`].
+    pub channel: Option<String>,
 }
 
 #[derive(Debug)]
@@ -29,12 +44,214 @@ pub enum MessageType {
     Connected,
     Disconnected,
     Message,
+    /// A process that is not allowed by the [`PeerPolicy`], or that failed the encryption handshake, tried to
+    /// connect and was disconnected. The message contains the reason.
+    ///
+    /// Clients that are not allowed on a channel are also reported, on the default channel, but stay connected.
+    Rejected,
+}
+
+/// What to do with a message for a client whose queue is full, because it doesn't read its messages as fast as they
+/// are sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Drop the oldest message in the queue to make room.
+    DropOldest,
+    /// Disconnect the client, which is reported with a [`MessageType::Disconnected`] message.
+    #[default]
+    Disconnect,
+    /// Wait until the client has read enough messages to make room.
+    Backpressure,
+}
+
+/// How the IPC server accepts and secures its connections.
+pub struct ServerOptions {
+    /// Which processes may connect.
+    pub policy: PeerPolicy,
+    /// If set, clients must complete the handshake of [`noise`] with these keys, and all messages are encrypted.
+    /// Otherwise, clients may still complete it with the keys of a channel, see [`Server::subscribe_with_keys`].
+    pub keys: Option<NoiseKeys>,
+    pub slow_client_policy: SlowClientPolicy,
+    /// The number of messages that can be waiting to be written to each client.
+    pub client_queue_capacity: usize,
+    /// How clients that are gone without closing their connection are detected, or `None` to wait for the OS to
+    /// notice.
+    pub heartbeat: Option<HeartbeatOptions>,
+    /// The size of the largest message accepted from a client, once its fragments are put back together.
+    pub max_message_size: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            policy: PeerPolicy::default(),
+            keys: None,
+            slow_client_policy: SlowClientPolicy::default(),
+            client_queue_capacity: MESSAGE_CHANNEL_BUFFER,
+            heartbeat: Some(HeartbeatOptions::default()),
+            max_message_size: MAX_MESSAGE_SIZE,
+        }
+    }
 }
 
+/// The messages waiting to be written to a client.
+struct ClientQueue {
+    client_id: u32,
+    /// The protocol version negotiated with the client, or 0 until then.
+    version: AtomicU32,
+    capacity: usize,
+    policy: SlowClientPolicy,
+    state: Mutex<QueueState>,
+    /// Notified when a message is pushed, or when the client must be disconnected.
+    pushed: Notify,
+    /// Notified when a message is popped, or when the client is gone.
+    popped: Notify,
+}
+
+/// A message waiting to be written to a client.
+#[derive(Debug, PartialEq, Eq)]
+struct Outgoing {
+    channel: Option<String>,
+    payload: String,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Outgoing>,
+    /// Why the client must be disconnected, if it must.
+    disconnect: Option<String>,
+    closed: bool,
+}
+
+impl ClientQueue {
+    fn new(client_id: u32, capacity: usize, policy: SlowClientPolicy) -> Self {
+        ClientQueue {
+            client_id,
+            version: AtomicU32::new(0),
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::default(),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        }
+    }
+
+    /// Queue `message`, applying the [`SlowClientPolicy`] if the queue is full.
+    async fn push(&self, message: Outgoing) -> Result<()> {
+        let client_id = self.client_id;
+        loop {
+            // Registered before checking the queue, so that a message popped in between is not missed
+            let popped = self.popped.notified();
+            tokio::pin!(popped);
+            popped.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().expect("Mutex is not poisoned");
+                if state.closed || state.disconnect.is_some() {
+                    return Err(anyhow!("Client {client_id} is not connected"));
+                }
+                if state.messages.len() < self.capacity {
+                    state.messages.push_back(message);
+                    self.pushed.notify_one();
+                    return Ok(());
+                }
+
+                match self.policy {
+                    SlowClientPolicy::DropOldest => {
+                        warn!("Client {client_id} is not reading its messages, dropping the oldest one");
+                        state.messages.pop_front();
+                        state.messages.push_back(message);
+                        return Ok(());
+                    }
+                    SlowClientPolicy::Disconnect => {
+                        let reason = format!(
+                            "Client {client_id} is not reading its messages, {} are queued",
+                            state.messages.len()
+                        );
+                        warn!("{reason}");
+                        state.disconnect = Some(reason.clone());
+                        self.pushed.notify_one();
+                        return Err(anyhow!(reason));
+                    }
+                    SlowClientPolicy::Backpressure => {}
+                }
+            }
+
+            popped.await;
+        }
+    }
+
+    /// Wait for the next message to write, or for the reason to disconnect the client.
+    async fn pop(&self) -> Result<Outgoing, String> {
+        loop {
+            {
+                let mut state = self.state.lock().expect("Mutex is not poisoned");
+                if let Some(reason) = &state.disconnect {
+                    return Err(reason.clone());
+                }
+                if let Some(message) = state.messages.pop_front() {
+                    self.popped.notify_waiters();
+                    return Ok(message);
+                }
+            }
+            // A notification sent since the check is kept until this
+            self.pushed.notified().await;
+        }
+    }
+
+    /// Fail the pending and future pushes, once the client is gone.
+    fn close(&self) {
+        self.state.lock().expect("Mutex is not poisoned").closed = true;
+        self.popped.notify_waiters();
+    }
+}
+
+/// The queues of the connected clients, by client id.
+type Clients = Arc<Mutex<HashMap<u32, Arc<ClientQueue>>>>;
+
+/// A channel that clients can send messages on, see [`Server::subscribe`].
+struct Channel {
+    send: mpsc::Sender<Message>,
+    policy: PeerPolicy,
+    keys: Option<NoiseKeys>,
+    /// The clients allowed on the channel, which are the only ones its messages are sent to.
+    members: HashMap<u32, Member>,
+    /// The clients that are not allowed on the channel, and why.
+    refused: HashMap<u32, String>,
+}
+
+/// A client that sent messages on a channel.
+#[derive(Clone)]
+struct Member {
+    peer: PeerIdentity,
+    /// The public key the client authenticated with in the handshake of [`noise`], if it did.
+    authenticated: Option<[u8; 32]>,
+}
+
+impl Member {
+    /// Check that the client may use a channel subscribed with `policy` and `keys`. Returns the reason if not.
+    async fn check(&self, policy: &PeerPolicy, keys: Option<&NoiseKeys>) -> Result<(), String> {
+        if let Some(keys) = keys {
+            if self.authenticated != Some(keys.remote_public_key()) {
+                return Err(
+                    "The client did not authenticate with the keys of the channel".to_owned(),
+                );
+            }
+        }
+        policy.check(&self.peer).await.map_err(|e| e.to_string())
+    }
+}
+
+/// The subscribed channels, by name.
+type Channels = Arc<Mutex<HashMap<String, Channel>>>;
+
 pub struct Server {
     pub path: PathBuf,
     cancel_token: CancellationToken,
-    server_to_clients_send: broadcast::Sender<String>,
+    clients: Clients,
+    channels: Channels,
+    /// The default channel, which doesn't keep the application's receiver open once the server is stopped.
+    default: mpsc::WeakSender<Message>,
 }
 
 impl Server {
@@ -47,6 +264,18 @@ impl Server {
     pub fn start(
         path: &Path,
         client_to_server_send: mpsc::Sender<Message>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::start_with_options(path, client_to_server_send, ServerOptions::default())
+    }
+
+    /// Create and start the IPC server without blocking, only accepting the clients allowed by `options`.
+    ///
+    /// Rejected clients are disconnected before any of their messages are read, and reported with a
+    /// [`MessageType::Rejected`] message.
+    pub fn start_with_options(
+        path: &Path,
+        client_to_server_send: mpsc::Sender<Message>,
+        options: ServerOptions,
     ) -> Result<Self, Box<dyn Error>> {
         // If the unix socket file already exists, we get an error when trying to bind to it. So we remove it first.
         // Any processes that were using the old socket should remain connected to it but any new connections will use the new socket.
@@ -58,26 +287,28 @@ impl Server {
         let opts = ListenerOptions::new().name(name);
         let listener = opts.create_tokio()?;
 
-        // This broadcast channel is used for sending messages to all connected clients, and so the sender
-        // will be stored in the server while the receiver will be cloned and passed to each client handler.
-        let (server_to_clients_send, server_to_clients_recv) =
-            broadcast::channel::<String>(MESSAGE_CHANNEL_BUFFER);
-
         // This cancellation token allows us to cleanly stop the server and all the spawned
         // tasks without having to wait on all the pending tasks finalizing first
         let cancel_token = CancellationToken::new();
 
+        let clients = Clients::default();
+        let channels = Channels::default();
+
         // Create the server and start listening for incoming connections
         // in a separate task to avoid blocking the current task
         let server = Server {
             path: path.to_owned(),
             cancel_token: cancel_token.clone(),
-            server_to_clients_send,
+            clients: clients.clone(),
+            channels: channels.clone(),
+            default: client_to_server_send.downgrade(),
         };
         tokio::spawn(listen_incoming(
             listener,
             client_to_server_send,
-            server_to_clients_recv,
+            clients,
+            channels,
+            Arc::new(options),
             cancel_token,
         ));
 
@@ -86,16 +317,178 @@ impl Server {
 
     /// Send a message over the IPC server to all the connected clients
     ///
+    /// With [`SlowClientPolicy::Backpressure`], this waits until every client has room for the message.
+    ///
     /// # Returns
     ///
     /// The number of clients that the message was sent to. Note that the number of messages
     /// sent may be less than the number of connected clients if some clients disconnect while
     /// the message is being sent.
-    pub fn send(&self, message: String) -> Result<usize> {
-        let sent = self.server_to_clients_send.send(message)?;
+    pub async fn broadcast(&self, message: String) -> Result<usize> {
+        let clients: Vec<_> = self
+            .clients
+            .lock()
+            .expect("Mutex is not poisoned")
+            .values()
+            .cloned()
+            .collect();
+        let mut sent = 0;
+        for client in clients {
+            let message = Outgoing {
+                channel: None,
+                payload: message.clone(),
+            };
+            if client.push(message).await.is_ok() {
+                sent += 1;
+            }
+        }
         Ok(sent)
     }
 
+    /// Send a message over the IPC server to the client `client_id` only.
+    ///
+    /// Fails if the client has disconnected, or if it is disconnected by [`SlowClientPolicy::Disconnect`]. With
+    /// [`SlowClientPolicy::Backpressure`], this waits until the client has room for the message.
+    pub async fn send_to(&self, client_id: u32, message: String) -> Result<()> {
+        self.push(client_id, None, message).await
+    }
+
+    /// Send a message to the client `client_id` on `channel`, like [`Server::send_to`].
+    ///
+    /// Also fails if the client doesn't support channels, or if it is not allowed on `channel`, which it joins by
+    /// sending a message on it.
+    pub async fn send_on(&self, client_id: u32, channel: &str, message: String) -> Result<()> {
+        protocol::check_channel(channel).map_err(|e| anyhow!(e))?;
+        let client = self.client(client_id)?;
+        if client.version.load(Ordering::Acquire) < protocol::CHANNEL_VERSION {
+            return Err(anyhow!("Client {client_id} does not support channels"));
+        }
+        let member = self
+            .channels
+            .lock()
+            .expect("Mutex is not poisoned")
+            .get(channel)
+            .is_some_and(|subscriber| subscriber.members.contains_key(&client_id));
+        if !member {
+            return Err(anyhow!("Client {client_id} is not on channel {channel}"));
+        }
+        client
+            .push(Outgoing {
+                channel: Some(channel.to_owned()),
+                payload: message,
+            })
+            .await
+    }
+
+    /// Send a message to all the clients on `channel`, like [`Server::broadcast`].
+    ///
+    /// # Returns
+    ///
+    /// The number of clients that the message was sent to.
+    pub async fn broadcast_on(&self, channel: &str, message: String) -> Result<usize> {
+        protocol::check_channel(channel).map_err(|e| anyhow!(e))?;
+        let members: Vec<_> = self
+            .channels
+            .lock()
+            .expect("Mutex is not poisoned")
+            .get(channel)
+            .map(|subscriber| subscriber.members.keys().copied().collect())
+            .unwrap_or_default();
+        let mut sent = 0;
+        for client_id in members {
+            if self
+                .send_on(client_id, channel, message.clone())
+                .await
+                .is_ok()
+            {
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn push(&self, client_id: u32, channel: Option<String>, payload: String) -> Result<()> {
+        self.client(client_id)?
+            .push(Outgoing { channel, payload })
+            .await
+    }
+
+    fn client(&self, client_id: u32) -> Result<Arc<ClientQueue>> {
+        self.clients
+            .lock()
+            .expect("Mutex is not poisoned")
+            .get(&client_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Client {client_id} is not connected"))
+    }
+
+    /// Receive the messages that clients send on `channel`, from the clients allowed by `policy`. This is on top of
+    /// the [`PeerPolicy`] of the server, and replaces the previous subscriber of the channel, if any.
+    ///
+    /// The subscriber gets a [`MessageType::Connected`] message the first time a client sends a message on the
+    /// channel, and a [`MessageType::Disconnected`] message once that client is gone.
+    ///
+    /// The clients on the channel are checked again against `policy`. Those it allows are reported to the new
+    /// subscriber with a [`MessageType::Connected`] message, and the others are evicted and reported with a
+    /// [`MessageType::Rejected`] message on the default channel.
+    pub async fn subscribe(
+        &self,
+        channel: &str,
+        policy: PeerPolicy,
+    ) -> Result<mpsc::Receiver<Message>> {
+        self.subscribe_with_keys(channel, policy, None).await
+    }
+
+    /// Like [`Server::subscribe`], but if `keys` are given, only the clients that completed the handshake of
+    /// [`noise`] with them are allowed on the channel. This lets clients with different keys share a server without
+    /// [`ServerOptions::keys`].
+    pub async fn subscribe_with_keys(
+        &self,
+        channel: &str,
+        policy: PeerPolicy,
+        keys: Option<NoiseKeys>,
+    ) -> Result<mpsc::Receiver<Message>> {
+        protocol::check_channel(channel).map_err(|e| anyhow!(e))?;
+        let (send, recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let subscriber = Channel {
+            send: send.clone(),
+            policy: policy.clone(),
+            keys: keys.clone(),
+            members: HashMap::new(),
+            refused: HashMap::new(),
+        };
+        let previous = self
+            .channels
+            .lock()
+            .expect("Mutex is not poisoned")
+            .insert(channel.to_owned(), subscriber);
+
+        // Nobody is allowed on the new channel until checked again, so the clients of the previous subscriber can be
+        // checked while the new one is already receiving messages
+        let members = previous
+            .map(|previous| previous.members)
+            .unwrap_or_default();
+        if !members.is_empty() {
+            tokio::spawn(recheck(
+                channel.to_owned(),
+                members,
+                send,
+                self.channels.clone(),
+                self.clients.clone(),
+                self.default.clone(),
+            ));
+        }
+        Ok(recv)
+    }
+
+    /// Stop receiving the messages sent on `channel`. Clients sending on it get an error.
+    pub fn unsubscribe(&self, channel: &str) {
+        self.channels
+            .lock()
+            .expect("Mutex is not poisoned")
+            .remove(channel);
+    }
+
     /// Stop the IPC server.
     pub fn stop(&self) {
         self.cancel_token.cancel();
@@ -108,10 +501,129 @@ impl Drop for Server {
     }
 }
 
+/// The server of [`shared`], while anyone uses it.
+static SHARED: Mutex<Weak<Server>> = Mutex::new(Weak::new());
+
+/// The server listening on [`crate::ipc::APP_ENDPOINT`], which the integrations of the app share, each subscribed
+/// to channels of their own, see [`Server::subscribe`]. It is started by the first caller, and stopped once the last
+/// one drops it.
+///
+/// All its clients must use a channel, so the messages on its default channel are only logged.
+pub fn shared() -> Result<Arc<Server>> {
+    let mut shared = SHARED.lock().expect("Mutex is not poisoned");
+    if let Some(server) = shared.upgrade() {
+        return Ok(server);
+    }
+
+    let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+    let server = Server::start(&crate::ipc::path(crate::ipc::APP_ENDPOINT), send)
+        .map_err(|e| anyhow!("Could not start the shared IPC server: {e}"))?;
+    tokio::spawn(async move {
+        while let Some(message) = recv.recv().await {
+            match message.kind {
+                MessageType::Rejected => warn!(
+                    "Rejected IPC client {} ({:?}) on channel {:?}: {:?}",
+                    message.client_id, message.peer, message.channel, message.message
+                ),
+                MessageType::Message => warn!(
+                    "Dropped a message from IPC client {}, which is not on a channel",
+                    message.client_id
+                ),
+                _ => {}
+            }
+        }
+    });
+
+    let server = Arc::new(server);
+    *shared = Arc::downgrade(&server);
+    Ok(server)
+}
+
+/// Check the former `members` of `channel` against its new subscriber `send`, see [`Server::subscribe`].
+async fn recheck(
+    channel: String,
+    members: HashMap<u32, Member>,
+    send: mpsc::Sender<Message>,
+    channels: Channels,
+    clients: Clients,
+    default: mpsc::WeakSender<Message>,
+) {
+    let (policy, keys) = {
+        let channels = channels.lock().expect("Mutex is not poisoned");
+        match channels.get(&channel) {
+            Some(subscriber) if subscriber.send.same_channel(&send) => {
+                (subscriber.policy.clone(), subscriber.keys.clone())
+            }
+            _ => return,
+        }
+    };
+
+    for (client_id, member) in members {
+        let allowed = member.check(&policy, keys.as_ref()).await;
+        match allowed {
+            Ok(()) => {
+                // Reserved first, so the subscriber gets the connected message before any message of the client
+                let Ok(permit) = send.reserve().await else {
+                    return;
+                };
+                let mut channels = channels.lock().expect("Mutex is not poisoned");
+                let Some(subscriber) = channels
+                    .get_mut(&channel)
+                    .filter(|subscriber| subscriber.send.same_channel(&send))
+                else {
+                    return;
+                };
+                // The client may have joined again, or be gone, since
+                let connected = clients
+                    .lock()
+                    .expect("Mutex is not poisoned")
+                    .contains_key(&client_id);
+                if connected && !subscriber.members.contains_key(&client_id) {
+                    permit.send(Message {
+                        client_id,
+                        kind: MessageType::Connected,
+                        message: None,
+                        peer: Some(member.peer.clone()),
+                        channel: Some(channel.clone()),
+                    });
+                    subscriber.members.insert(client_id, member);
+                }
+            }
+            Err(reason) => {
+                let reason = format!("Not allowed on channel {channel}: {reason}");
+                warn!(
+                    "Evicted IPC client {client_id} ({:?}): {reason}",
+                    member.peer
+                );
+                let refused = channels
+                    .lock()
+                    .expect("Mutex is not poisoned")
+                    .get_mut(&channel)
+                    .filter(|subscriber| subscriber.send.same_channel(&send))
+                    .map(|subscriber| subscriber.refused.insert(client_id, reason.clone()))
+                    .is_some();
+                if let (true, Some(default)) = (refused, default.upgrade()) {
+                    let _ = default
+                        .send(Message {
+                            client_id,
+                            kind: MessageType::Rejected,
+                            message: Some(reason),
+                            peer: Some(member.peer),
+                            channel: Some(channel.clone()),
+                        })
+                        .await;
+                }
+            }
+        }
+    }
+}
+
 async fn listen_incoming(
     listener: LocalSocketListener,
     client_to_server_send: mpsc::Sender<Message>,
-    server_to_clients_recv: broadcast::Receiver<String>,
+    clients: Clients,
+    channels: Channels,
+    options: Arc<ServerOptions>,
     cancel_token: CancellationToken,
 ) {
     // We use a simple incrementing ID for each client
@@ -131,19 +643,41 @@ async fn listen_incoming(
                         let client_id = next_client_id;
                         next_client_id += 1;
 
+                        // The queue of the messages sent to this client, see [`Server::send_to`] and [`Server::broadcast`].
+                        // Note that the client only receives messages sent after this point, but that is okay,
+                        // realistically we don't want any messages before we get a chance to send the connected
+                        // message to the client, which is done inside [`handle_connection`]
+                        let queue = Arc::new(ClientQueue::new(
+                            client_id,
+                            options.client_queue_capacity,
+                            options.slow_client_policy,
+                        ));
+                        clients
+                            .lock()
+                            .expect("Mutex is not poisoned")
+                            .insert(client_id, queue.clone());
+
                         let future = handle_connection(
                             client_stream,
                             client_to_server_send.clone(),
-                            // We resubscribe to the receiver here so this task can have it's own copy
-                            // Note that this copy will only receive messages sent after this point,
-                            // but that is okay, realistically we don't want any messages before we get a chance
-                            // to send the connected message to the client, which is done inside [`handle_connection`]
-                            server_to_clients_recv.resubscribe(),
+                            channels.clone(),
+                            queue.clone(),
+                            options.clone(),
                             cancel_token.clone(),
                             client_id
                         );
+                        let clients = clients.clone();
+                        let channels = channels.clone();
                         tokio::spawn(future.map_err(|e| {
                             error!("Error handling connection: {}", e)
+                        }).map(move |_| {
+                            clients.lock().expect("Mutex is not poisoned").remove(&client_id);
+                            queue.close();
+                            // Usually done when reporting the disconnection, unless the connection failed first
+                            for subscriber in channels.lock().expect("Mutex is not poisoned").values_mut() {
+                                subscriber.members.remove(&client_id);
+                                subscriber.refused.remove(&client_id);
+                            }
                         }));
                     },
                     Err(e) => {
@@ -157,22 +691,313 @@ async fn listen_incoming(
 }
 
 async fn handle_connection(
-    client_stream: impl AsyncRead + AsyncWrite + Unpin,
+    client_stream: LocalSocketStream,
     client_to_server_send: mpsc::Sender<Message>,
-    mut server_to_clients_recv: broadcast::Receiver<String>,
+    channels: Channels,
+    queue: Arc<ClientQueue>,
+    options: Arc<ServerOptions>,
     cancel_token: CancellationToken,
     client_id: u32,
 ) -> Result<(), Box<dyn Error>> {
+    let (client_stream, peer) = PeerIdentity::of(client_stream).await?;
+    let mut client_stream = crate::ipc::internal_ipc_codec(client_stream);
+
+    let accepted = match options.policy.check(&peer).await {
+        Ok(()) => tokio::time::timeout(
+            NEGOTIATION_TIMEOUT,
+            accept(&mut client_stream, &options, &channels),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("The client did not start the protocol negotiation"))),
+        Err(e) => Err(e),
+    };
+    let (version, authenticated) = match accepted {
+        Ok(accepted) => accepted,
+        Err(reason) => {
+            warn!("Rejected IPC client {client_id} ({peer:?}): {reason}");
+            client_to_server_send
+                .send(Message {
+                    client_id,
+                    kind: MessageType::Rejected,
+                    message: Some(reason.to_string()),
+                    peer: Some(peer),
+                    channel: None,
+                })
+                .await?;
+            return Ok(());
+        }
+    };
+
+    queue.version.store(version, Ordering::Release);
     client_to_server_send
         .send(Message {
             client_id,
             kind: MessageType::Connected,
             message: None,
+            peer: Some(peer.clone()),
+            channel: None,
         })
         .await?;
 
-    let mut client_stream = crate::ipc::internal_ipc_codec(client_stream);
+    let router = Router {
+        client_id,
+        member: Member {
+            peer,
+            authenticated,
+        },
+        default: client_to_server_send,
+        channels,
+    };
+    serve_connection(
+        client_stream,
+        router,
+        queue,
+        Link::new(version, options.heartbeat, options.max_message_size),
+        cancel_token,
+        client_id,
+    )
+    .await
+}
+
+/// Delivers the messages of a client to the application, on the default channel or to the subscriber of their
+/// channel.
+struct Router {
+    client_id: u32,
+    member: Member,
+    default: mpsc::Sender<Message>,
+    channels: Channels,
+}
+
+impl Router {
+    fn message(
+        &self,
+        kind: MessageType,
+        message: Option<String>,
+        channel: Option<String>,
+    ) -> Message {
+        Message {
+            client_id: self.client_id,
+            kind,
+            message,
+            peer: None,
+            channel,
+        }
+    }
+
+    /// Deliver a message from the client. Returns the reason if the channel refused it, and an error if the
+    /// application stopped listening on the default channel.
+    async fn deliver(
+        &mut self,
+        channel: Option<String>,
+        payload: String,
+    ) -> Result<Result<(), String>> {
+        let Some(channel) = channel else {
+            self.default
+                .send(self.message(MessageType::Message, Some(payload), None))
+                .await?;
+            return Ok(Ok(()));
+        };
+
+        let subscriber = match self.join(&channel).await? {
+            Ok(subscriber) => subscriber,
+            Err(reason) => return Ok(Err(reason)),
+        };
+        let message = self.message(MessageType::Message, Some(payload), Some(channel.clone()));
+        if subscriber.send(message).await.is_err() {
+            return Ok(Err(format!("Nobody listens on channel {channel}")));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Return the subscriber of `channel` if the client is allowed on it, checking the client the first time it uses
+    /// the channel. Returns the reason if it is not allowed.
+    async fn join(&self, channel: &str) -> Result<Result<mpsc::Sender<Message>, String>> {
+        loop {
+            let (subscriber, policy, keys) = {
+                let channels = self.channels.lock().expect("Mutex is not poisoned");
+                let Some(subscriber) = channels.get(channel) else {
+                    return Ok(Err(format!("Nobody listens on channel {channel}")));
+                };
+                if subscriber.members.contains_key(&self.client_id) {
+                    return Ok(Ok(subscriber.send.clone()));
+                }
+                if let Some(reason) = subscriber.refused.get(&self.client_id) {
+                    return Ok(Err(reason.clone()));
+                }
+                (
+                    subscriber.send.clone(),
+                    subscriber.policy.clone(),
+                    subscriber.keys.clone(),
+                )
+            };
+
+            // The permit is reserved first, so the subscriber gets the connected message before any message of the
+            // client
+            let allowed = match self.member.check(&policy, keys.as_ref()).await {
+                Ok(()) => match subscriber.clone().reserve_owned().await {
+                    Ok(permit) => Ok(permit),
+                    Err(_) => return Ok(Err(format!("Nobody listens on channel {channel}"))),
+                },
+                Err(reason) => Err(format!("Not allowed on channel {channel}: {reason}")),
+            };
+
+            let allowed = {
+                let mut channels = self.channels.lock().expect("Mutex is not poisoned");
+                // Checked again if the channel was subscribed again in the meantime
+                let Some(current) = channels
+                    .get_mut(channel)
+                    .filter(|current| current.send.same_channel(&subscriber))
+                else {
+                    continue;
+                };
+                match allowed {
+                    Ok(_) if current.members.contains_key(&self.client_id) => Ok(()),
+                    Ok(permit) => {
+                        let mut connected =
+                            self.message(MessageType::Connected, None, Some(channel.to_owned()));
+                        connected.peer = Some(self.member.peer.clone());
+                        permit.send(connected);
+                        current.members.insert(self.client_id, self.member.clone());
+                        Ok(())
+                    }
+                    Err(reason) => {
+                        current.refused.insert(self.client_id, reason.clone());
+                        Err(reason)
+                    }
+                }
+            };
 
+            return match allowed {
+                Ok(()) => Ok(Ok(subscriber)),
+                Err(reason) => {
+                    warn!(
+                        "Rejected IPC client {} ({:?}): {reason}",
+                        self.client_id, self.member.peer
+                    );
+                    let mut rejected = self.message(
+                        MessageType::Rejected,
+                        Some(reason.clone()),
+                        Some(channel.to_owned()),
+                    );
+                    rejected.peer = Some(self.member.peer.clone());
+                    self.default.send(rejected).await?;
+                    Ok(Err(reason))
+                }
+            };
+        }
+    }
+
+    /// Report that the client is gone on the default channel, and to the subscribers of the channels it is on.
+    async fn disconnected(&self, reason: String) -> Result<()> {
+        let subscribers: Vec<_> = {
+            let mut channels = self.channels.lock().expect("Mutex is not poisoned");
+            channels
+                .iter_mut()
+                .filter_map(|(channel, subscriber)| {
+                    subscriber.refused.remove(&self.client_id);
+                    subscriber
+                        .members
+                        .remove(&self.client_id)
+                        .map(|_| (channel.clone(), subscriber.send.clone()))
+                })
+                .collect()
+        };
+        for (channel, subscriber) in subscribers {
+            let message = self.message(
+                MessageType::Disconnected,
+                Some(reason.clone()),
+                Some(channel),
+            );
+            let _ = subscriber.send(message).await;
+        }
+
+        self.default
+            .send(self.message(MessageType::Disconnected, Some(reason), None))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Start the connection the way the client does: with the handshake of [`noise`], which is required if the server
+/// has keys, or with the protocol negotiation. Returns the protocol version, and the public key the client
+/// authenticated with if it completed the handshake.
+async fn accept(
+    client_stream: &mut Framed<impl AsyncRead + AsyncWrite + Unpin, IpcCodec>,
+    options: &ServerOptions,
+    channels: &Channels,
+) -> Result<(u32, Option<[u8; 32]>)> {
+    let first = next_frame(client_stream).await?;
+    // The handshake starts with random bytes, never with an envelope
+    let plaintext = serde_json::from_slice::<Envelope>(&first).is_ok();
+    if plaintext && options.keys.is_some() {
+        return Err(anyhow!("Handshake failed: The client did not start it"));
+    }
+
+    let candidates: Vec<_> = match &options.keys {
+        Some(keys) => vec![keys.clone()],
+        None => channels
+            .lock()
+            .expect("Mutex is not poisoned")
+            .values()
+            .filter_map(|subscriber| subscriber.keys.clone())
+            .collect(),
+    };
+    if plaintext || candidates.is_empty() {
+        return Ok((negotiate(client_stream, &first).await?, None));
+    }
+
+    let authenticated = noise::respond(client_stream, &first, &candidates)
+        .await
+        .map_err(|e| anyhow!("Handshake failed: {e}"))?;
+    let hello = next_frame(client_stream).await?;
+    Ok((negotiate(client_stream, &hello).await?, Some(authenticated)))
+}
+
+async fn next_frame(
+    client_stream: &mut Framed<impl AsyncRead + AsyncWrite + Unpin, IpcCodec>,
+) -> Result<bytes::BytesMut> {
+    Ok(client_stream
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Connection closed during the protocol negotiation"))??)
+}
+
+/// Agree on the protocol version with the client, which sent `hello`, see [`protocol`].
+async fn negotiate(
+    client_stream: &mut Framed<impl AsyncRead + AsyncWrite + Unpin, IpcCodec>,
+    hello: &[u8],
+) -> Result<u32> {
+    let (reply, result) = match serde_json::from_slice(hello) {
+        Ok(Envelope::Hello {
+            min_version,
+            max_version,
+        }) => match protocol::negotiate(min_version, max_version) {
+            Ok(version) => (Envelope::Welcome { version }, Ok(version)),
+            Err(reason) => (
+                Envelope::error(ErrorCode::UnsupportedVersion, &reason),
+                Err(anyhow!(reason)),
+            ),
+        },
+        _ => (
+            Envelope::error(
+                ErrorCode::HelloExpected,
+                "The connection must start with hello",
+            ),
+            Err(anyhow!("The client did not start with hello")),
+        ),
+    };
+    client_stream.send(reply.to_json().into()).await?;
+    result
+}
+
+async fn serve_connection(
+    mut client_stream: Framed<impl AsyncRead + AsyncWrite + Unpin, IpcCodec>,
+    mut router: Router,
+    queue: Arc<ClientQueue>,
+    mut link: Link,
+    cancel_token: CancellationToken,
+    client_id: u32,
+) -> Result<(), Box<dyn Error>> {
     loop {
         tokio::select! {
             _ = cancel_token.cancelled() => {
@@ -180,14 +1005,31 @@ async fn handle_connection(
                 break;
             },
 
+            // Check that the client is still alive when it has been quiet for a while
+            due = link.heartbeat.due() => {
+                match due {
+                    Ok(()) => client_stream.send(Envelope::Ping.to_json().into()).await?,
+                    Err(reason) => {
+                        info!("Client {client_id} is gone: {reason}");
+                        router.disconnected(reason).await?;
+                        break;
+                    }
+                }
+            },
+
             // Forward messages to the IPC clients
-            msg = server_to_clients_recv.recv() => {
+            msg = queue.pop() => {
                 match msg {
+                    Ok(msg) if msg.channel.is_some() && link.version < protocol::CHANNEL_VERSION => {
+                        warn!("Client {client_id} does not support channels, dropped a message for {:?}", msg.channel);
+                    },
                     Ok(msg) => {
-                        client_stream.send(msg.into()).await?;
+                        for frame in protocol::message_frames(&msg.payload, msg.channel.as_deref(), link.version) {
+                            client_stream.send(frame.into()).await?;
+                        }
                     },
-                    Err(e) => {
-                        info!("Error reading message: {}", e);
+                    Err(reason) => {
+                        router.disconnected(reason).await?;
                         break;
                     }
                 }
@@ -200,32 +1042,48 @@ async fn handle_connection(
                 match result {
                     Some(Err(e))  => {
                         info!("Error reading from client {client_id}: {e}");
-
-                        client_to_server_send.send(Message {
-                            client_id,
-                            kind: MessageType::Disconnected,
-                            message: None,
-                        }).await?;
+                        router.disconnected(format!("Error reading from the client: {e}")).await?;
                         break;
                     },
                     None => {
                         info!("Client {client_id} disconnected.");
-
-                        client_to_server_send.send(Message {
-                            client_id,
-                            kind: MessageType::Disconnected,
-                            message: None,
-                        }).await?;
+                        router.disconnected("The client closed the connection".to_owned()).await?;
                         break;
                     },
                     Some(Ok(bytes)) => {
-                        let msg = std::str::from_utf8(&bytes)?;
+                        link.heartbeat.seen();
 
-                        client_to_server_send.send(Message {
-                            client_id,
-                            kind: MessageType::Message,
-                            message: Some(msg.to_string()),
-                        }).await?;
+                        match serde_json::from_slice::<Envelope>(&bytes) {
+                            Ok(Envelope::Fragment { payload }) => link.reassembler.fragment(payload),
+                            Ok(Envelope::Message { payload, channel }) => match link.reassembler.message(payload) {
+                                Ok(message) => {
+                                    if let Err(reason) = router.deliver(channel, message).await? {
+                                        warn!("Dropped a message from client {client_id}: {reason}");
+                                        let reply = Envelope::error(ErrorCode::ChannelRefused, reason);
+                                        client_stream.send(reply.to_json().into()).await?;
+                                    }
+                                },
+                                Err(reason) => {
+                                    warn!("Dropped a message from client {client_id}: {reason}");
+                                    let reply = Envelope::error(ErrorCode::MessageTooLarge, reason);
+                                    client_stream.send(reply.to_json().into()).await?;
+                                },
+                            },
+                            Ok(Envelope::Ping) => {
+                                client_stream.send(Envelope::Pong.to_json().into()).await?;
+                            },
+                            Ok(Envelope::Pong) => {},
+                            Ok(Envelope::Error { code, message }) => {
+                                info!("Client {client_id} could not handle a message: {code:?} {message}");
+                            },
+                            Ok(_) | Err(_) => {
+                                let reply = Envelope::error(
+                                    ErrorCode::UnknownMessage,
+                                    format!("Unknown message from client {client_id}"),
+                                );
+                                client_stream.send(reply.to_json().into()).await?;
+                            },
+                        }
                     },
 
                 }
@@ -235,3 +1093,548 @@ async fn handle_connection(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_to() {
+        let path = std::env::temp_dir().join(format!("ipc-send-to-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, client_to_server_send).unwrap();
+        assert!(server.send_to(0, "nobody".to_owned()).await.is_err());
+
+        let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (_client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client = crate::ipc::client::connect(path.clone(), send, client_recv);
+        let (other_send, mut other_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (_other_client_send, other_client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let other_client = crate::ipc::client::connect(path.clone(), other_send, other_client_recv);
+
+        let test = async {
+            let mut client_ids = Vec::new();
+            while client_ids.len() < 2 {
+                let message = client_to_server_recv.recv().await.unwrap();
+                if let MessageType::Connected = message.kind {
+                    client_ids.push(message.client_id);
+                }
+            }
+            assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"connected\"}");
+            assert_eq!(
+                other_recv.recv().await.unwrap(),
+                "{\"command\":\"connected\"}"
+            );
+
+            // Either client could have connected first
+            for client_id in client_ids {
+                server
+                    .send_to(client_id, format!("to {client_id}"))
+                    .await
+                    .unwrap();
+            }
+            let message = recv.recv().await.unwrap();
+            let other_message = other_recv.recv().await.unwrap();
+            assert_ne!(message, other_message);
+
+            server.broadcast("everyone".to_owned()).await.unwrap();
+            assert_eq!(recv.recv().await.unwrap(), "everyone");
+            assert_eq!(other_recv.recv().await.unwrap(), "everyone");
+        };
+        tokio::select! {
+            _ = client => panic!("Client exited"),
+            _ = other_client => panic!("Client exited"),
+            _ = test => {},
+        }
+        server.stop();
+    }
+
+    fn outgoing(payload: &str) -> Outgoing {
+        Outgoing {
+            channel: None,
+            payload: payload.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_policies() {
+        let queue = ClientQueue::new(1, 2, SlowClientPolicy::DropOldest);
+        for message in ["first", "second", "third"] {
+            queue.push(outgoing(message)).await.unwrap();
+        }
+        assert_eq!(queue.pop().await.unwrap().payload, "second");
+        assert_eq!(queue.pop().await.unwrap().payload, "third");
+
+        let queue = ClientQueue::new(1, 1, SlowClientPolicy::Disconnect);
+        queue.push(outgoing("first")).await.unwrap();
+        assert!(queue.push(outgoing("second")).await.is_err());
+        assert!(queue.pop().await.is_err());
+
+        let queue = Arc::new(ClientQueue::new(1, 1, SlowClientPolicy::Backpressure));
+        queue.push(outgoing("first")).await.unwrap();
+        let push = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(outgoing("second")).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!push.is_finished());
+        assert_eq!(queue.pop().await.unwrap().payload, "first");
+        push.await.unwrap().unwrap();
+        assert_eq!(queue.pop().await.unwrap().payload, "second");
+
+        // Waiting pushes fail once the client is gone
+        queue.push(outgoing("third")).await.unwrap();
+        let push = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(outgoing("fourth")).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        queue.close();
+        assert!(push.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let path = std::env::temp_dir().join(format!("ipc-reconnect-{}.sock", std::process::id()));
+        let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client = crate::ipc::client::connect_reconnecting(
+            path.clone(),
+            send,
+            client_recv,
+            None,
+            crate::ipc::client::ReconnectOptions {
+                max_delay: std::time::Duration::from_millis(200),
+                ..Default::default()
+            },
+        );
+
+        let test = async {
+            // Queued until the server is started
+            client_send.send("queued".to_owned()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+            for _ in 0..2 {
+                let (client_to_server_send, mut client_to_server_recv) =
+                    mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+                let server = Server::start(&path, client_to_server_send).unwrap();
+                assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"connected\"}");
+
+                client_send.send("sent".to_owned()).await.unwrap();
+                let mut messages = Vec::new();
+                while messages.last().map(String::as_str) != Some("sent") {
+                    let message = client_to_server_recv.recv().await.unwrap();
+                    if let MessageType::Message = message.kind {
+                        messages.push(message.message.unwrap());
+                    }
+                }
+                assert_eq!(messages, ["queued", "sent"]);
+
+                server.stop();
+                assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"disconnected\"}");
+                client_send.send("queued".to_owned()).await.unwrap();
+            }
+        };
+        tokio::select! {
+            _ = client => panic!("Client exited"),
+            _ = test => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_peer() {
+        let path = std::env::temp_dir().join(format!("ipc-rejected-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start_with_options(
+            &path,
+            client_to_server_send,
+            ServerOptions {
+                policy: PeerPolicy::Allowlist(Vec::new()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let (send, _recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (_client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client = crate::ipc::client::connect(path.clone(), send, client_recv);
+        let message = tokio::select! {
+            _ = client => client_to_server_recv.recv().await.unwrap(),
+            message = client_to_server_recv.recv() => message.unwrap(),
+        };
+        assert!(matches!(message.kind, MessageType::Rejected));
+        assert_eq!(
+            message.peer.and_then(|peer| peer.pid),
+            Some(std::process::id())
+        );
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_large_message() {
+        let path = std::env::temp_dir().join(format!("ipc-large-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, client_to_server_send).unwrap();
+
+        let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client = crate::ipc::client::connect(path.clone(), send, client_recv);
+        let large = "x".repeat(3 * crate::ipc::NATIVE_MESSAGING_BUFFER_SIZE);
+
+        let test = async {
+            let client_id = client_to_server_recv.recv().await.unwrap().client_id;
+            assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"connected\"}");
+
+            client_send.send(large.clone()).await.unwrap();
+            let message = client_to_server_recv.recv().await.unwrap();
+            assert_eq!(message.message.as_ref(), Some(&large));
+
+            server.send_to(client_id, large.clone()).await.unwrap();
+            assert_eq!(recv.recv().await.unwrap(), large);
+        };
+        tokio::select! {
+            _ = client => panic!("Client exited"),
+            _ = test => {},
+        }
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        use crate::ipc::client::{connect_reconnecting, ReconnectOptions};
+
+        let path = std::env::temp_dir().join(format!("ipc-channels-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, client_to_server_send).unwrap();
+        let mut pkcs11_recv = server
+            .subscribe("pkcs11", PeerPolicy::AllowAll)
+            .await
+            .unwrap();
+        let _locked_recv = server
+            .subscribe("locked", PeerPolicy::Allowlist(Vec::new()))
+            .await
+            .unwrap();
+        assert!(server
+            .subscribe("not a channel", PeerPolicy::AllowAll)
+            .await
+            .is_err());
+
+        let on_channel = |channel: &str| ReconnectOptions {
+            channel: Some(channel.to_owned()),
+            max_attempts: Some(1),
+            ..Default::default()
+        };
+        let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client =
+            connect_reconnecting(path.clone(), send, client_recv, None, on_channel("pkcs11"));
+        let (locked_send, _locked_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (locked_client_send, locked_client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let locked_client = connect_reconnecting(
+            path.clone(),
+            locked_send,
+            locked_client_recv,
+            None,
+            on_channel("locked"),
+        );
+
+        let test = async {
+            assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"connected\"}");
+            client_send.send("sign".to_owned()).await.unwrap();
+
+            let connected = pkcs11_recv.recv().await.unwrap();
+            assert!(matches!(connected.kind, MessageType::Connected));
+            assert!(connected.peer.is_some());
+            let message = pkcs11_recv.recv().await.unwrap();
+            assert!(matches!(message.kind, MessageType::Message));
+            assert_eq!(message.channel.as_deref(), Some("pkcs11"));
+            assert_eq!(message.message.as_deref(), Some("sign"));
+
+            server
+                .send_on(message.client_id, "pkcs11", "signed".to_owned())
+                .await
+                .unwrap();
+            assert_eq!(recv.recv().await.unwrap(), "signed");
+
+            let client_id = message.client_id;
+            assert!(server
+                .send_on(client_id, "locked", "signed".to_owned())
+                .await
+                .is_err());
+
+            // The messages on a channel the client is not allowed on are reported, but don't reach the subscriber
+            locked_client_send.send("sign".to_owned()).await.unwrap();
+            loop {
+                let message = client_to_server_recv.recv().await.unwrap();
+                if let MessageType::Rejected = message.kind {
+                    assert_eq!(message.channel.as_deref(), Some("locked"));
+                    break;
+                }
+                assert!(message.channel.is_none());
+            }
+
+            // Subscribing again checks the clients on the channel again
+            let mut pkcs11_recv = server
+                .subscribe("pkcs11", PeerPolicy::AllowAll)
+                .await
+                .unwrap();
+            let connected = pkcs11_recv.recv().await.unwrap();
+            assert!(matches!(connected.kind, MessageType::Connected));
+            assert_eq!(connected.client_id, client_id);
+
+            let mut pkcs11_recv = server
+                .subscribe("pkcs11", PeerPolicy::Allowlist(Vec::new()))
+                .await
+                .unwrap();
+            assert!(server
+                .send_on(client_id, "pkcs11", "signed".to_owned())
+                .await
+                .is_err());
+            let rejected = client_to_server_recv.recv().await.unwrap();
+            assert!(matches!(rejected.kind, MessageType::Rejected));
+            assert_eq!(rejected.client_id, client_id);
+            assert_eq!(rejected.channel.as_deref(), Some("pkcs11"));
+
+            client_send.send("sign".to_owned()).await.unwrap();
+            server.broadcast("ping".to_owned()).await.unwrap();
+            assert_eq!(recv.recv().await.unwrap(), "ping");
+            assert!(pkcs11_recv.try_recv().is_err());
+        };
+        tokio::select! {
+            _ = client => panic!("Client exited"),
+            _ = locked_client => panic!("Client exited"),
+            _ = test => {},
+        }
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_request_on_channel() {
+        use crate::ipc::client::request_on_channel;
+
+        let path = std::env::temp_dir().join(format!("ipc-request-{}.sock", std::process::id()));
+        let (client_to_server_send, _client_to_server_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Arc::new(Server::start(&path, client_to_server_send).unwrap());
+        let mut echo_recv = server
+            .subscribe("echo", PeerPolicy::AllowAll)
+            .await
+            .unwrap();
+        let echo_server = server.clone();
+        let echo = tokio::spawn(async move {
+            while let Some(message) = echo_recv.recv().await {
+                let Some(request) = message.message else {
+                    continue;
+                };
+                // Ignored by the client, as it's not the response
+                echo_server
+                    .send_on(message.client_id, "echo", "\"other\"".to_owned())
+                    .await
+                    .unwrap();
+                if request != "\"silent\"" {
+                    echo_server
+                        .send_on(message.client_id, "echo", request)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let timeout = std::time::Duration::from_millis(500);
+        let response: String = request_on_channel(
+            path.clone(),
+            "echo",
+            &"ping",
+            |response: &String| response == "ping",
+            timeout,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response, "ping");
+
+        let error = request_on_channel(
+            path.clone(),
+            "echo",
+            &"silent",
+            |response: &String| response == "silent",
+            timeout,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "The app did not respond in time");
+
+        echo.abort();
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_channel_keys() {
+        use crate::ipc::client::connect_on_channel;
+
+        let path =
+            std::env::temp_dir().join(format!("ipc-channel-keys-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, client_to_server_send).unwrap();
+        let (app_keys, proxy_keys) = NoiseKeys::generate();
+        let (other_app_keys, _) = NoiseKeys::generate();
+        let mut agent_recv = server
+            .subscribe_with_keys("agent", PeerPolicy::AllowAll, Some(app_keys))
+            .await
+            .unwrap();
+        let _other_recv = server
+            .subscribe_with_keys("other", PeerPolicy::AllowAll, Some(other_app_keys))
+            .await
+            .unwrap();
+
+        let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client = connect_on_channel(path.clone(), "agent", send, client_recv, Some(proxy_keys));
+        let (plain_send, mut plain_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (plain_client_send, plain_client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let plain_client =
+            connect_on_channel(path.clone(), "agent", plain_send, plain_client_recv, None);
+
+        let test = async {
+            assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"connected\"}");
+            client_send.send("ready".to_owned()).await.unwrap();
+            let connected = agent_recv.recv().await.unwrap();
+            assert!(matches!(connected.kind, MessageType::Connected));
+            let message = agent_recv.recv().await.unwrap();
+            assert_eq!(message.message.as_deref(), Some("ready"));
+            server
+                .send_on(message.client_id, "agent", "hello".to_owned())
+                .await
+                .unwrap();
+            assert_eq!(recv.recv().await.unwrap(), "hello");
+
+            // Clients that didn't authenticate with the keys of the channel can connect, but not use the channel
+            assert_eq!(
+                plain_recv.recv().await.unwrap(),
+                "{\"command\":\"connected\"}"
+            );
+            plain_client_send.send("ready".to_owned()).await.unwrap();
+            loop {
+                let message = client_to_server_recv.recv().await.unwrap();
+                if let MessageType::Rejected = message.kind {
+                    assert_eq!(message.channel.as_deref(), Some("agent"));
+                    break;
+                }
+            }
+        };
+        tokio::select! {
+            _ = client => panic!("Client exited"),
+            _ = plain_client => panic!("Client exited"),
+            _ = test => {},
+        }
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_send_on_without_channels() {
+        use interprocess::local_socket::{tokio::Stream, ToFsName};
+
+        let path =
+            std::env::temp_dir().join(format!("ipc-no-channels-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, client_to_server_send).unwrap();
+        let _recv = server
+            .subscribe("pkcs11", PeerPolicy::AllowAll)
+            .await
+            .unwrap();
+
+        // A client of the last protocol version without channels
+        let name = path.as_os_str().to_fs_name::<GenericFilePath>().unwrap();
+        let mut conn = crate::ipc::internal_ipc_codec(Stream::connect(name).await.unwrap());
+        let hello = Envelope::Hello {
+            min_version: protocol::MIN_PROTOCOL_VERSION,
+            max_version: protocol::CHANNEL_VERSION - 1,
+        };
+        conn.send(hello.to_json().into()).await.unwrap();
+        conn.next().await.unwrap().unwrap();
+
+        let message = client_to_server_recv.recv().await.unwrap();
+        assert!(matches!(message.kind, MessageType::Connected));
+        let error = server
+            .send_on(message.client_id, "pkcs11", "signed".to_owned())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("does not support channels"));
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_missed_heartbeat() {
+        use interprocess::local_socket::{tokio::Stream, ToFsName};
+
+        let path = std::env::temp_dir().join(format!("ipc-heartbeat-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start_with_options(
+            &path,
+            client_to_server_send,
+            ServerOptions {
+                heartbeat: Some(HeartbeatOptions {
+                    interval: std::time::Duration::from_millis(20),
+                    timeout: std::time::Duration::from_millis(100),
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // A client that negotiates, but never answers the pings
+        let name = path.as_os_str().to_fs_name::<GenericFilePath>().unwrap();
+        let mut conn = crate::ipc::internal_ipc_codec(Stream::connect(name).await.unwrap());
+        conn.send(Envelope::hello().to_json().into()).await.unwrap();
+        conn.next().await.unwrap().unwrap();
+
+        let message = client_to_server_recv.recv().await.unwrap();
+        assert!(matches!(message.kind, MessageType::Connected));
+        let ping = conn.next().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Envelope>(&ping).unwrap(),
+            Envelope::Ping
+        );
+
+        let message = client_to_server_recv.recv().await.unwrap();
+        assert!(matches!(message.kind, MessageType::Disconnected));
+        assert!(message.message.unwrap().contains("heartbeat"));
+        server.stop();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_version() {
+        use interprocess::local_socket::{tokio::Stream, ToFsName};
+
+        let path = std::env::temp_dir().join(format!("ipc-version-{}.sock", std::process::id()));
+        let (client_to_server_send, mut client_to_server_recv) =
+            mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, client_to_server_send).unwrap();
+
+        let name = path.as_os_str().to_fs_name::<GenericFilePath>().unwrap();
+        let mut conn = crate::ipc::internal_ipc_codec(Stream::connect(name).await.unwrap());
+        let hello = Envelope::Hello {
+            min_version: protocol::PROTOCOL_VERSION + 1,
+            max_version: protocol::PROTOCOL_VERSION + 1,
+        };
+        conn.send(hello.to_json().into()).await.unwrap();
+
+        let reply = conn.next().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&reply).unwrap(),
+            Envelope::Error {
+                code: ErrorCode::UnsupportedVersion,
+                ..
+            }
+        ));
+        assert!(conn.next().await.is_none());
+        let message = client_to_server_recv.recv().await.unwrap();
+        assert!(matches!(message.kind, MessageType::Rejected));
+        server.stop();
+    }
+}
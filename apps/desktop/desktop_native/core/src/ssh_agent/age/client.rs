@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use super::{
+    format::{self, FileKey, Header},
+    AgeRequest, AgeResponse, IPC_NAME,
+};
+use crate::ipc;
+
+/// How long to wait for the app, which may wait for the user to unlock the vault and approve.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Ask the agent of the app to unwrap the file key of `header`.
+///
+/// Resolves once the user approved or denied the request in the app.
+pub async fn unwrap_file_key(header: &Header) -> Result<FileKey> {
+    let request_id = std::process::id().to_string();
+    let request = AgeRequest::UnwrapFileKey {
+        request_id: request_id.clone(),
+        header: STANDARD.encode(header.encode()),
+    };
+    let response = ipc::client::request_on_channel(
+        ipc::path(ipc::APP_ENDPOINT),
+        IPC_NAME,
+        &request,
+        |response: &AgeResponse| response.request_id() == request_id,
+        REQUEST_TIMEOUT,
+    )
+    .await?;
+    match response {
+        AgeResponse::FileKey { file_key, .. } => STANDARD
+            .decode(file_key)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid file key in the agent response")),
+        AgeResponse::Error { message, .. } => Err(anyhow!(message)),
+    }
+}
+
+/// Decrypt an age file, binary or armored, with a file key unwrapped by the agent of the app.
+pub async fn decrypt(data: &[u8]) -> Result<Vec<u8>> {
+    let data = format::dearmor(data)?;
+    let (header, payload) = Header::parse(&data)?;
+    let file_key = unwrap_file_key(&header).await?;
+    // The agent verifies the MAC as well, but the header is only trusted once it was checked locally
+    header.verify_mac(&file_key)?;
+    Ok(format::decrypt_payload(&file_key, payload)?)
+}
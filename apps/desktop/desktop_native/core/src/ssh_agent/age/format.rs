@@ -0,0 +1,247 @@
+//! The age v1 file format: the header with its recipient stanzas, and the STREAM encrypted payload.
+//!
+//! Based on https://github.com/C2SP/C2SP/blob/main/age.md
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine as _,
+};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::AgeError;
+
+const VERSION_LINE: &[u8] = b"age-encryption.org/v1";
+const STANZA_PREFIX: &[u8] = b"-> ";
+const MAC_PREFIX: &[u8] = b"---";
+/// Stanza bodies are wrapped at 64 columns, and end with a line shorter than that.
+const BODY_LINE_LENGTH: usize = 64;
+
+const ARMOR_HEADER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_FOOTER: &str = "-----END AGE ENCRYPTED FILE-----";
+
+const PAYLOAD_NONCE_LENGTH: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+pub const FILE_KEY_LENGTH: usize = 16;
+pub type FileKey = [u8; FILE_KEY_LENGTH];
+
+/// A recipient stanza, e.g. `-> ssh-ed25519 <tag> <ephemeral share>` followed by the wrapped file key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stanza {
+    /// The recipient type, e.g. `ssh-ed25519`.
+    pub tag: String,
+    pub args: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub stanzas: Vec<Stanza>,
+    /// The header up to and including `---`, which is authenticated by `mac`.
+    mac_input: Vec<u8>,
+    mac: [u8; 32],
+    /// The encoded header, including the MAC line.
+    encoded: Vec<u8>,
+}
+
+impl Header {
+    /// Parse the header at the start of a binary age file, returning it with the remaining payload.
+    pub fn parse(data: &[u8]) -> Result<(Header, &[u8]), AgeError> {
+        let mut lines = Lines { data, position: 0 };
+        if lines.next()? != VERSION_LINE {
+            return Err(AgeError::UnsupportedVersion);
+        }
+
+        let mut stanzas = Vec::new();
+        loop {
+            let line_start = lines.position;
+            let line = lines.next()?;
+            if let Some(arguments) = line.strip_prefix(STANZA_PREFIX) {
+                stanzas.push(parse_stanza(arguments, &mut lines)?);
+                continue;
+            }
+
+            let encoded_mac = line
+                .strip_prefix(MAC_PREFIX)
+                .and_then(|mac| mac.strip_prefix(b" "))
+                .ok_or(AgeError::InvalidHeader(
+                    "expected a stanza or the header MAC",
+                ))?;
+            let mac = STANDARD_NO_PAD
+                .decode(encoded_mac)
+                .ok()
+                .and_then(|mac| mac.try_into().ok())
+                .ok_or(AgeError::InvalidHeader("invalid header MAC"))?;
+            if stanzas.is_empty() {
+                return Err(AgeError::InvalidHeader("no recipient stanzas"));
+            }
+
+            let header = Header {
+                stanzas,
+                mac_input: data[..line_start + MAC_PREFIX.len()].to_vec(),
+                mac,
+                encoded: data[..lines.position].to_vec(),
+            };
+            return Ok((header, &data[lines.position..]));
+        }
+    }
+
+    pub fn encode(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    /// Check that the header was created with `file_key`, which proves that it was not modified.
+    pub fn verify_mac(&self, file_key: &FileKey) -> Result<(), AgeError> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&hkdf_sha256(&[], file_key, b"header"))
+            .expect("HMAC accepts keys of any length");
+        mac.update(&self.mac_input);
+        mac.verify_slice(&self.mac)
+            .map_err(|_| AgeError::InvalidHeaderMac)
+    }
+}
+
+/// Decrypt the payload following the header with `file_key`.
+pub fn decrypt_payload(file_key: &FileKey, payload: &[u8]) -> Result<Vec<u8>, AgeError> {
+    if payload.len() < PAYLOAD_NONCE_LENGTH {
+        return Err(AgeError::InvalidPayload);
+    }
+    let (nonce, ciphertext) = payload.split_at(PAYLOAD_NONCE_LENGTH);
+    let cipher = ChaCha20Poly1305::new(&hkdf_sha256(nonce, file_key, b"payload").into());
+
+    let chunks: Vec<&[u8]> = ciphertext.chunks(CHUNK_SIZE + TAG_SIZE).collect();
+    if chunks.is_empty() {
+        return Err(AgeError::InvalidPayload);
+    }
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    for (counter, chunk) in chunks.iter().enumerate() {
+        let is_last = counter + 1 == chunks.len();
+        // Only the first chunk may be empty, for an empty file
+        if is_last && chunk.len() == TAG_SIZE && counter > 0 {
+            return Err(AgeError::InvalidPayload);
+        }
+
+        let mut chunk_nonce = [0u8; 12];
+        chunk_nonce[3..11].copy_from_slice(&(counter as u64).to_be_bytes());
+        chunk_nonce[11] = is_last as u8;
+        plaintext.extend(
+            cipher
+                .decrypt(&chunk_nonce.into(), *chunk)
+                .map_err(|_| AgeError::InvalidPayload)?,
+        );
+    }
+    Ok(plaintext)
+}
+
+/// Remove the ASCII armor of a file created with `age --armor`. Binary files are returned unchanged.
+pub fn dearmor(data: &[u8]) -> Result<Vec<u8>, AgeError> {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) if text.trim_start().starts_with(ARMOR_HEADER) => text.trim(),
+        _ => return Ok(data.to_vec()),
+    };
+    let body = text
+        .strip_prefix(ARMOR_HEADER)
+        .and_then(|text| text.strip_suffix(ARMOR_FOOTER))
+        .ok_or(AgeError::InvalidArmor)?;
+    let encoded: String = body.lines().map(str::trim).collect();
+    STANDARD.decode(encoded).map_err(|_| AgeError::InvalidArmor)
+}
+
+pub(super) fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut output)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    output
+}
+
+fn parse_stanza(arguments: &[u8], lines: &mut Lines) -> Result<Stanza, AgeError> {
+    let arguments = std::str::from_utf8(arguments)
+        .map_err(|_| AgeError::InvalidHeader("stanza arguments are not ASCII"))?;
+    let mut arguments = arguments.split(' ').map(str::to_owned);
+    let tag = arguments
+        .next()
+        .filter(|tag| !tag.is_empty())
+        .ok_or(AgeError::InvalidHeader("missing stanza tag"))?;
+    let args: Vec<String> = arguments.collect();
+    if args.iter().any(String::is_empty) {
+        return Err(AgeError::InvalidHeader("empty stanza argument"));
+    }
+
+    let mut encoded_body = Vec::new();
+    loop {
+        let line = lines.next()?;
+        if line.len() > BODY_LINE_LENGTH {
+            return Err(AgeError::InvalidHeader("stanza body line too long"));
+        }
+        encoded_body.extend_from_slice(line);
+        if line.len() < BODY_LINE_LENGTH {
+            break;
+        }
+    }
+    let body = STANDARD_NO_PAD
+        .decode(encoded_body)
+        .map_err(|_| AgeError::InvalidHeader("invalid stanza body"))?;
+
+    Ok(Stanza { tag, args, body })
+}
+
+/// The `\n` terminated lines of the header.
+struct Lines<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn next(&mut self) -> Result<&'a [u8], AgeError> {
+        let remaining = &self.data[self.position..];
+        let length = remaining
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or(AgeError::InvalidHeader("unterminated line"))?;
+        self.position += length + 1;
+        Ok(&remaining[..length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let file = b"age-encryption.org/v1\n\
+            -> X25519 abc\n\
+            AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n\
+            \n\
+            -> empty\n\
+            \n\
+            --- AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n\
+            payload";
+        let (header, payload) = Header::parse(file).unwrap();
+        assert_eq!(payload, b"payload");
+        assert_eq!(header.encode(), &file[..file.len() - payload.len()]);
+        assert_eq!(header.stanzas.len(), 2);
+        assert_eq!(header.stanzas[0].tag, "X25519");
+        assert_eq!(header.stanzas[0].args, vec!["abc".to_string()]);
+        assert_eq!(header.stanzas[0].body, vec![0u8; 48]);
+        assert!(header.stanzas[1].body.is_empty());
+        assert!(header.mac_input.ends_with(b"\n---"));
+
+        assert!(matches!(
+            Header::parse(b"age-encryption.org/v2\n"),
+            Err(AgeError::UnsupportedVersion)
+        ));
+        // Bodies must end with a line shorter than 64 columns
+        assert!(Header::parse(
+            b"age-encryption.org/v1\n-> X25519\n\
+            AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n\
+            --- AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n"
+        )
+        .is_err());
+        assert!(Header::parse(b"age-encryption.org/v1\n-> X25519  a\n\n--- x\n").is_err());
+    }
+}
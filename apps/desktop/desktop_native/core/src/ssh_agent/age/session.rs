@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use super::{
+    format::{FileKey, Header},
+    AgeError, AgeRequest, AgeResponse, IPC_NAME, MAX_HEADER_LENGTH,
+};
+use crate::{
+    ipc::{peer::PeerPolicy, server::Server},
+    ssh_agent::{channel, peerinfo::models::PeerInfo, BitwardenDesktopAgent, BitwardenSshKey},
+};
+
+/// Subscribe to the `age` channel of `server`, and answer the file key requests of the clients until the agent is
+/// stopped.
+pub(super) async fn serve(
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    server: Arc<Server>,
+) -> Result<()> {
+    // Every decryption is approved in the app, which names the process that asked, so any client may ask
+    let cancellation_token = agent.cancellation_token.clone();
+    channel::serve(
+        server,
+        IPC_NAME,
+        PeerPolicy::AllowAll,
+        cancellation_token,
+        move |AgeRequest::UnwrapFileKey { request_id, header }, peer_info| {
+            let agent = agent.clone();
+            async move {
+                match unwrap_file_key(&agent, &header, &peer_info).await {
+                    Ok(file_key) => AgeResponse::FileKey {
+                        request_id,
+                        file_key: STANDARD.encode(file_key),
+                    },
+                    Err(e) => {
+                        println!("[SSH Agent] Could not unwrap age file key: {e}");
+                        AgeResponse::Error {
+                            request_id,
+                            message: e.to_string(),
+                        }
+                    }
+                }
+            }
+        },
+    )
+    .await
+}
+
+async fn unwrap_file_key(
+    agent: &BitwardenDesktopAgent<BitwardenSshKey>,
+    header: &str,
+    peer_info: &PeerInfo,
+) -> Result<FileKey, AgeError> {
+    let data = STANDARD
+        .decode(header)
+        .map_err(|_| AgeError::InvalidHeader("the header is not valid base64"))?;
+    if data.len() > MAX_HEADER_LENGTH {
+        return Err(AgeError::InvalidHeader("the header is too long"));
+    }
+    let (header, payload) = Header::parse(&data)?;
+    if !payload.is_empty() {
+        return Err(AgeError::InvalidHeader("trailing data after the header"));
+    }
+    agent.unwrap_age_file_key(&header, peer_info).await
+}
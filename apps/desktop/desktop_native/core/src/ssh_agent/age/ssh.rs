@@ -0,0 +1,93 @@
+//! Unwrapping of file keys encrypted to `ssh-ed25519` and `ssh-rsa` recipients, as done by `age -i ~/.ssh/id_ed25519`.
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use curve25519_dalek::{edwards::CompressedEdwardsY, MontgomeryPoint};
+use rsa::Oaep;
+use sha2::{Digest, Sha256, Sha512};
+use ssh_key::private::{KeypairData, PrivateKey};
+
+use super::{
+    format::{hkdf_sha256, FileKey, Stanza},
+    AgeError,
+};
+
+pub const ED25519_STANZA_TAG: &str = "ssh-ed25519";
+pub const RSA_STANZA_TAG: &str = "ssh-rsa";
+
+const ED25519_LABEL: &[u8] = b"age-encryption.org/v1/ssh-ed25519";
+const RSA_LABEL: &str = "age-encryption.org/v1/ssh-rsa";
+
+/// The key tag in ssh stanzas, the first 4 bytes of the SHA256 hash of the public key in SSH wire encoding.
+pub fn key_tag(public_key_bytes: &[u8]) -> String {
+    STANDARD_NO_PAD.encode(&Sha256::digest(public_key_bytes)[..4])
+}
+
+/// The key tag of an ssh stanza, or `None` for other recipient types.
+pub fn stanza_key_tag(stanza: &Stanza) -> Option<&str> {
+    match stanza.tag.as_str() {
+        ED25519_STANZA_TAG | RSA_STANZA_TAG => stanza.args.first().map(String::as_str),
+        _ => None,
+    }
+}
+
+/// Decrypt the file key in `stanza` with `private_key`.
+pub fn unwrap_file_key(private_key: &PrivateKey, stanza: &Stanza) -> Result<FileKey, AgeError> {
+    let public_key_bytes = private_key
+        .public_key()
+        .to_bytes()
+        .map_err(|e| AgeError::InvalidKey(e.to_string()))?;
+    if stanza_key_tag(stanza) != Some(key_tag(&public_key_bytes).as_str()) {
+        return Err(AgeError::NoMatchingKey);
+    }
+
+    let file_key = match (private_key.key_data(), stanza.tag.as_str()) {
+        (KeypairData::Ed25519(keypair), ED25519_STANZA_TAG) => {
+            let [_key_tag, ephemeral_share] = stanza.args.as_slice() else {
+                return Err(AgeError::InvalidHeader("invalid ssh-ed25519 stanza"));
+            };
+            let ephemeral_share: [u8; 32] = STANDARD_NO_PAD
+                .decode(ephemeral_share)
+                .ok()
+                .and_then(|share| share.try_into().ok())
+                .ok_or(AgeError::InvalidHeader(
+                    "invalid ssh-ed25519 ephemeral share",
+                ))?;
+            let recipient = CompressedEdwardsY(keypair.public.0)
+                .decompress()
+                .ok_or_else(|| AgeError::InvalidKey("invalid Ed25519 public key".to_string()))?
+                .to_montgomery();
+
+            // The X25519 secret key is the scalar Ed25519 derives from the seed
+            let hash = Sha512::digest(keypair.private.to_bytes());
+            let secret: [u8; 32] = hash[..32].try_into().expect("SHA512 is 64 bytes");
+            let shared_secret = MontgomeryPoint(ephemeral_share).mul_clamped(secret);
+            let tweak = hkdf_sha256(&public_key_bytes, &[], ED25519_LABEL);
+            let shared_secret = shared_secret.mul_clamped(tweak);
+            if shared_secret.0 == [0u8; 32] {
+                return Err(AgeError::InvalidHeader("ssh-ed25519 shared secret is zero"));
+            }
+
+            let salt = [ephemeral_share, recipient.0].concat();
+            let wrap_key = hkdf_sha256(&salt, &shared_secret.0, ED25519_LABEL);
+            ChaCha20Poly1305::new(&wrap_key.into())
+                .decrypt(&[0u8; 12].into(), stanza.body.as_slice())
+                .map_err(|_| AgeError::NoMatchingKey)?
+        }
+        (KeypairData::Rsa(keypair), RSA_STANZA_TAG) => {
+            if stanza.args.len() != 1 {
+                return Err(AgeError::InvalidHeader("invalid ssh-rsa stanza"));
+            }
+            let private_key = rsa::RsaPrivateKey::try_from(keypair)
+                .map_err(|e| AgeError::InvalidKey(e.to_string()))?;
+            private_key
+                .decrypt(Oaep::new_with_label::<Sha256, _>(RSA_LABEL), &stanza.body)
+                .map_err(|_| AgeError::NoMatchingKey)?
+        }
+        _ => return Err(AgeError::NoMatchingKey),
+    };
+
+    file_key
+        .try_into()
+        .map_err(|_| AgeError::InvalidHeader("the wrapped file key must be 16 bytes"))
+}
@@ -0,0 +1,345 @@
+//! The protocol between the `bitwarden-askpass` helper and the desktop app.
+//!
+//! OpenSSH runs the program in `SSH_ASKPASS` to ask for key passphrases and to confirm the use of keys added with
+//! `ssh-add -c`. The helper forwards the prompt to the app on the `askpass` channel of the app's IPC server, see
+//! [`crate::ipc::server::shared`], as a JSON encoded [`AskpassRequest`], and the app answers with an
+//! [`AskpassResponse`] carrying the same request id.
+//!
+//! The app listens with an [`AskpassServer`], which sends each answer only to the helper that asked.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::ipc::{
+    self,
+    peer::PeerPolicy,
+    server::{MessageType, Server},
+};
+
+/// The channel of the helpers on the app's IPC server.
+pub const IPC_NAME: &str = "askpass";
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// How long a prompt waits for the user. OpenSSH waits for the helper, so a forgotten prompt would block it forever.
+const ASK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The kind of prompt, from `SSH_ASKPASS_PROMPT`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AskpassPromptType {
+    /// Ask for a passphrase, which is the default when `SSH_ASKPASS_PROMPT` is not set.
+    Passphrase,
+    /// Ask the user to allow or deny, `SSH_ASKPASS_PROMPT=confirm`.
+    Confirm,
+    /// Only show the prompt, `SSH_ASKPASS_PROMPT=none`. OpenSSH terminates the helper once the prompt is no longer
+    /// relevant, for example after the user touched their security key, which closes the connection.
+    Notify,
+}
+
+impl AskpassPromptType {
+    pub fn from_env_value(value: Option<&str>) -> Self {
+        match value {
+            Some("confirm") => AskpassPromptType::Confirm,
+            Some("none") => AskpassPromptType::Notify,
+            _ => AskpassPromptType::Passphrase,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AskpassRequest {
+    pub request_id: String,
+    pub prompt_type: AskpassPromptType,
+    /// The prompt text OpenSSH passed as the first argument.
+    pub prompt: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AskpassResponse {
+    pub request_id: String,
+    /// Whether the user confirmed, or entered a passphrase instead of canceling.
+    pub approved: bool,
+    pub passphrase: Option<String>,
+}
+
+/// Show a prompt in the desktop app and wait for the user's answer.
+pub async fn ask(prompt_type: AskpassPromptType, prompt: &str) -> Result<AskpassResponse> {
+    let request = AskpassRequest {
+        request_id: format!(
+            "{}-{}",
+            std::process::id(),
+            REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ),
+        prompt_type,
+        prompt: prompt.to_string(),
+    };
+
+    ipc::client::request_on_channel(
+        ipc::path(ipc::APP_ENDPOINT),
+        IPC_NAME,
+        &request,
+        |response: &AskpassResponse| response.request_id == request.request_id,
+        ASK_TIMEOUT,
+    )
+    .await
+}
+
+/// What the [`AskpassServer`] reports to the app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AskpassEvent {
+    /// A helper shows a prompt. Answer it with [`AskpassServer::respond`]. The request id is assigned by the server,
+    /// as helpers only pick ids that are unique among their own requests.
+    Prompt(AskpassRequest),
+    /// The helper exited before its prompt was answered, so the prompt should be dismissed. OpenSSH does this for
+    /// [`AskpassPromptType::Notify`] prompts.
+    Canceled { request_id: String },
+}
+
+/// The prompts waiting for an answer, by the request id given to the app, with the client and the request id of the
+/// helper that asked.
+type Pending = Arc<Mutex<HashMap<String, (u32, String)>>>;
+
+/// The app side of the `askpass` channel.
+pub struct AskpassServer {
+    server: Arc<Server>,
+    pending: Pending,
+}
+
+impl AskpassServer {
+    /// Subscribe to the `askpass` channel of `server`, and report the prompts of the helpers to `event_send`.
+    ///
+    /// The prompts are shown in the app and their answers may be passphrases, so `policy` should only allow the
+    /// `bitwarden-askpass` helper. Otherwise any process of the user could ask for the master password in the app.
+    pub async fn start(
+        server: Arc<Server>,
+        policy: PeerPolicy,
+        event_send: mpsc::Sender<AskpassEvent>,
+    ) -> Result<Self> {
+        let mut client_to_server_recv = server.subscribe(IPC_NAME, policy).await?;
+        let pending = Pending::default();
+
+        let task_pending = pending.clone();
+        tokio::spawn(async move {
+            while let Some(message) = client_to_server_recv.recv().await {
+                let client_id = message.client_id;
+                let events = match message.kind {
+                    MessageType::Message => {
+                        match serde_json::from_str::<AskpassRequest>(
+                            message.message.as_deref().unwrap_or_default(),
+                        ) {
+                            Ok(mut request) => {
+                                let request_id = format!("{client_id}-{}", request.request_id);
+                                task_pending.lock().expect("Mutex is not poisoned").insert(
+                                    request_id.clone(),
+                                    (client_id, std::mem::take(&mut request.request_id)),
+                                );
+                                request.request_id = request_id;
+                                vec![AskpassEvent::Prompt(request)]
+                            }
+                            // Such as the `connected` message of the client
+                            Err(_) => continue,
+                        }
+                    }
+                    MessageType::Disconnected => {
+                        let mut pending = task_pending.lock().expect("Mutex is not poisoned");
+                        let canceled: Vec<_> = pending
+                            .iter()
+                            .filter(|(_, (id, _))| *id == client_id)
+                            .map(|(request_id, _)| request_id.clone())
+                            .collect();
+                        canceled
+                            .into_iter()
+                            .map(|request_id| {
+                                pending.remove(&request_id);
+                                AskpassEvent::Canceled { request_id }
+                            })
+                            .collect()
+                    }
+                    MessageType::Connected | MessageType::Rejected => continue,
+                };
+                for event in events {
+                    if event_send.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(AskpassServer { server, pending })
+    }
+
+    /// The socket path of the server, which the helpers find with [`crate::ipc::path`].
+    pub fn path(&self) -> &PathBuf {
+        &self.server.path
+    }
+
+    /// Answer the prompt `request_id` of an [`AskpassEvent::Prompt`]. Fails if the prompt was already answered or
+    /// its helper exited.
+    pub async fn respond(
+        &self,
+        request_id: &str,
+        approved: bool,
+        passphrase: Option<String>,
+    ) -> Result<()> {
+        let (client_id, request_id) = self
+            .pending
+            .lock()
+            .expect("Mutex is not poisoned")
+            .remove(request_id)
+            .ok_or_else(|| anyhow!("No helper is waiting for the prompt {request_id}"))?;
+        let response = AskpassResponse {
+            request_id,
+            approved,
+            passphrase: passphrase.filter(|_| approved),
+        };
+        self.server
+            .send_on(client_id, IPC_NAME, serde_json::to_string(&response)?)
+            .await
+    }
+
+    /// Stop listening on the channel. Helpers that are still waiting fail.
+    pub fn stop(&self) {
+        self.server.unsubscribe(IPC_NAME);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::MESSAGE_CHANNEL_BUFFER;
+
+    #[test]
+    fn test_protocol() {
+        assert_eq!(
+            AskpassPromptType::from_env_value(Some("confirm")),
+            AskpassPromptType::Confirm
+        );
+        assert_eq!(
+            AskpassPromptType::from_env_value(None),
+            AskpassPromptType::Passphrase
+        );
+
+        let request = AskpassRequest {
+            request_id: "1-0".to_string(),
+            prompt_type: AskpassPromptType::Notify,
+            prompt: "Confirm user presence for key ED25519-SK".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"requestId":"1-0","promptType":"notify","prompt":"Confirm user presence for key ED25519-SK"}"#
+        );
+
+        let response: AskpassResponse =
+            serde_json::from_str(r#"{"requestId":"1-0","approved":true,"passphrase":null}"#)
+                .unwrap();
+        assert!(response.approved);
+        assert_eq!(response.passphrase, None);
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let path = std::env::temp_dir().join(format!("askpass-{}.sock", std::process::id()));
+        let (default_send, _default_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let ipc_server = Arc::new(Server::start(&path, default_send).unwrap());
+        let (event_send, mut event_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = AskpassServer::start(ipc_server.clone(), PeerPolicy::AllowAll, event_send)
+            .await
+            .unwrap();
+
+        // Two helpers that picked the same request id
+        let mut helpers = Vec::new();
+        for prompt in ["first", "second"] {
+            let (to_server_send, to_server_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+            let (from_server_send, from_server_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+            let request = AskpassRequest {
+                request_id: "1-0".to_string(),
+                prompt_type: AskpassPromptType::Passphrase,
+                prompt: prompt.to_string(),
+            };
+            to_server_send
+                .send(serde_json::to_string(&request).unwrap())
+                .await
+                .unwrap();
+            let path = path.clone();
+            let connection = tokio::spawn(async move {
+                ipc::client::connect_on_channel(
+                    path,
+                    IPC_NAME,
+                    from_server_send,
+                    to_server_recv,
+                    None,
+                )
+                .await
+                .is_ok()
+            });
+            helpers.push((to_server_send, from_server_recv, connection));
+        }
+
+        let mut prompts = HashMap::new();
+        for _ in 0..2 {
+            let AskpassEvent::Prompt(request) = event_recv.recv().await.unwrap() else {
+                panic!("expected a prompt");
+            };
+            prompts.insert(request.prompt, request.request_id);
+        }
+        assert_ne!(prompts["first"], prompts["second"]);
+
+        server
+            .respond(&prompts["second"], true, Some("hunter2".to_string()))
+            .await
+            .unwrap();
+        assert!(server
+            .respond(&prompts["second"], true, None)
+            .await
+            .is_err());
+
+        // Only the helper that asked gets the answer, with its own request id
+        let (_, second_recv, _) = &mut helpers[1];
+        assert_eq!(
+            second_recv.recv().await.unwrap(),
+            r#"{"command":"connected"}"#
+        );
+        let response: AskpassResponse =
+            serde_json::from_str(&second_recv.recv().await.unwrap()).unwrap();
+        assert_eq!(
+            response,
+            AskpassResponse {
+                request_id: "1-0".to_string(),
+                approved: true,
+                passphrase: Some("hunter2".to_string()),
+            }
+        );
+        let (_, first_recv, first_connection) = &mut helpers[0];
+        assert_eq!(
+            first_recv.recv().await.unwrap(),
+            r#"{"command":"connected"}"#
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(first_recv.try_recv().is_err());
+
+        // The prompt of a helper that exits is canceled
+        first_connection.abort();
+        assert_eq!(
+            event_recv.recv().await.unwrap(),
+            AskpassEvent::Canceled {
+                request_id: prompts["first"].clone()
+            }
+        );
+        server.stop();
+        ipc_server.stop();
+    }
+}
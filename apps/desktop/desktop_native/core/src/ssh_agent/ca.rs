@@ -0,0 +1,405 @@
+//! Issuance of OpenSSH user certificates, signed with a CA key stored in the vault.
+//!
+//! Every issuance has to be approved by the user through the same UI prompt as agent sign requests.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use ssh_key::{
+    certificate::{Builder, CertType},
+    public::KeyData,
+    PublicKey,
+};
+
+use super::{
+    fingerprint::fingerprint_public_key, BitwardenDesktopAgent, BitwardenSshKey, SshAgentUIRequest,
+};
+
+/// The extensions `ssh-keygen -s` adds to user certificates by default.
+pub const DEFAULT_USER_EXTENSIONS: &[&str] = &[
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+/// Shown as the requesting application in the approval prompt, since certificates are requested from the app itself.
+const CA_PROCESS_NAME: &str = "Bitwarden SSH CA";
+
+#[derive(Debug, Clone)]
+pub struct CertificateRequest {
+    /// The cipher of the CA key in the agent keystore.
+    pub ca_cipher_id: String,
+    /// The OpenSSH public key to certify.
+    pub public_key: String,
+    /// Identifies the certificate in the logs of the server, e.g. the user's email address.
+    pub key_id: String,
+    /// The users the certificate is valid for. At least one principal is required.
+    pub principals: Vec<String>,
+    /// Unix timestamp from which the certificate is valid.
+    pub valid_after: u64,
+    /// Unix timestamp until which the certificate is valid.
+    pub valid_before: u64,
+    /// Critical options such as `force-command` or `source-address`, which the server must understand.
+    pub critical_options: BTreeMap<String, String>,
+    /// Extensions such as `permit-pty`. Most extensions have an empty value.
+    pub extensions: BTreeMap<String, String>,
+    pub serial: u64,
+}
+
+/// What is shown to the user to approve the issuance of a certificate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateApproval {
+    pub key_id: String,
+    pub principals: Vec<String>,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    /// SHA256 fingerprint of the certified public key.
+    pub public_key_fingerprint: String,
+    /// Critical options such as `force-command`, with their values.
+    pub critical_options: BTreeMap<String, String>,
+    /// Extensions such as `permit-pty`, with their values. Most extensions have an empty value.
+    pub extensions: BTreeMap<String, String>,
+}
+
+impl CertificateApproval {
+    /// Describe the certificate in one line, for prompts that can only show text. Names and values come from the
+    /// caller and are escaped.
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "the certificate {} for {}",
+            self.key_id.escape_debug(),
+            self.principals
+                .iter()
+                .map(|principal| principal.escape_debug().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut grants = Vec::new();
+        if !self.critical_options.is_empty() {
+            grants.push(format!(
+                "the critical options {}",
+                describe_options(&self.critical_options)
+            ));
+        }
+        if !self.extensions.is_empty() {
+            grants.push(format!(
+                "the extensions {}",
+                describe_options(&self.extensions)
+            ));
+        }
+        if !grants.is_empty() {
+            description.push_str(&format!(" with {}", grants.join(" and ")));
+        }
+        description
+    }
+}
+
+fn describe_options(options: &BTreeMap<String, String>) -> String {
+    options
+        .iter()
+        .map(|(name, value)| {
+            if value.is_empty() {
+                name.escape_debug().to_string()
+            } else {
+                format!("{}={value:?}", name.escape_debug())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Issue a user certificate for `request.public_key`, signed with the CA key of `request.ca_cipher_id`.
+    ///
+    /// The CA key must be unlocked, and the user has to approve the issuance in the UI. Returns the certificate
+    /// in OpenSSH format, to be saved as `<key>-cert.pub`.
+    pub async fn issue_certificate(&self, request: CertificateRequest) -> Result<String> {
+        if !self.is_running() {
+            return Err(anyhow!(
+                "[BitwardenDesktopAgent] Tried to issue a certificate while agent is not running"
+            ));
+        }
+
+        let (ca_key, ca_public_key) = self.unlocked_ca_key(&request.ca_cipher_id)?;
+
+        // The request is checked before it's shown, so the user is never asked to approve a certificate that can't
+        // be issued
+        let public_key = PublicKey::from_openssh(request.public_key.trim())
+            .map_err(|e| anyhow!("Invalid public key: {e}"))?;
+        let builder = certificate_builder(&public_key, &request)?;
+        let approval = CertificateApproval {
+            key_id: request.key_id.clone(),
+            principals: request.principals.clone(),
+            valid_after: request.valid_after,
+            valid_before: request.valid_before,
+            public_key_fingerprint: fingerprint_public_key(&public_key)?.sha256,
+            critical_options: request.critical_options.clone(),
+            extensions: request.extensions.clone(),
+        };
+
+        let request_id = self.get_request_id().await;
+        let approved = self
+            .request_approval(SshAgentUIRequest {
+                request_id,
+                cipher_id: Some(ca_key.cipher_uuid.clone()),
+                account_id: ca_key.account_id.clone(),
+                process_name: CA_PROCESS_NAME.to_string(),
+                is_list: false,
+                namespace: None,
+                is_forwarding: false,
+                certificate: Some(approval),
+                gpg_operation: None,
+                is_age_decryption: false,
+            })
+            .await;
+        if !approved {
+            return Err(anyhow!("Certificate issuance was denied"));
+        }
+
+        // The vault may have been locked, or the key changed, while the prompt was open
+        let (ca_key, public_key_now) = self.unlocked_ca_key(&request.ca_cipher_id)?;
+        if public_key_now != ca_public_key {
+            return Err(anyhow!(
+                "The CA key {} changed while waiting for approval",
+                request.ca_cipher_id
+            ));
+        }
+        let ca_private_key = ca_key.private_key.expect("The key is unlocked");
+        let certificate = builder
+            .sign(&ca_private_key)
+            .map_err(|e| anyhow!("Failed to sign certificate: {e}"))?;
+        println!(
+            "[BitwardenDesktopAgent] Issued certificate {} with serial {} for {}",
+            certificate.key_id(),
+            certificate.serial(),
+            request.principals.join(",")
+        );
+        certificate
+            .to_openssh()
+            .map_err(|e| anyhow!("Failed to encode certificate: {e}"))
+    }
+
+    /// The key of `cipher_id`, which must be unlocked, and its public key.
+    fn unlocked_ca_key(&self, cipher_id: &str) -> Result<(BitwardenSshKey, KeyData)> {
+        let ca_key = self
+            .key_by_cipher(cipher_id)
+            .ok_or_else(|| anyhow!("No SSH key found for cipher {cipher_id}"))?;
+        let public_key = ca_key
+            .private_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("The CA key {cipher_id} is locked"))?
+            .public_key()
+            .key_data()
+            .clone();
+        Ok((ca_key, public_key))
+    }
+}
+
+/// Check `request`, and prepare the certificate for signing.
+fn certificate_builder(public_key: &PublicKey, request: &CertificateRequest) -> Result<Builder> {
+    if request.principals.is_empty() {
+        return Err(anyhow!("A certificate needs at least one principal"));
+    }
+
+    let mut nonce = vec![0u8; Builder::RECOMMENDED_NONCE_SIZE];
+    rand::rng().fill_bytes(&mut nonce);
+
+    let invalid_field = |e: ssh_key::Error| anyhow!("Invalid certificate: {e}");
+    let mut builder = Builder::new(
+        nonce,
+        public_key.key_data().clone(),
+        request.valid_after,
+        request.valid_before,
+    )
+    .map_err(invalid_field)?;
+    builder
+        .serial(request.serial)
+        .and_then(|builder| builder.cert_type(CertType::User))
+        .and_then(|builder| builder.key_id(request.key_id.clone()))
+        .and_then(|builder| builder.comment(public_key.comment()))
+        .map_err(invalid_field)?;
+    for principal in &request.principals {
+        builder
+            .valid_principal(principal.clone())
+            .map_err(invalid_field)?;
+    }
+    for (name, value) in &request.critical_options {
+        builder
+            .critical_option(name.clone(), value.clone())
+            .map_err(invalid_field)?;
+    }
+    for (name, value) in &request.extensions {
+        builder
+            .extension(name.clone(), value.clone())
+            .map_err(invalid_field)?;
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::{HashAlg, PrivateKey};
+
+    use super::*;
+    use crate::ssh_agent::test_keys::ED25519_PRIVATE_KEY;
+
+    const USER_PUBLIC_KEY: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBABvqtqW6apCsUI3IvWKPMOCHqSG00lr23jMI7veNGzsbHk8iwgS/RZPP3Khc7ytUWVBZlR9nQffPgjnyKtYuFY= user@example.com";
+
+    #[test]
+    fn test_build_certificate() {
+        let ca_private_key = PrivateKey::from_openssh(ED25519_PRIVATE_KEY).unwrap();
+        let public_key = PublicKey::from_openssh(USER_PUBLIC_KEY).unwrap();
+        let request = CertificateRequest {
+            ca_cipher_id: "ca-cipher".to_string(),
+            public_key: USER_PUBLIC_KEY.to_string(),
+            key_id: "user@example.com".to_string(),
+            principals: vec!["user".to_string(), "deploy".to_string()],
+            valid_after: 1_700_000_000,
+            valid_before: 1_700_003_600,
+            critical_options: BTreeMap::from([(
+                "source-address".to_string(),
+                "10.0.0.0/8".to_string(),
+            )]),
+            extensions: DEFAULT_USER_EXTENSIONS
+                .iter()
+                .map(|extension| (extension.to_string(), String::new()))
+                .collect(),
+            serial: 42,
+        };
+
+        let certificate = certificate_builder(&public_key, &request)
+            .unwrap()
+            .sign(&ca_private_key)
+            .unwrap();
+        let ca_fingerprint = ca_private_key.public_key().fingerprint(HashAlg::Sha256);
+        certificate
+            .validate_at(1_700_000_100, [&ca_fingerprint])
+            .unwrap();
+        assert!(certificate
+            .validate_at(1_700_003_601, [&ca_fingerprint])
+            .is_err());
+
+        assert_eq!(certificate.cert_type(), CertType::User);
+        assert_eq!(certificate.serial(), 42);
+        assert_eq!(certificate.valid_principals(), ["user", "deploy"]);
+        assert_eq!(certificate.public_key(), public_key.key_data());
+        assert_eq!(
+            certificate.extensions().len(),
+            DEFAULT_USER_EXTENSIONS.len()
+        );
+        assert_eq!(
+            certificate.critical_options().get("source-address"),
+            Some(&"10.0.0.0/8".to_string())
+        );
+        assert!(certificate
+            .to_openssh()
+            .unwrap()
+            .starts_with("ecdsa-sha2-nistp256-cert-v01@openssh.com "));
+    }
+
+    #[test]
+    fn test_build_certificate_requires_principals() {
+        let public_key = PublicKey::from_openssh(USER_PUBLIC_KEY).unwrap();
+        let request = CertificateRequest {
+            ca_cipher_id: "ca-cipher".to_string(),
+            public_key: USER_PUBLIC_KEY.to_string(),
+            key_id: "user@example.com".to_string(),
+            principals: Vec::new(),
+            valid_after: 1_700_000_000,
+            valid_before: 1_700_003_600,
+            critical_options: BTreeMap::new(),
+            extensions: BTreeMap::new(),
+            serial: 0,
+        };
+
+        assert!(certificate_builder(&public_key, &request).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_issue_certificate() {
+        let (request_tx, mut request_rx) = tokio::sync::mpsc::channel(1);
+        let (response_tx, response_rx) = tokio::sync::broadcast::channel(1);
+        let mut agent = BitwardenDesktopAgent::new(
+            request_tx,
+            std::sync::Arc::new(tokio::sync::Mutex::new(response_rx)),
+        );
+        agent
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        agent
+            .set_keys(vec![(
+                ED25519_PRIVATE_KEY.to_string(),
+                "ca".to_string(),
+                "ca-cipher".to_string(),
+            )])
+            .unwrap();
+        let request = CertificateRequest {
+            ca_cipher_id: "ca-cipher".to_string(),
+            public_key: USER_PUBLIC_KEY.to_string(),
+            key_id: "user@example.com".to_string(),
+            principals: Vec::new(),
+            valid_after: 1_700_000_000,
+            valid_before: 1_700_003_600,
+            critical_options: BTreeMap::new(),
+            extensions: BTreeMap::new(),
+            serial: 0,
+        };
+
+        // An invalid request is refused without asking the user
+        assert!(agent.issue_certificate(request.clone()).await.is_err());
+        assert!(request_rx.try_recv().is_err());
+
+        // Locking the vault while the prompt is open keeps the certificate from being signed
+        let request = CertificateRequest {
+            principals: vec!["user".to_string()],
+            ..request
+        };
+        let issuing_agent = agent.clone();
+        let issued = tokio::spawn(async move { issuing_agent.issue_certificate(request).await });
+        let ui_request = request_rx.recv().await.unwrap();
+        assert!(ui_request.certificate.is_some());
+        agent.lock().unwrap();
+        response_tx.send((ui_request.request_id, true)).unwrap();
+        let error = issued.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("locked"));
+    }
+
+    #[test]
+    fn test_describe_approval() {
+        let approval = CertificateApproval {
+            key_id: "user@example.com".to_string(),
+            principals: vec!["user".to_string(), "root\nroot".to_string()],
+            valid_after: 1_700_000_000,
+            valid_before: 1_700_003_600,
+            public_key_fingerprint: "SHA256:abc".to_string(),
+            critical_options: BTreeMap::from([(
+                "force-command".to_string(),
+                "rm -rf /\n".to_string(),
+            )]),
+            extensions: BTreeMap::from([
+                ("permit-pty".to_string(), String::new()),
+                ("login@example.com".to_string(), "admin".to_string()),
+            ]),
+        };
+        assert_eq!(
+            approval.describe(),
+            "the certificate user@example.com for user, root\\nroot with the critical options \
+             force-command=\"rm -rf /\\n\" and the extensions login@example.com=\"admin\", permit-pty"
+        );
+
+        let approval = CertificateApproval {
+            critical_options: BTreeMap::new(),
+            extensions: BTreeMap::new(),
+            ..approval
+        };
+        assert_eq!(
+            approval.describe(),
+            "the certificate user@example.com for user, root\\nroot"
+        );
+    }
+}
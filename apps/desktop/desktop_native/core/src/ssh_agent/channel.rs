@@ -0,0 +1,95 @@
+//! Answering the JSON requests of helpers, such as the PKCS#11 module and `bitwarden-age`, on a channel of the app's
+//! IPC server, see [`crate::ipc::server::shared`]. The helpers send them with
+//! [`crate::ipc::client::request_on_channel`].
+
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use super::peerinfo::{self, models::PeerInfo};
+use crate::ipc::{
+    peer::PeerPolicy,
+    server::{MessageType, Server},
+};
+
+/// Subscribe to `channel` of `server`, and answer every request of a client with the response `handle` resolves to,
+/// until `cancellation_token` is cancelled. Responses are sent only to the client that made the request.
+///
+/// `handle` is given the application on the other end of the connection, as reported by the OS. Requests usually
+/// wait for the user, so they are handled concurrently.
+pub(super) async fn serve<Req, Res, F, Fut>(
+    server: Arc<Server>,
+    channel: &'static str,
+    policy: PeerPolicy,
+    cancellation_token: CancellationToken,
+    handle: F,
+) -> Result<()>
+where
+    Req: DeserializeOwned + Send + 'static,
+    Res: Serialize + Send + 'static,
+    F: Fn(Req, PeerInfo) -> Fut + Send + 'static,
+    Fut: Future<Output = Res> + Send + 'static,
+{
+    let mut client_to_server_recv = server.subscribe(channel, policy).await?;
+    println!(
+        "[SSH Agent] Listening on the {channel} channel of {}",
+        server.path.display()
+    );
+
+    tokio::spawn(async move {
+        // The process id of each client, from the credentials of its connection
+        let mut peers: HashMap<u32, Option<u32>> = HashMap::new();
+        loop {
+            let message = tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                message = client_to_server_recv.recv() => message,
+            };
+            let Some(message) = message else {
+                break;
+            };
+            let client_id = message.client_id;
+            let message = match message.kind {
+                MessageType::Connected => {
+                    peers.insert(client_id, message.peer.and_then(|peer| peer.pid));
+                    continue;
+                }
+                MessageType::Disconnected | MessageType::Rejected => {
+                    peers.remove(&client_id);
+                    continue;
+                }
+                MessageType::Message => match message.message {
+                    Some(message) => message,
+                    None => continue,
+                },
+            };
+            let request: Req = match serde_json::from_str(&message) {
+                Ok(request) => request,
+                Err(e) => {
+                    println!("[SSH Agent] Invalid request on the {channel} channel: {e}");
+                    continue;
+                }
+            };
+
+            let response = handle(
+                request,
+                peerinfo::gather::peer_info(peers.get(&client_id).copied().flatten()),
+            );
+            let server = server.clone();
+            tokio::spawn(async move {
+                let response =
+                    serde_json::to_string(&response.await).expect("Responses can be serialized");
+                if let Err(e) = server.send_on(client_id, channel, response).await {
+                    println!("[SSH Agent] Could not send a response on the {channel} channel: {e}");
+                }
+            });
+        }
+        // A closed receiver means the channel was subscribed to again, which must be left alone
+        if !client_to_server_recv.is_closed() {
+            server.unsubscribe(channel);
+        }
+        println!("[SSH Agent] Stopped listening on the {channel} channel");
+    });
+    Ok(())
+}
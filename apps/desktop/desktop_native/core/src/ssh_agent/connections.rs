@@ -0,0 +1,273 @@
+//! Tracking of the live connections to the agent socket, so they can be listed and terminated.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ssh_key::{HashAlg, PublicKey};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use super::peerinfo::models::PeerInfo;
+
+/// A connection to the agent, as shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentConnection {
+    pub id: u32,
+    pub process_name: String,
+    pub pid: u32,
+    /// Seconds since the Unix epoch.
+    pub connected_at: u64,
+    /// The number of agent protocol requests received on the connection.
+    pub requests_served: u64,
+    pub is_forwarding: bool,
+    /// The SHA256 fingerprint of the host key the connection was bound to with the `session-bind` extension.
+    pub bound_host_key: Option<String>,
+}
+
+struct TrackedConnection {
+    peer_info: PeerInfo,
+    connected_at: u64,
+    requests_served: Arc<AtomicU64>,
+    cancellation_token: CancellationToken,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionRegistry {
+    connections: Arc<Mutex<HashMap<u32, TrackedConnection>>>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl ConnectionRegistry {
+    /// Register a new connection, which stays listed until the returned stream is dropped.
+    ///
+    /// `peer_info` is shared with the agent, so the forwarding state and bound host set by `session-bind`
+    /// requests show up in the list.
+    pub(crate) fn track<S>(&self, stream: S, peer_info: &PeerInfo) -> TrackedStream<S> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let requests_served = Arc::new(AtomicU64::new(0));
+        let cancellation_token = CancellationToken::new();
+        self.connections
+            .lock()
+            .expect("Mutex is not poisoned")
+            .insert(
+                id,
+                TrackedConnection {
+                    peer_info: peer_info.clone(),
+                    connected_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|duration| duration.as_secs())
+                        .unwrap_or_default(),
+                    requests_served: requests_served.clone(),
+                    cancellation_token: cancellation_token.clone(),
+                },
+            );
+
+        TrackedStream {
+            inner: stream,
+            id,
+            registry: self.clone(),
+            requests_served,
+            frames: FrameCounter::default(),
+            terminated: Box::pin(cancellation_token.cancelled_owned()),
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<AgentConnection> {
+        let mut connections = self
+            .connections
+            .lock()
+            .expect("Mutex is not poisoned")
+            .iter()
+            .map(|(id, connection)| {
+                let host_key = connection.peer_info.host_key();
+                AgentConnection {
+                    id: *id,
+                    process_name: connection.peer_info.process_name().to_string(),
+                    pid: connection.peer_info.pid(),
+                    connected_at: connection.connected_at,
+                    requests_served: connection.requests_served.load(Ordering::Relaxed),
+                    is_forwarding: connection.peer_info.is_forwarding(),
+                    bound_host_key: PublicKey::from_bytes(&host_key)
+                        .ok()
+                        .map(|key| key.fingerprint(HashAlg::Sha256).to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Close the connection `id`. Returns false if there is no such connection.
+    pub(crate) fn terminate(&self, id: u32) -> bool {
+        match self
+            .connections
+            .lock()
+            .expect("Mutex is not poisoned")
+            .get(&id)
+        {
+            Some(connection) => {
+                connection.cancellation_token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close all connections that were forwarded from another host. Returns the number of connections closed.
+    pub(crate) fn terminate_forwarded(&self) -> usize {
+        let connections = self.connections.lock().expect("Mutex is not poisoned");
+        connections
+            .values()
+            .filter(|connection| connection.peer_info.is_forwarding())
+            .inspect(|connection| connection.cancellation_token.cancel())
+            .count()
+    }
+}
+
+/// A connection stream that counts the requests read from it, and ends once the connection is terminated.
+///
+/// A terminated stream reads as closed and fails all writes, so the agent drops the connection the next time
+/// it touches it, even in the middle of a request.
+pub(crate) struct TrackedStream<S> {
+    inner: S,
+    id: u32,
+    registry: ConnectionRegistry,
+    requests_served: Arc<AtomicU64>,
+    frames: FrameCounter,
+    terminated: Pin<Box<WaitForCancellationFutureOwned>>,
+}
+
+impl<S> Drop for TrackedStream<S> {
+    fn drop(&mut self) {
+        self.registry
+            .connections
+            .lock()
+            .expect("Mutex is not poisoned")
+            .remove(&self.id);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.terminated.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let requests = this.frames.feed(&buf.filled()[filled..]);
+            this.requests_served.fetch_add(requests, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.terminated.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Counts the messages of the agent protocol, each a big-endian `u32` length followed by the message, in data
+/// that arrives in arbitrary chunks.
+#[derive(Default)]
+struct FrameCounter {
+    length: [u8; 4],
+    length_read: usize,
+    remaining: usize,
+}
+
+impl FrameCounter {
+    /// Returns the number of messages that start in `data`.
+    fn feed(&mut self, mut data: &[u8]) -> u64 {
+        let mut frames = 0;
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min(data.len());
+                self.remaining -= skipped;
+                data = &data[skipped..];
+                continue;
+            }
+
+            let read = (4 - self.length_read).min(data.len());
+            self.length[self.length_read..self.length_read + read].copy_from_slice(&data[..read]);
+            self.length_read += read;
+            data = &data[read..];
+            if self.length_read == 4 {
+                self.remaining = u32::from_be_bytes(self.length) as usize;
+                self.length_read = 0;
+                frames += 1;
+            }
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_counter() {
+        let mut counter = FrameCounter::default();
+        // Two requests in one read, then a third split in the middle of its length
+        assert_eq!(counter.feed(&[0, 0, 0, 1, 11, 0, 0, 0, 2, 13, 0]), 2);
+        assert_eq!(counter.feed(&[0, 0]), 0);
+        assert_eq!(counter.feed(&[0, 1, 11]), 1);
+    }
+
+    #[tokio::test]
+    async fn test_terminate() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let registry = ConnectionRegistry::default();
+        let (client, server) = tokio::io::duplex(64);
+        let peer_info = PeerInfo::unknown();
+        let mut stream = registry.track(server, &peer_info);
+        assert_eq!(registry.list().len(), 1);
+
+        peer_info.set_forwarding(true);
+        assert!(registry.list()[0].is_forwarding);
+        assert_eq!(registry.terminate_forwarded(), 1);
+        let mut buf = [0u8; 4];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(stream.write_all(b"data").await.is_err());
+
+        drop(stream);
+        drop(client);
+        assert!(registry.list().is_empty());
+        assert!(!registry.terminate(0));
+    }
+}
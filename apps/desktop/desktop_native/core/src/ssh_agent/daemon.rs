@@ -0,0 +1,331 @@
+//! The protocol between the headless agent daemon, `bitwarden-ssh-agent`, and the desktop app.
+//!
+//! The daemon runs the agent outside of the app, and connects to the `ssh-agent` channel of the app's IPC server,
+//! see [`crate::ipc::server::shared`], whenever the app is running. The app sends the keys and the user's approvals
+//! as [`DaemonCommand`]s, and the daemon sends [`DaemonEvent`]s, both JSON encoded. While the app is not connected,
+//! the daemon keeps the keys it was last sent and asks for approval itself.
+//!
+//! The connection is encrypted with the keys of the `ssh-agent` channel, see [`crate::ipc::noise`]. The app
+//! subscribes to it with a [`DaemonServer`] holding the app keys, and the daemon connects with the proxy keys, so that
+//! other processes can neither pose as the app to feed the daemon keys and approvals, nor pose as a daemon to receive
+//! the keys.
+
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::SshAgentUIRequest;
+use crate::ipc::{
+    noise::NoiseKeys,
+    peer::PeerPolicy,
+    server::{MessageType, Server},
+};
+
+/// The channel of the daemons on the app's IPC server, and the name of the keys they connect with, see
+/// [`crate::ipc::noise::key_path`].
+pub const IPC_NAME: &str = "ssh-agent";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(
+    tag = "command",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum DaemonCommand {
+    /// Sent by the IPC client itself once the connection to the app is established.
+    Connected,
+    /// Sent by the IPC client itself once the connection to the app is lost.
+    Disconnected,
+    /// Replace the keys of `account_id`, or all keys if it is not set.
+    SetKeys {
+        account_id: Option<String>,
+        keys: Vec<DaemonKey>,
+    },
+    /// Remove the private keys of `account_id`, or of all accounts, keeping the public keys listable.
+    Lock { account_id: Option<String> },
+    /// Remove the keys of `account_id`, or all keys.
+    ClearKeys { account_id: Option<String> },
+    /// The user's answer to an [`DaemonEvent::ApprovalRequest`].
+    ApprovalResponse { request_id: u32, approved: bool },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DaemonKey {
+    pub private_key: String,
+    pub name: String,
+    pub cipher_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(
+    tag = "command",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum DaemonEvent {
+    /// Sent after connecting, so the app can send the keys.
+    Ready { socket_path: String },
+    /// Ask the user to approve a request, the same way as the serve callback of the in-app agent.
+    ApprovalRequest(SshAgentUIRequest),
+}
+
+/// What the [`DaemonServer`] reports to the app.
+#[derive(Debug, Clone)]
+pub enum DaemonServerEvent {
+    /// A daemon connected, and should be sent the keys.
+    Ready { client_id: u32, socket_path: String },
+    /// A daemon asks the user to approve a request. Answer it with a [`DaemonCommand::ApprovalResponse`] sent to
+    /// that daemon only.
+    ApprovalRequest {
+        client_id: u32,
+        request: SshAgentUIRequest,
+    },
+    /// A daemon disconnected, which denies the requests it was waiting for.
+    Disconnected { client_id: u32 },
+}
+
+/// The app side of the `ssh-agent` channel.
+pub struct DaemonServer {
+    server: Arc<Server>,
+    /// The clients that announced themselves as daemons, which are the only ones sent keys.
+    daemons: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl DaemonServer {
+    /// Subscribe to the `ssh-agent` channel of `server`, accepting only the daemons that hold the other half of
+    /// `keys`, and report what they send to `event_send`.
+    pub async fn start(
+        server: Arc<Server>,
+        keys: NoiseKeys,
+        event_send: mpsc::Sender<DaemonServerEvent>,
+    ) -> Result<Self> {
+        let mut client_to_server_recv = server
+            .subscribe_with_keys(IPC_NAME, PeerPolicy::AllowAll, Some(keys))
+            .await?;
+        let daemons = Arc::new(Mutex::new(HashSet::new()));
+
+        let task_daemons = daemons.clone();
+        tokio::spawn(async move {
+            while let Some(message) = client_to_server_recv.recv().await {
+                let client_id = message.client_id;
+                let event = match message.kind {
+                    MessageType::Message => {
+                        match serde_json::from_str::<DaemonEvent>(
+                            message.message.as_deref().unwrap_or_default(),
+                        ) {
+                            Ok(DaemonEvent::Ready { socket_path }) => {
+                                task_daemons
+                                    .lock()
+                                    .expect("Mutex is not poisoned")
+                                    .insert(client_id);
+                                DaemonServerEvent::Ready {
+                                    client_id,
+                                    socket_path,
+                                }
+                            }
+                            Ok(DaemonEvent::ApprovalRequest(request)) => {
+                                DaemonServerEvent::ApprovalRequest { client_id, request }
+                            }
+                            Err(e) => {
+                                println!(
+                                    "[SSH Agent] Invalid message from daemon {client_id}: {e}"
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    MessageType::Disconnected => {
+                        if !task_daemons
+                            .lock()
+                            .expect("Mutex is not poisoned")
+                            .remove(&client_id)
+                        {
+                            continue;
+                        }
+                        DaemonServerEvent::Disconnected { client_id }
+                    }
+                    MessageType::Connected | MessageType::Rejected => continue,
+                };
+                if event_send.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(DaemonServer { server, daemons })
+    }
+
+    /// Send `command` to every daemon. Returns the number of daemons it was sent to.
+    pub async fn send(&self, command: &DaemonCommand) -> Result<usize> {
+        let message = serde_json::to_string(command)?;
+        let daemons: Vec<_> = self
+            .daemons
+            .lock()
+            .expect("Mutex is not poisoned")
+            .iter()
+            .copied()
+            .collect();
+        let mut sent = 0;
+        for client_id in daemons {
+            if self
+                .server
+                .send_on(client_id, IPC_NAME, message.clone())
+                .await
+                .is_ok()
+            {
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Send `command` to the daemon `client_id` only, such as the answer to one of its approval requests.
+    pub async fn send_to(&self, client_id: u32, command: &DaemonCommand) -> Result<()> {
+        if !self
+            .daemons
+            .lock()
+            .expect("Mutex is not poisoned")
+            .contains(&client_id)
+        {
+            return Err(anyhow!("Daemon {client_id} is not connected"));
+        }
+        self.server
+            .send_on(client_id, IPC_NAME, serde_json::to_string(command)?)
+            .await
+    }
+
+    /// The socket path of the server, which the daemons find with [`crate::ipc::path`].
+    pub fn path(&self) -> &Path {
+        &self.server.path
+    }
+
+    /// Stop listening on the channel. The daemons stay connected to the server, but no longer reach the app.
+    pub fn stop(&self) {
+        self.server.unsubscribe(IPC_NAME);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::MESSAGE_CHANNEL_BUFFER;
+
+    #[test]
+    fn test_protocol() {
+        let command: DaemonCommand =
+            serde_json::from_str(r#"{"command":"approvalResponse","requestId":3,"approved":true}"#)
+                .unwrap();
+        assert_eq!(
+            command,
+            DaemonCommand::ApprovalResponse {
+                request_id: 3,
+                approved: true
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<DaemonCommand>(r#"{"command":"connected"}"#).unwrap(),
+            DaemonCommand::Connected
+        );
+
+        let event = serde_json::to_value(DaemonEvent::ApprovalRequest(SshAgentUIRequest {
+            request_id: 3,
+            cipher_id: Some("cipher".to_string()),
+            account_id: None,
+            process_name: "ssh".to_string(),
+            is_list: false,
+            namespace: None,
+            is_forwarding: false,
+            certificate: None,
+            gpg_operation: None,
+            is_age_decryption: false,
+        }))
+        .unwrap();
+        assert_eq!(event["command"], "approvalRequest");
+        assert_eq!(event["requestId"], 3);
+        assert_eq!(event["cipherId"], "cipher");
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let path = std::env::temp_dir().join(format!("daemon-{}.sock", std::process::id()));
+        let (default_send, _default_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let ipc_server = Arc::new(Server::start(&path, default_send).unwrap());
+        let (app_keys, daemon_keys) = NoiseKeys::generate();
+        let (event_send, mut event_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = DaemonServer::start(ipc_server.clone(), app_keys, event_send)
+            .await
+            .unwrap();
+
+        let (to_app_send, to_app_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (from_app_send, mut from_app_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let ready = DaemonEvent::Ready {
+            socket_path: "/tmp/agent.sock".to_string(),
+        };
+        to_app_send
+            .send(serde_json::to_string(&ready).unwrap())
+            .await
+            .unwrap();
+        let daemon_path = path.clone();
+        let daemon = tokio::spawn(async move {
+            crate::ipc::client::connect_on_channel(
+                daemon_path,
+                IPC_NAME,
+                from_app_send,
+                to_app_recv,
+                Some(daemon_keys),
+            )
+            .await
+            .is_ok()
+        });
+
+        // A process without the keys can't pose as a daemon
+        let (_, other_keys) = NoiseKeys::generate();
+        let (other_send, _other_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (_other_to_app_send, other_to_app_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let result = crate::ipc::client::connect_on_channel(
+            path.clone(),
+            IPC_NAME,
+            other_send,
+            other_to_app_recv,
+            Some(other_keys),
+        )
+        .await;
+        assert!(result.is_err());
+
+        let DaemonServerEvent::Ready {
+            client_id,
+            socket_path,
+        } = event_recv.recv().await.unwrap()
+        else {
+            panic!("expected the daemon to be ready");
+        };
+        assert_eq!(socket_path, "/tmp/agent.sock");
+
+        let command = DaemonCommand::Lock { account_id: None };
+        assert_eq!(server.send(&command).await.unwrap(), 1);
+        assert_eq!(
+            from_app_recv.recv().await.unwrap(),
+            r#"{"command":"connected"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<DaemonCommand>(&from_app_recv.recv().await.unwrap()).unwrap(),
+            command
+        );
+        assert!(server.send_to(client_id + 1, &command).await.is_err());
+
+        daemon.abort();
+        assert!(matches!(
+            event_recv.recv().await.unwrap(),
+            DaemonServerEvent::Disconnected { client_id: id } if id == client_id
+        ));
+        server.stop();
+        ipc_server.stop();
+    }
+}
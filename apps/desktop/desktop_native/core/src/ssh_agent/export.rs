@@ -0,0 +1,422 @@
+//! Export of agent public keys to disk, for use with `IdentitiesOnly yes` and `IdentityFile` in ssh_config.
+//!
+//! All files are written to a directory managed by Bitwarden (by default `~/.ssh/bitwarden`). The files written
+//! are listed in a manifest in the directory, and the ones that are no longer exported are removed on every export,
+//! so public keys of entries that were removed or deselected do not linger. A directory that holds files Bitwarden
+//! did not write, such as `~/.ssh` itself, is refused, so that no file of the user is ever overwritten or removed.
+//!
+//! Key names and host patterns come from the vault, possibly from an item shared by an organization, so they are
+//! never written with line breaks or other control characters that could add lines to `authorized_keys` or
+//! directives to ssh_config.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use homedir::my_home;
+use ssh_key::PublicKey;
+
+use super::{BitwardenDesktopAgent, BitwardenSshKey};
+
+/// Name of the ssh_config fragment written to the export directory.
+pub const SSH_CONFIG_FILE_NAME: &str = "bitwarden_ssh_config";
+
+/// Name of the file listing the files written to the export directory.
+const MANIFEST_FILE_NAME: &str = ".bitwarden-export";
+
+const PUBLIC_KEY_EXTENSION: &str = "pub";
+const MANAGED_HEADER: &str =
+    "# Managed by Bitwarden. This file is overwritten whenever SSH keys are exported.";
+
+/// Map an ssh_config `Host` pattern to the keys that should be offered for it.
+#[derive(Debug, Clone)]
+pub struct HostMapping {
+    pub host_pattern: String,
+    pub cipher_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportedPublicKey {
+    pub cipher_id: String,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct PublicKeyExport {
+    pub keys: Vec<ExportedPublicKey>,
+    /// The exported keys in `authorized_keys` format, one per line.
+    pub authorized_keys: String,
+    /// Path of the written ssh_config fragment, to be referenced with an `Include` directive.
+    pub ssh_config_path: Option<PathBuf>,
+}
+
+/// The default directory public keys are exported to: `~/.ssh/bitwarden`.
+pub fn default_export_directory() -> Result<PathBuf> {
+    match my_home() {
+        Ok(Some(home)) => Ok(home.join(".ssh").join("bitwarden")),
+        _ => Err(anyhow!("Could not determine home directory")),
+    }
+}
+
+impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Write a `.pub` file for every keystore entry in `cipher_ids` into `directory`, and remove the public keys
+    /// of all other entries from it.
+    ///
+    /// If `hosts` is given, an ssh_config fragment mapping each host pattern to the `IdentityFile`s of its keys is
+    /// written as well. Otherwise any previously written fragment is removed.
+    ///
+    /// Fails without touching `directory` if it holds files that were not written by a previous export, or if a
+    /// host pattern contains control characters.
+    pub fn export_public_keys(
+        &self,
+        directory: &Path,
+        cipher_ids: &[String],
+        hosts: Option<&[HostMapping]>,
+    ) -> Result<PublicKeyExport> {
+        for host in hosts.unwrap_or_default() {
+            check_host_pattern(&host.host_pattern)?;
+        }
+
+        let selected: Vec<(String, String, PublicKey)> = self
+            .accounts
+            .read()
+            .expect("RwLock is not poisoned")
+            .values()
+            .flat_map(|account| account.keys.iter())
+            .filter(|(_public_key, key)| cipher_ids.contains(&key.cipher_uuid))
+            .map(|(public_key, key)| {
+                let mut public_key = PublicKey::from_bytes(public_key)
+                    .map_err(|e| anyhow!("Failed to parse public key: {e}"))?;
+                let name = sanitize_name(&key.name);
+                public_key.set_comment(name.clone());
+                Ok((key.cipher_uuid.clone(), name, public_key))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut written = Vec::with_capacity(selected.len() + 1);
+        for (cipher_id, _name, _public_key) in selected.iter() {
+            written.push(public_key_file_name(cipher_id)?);
+        }
+        if hosts.is_some() {
+            written.push(SSH_CONFIG_FILE_NAME.to_owned());
+        }
+
+        fs::create_dir_all(directory)?;
+        let previous = read_manifest(directory)?;
+        // Files are listed before they are written and unlisted after they are removed, so an interrupted export
+        // never leaves files behind that a later export would refuse
+        write_manifest(directory, previous.iter().chain(&written))?;
+
+        let mut keys = Vec::with_capacity(selected.len());
+        for ((cipher_id, name, public_key), file_name) in selected.iter().zip(&written) {
+            let path = directory.join(file_name);
+            fs::write(&path, format!("{}\n", encode_public_key(public_key)?))?;
+            keys.push(ExportedPublicKey {
+                cipher_id: cipher_id.clone(),
+                name: name.clone(),
+                path,
+            });
+        }
+
+        let ssh_config_path = match hosts {
+            Some(hosts) => {
+                let config_path = directory.join(SSH_CONFIG_FILE_NAME);
+                fs::write(&config_path, render_ssh_config(hosts, &keys))?;
+                Some(config_path)
+            }
+            None => None,
+        };
+
+        for stale in previous.iter().filter(|file| !written.contains(file)) {
+            remove_if_exists(&directory.join(stale))?;
+        }
+        write_manifest(directory, &written)?;
+
+        Ok(PublicKeyExport {
+            keys,
+            authorized_keys: render_authorized_keys(&selected)?,
+            ssh_config_path,
+        })
+    }
+}
+
+fn public_key_file_name(cipher_id: &str) -> Result<String> {
+    let is_file_name = !cipher_id.is_empty()
+        && cipher_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !is_file_name {
+        return Err(anyhow!("Invalid cipher id {cipher_id:?}"));
+    }
+    Ok(format!("{cipher_id}.{PUBLIC_KEY_EXTENSION}"))
+}
+
+/// The files written by the previous export to `directory`. Fails if the directory holds any other file.
+fn read_manifest(directory: &Path) -> Result<Vec<String>> {
+    let manifest = match fs::read_to_string(directory.join(MANIFEST_FILE_NAME)) {
+        // Only plain file names are listed, anything else was not written by an export
+        Ok(manifest) => manifest
+            .lines()
+            .filter(|file| Path::new(file).file_name() == Some(std::ffi::OsStr::new(file)))
+            .map(str::to_owned)
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut unknown = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let file_name = entry?.file_name().to_string_lossy().to_string();
+        if file_name != MANIFEST_FILE_NAME && !manifest.contains(&file_name) {
+            unknown.push(file_name);
+        }
+    }
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(anyhow!(
+            "{} contains files that were not exported by Bitwarden: {}",
+            directory.display(),
+            unknown.join(", ")
+        ));
+    }
+
+    Ok(manifest)
+}
+
+fn write_manifest<'a>(directory: &Path, files: impl IntoIterator<Item = &'a String>) -> Result<()> {
+    let mut manifest = String::new();
+    for file in files {
+        manifest.push_str(file);
+        manifest.push('\n');
+    }
+    fs::write(directory.join(MANIFEST_FILE_NAME), manifest)?;
+    Ok(())
+}
+
+/// Replace the control characters in a key name, so that it stays on a single line of `authorized_keys` or of
+/// an ssh_config comment.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Host patterns are written as is, so they must not break out of their `Host` line.
+fn check_host_pattern(host_pattern: &str) -> Result<()> {
+    if host_pattern.trim().is_empty() || host_pattern.chars().any(|c| c.is_control()) {
+        return Err(anyhow!("Invalid host pattern {host_pattern:?}"));
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn encode_public_key(public_key: &PublicKey) -> Result<String> {
+    public_key
+        .to_openssh()
+        .map_err(|e| anyhow!("Failed to encode public key: {e}"))
+}
+
+fn render_authorized_keys(keys: &[(String, String, PublicKey)]) -> Result<String> {
+    let mut authorized_keys = String::new();
+    for (_cipher_id, _name, public_key) in keys {
+        authorized_keys.push_str(&encode_public_key(public_key)?);
+        authorized_keys.push('\n');
+    }
+    Ok(authorized_keys)
+}
+
+fn render_ssh_config(hosts: &[HostMapping], keys: &[ExportedPublicKey]) -> String {
+    let mut config = format!("{MANAGED_HEADER}\n");
+    for host in hosts {
+        let identity_files: Vec<&ExportedPublicKey> = keys
+            .iter()
+            .filter(|key| host.cipher_ids.contains(&key.cipher_id))
+            .collect();
+        if identity_files.is_empty() {
+            continue;
+        }
+
+        config.push_str(&format!("\nHost {}\n", host.host_pattern));
+        for key in identity_files {
+            config.push_str(&format!("    # {}\n", key.name));
+            config.push_str(&format!(
+                "    IdentityFile \"{}\"\n",
+                key.path.to_string_lossy()
+            ));
+        }
+        config.push_str("    IdentitiesOnly yes\n");
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::tests::{new_agent, random_key};
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("ssh-export-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn agent_with_keys(keys: &[(&str, &str)]) -> BitwardenDesktopAgent<BitwardenSshKey> {
+        let mut agent = new_agent();
+        agent
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        agent
+            .set_keys(
+                keys.iter()
+                    .map(|(name, cipher_id)| {
+                        (random_key(), name.to_string(), cipher_id.to_string())
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        agent
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_export_and_remove_stale_files() {
+        let directory = test_directory("stale");
+        let agent = agent_with_keys(&[("GitHub", "cipher-1"), ("GitLab", "cipher-2")]);
+        let hosts = [HostMapping {
+            host_pattern: "github.com".to_string(),
+            cipher_ids: vec!["cipher-1".to_string()],
+        }];
+
+        let export = agent
+            .export_public_keys(
+                &directory,
+                &["cipher-1".to_string(), "cipher-2".to_string()],
+                Some(&hosts),
+            )
+            .unwrap();
+        assert_eq!(export.keys.len(), 2);
+        assert_eq!(export.authorized_keys.lines().count(), 2);
+        assert_eq!(
+            export.ssh_config_path,
+            Some(directory.join(SSH_CONFIG_FILE_NAME))
+        );
+        assert_eq!(
+            file_names(&directory),
+            [
+                MANIFEST_FILE_NAME,
+                SSH_CONFIG_FILE_NAME,
+                "cipher-1.pub",
+                "cipher-2.pub"
+            ]
+        );
+        let public_key = fs::read_to_string(directory.join("cipher-1.pub")).unwrap();
+        assert!(public_key.starts_with("ssh-ed25519 ") && public_key.ends_with(" GitHub\n"));
+
+        // Deselected keys and the fragment are removed once no longer exported
+        let export = agent
+            .export_public_keys(&directory, &["cipher-2".to_string()], None)
+            .unwrap();
+        assert_eq!(export.ssh_config_path, None);
+        assert_eq!(file_names(&directory), [MANIFEST_FILE_NAME, "cipher-2.pub"]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_export_refuses_foreign_files() {
+        let directory = test_directory("foreign");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("id_ed25519.pub"), "ssh-ed25519 AAAA user\n").unwrap();
+        fs::write(directory.join("config"), "Host *\n").unwrap();
+        let agent = agent_with_keys(&[("GitHub", "cipher-1")]);
+
+        assert!(agent
+            .export_public_keys(&directory, &["cipher-1".to_string()], None)
+            .is_err());
+        assert_eq!(file_names(&directory), ["config", "id_ed25519.pub"]);
+        assert_eq!(
+            fs::read_to_string(directory.join("config")).unwrap(),
+            "Host *\n"
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_export_hostile_names() {
+        let directory = test_directory("hostile");
+        let agent = agent_with_keys(&[("GitHub\nssh-ed25519 AAAA attacker", "cipher-1")]);
+        let hosts = [HostMapping {
+            host_pattern: "github.com".to_string(),
+            cipher_ids: vec!["cipher-1".to_string()],
+        }];
+
+        let export = agent
+            .export_public_keys(&directory, &["cipher-1".to_string()], Some(&hosts))
+            .unwrap();
+        assert_eq!(export.authorized_keys.lines().count(), 1);
+        assert!(export
+            .authorized_keys
+            .ends_with(" GitHub ssh-ed25519 AAAA attacker\n"));
+        let config = fs::read_to_string(export.ssh_config_path.unwrap()).unwrap();
+        assert!(config.contains("    # GitHub ssh-ed25519 AAAA attacker\n"));
+
+        let hosts = [HostMapping {
+            host_pattern: "github.com\n    ProxyCommand sh -c evil".to_string(),
+            cipher_ids: vec!["cipher-1".to_string()],
+        }];
+        assert!(agent
+            .export_public_keys(&directory, &["cipher-1".to_string()], Some(&hosts))
+            .is_err());
+        assert!(!fs::read_to_string(directory.join(SSH_CONFIG_FILE_NAME))
+            .unwrap()
+            .contains("ProxyCommand"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_render_ssh_config_skips_hosts_without_keys() {
+        let keys = vec![ExportedPublicKey {
+            cipher_id: "cipher-1".to_string(),
+            name: "GitHub".to_string(),
+            path: PathBuf::from("/home/user/.ssh/bitwarden/cipher-1.pub"),
+        }];
+        let hosts = vec![
+            HostMapping {
+                host_pattern: "github.com".to_string(),
+                cipher_ids: vec!["cipher-1".to_string()],
+            },
+            HostMapping {
+                host_pattern: "*.example.com".to_string(),
+                cipher_ids: vec!["cipher-2".to_string()],
+            },
+        ];
+
+        assert_eq!(
+            render_ssh_config(&hosts, &keys),
+            format!(
+                "{MANAGED_HEADER}\n\nHost github.com\n    # GitHub\n    IdentityFile \"/home/user/.ssh/bitwarden/cipher-1.pub\"\n    IdentitiesOnly yes\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Key fingerprints, matching the output of `ssh-keygen -lv`.
+
+use anyhow::{anyhow, Result};
+use md5::{Digest, Md5};
+use ssh_key::{public::KeyData, EcdsaCurve, HashAlg, Mpint, PrivateKey, PublicKey};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshKeyFingerprint {
+    /// The key type as shown by `ssh-keygen`, e.g. `ED25519` or `RSA`.
+    pub key_type: String,
+    /// The key size in bits.
+    pub bits: u32,
+    /// The SHA256 fingerprint, e.g. `SHA256:r17nBbZt...`.
+    pub sha256: String,
+    /// The legacy MD5 fingerprint, e.g. `MD5:55:a4:5a:...`.
+    pub md5: String,
+    pub comment: String,
+    /// The randomart image of the SHA256 fingerprint.
+    pub randomart: String,
+}
+
+impl SshKeyFingerprint {
+    /// The summary line printed by `ssh-keygen -l`, e.g. `256 SHA256:... user@host (ED25519)`.
+    pub fn summary(&self) -> String {
+        let comment = if self.comment.is_empty() {
+            "no comment"
+        } else {
+            &self.comment
+        };
+        format!(
+            "{} {} {} ({})",
+            self.bits, self.sha256, comment, self.key_type
+        )
+    }
+}
+
+/// Compute the fingerprints of an OpenSSH public key (`ssh-ed25519 AAAA... comment`) or private key.
+///
+/// Encrypted private keys are supported without a passphrase, since their public key is stored in plaintext.
+pub fn fingerprint(key: &str) -> Result<SshKeyFingerprint> {
+    let public_key = parse_public_key(key)?;
+    fingerprint_public_key(&public_key)
+}
+
+pub fn fingerprint_public_key(public_key: &PublicKey) -> Result<SshKeyFingerprint> {
+    let key_type = key_type(public_key.key_data());
+    let bits = key_bits(public_key.key_data());
+    let sha256 = public_key.fingerprint(HashAlg::Sha256);
+    let randomart = sha256.to_randomart(&format!("[{key_type} {bits}]"));
+
+    let key_bytes = public_key
+        .to_bytes()
+        .map_err(|e| anyhow!("Failed to encode public key: {e}"))?;
+    let md5 = Md5::digest(&key_bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<String>>()
+        .join(":");
+
+    Ok(SshKeyFingerprint {
+        key_type,
+        bits,
+        sha256: sha256.to_string(),
+        md5: format!("MD5:{md5}"),
+        comment: public_key.comment().to_string(),
+        randomart,
+    })
+}
+
+pub(crate) fn parse_public_key(key: &str) -> Result<PublicKey> {
+    let key = key.trim();
+    if let Ok(public_key) = PublicKey::from_openssh(key) {
+        return Ok(public_key);
+    }
+
+    match PrivateKey::from_openssh(key) {
+        Ok(private_key) => {
+            let mut public_key = private_key.public_key().clone();
+            public_key.set_comment(private_key.comment());
+            Ok(public_key)
+        }
+        Err(e) => Err(anyhow!("Failed to parse key: {e}")),
+    }
+}
+
+fn key_type(key_data: &KeyData) -> String {
+    match key_data {
+        KeyData::Dsa(_) => "DSA".to_string(),
+        KeyData::Ecdsa(_) => "ECDSA".to_string(),
+        KeyData::Ed25519(_) => "ED25519".to_string(),
+        KeyData::Rsa(_) => "RSA".to_string(),
+        KeyData::SkEcdsaSha2NistP256(_) => "ECDSA-SK".to_string(),
+        KeyData::SkEd25519(_) => "ED25519-SK".to_string(),
+        other => other.algorithm().to_string(),
+    }
+}
+
+fn key_bits(key_data: &KeyData) -> u32 {
+    match key_data {
+        KeyData::Dsa(key) => mpint_bits(&key.p),
+        KeyData::Ecdsa(key) => match key.curve() {
+            EcdsaCurve::NistP256 => 256,
+            EcdsaCurve::NistP384 => 384,
+            EcdsaCurve::NistP521 => 521,
+        },
+        KeyData::Ed25519(_) | KeyData::SkEd25519(_) => 256,
+        KeyData::Rsa(key) => mpint_bits(&key.n),
+        KeyData::SkEcdsaSha2NistP256(_) => 256,
+        _ => 0,
+    }
+}
+
+fn mpint_bits(value: &Mpint) -> u32 {
+    match value.as_positive_bytes() {
+        Some([first, rest @ ..]) => rest.len() as u32 * 8 + (8 - first.leading_zeros()),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::test_keys::{ED25519_PRIVATE_KEY, ED25519_PUBLIC_KEY};
+
+    const RSA_PUBLIC_KEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC7VexZSVGERSDkL39KiwaaDuvGV4DSX4ByCWhACDeRKPv6/1o/9V4lIhTPlENEV/MPSgXs2f4KY3Oa81qCssk9mpYit++6nrtX9mddIBb4nnYgQqOnXNSC0hLEpcPqloZSSjwXDyR7TLB7f5bOtcMXE8fkpdLuymUATkOcPaqgCrk1/22tpRKEqnbvqpxlh81DpukXzbS/cvz16j0EErMJlOJDnTgOHx/ZjoJM7F+Ql1dp+DkaezbsqwgiSY09JD0TpV8mp7LRBxILcbUdptXTF4TUNqPbynuGFRrj9lemyPVUB3dc1shM4K6vb1sZy2tM/Kq08d0Y2IsQTomace6P rsa-test";
+
+    // Output of `ssh-keygen -lv -f` for ED25519_PUBLIC_KEY
+    const ED25519_RANDOMART: &str = "+--[ED25519 256]--+
+|       .oo..     |
+|        . . .    |
+|   E o . . . +   |
+|    . o .   =.+  |
+|        S  .+Bo. |
+|      . .. o *=o |
+|     . . .+.o.O.o|
+|      ...+.oo+=*+|
+|      .+=. ...+*o|
++----[SHA256]-----+";
+
+    #[test]
+    fn test_fingerprint_public_key() {
+        let fingerprint = fingerprint(ED25519_PUBLIC_KEY).unwrap();
+        assert_eq!(
+            fingerprint.summary(),
+            "256 SHA256:r17nBbZtrV3L8okYgvj2eNVPsM8LBzkcWn4SSEDwIiM test@bitwarden (ED25519)"
+        );
+        assert_eq!(
+            fingerprint.md5,
+            "MD5:55:a4:5a:76:c7:29:33:fc:39:ee:49:4a:aa:aa:6b:c4"
+        );
+        assert_eq!(fingerprint.randomart, ED25519_RANDOMART);
+    }
+
+    #[test]
+    fn test_fingerprint_private_key_matches_public_key() {
+        assert_eq!(
+            fingerprint(ED25519_PRIVATE_KEY).unwrap(),
+            fingerprint(ED25519_PUBLIC_KEY).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_rsa_public_key() {
+        let fingerprint = fingerprint(RSA_PUBLIC_KEY).unwrap();
+        assert_eq!(
+            fingerprint.summary(),
+            "2048 SHA256:MRk7LSiSE8CYU8R4z8dXFj7d5fo8Wm93bS4FSOFcvjA rsa-test (RSA)"
+        );
+        assert_eq!(
+            fingerprint.md5,
+            "MD5:7f:cc:d8:d5:b4:6d:61:2a:cf:11:dd:1d:1d:16:72:34"
+        );
+    }
+}
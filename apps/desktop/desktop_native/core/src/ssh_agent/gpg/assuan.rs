@@ -0,0 +1,267 @@
+//! The line based Assuan protocol spoken between gpg and gpg-agent.
+//!
+//! Based on https://www.gnupg.org/documentation/manuals/assuan/
+
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Assuan lines are limited to 1000 bytes, including the line feed.
+const MAX_LINE_LENGTH: usize = 1000;
+/// The libgpg-error source of errors reported by gpg-agent.
+const GPG_ERR_SOURCE_GPGAGENT: u32 = 4;
+
+/// The libgpg-error codes gpg expects from gpg-agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssuanError {
+    NoSecretKey,
+    NotFound,
+    WrongPublicKeyAlgorithm,
+    NoData,
+    NotImplemented,
+    InvalidData,
+    UnsupportedAlgorithm,
+    Canceled,
+    TooLarge,
+    LineTooLong,
+    UnknownCommand,
+    InquireCanceled,
+    Parameter(&'static str),
+}
+
+impl AssuanError {
+    fn code(&self) -> u32 {
+        match self {
+            AssuanError::NoSecretKey => 17,
+            AssuanError::NotFound => 27,
+            AssuanError::WrongPublicKeyAlgorithm => 41,
+            AssuanError::NoData => 58,
+            AssuanError::NotImplemented => 69,
+            AssuanError::InvalidData => 79,
+            AssuanError::UnsupportedAlgorithm => 84,
+            AssuanError::Canceled => 99,
+            AssuanError::TooLarge => 67,
+            AssuanError::LineTooLong => 263,
+            AssuanError::UnknownCommand => 275,
+            AssuanError::InquireCanceled => 277,
+            AssuanError::Parameter(_) => 280,
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            AssuanError::NoSecretKey => "No secret key".to_string(),
+            AssuanError::NotFound => "Not found".to_string(),
+            AssuanError::WrongPublicKeyAlgorithm => "Wrong public key algorithm".to_string(),
+            AssuanError::NoData => "No data".to_string(),
+            AssuanError::NotImplemented => "Not implemented".to_string(),
+            AssuanError::InvalidData => "Invalid data".to_string(),
+            AssuanError::UnsupportedAlgorithm => "Unsupported algorithm".to_string(),
+            AssuanError::Canceled => "Operation cancelled".to_string(),
+            AssuanError::TooLarge => "Provided object is too large".to_string(),
+            AssuanError::LineTooLong => "Line passed to IPC too long".to_string(),
+            AssuanError::UnknownCommand => "Unknown IPC command".to_string(),
+            AssuanError::InquireCanceled => "IPC call has been cancelled".to_string(),
+            AssuanError::Parameter(detail) => format!("IPC parameter error <GPG Agent> - {detail}"),
+        }
+    }
+
+    /// The `ERR` line sent to the client.
+    fn to_line(self) -> String {
+        let code = (GPG_ERR_SOURCE_GPGAGENT << 24) | self.code();
+        match self {
+            AssuanError::Parameter(_) => format!("ERR {code} {}", self.description()),
+            _ => format!("ERR {code} {} <GPG Agent>", self.description()),
+        }
+    }
+}
+
+/// Failure of a command, either reported to the client or ending the connection.
+#[derive(Debug)]
+pub enum CommandError {
+    Assuan(AssuanError),
+    Io(io::Error),
+}
+
+impl From<AssuanError> for CommandError {
+    fn from(error: AssuanError) -> Self {
+        CommandError::Assuan(error)
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(error: io::Error) -> Self {
+        CommandError::Io(error)
+    }
+}
+
+pub struct Connection<R, W> {
+    reader: BufReader<R>,
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    /// Read the next line without the line feed. Returns `None` once the client closed the connection.
+    ///
+    /// Lines longer than the Assuan limit are rejected, and the connection is closed since the rest of the line
+    /// cannot be told apart from the next command.
+    pub async fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let read = (&mut self.reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.pop() != Some(b'\n') {
+            if line.len() + 1 >= MAX_LINE_LENGTH {
+                self.send_error(AssuanError::LineTooLong).await?;
+            }
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+
+    pub async fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.writer.flush().await
+    }
+
+    pub async fn send_ok(&mut self) -> io::Result<()> {
+        self.send_line("OK").await
+    }
+
+    pub async fn send_error(&mut self, error: AssuanError) -> io::Result<()> {
+        self.send_line(&error.to_line()).await
+    }
+
+    pub async fn send_status(&mut self, keyword: &str, arguments: &str) -> io::Result<()> {
+        self.send_line(&format!("S {keyword} {arguments}")).await
+    }
+
+    /// Send `data` in as many `D` lines as needed.
+    pub async fn send_data(&mut self, data: &[u8]) -> io::Result<()> {
+        for line in escape_data(data) {
+            self.writer.write_all(&line).await?;
+        }
+        self.writer.flush().await
+    }
+
+    /// Ask the client for `keyword`, and collect the `D` lines it answers with until `END`.
+    pub async fn inquire(
+        &mut self,
+        keyword: &str,
+        max_length: usize,
+    ) -> Result<Vec<u8>, CommandError> {
+        self.send_status("INQUIRE_MAXLEN", &max_length.to_string())
+            .await?;
+        self.send_line(&format!("INQUIRE {keyword}")).await?;
+
+        let mut data = Vec::new();
+        loop {
+            let Some(line) = self.read_line().await? else {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            };
+            match line.as_slice() {
+                b"END" => return Ok(data),
+                b"CAN" => return Err(AssuanError::InquireCanceled.into()),
+                _ => {
+                    let Some(encoded) = line.strip_prefix(b"D ") else {
+                        return Err(AssuanError::Parameter("unexpected line in inquiry").into());
+                    };
+                    data.extend(unescape(encoded)?);
+                    if data.len() > max_length {
+                        return Err(AssuanError::TooLarge.into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Split `data` into `D` lines, percent-escaping the bytes that would break the line framing.
+fn escape_data(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+    let mut line = b"D ".to_vec();
+    for byte in data {
+        // Leave room for an escaped byte and the line feed
+        if line.len() + 4 > MAX_LINE_LENGTH {
+            line.push(b'\n');
+            lines.push(std::mem::replace(&mut line, b"D ".to_vec()));
+        }
+        match byte {
+            b'%' | b'\r' | b'\n' => line.extend_from_slice(format!("%{byte:02X}").as_bytes()),
+            _ => line.push(*byte),
+        }
+    }
+    line.push(b'\n');
+    lines.push(line);
+    lines
+}
+
+fn unescape(data: &[u8]) -> Result<Vec<u8>, AssuanError> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'%' {
+            unescaped.push(*byte);
+            continue;
+        }
+        let escaped = [
+            *bytes
+                .next()
+                .ok_or(AssuanError::Parameter("invalid escape"))?,
+            *bytes
+                .next()
+                .ok_or(AssuanError::Parameter("invalid escape"))?,
+        ];
+        let value = std::str::from_utf8(&escaped)
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or(AssuanError::Parameter("invalid escape"))?;
+        unescaped.push(value);
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_data() {
+        let data = b"(5:value3:%\n\r)";
+        let lines = escape_data(data);
+        assert_eq!(lines, vec![b"D (5:value3:%25%0A%0D)\n".to_vec()]);
+        assert_eq!(unescape(&lines[0][2..lines[0].len() - 1]).unwrap(), data);
+
+        let lines = escape_data(&[b'%'; 2000]);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH));
+        let unescaped: Vec<u8> = lines
+            .iter()
+            .flat_map(|line| unescape(&line[2..line.len() - 1]).unwrap())
+            .collect();
+        assert_eq!(unescaped, [b'%'; 2000]);
+    }
+
+    #[test]
+    fn test_error_line() {
+        assert_eq!(
+            AssuanError::NoSecretKey.to_line(),
+            "ERR 67108881 No secret key <GPG Agent>"
+        );
+        assert_eq!(
+            AssuanError::Parameter("invalid hexstring").to_line(),
+            "ERR 67109144 IPC parameter error <GPG Agent> - invalid hexstring"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! A gpg-agent compatible listener, which lets gpg sign and decrypt with OpenPGP keys stored in the vault.
+//!
+//! gpg keeps the public keyring, and only delegates the private key operations (`PKSIGN` and `PKDECRYPT`) to the agent
+//! over the Assuan protocol. Every operation is approved through the same UI request as SSH sign requests, so no local
+//! secret keyring is needed. The public keys still have to be imported into gpg.
+//!
+//! gpg must not start its own agent, which can be prevented with `no-autostart` in `gpg.conf`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    peerinfo::models::PeerInfo, BitwardenDesktopAgent, BitwardenSshKey, SshAgentUIRequest,
+};
+
+mod assuan;
+pub mod openpgp;
+#[cfg_attr(target_os = "windows", allow(dead_code))]
+pub(super) mod session;
+pub mod sexp;
+
+use assuan::AssuanError;
+
+/// The private key operation a gpg request asks the user to approve.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum GpgOperation {
+    Sign { hash_algorithm: String },
+    Decrypt,
+}
+
+#[derive(Clone)]
+pub struct BitwardenGpgKey {
+    /// `None` while the vault is locked. The key is still listed, so that gpg asks the agent to use it.
+    pub secret_key: Option<openpgp::SecretKey>,
+    pub public_key: sexp::Sexp,
+    pub fingerprint: String,
+    pub can_sign: bool,
+    pub can_decrypt: bool,
+    pub name: String,
+    pub cipher_uuid: String,
+    /// The account the key was loaded from. `None` for keys set through [`BitwardenDesktopAgent::set_gpg_keys`].
+    pub account_id: Option<String>,
+}
+
+impl BitwardenGpgKey {
+    /// Whether the key was loaded from `account_id`, or `account_id` is `None` for all accounts.
+    fn is_of(&self, account_id: Option<&str>) -> bool {
+        account_id.is_none() || self.account_id.as_deref() == account_id
+    }
+}
+
+/// The OpenPGP keys and subkeys of the agent, indexed by keygrip.
+#[derive(Clone, Default)]
+pub struct GpgKeyStore(pub Arc<RwLock<HashMap<String, BitwardenGpgKey>>>);
+
+impl GpgKeyStore {
+    /// Remove the secret keys of `account_id`, or of all accounts, keeping the keys listed.
+    pub(crate) fn lock(&self, account_id: Option<&str>) {
+        self.0
+            .write()
+            .expect("RwLock is not poisoned")
+            .values_mut()
+            .filter(|key| key.is_of(account_id))
+            .for_each(|key| key.secret_key = None);
+    }
+
+    /// Remove the keys of `account_id`, or of all accounts.
+    pub(crate) fn clear(&self, account_id: Option<&str>) {
+        self.0
+            .write()
+            .expect("RwLock is not poisoned")
+            .retain(|_keygrip, key| !key.is_of(account_id));
+    }
+}
+
+impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Replace all OpenPGP keys in the agent, regardless of the account they were loaded from.
+    ///
+    /// Keys are `(armored private key, name, cipher id)`. Every secret key and subkey in the armored block is added.
+    pub fn set_gpg_keys(&mut self, new_keys: Vec<(String, String, String)>) -> Result<()> {
+        self.replace_gpg_keys(None, new_keys)
+    }
+
+    /// Replace the OpenPGP keys of `account_id`, keeping the keys of all other accounts.
+    pub fn set_account_gpg_keys(
+        &mut self,
+        account_id: &str,
+        new_keys: Vec<(String, String, String)>,
+    ) -> Result<()> {
+        self.replace_gpg_keys(Some(account_id), new_keys)
+    }
+
+    fn replace_gpg_keys(
+        &mut self,
+        account_id: Option<&str>,
+        new_keys: Vec<(String, String, String)>,
+    ) -> Result<()> {
+        if !self.is_running() {
+            return Err(anyhow::anyhow!(
+                "[BitwardenDesktopAgent] Tried to set GPG keys while agent is not running"
+            ));
+        }
+
+        // The keys are parsed first, and swapped in under a single lock, so that gpg never sees the keys missing
+        let mut keys = HashMap::new();
+        for (armored, name, cipher_id) in new_keys.iter() {
+            let secret_keys = match openpgp::parse_secret_keys(armored) {
+                Ok(secret_keys) => secret_keys,
+                Err(e) => {
+                    eprintln!("[GPG Agent] Error while parsing key: {e}");
+                    continue;
+                }
+            };
+            for secret_key in secret_keys {
+                keys.insert(
+                    secret_key.keygrip.clone(),
+                    BitwardenGpgKey {
+                        public_key: secret_key.public_key(),
+                        fingerprint: secret_key.fingerprint.clone(),
+                        can_sign: secret_key.can_sign(),
+                        can_decrypt: secret_key.can_decrypt(),
+                        secret_key: Some(secret_key),
+                        name: name.clone(),
+                        cipher_uuid: cipher_id.clone(),
+                        account_id: account_id.map(str::to_owned),
+                    },
+                );
+            }
+        }
+
+        let mut keystore = self.gpg_keystore.0.write().expect("RwLock is not poisoned");
+        keystore.retain(|_keygrip, key| !key.is_of(account_id));
+        keystore.extend(keys);
+
+        Ok(())
+    }
+
+    fn gpg_keygrips(&self) -> Vec<String> {
+        let mut keygrips: Vec<String> = self
+            .gpg_keystore
+            .0
+            .read()
+            .expect("RwLock is not poisoned")
+            .keys()
+            .cloned()
+            .collect();
+        keygrips.sort();
+        keygrips
+    }
+
+    fn gpg_public_key(&self, keygrip: &str) -> Option<sexp::Sexp> {
+        self.gpg_keystore
+            .0
+            .read()
+            .expect("RwLock is not poisoned")
+            .get(keygrip)
+            .map(|key| key.public_key.clone())
+    }
+
+    /// Ask the user to approve `operation` with the key `keygrip`, and return the secret key once approved.
+    ///
+    /// The key may be locked when the request arrives, in which case the UI unlocks the vault and sets the keys
+    /// again before approving.
+    async fn approve_gpg_operation(
+        &self,
+        keygrip: &str,
+        operation: GpgOperation,
+        info: &PeerInfo,
+    ) -> Result<openpgp::SecretKey, AssuanError> {
+        if !self.is_running() {
+            println!("[BitwardenDesktopAgent] Agent is not running, but tried to use a GPG key");
+            return Err(AssuanError::Canceled);
+        }
+
+        let key = self
+            .gpg_keystore
+            .0
+            .read()
+            .expect("RwLock is not poisoned")
+            .get(keygrip)
+            .cloned()
+            .ok_or(AssuanError::NoSecretKey)?;
+        let supported = match operation {
+            GpgOperation::Sign { .. } => key.can_sign,
+            GpgOperation::Decrypt => key.can_decrypt,
+        };
+        if !supported {
+            return Err(AssuanError::WrongPublicKeyAlgorithm);
+        }
+
+        println!(
+            "[GPG Agent] Confirming {operation:?} with key {} from application: {}",
+            key.fingerprint,
+            info.process_name()
+        );
+        let request_id = self.get_request_id().await;
+        let approved = self
+            .request_approval(SshAgentUIRequest {
+                request_id,
+                cipher_id: Some(key.cipher_uuid.clone()),
+                account_id: key.account_id.clone(),
+                process_name: info.process_name().to_string(),
+                is_list: false,
+                namespace: None,
+                is_forwarding: false,
+                certificate: None,
+                gpg_operation: Some(operation),
+                is_age_decryption: false,
+            })
+            .await;
+        if !approved {
+            return Err(AssuanError::Canceled);
+        }
+
+        self.gpg_keystore
+            .0
+            .read()
+            .expect("RwLock is not poisoned")
+            .get(keygrip)
+            .and_then(|key| key.secret_key.clone())
+            .ok_or(AssuanError::NoSecretKey)
+    }
+}
@@ -0,0 +1,318 @@
+//! The gpg-agent commands gpg uses to sign and decrypt with keys it does not hold itself.
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{
+    assuan::{AssuanError, CommandError, Connection},
+    openpgp::HashAlgorithm,
+    sexp::Sexp,
+    GpgOperation,
+};
+use crate::ssh_agent::{peerinfo::models::PeerInfo, BitwardenDesktopAgent, BitwardenSshKey};
+
+/// The version reported to gpg, which warns when the agent is older than itself.
+const AGENT_VERSION: &str = "2.4.7";
+/// The maximum size of a ciphertext sent with `PKDECRYPT`, matching gpg-agent.
+const MAX_CIPHERTEXT_LENGTH: usize = 4096;
+const KEYGRIP_LENGTH: usize = 20;
+
+/// Serve a single gpg connection until the client disconnects or the agent is stopped.
+pub(crate) async fn serve_connection<S>(
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    stream: S,
+    peer_info: PeerInfo,
+) where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut connection = Connection::new(reader, writer);
+    let cancellation_token = agent.cancellation_token.clone();
+    let mut session = Session {
+        agent,
+        peer_info,
+        sign_keygrip: None,
+        decrypt_keygrip: None,
+        hash: None,
+    };
+
+    tokio::select! {
+        _ = cancellation_token.cancelled() => {}
+        result = session.run(&mut connection) => {
+            if let Err(e) = result {
+                println!("[GPG Agent] Connection closed with error: {e}");
+            }
+        }
+    }
+}
+
+struct Session {
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    peer_info: PeerInfo,
+    /// The key set with `SIGKEY`.
+    sign_keygrip: Option<String>,
+    /// The key set with `SETKEY`.
+    decrypt_keygrip: Option<String>,
+    /// The digest set with `SETHASH`.
+    hash: Option<(HashAlgorithm, Vec<u8>)>,
+}
+
+impl Session {
+    async fn run<R, W>(&mut self, connection: &mut Connection<R, W>) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        connection
+            .send_line(&format!(
+                "OK Pleased to meet you, process {}",
+                std::process::id()
+            ))
+            .await?;
+
+        while let Some(line) = connection.read_line().await? {
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+            let line = String::from_utf8_lossy(&line);
+            let (command, arguments) = line.split_once(' ').unwrap_or((&line, ""));
+            let command = command.to_ascii_uppercase();
+
+            if command == "BYE" {
+                connection.send_line("OK closing connection").await?;
+                return Ok(());
+            }
+            match self.handle(&command, arguments.trim(), connection).await {
+                Ok(()) => connection.send_ok().await?,
+                Err(CommandError::Assuan(error)) => connection.send_error(error).await?,
+                Err(CommandError::Io(error)) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle<R, W>(
+        &mut self,
+        command: &str,
+        arguments: &str,
+        connection: &mut Connection<R, W>,
+    ) -> Result<(), CommandError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match command {
+            "NOP" | "SETKEYDESC" => Ok(()),
+            // Options such as the display or tty are only needed for pinentry, which is replaced by the app's prompt
+            "OPTION" => Ok(()),
+            "RESET" => {
+                self.sign_keygrip = None;
+                self.decrypt_keygrip = None;
+                self.hash = None;
+                Ok(())
+            }
+            "GETINFO" => match arguments {
+                "version" => Ok(connection.send_data(AGENT_VERSION.as_bytes()).await?),
+                "pid" => Ok(connection
+                    .send_data(std::process::id().to_string().as_bytes())
+                    .await?),
+                _ => Err(AssuanError::Parameter("unknown value for WHAT").into()),
+            },
+            "HAVEKEY" => self.have_key(arguments, connection).await,
+            "KEYINFO" => self.key_info(arguments, connection).await,
+            "READKEY" => {
+                let keygrip = parse_keygrip(last_argument(arguments))?;
+                let public_key = self
+                    .agent
+                    .gpg_public_key(&keygrip)
+                    .ok_or(AssuanError::NoSecretKey)?;
+                Ok(connection.send_data(&public_key.encode()).await?)
+            }
+            "SIGKEY" => {
+                self.sign_keygrip = Some(parse_keygrip(arguments)?);
+                Ok(())
+            }
+            "SETKEY" => {
+                self.decrypt_keygrip = Some(parse_keygrip(arguments)?);
+                Ok(())
+            }
+            "SETHASH" => {
+                self.hash = Some(parse_hash(arguments)?);
+                Ok(())
+            }
+            "PKSIGN" => {
+                let keygrip = self.sign_keygrip.clone().ok_or(AssuanError::NoSecretKey)?;
+                let (hash_algorithm, digest) = self.hash.clone().ok_or(AssuanError::NoData)?;
+                let secret_key = self
+                    .agent
+                    .approve_gpg_operation(
+                        &keygrip,
+                        GpgOperation::Sign {
+                            hash_algorithm: hash_algorithm.name().to_string(),
+                        },
+                        &self.peer_info,
+                    )
+                    .await?;
+                let signature = secret_key.sign(hash_algorithm, &digest).map_err(|e| {
+                    println!("[GPG Agent] Error while signing: {e}");
+                    AssuanError::InvalidData
+                })?;
+                Ok(connection.send_data(&signature.encode()).await?)
+            }
+            "PKDECRYPT" => {
+                let keygrip = self
+                    .decrypt_keygrip
+                    .clone()
+                    .ok_or(AssuanError::NoSecretKey)?;
+                let ciphertext = connection
+                    .inquire("CIPHERTEXT", MAX_CIPHERTEXT_LENGTH)
+                    .await?;
+                let ciphertext = Sexp::parse(&ciphertext).map_err(|_| AssuanError::InvalidData)?;
+                let secret_key = self
+                    .agent
+                    .approve_gpg_operation(&keygrip, GpgOperation::Decrypt, &self.peer_info)
+                    .await?;
+                let plaintext = secret_key.decrypt(&ciphertext).map_err(|e| {
+                    println!("[GPG Agent] Error while decrypting: {e}");
+                    AssuanError::InvalidData
+                })?;
+                Ok(connection.send_data(&plaintext.encode()).await?)
+            }
+            _ => Err(AssuanError::UnknownCommand.into()),
+        }
+    }
+
+    /// `HAVEKEY <keygrips>` succeeds if any of the keys is available. `HAVEKEY --list` returns all keygrips.
+    async fn have_key<R, W>(
+        &self,
+        arguments: &str,
+        connection: &mut Connection<R, W>,
+    ) -> Result<(), CommandError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let keygrips = self.agent.gpg_keygrips();
+        if arguments == "--list" || arguments.starts_with("--list=") {
+            let data: Vec<u8> = keygrips
+                .iter()
+                .flat_map(|keygrip| hex::decode(keygrip).expect("keygrips are valid hex"))
+                .collect();
+            return Ok(connection.send_data(&data).await?);
+        }
+
+        let requested = arguments
+            .split_whitespace()
+            .map(parse_keygrip)
+            .collect::<Result<Vec<_>, _>>()?;
+        if requested.iter().any(|keygrip| keygrips.contains(keygrip)) {
+            Ok(())
+        } else {
+            Err(AssuanError::NoSecretKey.into())
+        }
+    }
+
+    /// `KEYINFO [--data] <keygrip>` or `KEYINFO [--data] --list`.
+    async fn key_info<R, W>(
+        &self,
+        arguments: &str,
+        connection: &mut Connection<R, W>,
+    ) -> Result<(), CommandError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut as_data = false;
+        let mut list = false;
+        let mut keygrip = None;
+        for argument in arguments.split_whitespace() {
+            match argument {
+                "--data" => as_data = true,
+                "--list" => list = true,
+                _ if argument.starts_with("--") => {}
+                _ => keygrip = Some(parse_keygrip(argument)?),
+            }
+        }
+
+        let keygrips = match (list, keygrip) {
+            (true, _) => self.agent.gpg_keygrips(),
+            (false, Some(keygrip)) if self.agent.gpg_keygrips().contains(&keygrip) => {
+                vec![keygrip]
+            }
+            (false, Some(_)) => return Err(AssuanError::NotFound.into()),
+            (false, None) => return Err(AssuanError::Parameter("missing keygrip").into()),
+        };
+        for keygrip in keygrips {
+            // Keys are stored without protection, in the vault instead of on disk
+            let info = format!("{keygrip} D - - - C - - -");
+            if as_data {
+                connection
+                    .send_data(format!("KEYINFO {info}").as_bytes())
+                    .await?;
+            } else {
+                connection.send_status("KEYINFO", &info).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Commands taking a keygrip can have options before it, such as `READKEY --no-data <keygrip>`.
+fn last_argument(arguments: &str) -> &str {
+    arguments.split_whitespace().last().unwrap_or_default()
+}
+
+fn parse_keygrip(keygrip: &str) -> Result<String, AssuanError> {
+    match hex::decode(keygrip) {
+        Ok(bytes) if bytes.len() == KEYGRIP_LENGTH => Ok(keygrip.to_ascii_uppercase()),
+        _ => Err(AssuanError::Parameter("invalid hexstring")),
+    }
+}
+
+/// `SETHASH <algorithm id> <hex digest>` or `SETHASH --hash=<name> <hex digest>`.
+fn parse_hash(arguments: &str) -> Result<(HashAlgorithm, Vec<u8>), AssuanError> {
+    let (algorithm, digest) = arguments
+        .split_once(' ')
+        .ok_or(AssuanError::Parameter("missing digest"))?;
+    if algorithm == "--inquire" {
+        return Err(AssuanError::NotImplemented);
+    }
+    let hash_algorithm = match algorithm.strip_prefix("--hash=") {
+        Some(name) => HashAlgorithm::from_name(name),
+        None => algorithm
+            .parse()
+            .ok()
+            .and_then(HashAlgorithm::from_gcrypt_id),
+    }
+    .ok_or(AssuanError::UnsupportedAlgorithm)?;
+
+    let digest =
+        hex::decode(digest.trim()).map_err(|_| AssuanError::Parameter("invalid hexstring"))?;
+    if digest.len() != hash_algorithm.digest_size() {
+        return Err(AssuanError::Parameter("unsupported length of hash"));
+    }
+    Ok((hash_algorithm, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hash() {
+        let digest = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let (algorithm, bytes) = parse_hash(&format!("8 {digest}")).unwrap();
+        assert_eq!(algorithm, HashAlgorithm::Sha256);
+        assert_eq!(hex::encode_upper(bytes), digest);
+        assert_eq!(
+            parse_hash(&format!("--hash=sha256 {digest}")).unwrap().0,
+            HashAlgorithm::Sha256
+        );
+
+        assert_eq!(
+            parse_hash(&format!("99 {digest}")),
+            Err(AssuanError::UnsupportedAlgorithm)
+        );
+        assert!(parse_hash(&format!("10 {digest}")).is_err());
+        assert!(parse_keygrip("00").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Canonical S-expressions, as used by gpg-agent for keys, signatures and ciphertexts.
+//!
+//! Only the canonical encoding (`(3:rsa(1:n3:...))`) is supported, which is the only one sent over the Assuan socket.
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sexp {
+    Atom(Vec<u8>),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    pub fn atom(value: impl Into<Vec<u8>>) -> Self {
+        Sexp::Atom(value.into())
+    }
+
+    /// A list of a name and a single value, such as `(1:s32:...)`.
+    pub fn param(name: &str, value: impl Into<Vec<u8>>) -> Self {
+        Sexp::List(vec![Sexp::atom(name), Sexp::atom(value)])
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut position = 0;
+        let sexp = parse_list(data, &mut position)?;
+        if position != data.len() {
+            return Err(anyhow!("Trailing data after S-expression"));
+        }
+        Ok(sexp)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        self.encode_into(&mut encoded);
+        encoded
+    }
+
+    fn encode_into(&self, encoded: &mut Vec<u8>) {
+        match self {
+            Sexp::Atom(value) => {
+                encoded.extend_from_slice(value.len().to_string().as_bytes());
+                encoded.push(b':');
+                encoded.extend_from_slice(value);
+            }
+            Sexp::List(items) => {
+                encoded.push(b'(');
+                for item in items {
+                    item.encode_into(encoded);
+                }
+                encoded.push(b')');
+            }
+        }
+    }
+
+    /// The name of a list, which is its first atom.
+    pub fn name(&self) -> Option<&[u8]> {
+        match self {
+            Sexp::List(items) => match items.first() {
+                Some(Sexp::Atom(name)) => Some(name),
+                _ => None,
+            },
+            Sexp::Atom(_) => None,
+        }
+    }
+
+    /// Find the first list named `name`, searching depth-first.
+    pub fn find(&self, name: &str) -> Option<&Sexp> {
+        if self.name() == Some(name.as_bytes()) {
+            return Some(self);
+        }
+        match self {
+            Sexp::List(items) => items.iter().find_map(|item| item.find(name)),
+            Sexp::Atom(_) => None,
+        }
+    }
+
+    /// The value of the parameter `name`, e.g. the bytes of `s` in `(1:s32:...)`.
+    pub fn find_value(&self, name: &str) -> Option<&[u8]> {
+        match self.find(name)? {
+            Sexp::List(items) => match items.get(1) {
+                Some(Sexp::Atom(value)) => Some(value),
+                _ => None,
+            },
+            Sexp::Atom(_) => None,
+        }
+    }
+}
+
+fn parse_list(data: &[u8], position: &mut usize) -> Result<Sexp> {
+    if data.get(*position) != Some(&b'(') {
+        return Err(anyhow!("Expected a list at offset {position}"));
+    }
+    *position += 1;
+
+    let mut items = Vec::new();
+    loop {
+        match data.get(*position) {
+            Some(b')') => {
+                *position += 1;
+                return Ok(Sexp::List(items));
+            }
+            Some(b'(') => items.push(parse_list(data, position)?),
+            Some(b'0'..=b'9') => items.push(parse_atom(data, position)?),
+            Some(_) => return Err(anyhow!("Unexpected character at offset {position}")),
+            None => return Err(anyhow!("Unterminated list")),
+        }
+    }
+}
+
+fn parse_atom(data: &[u8], position: &mut usize) -> Result<Sexp> {
+    let length_end = data[*position..]
+        .iter()
+        .position(|byte| *byte == b':')
+        .map(|offset| *position + offset)
+        .ok_or_else(|| anyhow!("Unterminated atom length"))?;
+    let length: usize = std::str::from_utf8(&data[*position..length_end])?
+        .parse()
+        .map_err(|_| anyhow!("Invalid atom length"))?;
+    let start = length_end + 1;
+    let end = start
+        .checked_add(length)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| anyhow!("Atom exceeds the S-expression"))?;
+    *position = end;
+    Ok(Sexp::Atom(data[start..end].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_encode() {
+        let data = b"(7:enc-val(4:ecdh(1:s3:abc)(1:e4:()):)))";
+        let sexp = Sexp::parse(data).unwrap();
+        assert_eq!(sexp.name(), Some(&b"enc-val"[..]));
+        assert_eq!(sexp.find("ecdh").unwrap().name(), Some(&b"ecdh"[..]));
+        assert_eq!(sexp.find_value("e"), Some(&b"()):"[..]));
+        assert_eq!(sexp.find_value("x"), None);
+        assert_eq!(sexp.encode(), data);
+
+        assert!(Sexp::parse(b"(3:rsa(1:a5:abc))").is_err());
+        assert!(Sexp::parse(b"(3:rsa").is_err());
+    }
+}
@@ -1,10 +1,14 @@
-use std::sync::{
-    atomic::{AtomicBool, AtomicU32},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        Arc, RwLock,
+    },
 };
 
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex};
 use tokio_util::sync::CancellationToken;
 
 use bitwarden_russh::{
@@ -20,35 +24,93 @@ mod platform_ssh_agent;
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 mod peercred_unix_listener_stream;
 
+pub mod age;
+pub mod askpass;
+pub mod ca;
+mod channel;
+pub mod connections;
+pub mod daemon;
+pub mod discovery;
+pub mod export;
+pub mod fingerprint;
+pub mod gpg;
 pub mod peerinfo;
+pub mod pkcs11;
+pub mod ppk;
 mod request_parser;
+pub mod sshsig;
+#[cfg(test)]
+mod test_keys;
 
 #[derive(Clone)]
 pub struct BitwardenDesktopAgent<Key> {
     keystore: ssh_agent::KeyStore<Key>,
+    gpg_keystore: gpg::GpgKeyStore,
     cancellation_token: CancellationToken,
     show_ui_request_tx: tokio::sync::mpsc::Sender<SshAgentUIRequest>,
     get_ui_response_rx: Arc<Mutex<tokio::sync::broadcast::Receiver<(u32, bool)>>>,
     request_id: Arc<AtomicU32>,
-    /// before first unlock, or after account switching, listing keys should require an unlock to get a list of public keys
-    needs_unlock: Arc<AtomicBool>,
+    /// The keys of each account, which [`BitwardenDesktopAgent::keystore`] serves merged together.
+    accounts: Accounts,
     is_running: Arc<AtomicBool>,
+    status: Arc<watch::Sender<SshAgentStatus>>,
+    connections: connections::ConnectionRegistry,
 }
 
+/// Lifecycle of the agent's listening socket / named pipe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SshAgentStatus {
+    /// The agent was created but is not accepting connections yet.
+    Starting,
+    /// The agent is accepting connections on `path`.
+    Listening { path: String },
+    /// The agent could not start, or stopped listening because of an error.
+    Failed { reason: String },
+    /// Another agent is already listening on `path`, so this agent refused to replace it, or another agent took
+    /// the socket over, so this agent stopped.
+    Conflict { path: String },
+    /// The agent was stopped and no longer accepts connections.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SshAgentUIRequest {
     pub request_id: u32,
     pub cipher_id: Option<String>,
+    /// The account the requested key belongs to, so the UI can switch to it before prompting.
+    pub account_id: Option<String>,
     pub process_name: String,
     pub is_list: bool,
     pub namespace: Option<String>,
     pub is_forwarding: bool,
+    /// Set when the request is to issue a certificate with the CA key `cipher_id`.
+    pub certificate: Option<ca::CertificateApproval>,
+    /// Set when the request comes from gpg, to sign or decrypt with the OpenPGP key `cipher_id`.
+    pub gpg_operation: Option<gpg::GpgOperation>,
+    /// Set when the request is to decrypt an age file with the SSH key `cipher_id`.
+    pub is_age_decryption: bool,
 }
 
+/// The keys set by one account, or by [`BitwardenDesktopAgent::set_keys`] for the `None` account.
+#[derive(Default)]
+struct AccountKeys {
+    /// The keys, by public key.
+    keys: HashMap<Vec<u8>, BitwardenSshKey>,
+    /// The keys of the account were cleared, e.g. when switching accounts, so listing keys requires an unlock
+    /// to get them back.
+    needs_unlock: bool,
+}
+
+type Accounts = Arc<RwLock<HashMap<Option<String>, AccountKeys>>>;
+
 #[derive(Clone)]
 pub struct BitwardenSshKey {
     pub private_key: Option<ssh_key::private::PrivateKey>,
     pub name: String,
     pub cipher_uuid: String,
+    /// The account the key was loaded from. `None` for keys set without an account, through [`BitwardenDesktopAgent::set_keys`].
+    pub account_id: Option<String>,
 }
 
 impl SshKey for BitwardenSshKey {
@@ -98,67 +160,57 @@ impl ssh_agent::Agent<peerinfo::models::PeerInfo, BitwardenSshKey>
                 return false;
             }
         };
-        let namespace = match request_data {
+        let (namespace, hash_algorithm) = match request_data {
             request_parser::SshAgentSignRequest::SshSigRequest(ref req) => {
-                Some(req.namespace.clone())
+                (Some(req.namespace.clone()), Some(req.hash_algorithm))
             }
-            _ => None,
+            _ => (None, None),
         };
 
         println!(
-            "[SSH Agent] Confirming request from application: {}, is_forwarding: {}, namespace: {}, host_key: {}",
+            "[SSH Agent] Confirming request from application: {}, is_forwarding: {}, namespace: {}, hash_algorithm: {}, host_key: {}",
             info.process_name(),
             info.is_forwarding(),
             namespace.clone().unwrap_or_default(),
+            hash_algorithm.map(|alg| alg.as_str()).unwrap_or_default(),
             STANDARD.encode(info.host_key())
         );
 
-        let mut rx_channel = self.get_ui_response_rx.lock().await.resubscribe();
-        self.show_ui_request_tx
-            .send(SshAgentUIRequest {
-                request_id,
-                cipher_id: Some(ssh_key.cipher_uuid.clone()),
-                process_name: info.process_name().to_string(),
-                is_list: false,
-                namespace,
-                is_forwarding: info.is_forwarding(),
-            })
-            .await
-            .expect("Should send request to ui");
-        while let Ok((id, response)) = rx_channel.recv().await {
-            if id == request_id {
-                return response;
-            }
-        }
-        false
+        self.request_approval(SshAgentUIRequest {
+            request_id,
+            cipher_id: Some(ssh_key.cipher_uuid.clone()),
+            account_id: ssh_key.account_id.clone(),
+            process_name: info.process_name().to_string(),
+            is_list: false,
+            namespace,
+            is_forwarding: info.is_forwarding(),
+            certificate: None,
+            gpg_operation: None,
+            is_age_decryption: false,
+        })
+        .await
     }
 
     async fn can_list(&self, info: &peerinfo::models::PeerInfo) -> bool {
-        if !self.needs_unlock.load(std::sync::atomic::Ordering::Relaxed) {
+        let Some(account_id) = self.account_needing_unlock() else {
             return true;
-        }
+        };
 
         let request_id = self.get_request_id().await;
 
-        let mut rx_channel = self.get_ui_response_rx.lock().await.resubscribe();
-        let message = SshAgentUIRequest {
+        self.request_approval(SshAgentUIRequest {
             request_id,
             cipher_id: None,
+            account_id,
             process_name: info.process_name().to_string(),
             is_list: true,
             namespace: None,
             is_forwarding: info.is_forwarding(),
-        };
-        self.show_ui_request_tx
-            .send(message)
-            .await
-            .expect("Should send request to ui");
-        while let Ok((id, response)) = rx_channel.recv().await {
-            if id == request_id {
-                return response;
-            }
-        }
-        false
+            certificate: None,
+            gpg_operation: None,
+            is_age_decryption: false,
+        })
+        .await
     }
 
     async fn set_sessionbind_info(
@@ -179,24 +231,70 @@ impl ssh_agent::Agent<peerinfo::models::PeerInfo, BitwardenSshKey>
 }
 
 impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Create an agent that is not listening yet, in the [`SshAgentStatus::Starting`] state.
+    fn new(
+        auth_request_tx: tokio::sync::mpsc::Sender<SshAgentUIRequest>,
+        auth_response_rx: Arc<Mutex<tokio::sync::broadcast::Receiver<(u32, bool)>>>,
+    ) -> Self {
+        BitwardenDesktopAgent {
+            keystore: ssh_agent::KeyStore(Arc::new(RwLock::new(HashMap::new()))),
+            gpg_keystore: gpg::GpgKeyStore::default(),
+            cancellation_token: CancellationToken::new(),
+            show_ui_request_tx: auth_request_tx,
+            get_ui_response_rx: auth_response_rx,
+            request_id: Arc::new(AtomicU32::new(0)),
+            accounts: Default::default(),
+            is_running: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(watch::channel(SshAgentStatus::Starting).0),
+            connections: Default::default(),
+        }
+    }
+
     pub fn stop(&self) {
         if !self.is_running() {
             println!("[BitwardenDesktopAgent] Tried to stop agent while it is not running");
             return;
         }
 
+        self.shut_down(SshAgentStatus::Stopped);
+    }
+
+    /// Stop serving and forget all keys, reporting `status`.
+    fn shut_down(&self, status: SshAgentStatus) {
         self.is_running
             .store(false, std::sync::atomic::Ordering::Relaxed);
-        self.keystore
-            .0
-            .write()
-            .expect("RwLock is not poisoned")
-            .clear();
+        self.cancellation_token.cancel();
+        self.set_status(status);
+        let mut accounts = self.accounts.write().expect("RwLock is not poisoned");
+        accounts.clear();
+        self.sync_keystore(&accounts);
+        self.gpg_keystore.clear(None);
     }
 
+    /// Replace all keys in the agent, regardless of the account they were loaded from.
     pub fn set_keys(
         &mut self,
         new_keys: Vec<(String, String, String)>,
+    ) -> Result<(), anyhow::Error> {
+        self.replace_keys(None, new_keys)
+    }
+
+    /// Replace the keys of `account_id`, keeping the keys of all other accounts.
+    ///
+    /// A key that is present in several accounts is kept until it is removed from all of them, and can be used
+    /// as long as one of them is unlocked.
+    pub fn set_account_keys(
+        &mut self,
+        account_id: &str,
+        new_keys: Vec<(String, String, String)>,
+    ) -> Result<(), anyhow::Error> {
+        self.replace_keys(Some(account_id), new_keys)
+    }
+
+    fn replace_keys(
+        &mut self,
+        account_id: Option<&str>,
+        new_keys: Vec<(String, String, String)>,
     ) -> Result<(), anyhow::Error> {
         if !self.is_running() {
             return Err(anyhow::anyhow!(
@@ -204,12 +302,7 @@ impl BitwardenDesktopAgent<BitwardenSshKey> {
             ));
         }
 
-        let keystore = &mut self.keystore;
-        keystore.0.write().expect("RwLock is not poisoned").clear();
-
-        self.needs_unlock
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-
+        let mut keys = HashMap::new();
         for (key, name, cipher_id) in new_keys.iter() {
             match parse_key_safe(key) {
                 Ok(private_key) => {
@@ -217,12 +310,13 @@ impl BitwardenDesktopAgent<BitwardenSshKey> {
                         .public_key()
                         .to_bytes()
                         .expect("Cipher private key is always correctly parsed");
-                    keystore.0.write().expect("RwLock is not poisoned").insert(
+                    keys.insert(
                         public_key_bytes,
                         BitwardenSshKey {
                             private_key: Some(private_key),
                             name: name.clone(),
                             cipher_uuid: cipher_id.clone(),
+                            account_id: account_id.map(str::to_owned),
                         },
                     );
                 }
@@ -232,37 +326,146 @@ impl BitwardenDesktopAgent<BitwardenSshKey> {
             }
         }
 
+        let mut accounts = self.accounts.write().expect("RwLock is not poisoned");
+        if account_id.is_none() {
+            accounts.clear();
+        }
+        accounts.insert(
+            account_id.map(str::to_owned),
+            AccountKeys {
+                keys,
+                needs_unlock: false,
+            },
+        );
+        self.sync_keystore(&accounts);
+
         Ok(())
     }
 
+    /// Remove the private keys of all accounts, keeping the public keys listable.
     pub fn lock(&mut self) -> Result<(), anyhow::Error> {
+        self.lock_keys(None)
+    }
+
+    /// Remove the private keys of `account_id`, keeping the public keys listable.
+    pub fn lock_account(&mut self, account_id: &str) -> Result<(), anyhow::Error> {
+        self.lock_keys(Some(account_id))
+    }
+
+    fn lock_keys(&mut self, account_id: Option<&str>) -> Result<(), anyhow::Error> {
         if !self.is_running() {
             return Err(anyhow::anyhow!(
                 "[BitwardenDesktopAgent] Tried to lock agent, but it is not running"
             ));
         }
 
-        let keystore = &mut self.keystore;
-        keystore
-            .0
-            .write()
-            .expect("RwLock is not poisoned")
+        let mut accounts = self.accounts.write().expect("RwLock is not poisoned");
+        accounts
             .iter_mut()
-            .for_each(|(_public_key, key)| {
-                key.private_key = None;
-            });
+            .filter(|(id, _keys)| account_id.is_none() || id.as_deref() == account_id)
+            .flat_map(|(_id, account)| account.keys.values_mut())
+            .for_each(|key| key.private_key = None);
+        self.sync_keystore(&accounts);
+        self.gpg_keystore.lock(account_id);
         Ok(())
     }
 
     pub fn clear_keys(&mut self) -> Result<(), anyhow::Error> {
-        let keystore = &mut self.keystore;
-        keystore.0.write().expect("RwLock is not poisoned").clear();
-        self.needs_unlock
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let mut accounts = self.accounts.write().expect("RwLock is not poisoned");
+        accounts.clear();
+        self.sync_keystore(&accounts);
+        self.gpg_keystore.clear(None);
 
         Ok(())
     }
 
+    /// Remove all keys of `account_id` from the agent, keeping the keys of all other accounts. Listing keys
+    /// requires an unlock until the keys of the account are set again.
+    pub fn clear_account_keys(&mut self, account_id: &str) -> Result<(), anyhow::Error> {
+        if !self.is_running() {
+            return Err(anyhow::anyhow!(
+                "[BitwardenDesktopAgent] Tried to clear keys while agent is not running"
+            ));
+        }
+
+        let mut accounts = self.accounts.write().expect("RwLock is not poisoned");
+        accounts.insert(
+            Some(account_id.to_owned()),
+            AccountKeys {
+                keys: HashMap::new(),
+                needs_unlock: true,
+            },
+        );
+        self.sync_keystore(&accounts);
+        self.gpg_keystore.clear(Some(account_id));
+
+        Ok(())
+    }
+
+    /// Rebuild the keystore served to SSH clients from the keys of all accounts. A key present in several
+    /// accounts is served with the private key of an account it is unlocked in, if any.
+    fn sync_keystore(&self, accounts: &HashMap<Option<String>, AccountKeys>) {
+        let mut account_ids: Vec<_> = accounts.keys().collect();
+        account_ids.sort();
+
+        let mut keystore = self.keystore.0.write().expect("RwLock is not poisoned");
+        keystore.clear();
+        for account_id in account_ids {
+            for (public_key, key) in &accounts[account_id].keys {
+                let unlocked = keystore
+                    .get(public_key)
+                    .is_some_and(|served: &BitwardenSshKey| served.private_key.is_some());
+                if !unlocked {
+                    keystore.insert(public_key.clone(), key.clone());
+                }
+            }
+        }
+    }
+
+    /// The account whose keys must be unlocked before listing keys, or `Some(None)` if no keys were set yet.
+    /// `None` if listing keys doesn't require an unlock.
+    fn account_needing_unlock(&self) -> Option<Option<String>> {
+        let accounts = self.accounts.read().expect("RwLock is not poisoned");
+        if accounts.is_empty() {
+            return Some(None);
+        }
+
+        let mut account_ids: Vec<_> = accounts
+            .iter()
+            .filter(|(_id, account)| account.needs_unlock)
+            .map(|(id, _account)| id)
+            .collect();
+        account_ids.sort();
+        account_ids.first().map(|id| (*id).clone())
+    }
+
+    /// The key of `cipher_id`, in the account it was set by, whether or not it is served.
+    fn key_by_cipher(&self, cipher_id: &str) -> Option<BitwardenSshKey> {
+        self.accounts
+            .read()
+            .expect("RwLock is not poisoned")
+            .values()
+            .flat_map(|account| account.keys.values())
+            .find(|key| key.cipher_uuid == cipher_id)
+            .cloned()
+    }
+
+    /// Show `request` in the UI and wait for the user to approve or deny it.
+    async fn request_approval(&self, request: SshAgentUIRequest) -> bool {
+        let request_id = request.request_id;
+        let mut rx_channel = self.get_ui_response_rx.lock().await.resubscribe();
+        self.show_ui_request_tx
+            .send(request)
+            .await
+            .expect("Should send request to ui");
+        while let Ok((id, response)) = rx_channel.recv().await {
+            if id == request_id {
+                return response;
+            }
+        }
+        false
+    }
+
     async fn get_request_id(&self) -> u32 {
         if !self.is_running() {
             println!("[BitwardenDesktopAgent] Agent is not running, but tried to get request id");
@@ -273,12 +476,71 @@ impl BitwardenDesktopAgent<BitwardenSshKey> {
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// The connections to the agent socket that are currently open.
+    pub fn connections(&self) -> Vec<connections::AgentConnection> {
+        self.connections.list()
+    }
+
+    /// Close the connection `id` immediately. Returns false if it is no longer open.
+    pub fn terminate_connection(&self, id: u32) -> bool {
+        println!("[BitwardenDesktopAgent] Terminating connection {id}");
+        self.connections.terminate(id)
+    }
+
+    /// Close all connections forwarded from other hosts immediately. Returns the number of connections closed.
+    pub fn terminate_forwarded_connections(&self) -> usize {
+        let count = self.connections.terminate_forwarded();
+        println!("[BitwardenDesktopAgent] Terminated {count} forwarded connections");
+        count
+    }
+
+    /// The name of the SSH key of `cipher_id`, to describe requests outside of the UI.
+    pub fn key_name(&self, cipher_id: &str) -> Option<String> {
+        self.key_by_cipher(cipher_id).map(|key| key.name)
+    }
+
     pub fn is_running(&self) -> bool {
         self.is_running.load(std::sync::atomic::Ordering::Relaxed)
     }
+
+    /// Returns the current lifecycle status of the agent.
+    pub fn status(&self) -> SshAgentStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Subscribe to lifecycle status changes. The receiver starts out with the current status marked as seen.
+    pub fn subscribe_status(&self) -> watch::Receiver<SshAgentStatus> {
+        self.status.subscribe()
+    }
+
+    fn set_status(&self, status: SshAgentStatus) {
+        println!("[BitwardenDesktopAgent] Status changed: {status:?}");
+        self.status.send_replace(status);
+    }
+
+    /// Marks the agent as stopped once the serve loop exits, unless it already failed or was stopped.
+    fn set_exited(&self) {
+        self.is_running
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        self.status.send_if_modified(|status| {
+            if matches!(
+                status,
+                SshAgentStatus::Starting | SshAgentStatus::Listening { .. }
+            ) {
+                *status = SshAgentStatus::Stopped;
+                true
+            } else {
+                false
+            }
+        });
+    }
 }
 
 fn parse_key_safe(pem: &str) -> Result<ssh_key::private::PrivateKey, anyhow::Error> {
+    if ppk::is_ppk(pem) {
+        return ppk::from_ppk(pem, None).map_err(anyhow::Error::from);
+    }
+
     match ssh_key::private::PrivateKey::from_openssh(pem) {
         Ok(key) => match key.public_key().to_bytes() {
             Ok(_) => Ok(key),
@@ -289,3 +551,126 @@ fn parse_key_safe(pem: &str) -> Result<ssh_key::private::PrivateKey, anyhow::Err
         Err(e) => Err(anyhow::Error::msg(format!("Failed to parse key: {e}"))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn new_agent() -> BitwardenDesktopAgent<BitwardenSshKey> {
+        let (request_tx, _request_rx) = tokio::sync::mpsc::channel(1);
+        let (_response_tx, response_rx) = tokio::sync::broadcast::channel(1);
+        BitwardenDesktopAgent::new(request_tx, Arc::new(Mutex::new(response_rx)))
+    }
+
+    #[test]
+    fn test_exited_status() {
+        let agent = new_agent();
+        assert_eq!(agent.status(), SshAgentStatus::Starting);
+        assert!(!agent.is_running());
+
+        agent
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        agent.set_status(SshAgentStatus::Listening {
+            path: "agent.sock".to_owned(),
+        });
+        let mut status = agent.subscribe_status();
+        agent.set_exited();
+        assert!(!agent.is_running());
+        assert!(status.has_changed().unwrap());
+        assert_eq!(*status.borrow_and_update(), SshAgentStatus::Stopped);
+
+        // The reason the agent failed is kept once the serve loop exits
+        let agent = new_agent();
+        let failed = SshAgentStatus::Failed {
+            reason: "Address in use".to_owned(),
+        };
+        agent.set_status(failed.clone());
+        agent.set_exited();
+        assert_eq!(agent.status(), failed);
+    }
+
+    pub(super) fn random_key() -> String {
+        ssh_key::PrivateKey::random(&mut ssh_key::rand_core::OsRng, ssh_key::Algorithm::Ed25519)
+            .unwrap()
+            .to_openssh(ssh_key::LineEnding::LF)
+            .unwrap()
+            .to_string()
+    }
+
+    /// Whether the key is served, and whether it is unlocked if it is.
+    fn served(agent: &BitwardenDesktopAgent<BitwardenSshKey>, key: &str) -> Option<bool> {
+        let public_key = parse_key_safe(key)
+            .unwrap()
+            .public_key()
+            .to_bytes()
+            .unwrap();
+        agent
+            .keystore
+            .0
+            .read()
+            .unwrap()
+            .get(&public_key)
+            .map(|key| key.private_key.is_some())
+    }
+
+    #[test]
+    fn test_account_lock_and_clear() {
+        let mut agent = new_agent();
+        agent
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(agent.account_needing_unlock(), Some(None));
+
+        let (personal, shared, work) = (random_key(), random_key(), random_key());
+        let entry = |key: &str, cipher_id: &str| {
+            (key.to_owned(), cipher_id.to_owned(), cipher_id.to_owned())
+        };
+        agent
+            .set_account_keys(
+                "personal",
+                vec![entry(&personal, "p1"), entry(&shared, "p2")],
+            )
+            .unwrap();
+        agent
+            .set_account_keys("work", vec![entry(&shared, "w1"), entry(&work, "w2")])
+            .unwrap();
+        assert_eq!(agent.account_needing_unlock(), None);
+        assert_eq!(agent.keystore.0.read().unwrap().len(), 3);
+
+        // A shared key stays usable while one of its accounts is unlocked
+        agent.lock_account("personal").unwrap();
+        assert_eq!(served(&agent, &personal), Some(false));
+        assert_eq!(served(&agent, &shared), Some(true));
+        assert_eq!(served(&agent, &work), Some(true));
+        assert!(agent.key_by_cipher("p2").unwrap().private_key.is_none());
+        assert!(agent.key_by_cipher("w1").unwrap().private_key.is_some());
+
+        // Clearing an account keeps the keys it shares with others, and only that account needs an unlock
+        agent.clear_account_keys("work").unwrap();
+        assert_eq!(served(&agent, &shared), Some(false));
+        assert_eq!(served(&agent, &work), None);
+        assert_eq!(agent.key_name("p2"), Some("p2".to_owned()));
+        assert_eq!(agent.key_name("w1"), None);
+        assert_eq!(
+            agent.account_needing_unlock(),
+            Some(Some("work".to_owned()))
+        );
+
+        agent
+            .set_account_keys("work", vec![entry(&work, "w2")])
+            .unwrap();
+        assert_eq!(agent.account_needing_unlock(), None);
+        assert_eq!(served(&agent, &personal), Some(false));
+        assert_eq!(served(&agent, &work), Some(true));
+
+        agent.lock().unwrap();
+        assert_eq!(served(&agent, &work), Some(false));
+        agent.clear_keys().unwrap();
+        assert!(agent.keystore.0.read().unwrap().is_empty());
+        assert_eq!(agent.account_needing_unlock(), Some(None));
+
+        agent.stop();
+        assert!(agent.clear_account_keys("work").is_err());
+    }
+}
@@ -12,13 +12,17 @@ use std::{
 use tokio::{
     net::windows::named_pipe::{NamedPipeServer, ServerOptions},
     select,
+    sync::watch,
 };
 use tokio_util::sync::CancellationToken;
 use windows::Win32::{Foundation::HANDLE, System::Pipes::GetNamedPipeClientProcessId};
 
-use crate::ssh_agent::peerinfo::{self, models::PeerInfo};
+use crate::ssh_agent::{
+    peerinfo::{self, models::PeerInfo},
+    SshAgentStatus,
+};
 
-const PIPE_NAME: &str = r"\\.\pipe\openssh-ssh-agent";
+pub const PIPE_NAME: &str = r"\\.\pipe\openssh-ssh-agent";
 
 #[pin_project::pin_project]
 pub struct NamedPipeServerStream {
@@ -26,23 +30,34 @@ pub struct NamedPipeServerStream {
 }
 
 impl NamedPipeServerStream {
-    pub fn new(cancellation_token: CancellationToken, is_running: Arc<AtomicBool>) -> Self {
+    /// Creates the first pipe instance and starts accepting connections in the background.
+    /// Fails if the pipe cannot be created, for example when the system's OpenSSH agent service
+    /// or another Bitwarden instance owns it.
+    pub fn new(
+        cancellation_token: CancellationToken,
+        is_running: Arc<AtomicBool>,
+        status: Arc<watch::Sender<SshAgentStatus>>,
+    ) -> io::Result<Self> {
+        println!(
+            "[SSH Agent Native Module] Creating named pipe server on {}",
+            PIPE_NAME
+        );
+        // Creating the first instance fails with access denied if another process already owns the pipe,
+        // instead of silently adding an instance that competes with it for connections.
+        let mut listener = match ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(PIPE_NAME)
+        {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                println!("[SSH Agent Native Module] Encountered an error creating the first pipe. The system's openssh service must likely be disabled");
+                println!("[SSH Agent Natvie Module] error: {}", err);
+                return Err(err);
+            }
+        };
+
         let (tx, rx) = tokio::sync::mpsc::channel(16);
         tokio::spawn(async move {
-            println!(
-                "[SSH Agent Native Module] Creating named pipe server on {}",
-                PIPE_NAME
-            );
-            let mut listener = match ServerOptions::new().create(PIPE_NAME) {
-                Ok(pipe) => pipe,
-                Err(err) => {
-                    println!("[SSH Agent Native Module] Encountered an error creating the first pipe. The system's openssh service must likely be disabled");
-                    println!("[SSH Agent Natvie Module] error: {}", err);
-                    cancellation_token.cancel();
-                    is_running.store(false, Ordering::Relaxed);
-                    return;
-                }
-            };
             loop {
                 println!("[SSH Agent Native Module] Waiting for connection");
                 select! {
@@ -76,6 +91,7 @@ impl NamedPipeServerStream {
                             Ok(pipe) => pipe,
                             Err(err) => {
                                 println!("[SSH Agent Native Module] Encountered an error creating a new pipe {}", err);
+                                status.send_replace(SshAgentStatus::Failed { reason: err.to_string() });
                                 cancellation_token.cancel();
                                 is_running.store(false, Ordering::Relaxed);
                                 return;
@@ -85,7 +101,7 @@ impl NamedPipeServerStream {
                 }
             }
         });
-        Self { rx }
+        Ok(Self { rx })
     }
 }
 
@@ -3,6 +3,7 @@ use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
 
 use super::peerinfo;
 use super::peerinfo::models::PeerInfo;
@@ -10,11 +11,17 @@ use super::peerinfo::models::PeerInfo;
 #[derive(Debug)]
 pub struct PeercredUnixListenerStream {
     inner: UnixListener,
+    replacements: mpsc::Receiver<UnixListener>,
 }
 
 impl PeercredUnixListenerStream {
-    pub fn new(listener: UnixListener) -> Self {
-        Self { inner: listener }
+    /// Listeners received on `replacements` take the place of the current listener,
+    /// which allows the socket to be re-created without restarting the agent.
+    pub fn new(listener: UnixListener, replacements: mpsc::Receiver<UnixListener>) -> Self {
+        Self {
+            inner: listener,
+            replacements,
+        }
     }
 }
 
@@ -25,7 +32,12 @@ impl Stream for PeercredUnixListenerStream {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<io::Result<(UnixStream, PeerInfo)>>> {
-        match self.inner.poll_accept(cx) {
+        let this = self.get_mut();
+        while let Poll::Ready(Some(listener)) = this.replacements.poll_recv(cx) {
+            this.inner = listener;
+        }
+
+        match this.inner.poll_accept(cx) {
             Poll::Ready(Ok((stream, _))) => {
                 let pid = match stream.peer_cred() {
                     Ok(peer) => match peer.pid() {
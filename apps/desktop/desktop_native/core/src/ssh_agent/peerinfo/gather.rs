@@ -21,3 +21,9 @@ pub fn get_peer_info(peer_pid: u32) -> Result<PeerInfo, String> {
 
     Err("Failed to get process".to_string())
 }
+
+/// The application on the other end of an IPC connection, identified by the process id of the connection.
+pub fn peer_info(pid: Option<u32>) -> PeerInfo {
+    pid.and_then(|pid| get_peer_info(pid).ok())
+        .unwrap_or_else(PeerInfo::unknown)
+}
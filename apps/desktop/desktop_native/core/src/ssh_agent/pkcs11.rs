@@ -0,0 +1,438 @@
+//! The app side of the PKCS#11 module, which lets applications that speak PKCS#11 instead of the agent protocol
+//! (`ssh -I`, Firefox, OpenVPN) sign with the SSH keys in the agent.
+//!
+//! The module connects to the `pkcs11` channel of the app's IPC server, see [`crate::ipc::server::shared`], for
+//! every call, and exchanges JSON encoded [`Pkcs11Request`]s and
+//! [`Pkcs11Response`]s. Responses are sent only to the client that made the request, and carry the id of the request
+//! they answer. They only contain public keys and signatures.
+//!
+//! The application shown in approval prompts is the process on the other end of the socket, as reported by the OS.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bitwarden_russh::ssh_agent::Agent;
+use ed25519_dalek::Signer;
+use p256::ecdsa::signature::hazmat::PrehashSigner;
+use rsa::Pkcs1v15Sign;
+use serde::{Deserialize, Serialize};
+use ssh_key::{
+    private::{EcdsaKeypair, KeypairData},
+    PrivateKey, PublicKey,
+};
+
+use super::{
+    channel, peerinfo::models::PeerInfo, BitwardenDesktopAgent, BitwardenSshKey, SshAgentUIRequest,
+};
+use crate::ipc::{peer::PeerPolicy, server::Server};
+
+/// The channel of the PKCS#11 module on the app's IPC server.
+pub const IPC_NAME: &str = "pkcs11";
+
+/// The PKCS#11 signing mechanisms supported for the key types of the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Pkcs11Mechanism {
+    /// `CKM_RSA_PKCS`, PKCS#1 v1.5 padding of data the caller already wrapped in a DigestInfo.
+    RsaPkcs,
+    /// `CKM_ECDSA`, signing a digest the caller computed.
+    Ecdsa,
+    /// `CKM_EDDSA`, signing the whole message with Ed25519.
+    Eddsa,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(
+    tag = "command",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Pkcs11Request {
+    ListKeys {
+        request_id: String,
+    },
+    Sign {
+        request_id: String,
+        /// The OpenSSH public key of the key to sign with.
+        public_key: String,
+        mechanism: Pkcs11Mechanism,
+        /// Base64 encoded.
+        data: String,
+    },
+}
+
+impl Pkcs11Request {
+    pub fn request_id(&self) -> &str {
+        match self {
+            Pkcs11Request::ListKeys { request_id, .. } | Pkcs11Request::Sign { request_id, .. } => {
+                request_id
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(
+    tag = "command",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Pkcs11Response {
+    Keys {
+        request_id: String,
+        keys: Vec<Pkcs11Key>,
+    },
+    Signature {
+        request_id: String,
+        /// Base64 encoded, in the format of the mechanism.
+        signature: String,
+    },
+    Error {
+        request_id: String,
+        message: String,
+    },
+}
+
+impl Pkcs11Response {
+    pub fn request_id(&self) -> &str {
+        match self {
+            Pkcs11Response::Keys { request_id, .. }
+            | Pkcs11Response::Signature { request_id, .. }
+            | Pkcs11Response::Error { request_id, .. } => request_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Pkcs11Key {
+    pub name: String,
+    /// The OpenSSH public key.
+    pub public_key: String,
+}
+
+/// Sign `data` with `private_key` as the PKCS#11 `mechanism` does, returning the signature in PKCS#11 format.
+pub fn sign(private_key: &PrivateKey, mechanism: Pkcs11Mechanism, data: &[u8]) -> Result<Vec<u8>> {
+    match (private_key.key_data(), mechanism) {
+        (KeypairData::Rsa(keypair), Pkcs11Mechanism::RsaPkcs) => {
+            let private_key = rsa::RsaPrivateKey::try_from(keypair)
+                .map_err(|e| anyhow!("Invalid RSA key: {e}"))?;
+            Ok(private_key.sign(Pkcs1v15Sign::new_unprefixed(), data)?)
+        }
+        (KeypairData::Ecdsa(EcdsaKeypair::NistP256 { private, .. }), Pkcs11Mechanism::Ecdsa) => {
+            let signing_key = p256::ecdsa::SigningKey::from_slice(private.as_slice())?;
+            let signature: p256::ecdsa::Signature = signing_key.sign_prehash(data)?;
+            Ok(signature.to_bytes().to_vec())
+        }
+        (KeypairData::Ecdsa(EcdsaKeypair::NistP384 { private, .. }), Pkcs11Mechanism::Ecdsa) => {
+            let signing_key = p384::ecdsa::SigningKey::from_slice(private.as_slice())?;
+            let signature: p384::ecdsa::Signature = signing_key.sign_prehash(data)?;
+            Ok(signature.to_bytes().to_vec())
+        }
+        (KeypairData::Ed25519(keypair), Pkcs11Mechanism::Eddsa) => {
+            let signing_key = ed25519_dalek::SigningKey::from_bytes(&keypair.private.to_bytes());
+            Ok(signing_key.sign(data).to_bytes().to_vec())
+        }
+        _ => Err(anyhow!(
+            "{mechanism:?} is not supported for {} keys",
+            private_key.algorithm()
+        )),
+    }
+}
+
+impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Subscribe to the `pkcs11` channel of `server`, and answer the requests of the PKCS#11 module with the SSH keys
+    /// in the agent. Returns the socket path.
+    pub async fn start_pkcs11_server(&self, server: Arc<Server>) -> Result<String> {
+        if !self.is_running() {
+            return Err(anyhow!(
+                "[BitwardenDesktopAgent] Tried to start the PKCS#11 server while agent is not running"
+            ));
+        }
+
+        let path = server
+            .path
+            .to_str()
+            .map(|path| path.to_owned())
+            .ok_or_else(|| anyhow!("Socket path is not valid UTF-8"))?;
+        self.serve_pkcs11(server).await?;
+        Ok(path)
+    }
+
+    async fn serve_pkcs11(&self, server: Arc<Server>) -> Result<()> {
+        // The module is loaded into any application that speaks PKCS#11, so there is no executable to allow
+        let agent = self.clone();
+        channel::serve(
+            server,
+            IPC_NAME,
+            PeerPolicy::AllowAll,
+            self.cancellation_token.clone(),
+            move |request: Pkcs11Request, peer_info| {
+                let agent = agent.clone();
+                async move { agent.handle_pkcs11_request(request, &peer_info).await }
+            },
+        )
+        .await
+    }
+
+    async fn handle_pkcs11_request(
+        &self,
+        request: Pkcs11Request,
+        peer_info: &PeerInfo,
+    ) -> Pkcs11Response {
+        let request_id = request.request_id().to_owned();
+        let result = match request {
+            Pkcs11Request::ListKeys { .. } => {
+                self.list_pkcs11_keys(peer_info)
+                    .await
+                    .map(|keys| Pkcs11Response::Keys {
+                        request_id: request_id.clone(),
+                        keys,
+                    })
+            }
+            Pkcs11Request::Sign {
+                public_key,
+                mechanism,
+                data,
+                ..
+            } => self
+                .sign_pkcs11(peer_info, &public_key, mechanism, &data)
+                .await
+                .map(|signature| Pkcs11Response::Signature {
+                    request_id: request_id.clone(),
+                    signature: STANDARD.encode(signature),
+                }),
+        };
+        result.unwrap_or_else(|e| {
+            println!("[SSH Agent] PKCS#11 request failed: {e}");
+            Pkcs11Response::Error {
+                request_id,
+                message: e.to_string(),
+            }
+        })
+    }
+
+    async fn list_pkcs11_keys(&self, info: &PeerInfo) -> Result<Vec<Pkcs11Key>> {
+        if !self.can_list(info).await {
+            return Err(anyhow!("Listing keys was not approved"));
+        }
+
+        let keystore = self.keystore.0.read().expect("RwLock is not poisoned");
+        let mut keys = keystore
+            .iter()
+            .map(|(public_key_bytes, key)| {
+                let public_key = PublicKey::from_bytes(public_key_bytes)
+                    .and_then(|public_key| public_key.to_openssh())
+                    .map_err(|e| anyhow!("Failed to encode public key: {e}"))?;
+                Ok(Pkcs11Key {
+                    name: key.name.clone(),
+                    public_key,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(keys)
+    }
+
+    async fn sign_pkcs11(
+        &self,
+        info: &PeerInfo,
+        public_key: &str,
+        mechanism: Pkcs11Mechanism,
+        data: &str,
+    ) -> Result<Vec<u8>> {
+        if !self.is_running() {
+            return Err(anyhow!("The agent is not running"));
+        }
+
+        let public_key_bytes = PublicKey::from_openssh(public_key)
+            .and_then(|public_key| public_key.to_bytes())
+            .map_err(|e| anyhow!("Invalid public key: {e}"))?;
+        let data = STANDARD.decode(data)?;
+        let key = self
+            .keystore
+            .0
+            .read()
+            .expect("RwLock is not poisoned")
+            .get(&public_key_bytes)
+            .cloned()
+            .ok_or_else(|| anyhow!("The key is not in the agent"))?;
+
+        println!(
+            "[SSH Agent] Confirming PKCS#11 {mechanism:?} signature with key {} from application: {}",
+            key.name,
+            info.process_name()
+        );
+        let request_id = self.get_request_id().await;
+        let approved = self
+            .request_approval(SshAgentUIRequest {
+                request_id,
+                cipher_id: Some(key.cipher_uuid.clone()),
+                account_id: key.account_id.clone(),
+                process_name: info.process_name().to_string(),
+                is_list: false,
+                namespace: None,
+                is_forwarding: false,
+                certificate: None,
+                gpg_operation: None,
+                is_age_decryption: false,
+            })
+            .await;
+        if !approved {
+            return Err(anyhow!("Signing was not approved"));
+        }
+
+        // The vault may have been unlocked while approving, so the private key is only read now
+        let private_key = self
+            .keystore
+            .0
+            .read()
+            .expect("RwLock is not poisoned")
+            .get(&public_key_bytes)
+            .and_then(|key| key.private_key.clone())
+            .ok_or_else(|| anyhow!("The key is locked"))?;
+        sign(&private_key, mechanism, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        ipc::MESSAGE_CHANNEL_BUFFER,
+        ssh_agent::{peerinfo::gather::peer_info, test_keys::ECDSA_P256_PRIVATE_KEY},
+    };
+
+    #[test]
+    fn test_request_format() {
+        let request: Pkcs11Request = serde_json::from_str(
+            r#"{"command":"sign","requestId":"1","publicKey":"ssh-ed25519 AAAA","mechanism":"eddsa","data":"aGk="}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Pkcs11Request::Sign {
+                request_id: "1".to_string(),
+                public_key: "ssh-ed25519 AAAA".to_string(),
+                mechanism: Pkcs11Mechanism::Eddsa,
+                data: "aGk=".to_string(),
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&Pkcs11Response::Error {
+                request_id: "1".to_string(),
+                message: "denied".to_string(),
+            })
+            .unwrap(),
+            r#"{"command":"error","requestId":"1","message":"denied"}"#
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        use p256::ecdsa::signature::hazmat::PrehashVerifier;
+
+        let private_key = PrivateKey::from_openssh(ECDSA_P256_PRIVATE_KEY).unwrap();
+        let digest = [7u8; 32];
+        let signature = sign(&private_key, Pkcs11Mechanism::Ecdsa, &digest).unwrap();
+
+        let KeypairData::Ecdsa(EcdsaKeypair::NistP256 { public, .. }) = private_key.key_data()
+        else {
+            panic!("not a P-256 key");
+        };
+        let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public.as_bytes()).unwrap();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key.verify_prehash(&digest, &signature).is_ok());
+
+        assert!(sign(&private_key, Pkcs11Mechanism::RsaPkcs, &digest).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_pkcs11() {
+        let (request_tx, mut request_rx) = mpsc::channel(1);
+        let (response_tx, response_rx) = tokio::sync::broadcast::channel(1);
+        let mut agent =
+            BitwardenDesktopAgent::new(request_tx, Arc::new(tokio::sync::Mutex::new(response_rx)));
+        agent
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        agent
+            .set_keys(vec![(
+                ECDSA_P256_PRIVATE_KEY.to_owned(),
+                "key".to_owned(),
+                "cipher".to_owned(),
+            )])
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("pkcs11-{}.sock", std::process::id()));
+        let (default_send, _default_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Arc::new(Server::start(&path, default_send).unwrap());
+        agent.serve_pkcs11(server.clone()).await.unwrap();
+
+        let (send, mut recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (client_send, client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client =
+            crate::ipc::client::connect_on_channel(path.clone(), IPC_NAME, send, client_recv, None);
+        let (other_send, mut other_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (_other_client_send, other_client_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let other_client = crate::ipc::client::connect_on_channel(
+            path.clone(),
+            IPC_NAME,
+            other_send,
+            other_client_recv,
+            None,
+        );
+
+        let public_key = PrivateKey::from_openssh(ECDSA_P256_PRIVATE_KEY)
+            .unwrap()
+            .public_key()
+            .to_openssh()
+            .unwrap();
+        let test = async {
+            assert_eq!(recv.recv().await.unwrap(), "{\"command\":\"connected\"}");
+            assert_eq!(
+                other_recv.recv().await.unwrap(),
+                "{\"command\":\"connected\"}"
+            );
+            let request = Pkcs11Request::Sign {
+                request_id: "1".to_owned(),
+                public_key,
+                mechanism: Pkcs11Mechanism::Ecdsa,
+                data: STANDARD.encode([7u8; 32]),
+            };
+            client_send
+                .send(serde_json::to_string(&request).unwrap())
+                .await
+                .unwrap();
+
+            // The approval names the process on the other end of the socket, which is this test
+            let ui_request = request_rx.recv().await.unwrap();
+            assert_eq!(
+                ui_request.process_name,
+                peer_info(Some(std::process::id())).process_name()
+            );
+            response_tx.send((ui_request.request_id, true)).unwrap();
+
+            let response: Pkcs11Response =
+                serde_json::from_str(&recv.recv().await.unwrap()).unwrap();
+            assert!(matches!(
+                response,
+                Pkcs11Response::Signature { request_id, .. } if request_id == "1"
+            ));
+
+            // The response is only sent to the client that made the request
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            assert!(other_recv.try_recv().is_err());
+        };
+        tokio::select! {
+            _ = client => panic!("client disconnected"),
+            _ = other_client => panic!("client disconnected"),
+            _ = test => {}
+        }
+        agent.cancellation_token.cancel();
+        server.stop();
+    }
+}
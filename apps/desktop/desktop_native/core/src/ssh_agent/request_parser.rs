@@ -1,8 +1,15 @@
-use bytes::{Buf, Bytes};
+use ssh_encoding::Decode;
+use ssh_key::HashAlg;
 
+const SSHSIG_MAGIC_PREAMBLE: &[u8] = b"SSHSIG";
+
+/// The data that is signed to create an SSHSIG signature.
+///
+/// Based on https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.sshsig
 #[derive(Debug)]
 pub(crate) struct SshSigRequest {
     pub namespace: String,
+    pub hash_algorithm: HashAlg,
 }
 
 #[derive(Debug)]
@@ -15,27 +22,57 @@ pub(crate) enum SshAgentSignRequest {
 }
 
 pub(crate) fn parse_request(data: &[u8]) -> Result<SshAgentSignRequest, anyhow::Error> {
-    let mut data = Bytes::copy_from_slice(data);
-    let magic_header = "SSHSIG";
-    let header = data.split_to(magic_header.len());
-
-    // sshsig; based on https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.sshsig
-    if header == magic_header.as_bytes() {
-        let _version = data.get_u32();
-
-        // read until null byte
-        let namespace = data
-            .into_iter()
-            .take_while(|&x| x != 0)
-            .collect::<Vec<u8>>();
-        let namespace =
-            String::from_utf8(namespace).map_err(|_| anyhow::anyhow!("Invalid namespace"))?;
-
-        Ok(SshAgentSignRequest::SshSigRequest(SshSigRequest {
-            namespace,
-        }))
-    } else {
+    match data.strip_prefix(SSHSIG_MAGIC_PREAMBLE) {
+        Some(signed_data) => Ok(SshAgentSignRequest::SshSigRequest(
+            parse_sshsig_signed_data(signed_data)?,
+        )),
         // regular sign request
-        Ok(SshAgentSignRequest::SignRequest(SignRequest {}))
+        None => Ok(SshAgentSignRequest::SignRequest(SignRequest {})),
+    }
+}
+
+/// Parse the SSHSIG signed data following the magic preamble.
+pub(crate) fn parse_sshsig_signed_data(mut data: &[u8]) -> Result<SshSigRequest, anyhow::Error> {
+    let namespace =
+        String::decode(&mut data).map_err(|e| anyhow::anyhow!("Invalid namespace: {e}"))?;
+    let _reserved =
+        Vec::<u8>::decode(&mut data).map_err(|e| anyhow::anyhow!("Invalid reserved field: {e}"))?;
+    let hash_algorithm =
+        String::decode(&mut data).map_err(|e| anyhow::anyhow!("Invalid hash algorithm: {e}"))?;
+    let hash_algorithm = HashAlg::new(&hash_algorithm)
+        .map_err(|_| anyhow::anyhow!("Unsupported hash algorithm: {hash_algorithm}"))?;
+    let message_hash =
+        Vec::<u8>::decode(&mut data).map_err(|e| anyhow::anyhow!("Invalid message hash: {e}"))?;
+
+    if namespace.is_empty() {
+        return Err(anyhow::anyhow!("Invalid namespace: namespace is empty"));
+    }
+    if message_hash.len() != hash_algorithm.digest_size() {
+        return Err(anyhow::anyhow!("Invalid message hash: unexpected length"));
+    }
+
+    Ok(SshSigRequest {
+        namespace,
+        hash_algorithm,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sshsig_request() {
+        let data = ssh_key::SshSig::signed_data("git", HashAlg::Sha512, b"commit").unwrap();
+        let SshAgentSignRequest::SshSigRequest(request) = parse_request(&data).unwrap() else {
+            panic!("Expected an SSHSIG request");
+        };
+        assert_eq!(request.namespace, "git");
+        assert_eq!(request.hash_algorithm, HashAlg::Sha512);
+    }
+
+    #[test]
+    fn test_parse_truncated_sshsig_request() {
+        assert!(parse_request(b"SSHSIG\x00\x00").is_err());
     }
 }
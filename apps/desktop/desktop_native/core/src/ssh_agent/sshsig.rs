@@ -0,0 +1,462 @@
+//! SSHSIG signatures, mirroring `ssh-keygen -Y sign`, `-Y verify`, `-Y find-principals` and `-Y check-novalidate`.
+//!
+//! The format of `allowed_signers` files is described in the ALLOWED SIGNERS section of ssh-keygen(1).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use ssh_key::{public::KeyData, Algorithm, HashAlg, LineEnding, PrivateKey, PublicKey, SshSig};
+use time::{Date, Month, PrimitiveDateTime, Time, UtcOffset};
+
+use super::{
+    fingerprint::{fingerprint_public_key, SshKeyFingerprint},
+    BitwardenDesktopAgent, BitwardenSshKey,
+};
+
+/// The hash algorithm used by `ssh-keygen -Y sign`.
+pub const DEFAULT_HASH_ALG: HashAlg = HashAlg::Sha512;
+
+/// A line of an `allowed_signers` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedSigner {
+    /// Comma separated principal patterns, as written in the file.
+    pub principals: String,
+    /// Whether the key is a certificate authority. SSHSIG signatures made with certificates are not supported,
+    /// so these lines never match a signature.
+    pub cert_authority: bool,
+    /// Comma separated namespace patterns the key may sign for. `None` allows all namespaces.
+    pub namespaces: Option<String>,
+    /// Unix timestamp before which the key is not valid.
+    pub valid_after: Option<i64>,
+    /// Unix timestamp after which the key is not valid.
+    pub valid_before: Option<i64>,
+    pub public_key: PublicKey,
+}
+
+impl AllowedSigner {
+    fn matches_key(&self, key_data: &KeyData) -> bool {
+        !self.cert_authority && self.public_key.key_data() == key_data
+    }
+
+    fn is_valid_at(&self, time: i64) -> bool {
+        self.valid_after
+            .is_none_or(|valid_after| time >= valid_after)
+            && self
+                .valid_before
+                .is_none_or(|valid_before| time <= valid_before)
+    }
+
+    fn allows_namespace(&self, namespace: &str) -> bool {
+        self.namespaces
+            .as_deref()
+            .is_none_or(|namespaces| match_pattern_list(namespace, namespaces))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifiedSignature {
+    pub principal: String,
+    pub namespace: String,
+    pub fingerprint: SshKeyFingerprint,
+}
+
+impl VerifiedSignature {
+    /// The message printed by `ssh-keygen -Y verify`, e.g. `Good "git" signature for user@example.com with ED25519 key SHA256:...`.
+    pub fn summary(&self) -> String {
+        format!(
+            "Good \"{}\" signature for {} with {} key {}",
+            self.namespace, self.principal, self.fingerprint.key_type, self.fingerprint.sha256
+        )
+    }
+}
+
+/// Create an armored SSHSIG signature over `data`.
+pub fn sign(private_key: &PrivateKey, namespace: &str, data: &[u8]) -> Result<String> {
+    private_key
+        .sign(namespace, DEFAULT_HASH_ALG, data)
+        .map_err(|e| anyhow!("Failed to sign data: {e}"))?
+        .to_pem(LineEnding::LF)
+        .map_err(|e| anyhow!("Failed to encode signature: {e}"))
+}
+
+impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Create an armored SSHSIG signature over `data` with the key of `cipher_id`, like `ssh-keygen -Y sign`.
+    ///
+    /// The key must be unlocked.
+    pub fn sign_sshsig(&self, cipher_id: &str, namespace: &str, data: &[u8]) -> Result<String> {
+        let private_key = self
+            .key_by_cipher(cipher_id)
+            .ok_or_else(|| anyhow!("No SSH key found for cipher {cipher_id}"))?
+            .private_key
+            .ok_or_else(|| anyhow!("The SSH key for cipher {cipher_id} is locked"))?;
+
+        sign(&private_key, namespace, data)
+    }
+}
+
+/// Parse an `allowed_signers` file. Empty lines and comments are skipped.
+pub fn parse_allowed_signers(contents: &str) -> Result<Vec<AllowedSigner>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_index, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            parse_allowed_signer(line)
+                .map_err(|e| anyhow!("Invalid allowed signer on line {}: {e}", index + 1))
+        })
+        .collect()
+}
+
+/// Verify `signature` over `data`, and check that it was made by `principal` with a key in `allowed_signers`
+/// that may sign for `namespace` at `time`.
+pub fn verify(
+    allowed_signers: &[AllowedSigner],
+    principal: &str,
+    namespace: &str,
+    data: &[u8],
+    signature: &str,
+    time: SystemTime,
+) -> Result<VerifiedSignature> {
+    let signature = parse_signature(signature)?;
+    let public_key = verify_signature(namespace, data, &signature)?;
+
+    let time = unix_timestamp(time);
+    let allowed = allowed_signers.iter().any(|signer| {
+        signer.matches_key(public_key.key_data())
+            && signer.is_valid_at(time)
+            && signer.allows_namespace(namespace)
+            && match_pattern_list(principal, &signer.principals)
+    });
+    if !allowed {
+        return Err(anyhow!(
+            "Signature is valid, but {principal} is not an allowed signer for this key"
+        ));
+    }
+
+    Ok(VerifiedSignature {
+        principal: principal.to_string(),
+        namespace: namespace.to_string(),
+        fingerprint: fingerprint_public_key(&public_key)?,
+    })
+}
+
+/// Find the principals of the key that made `signature`, that are valid at `time`.
+///
+/// Like `ssh-keygen -Y find-principals`, the signature itself is not verified.
+pub fn find_principals(
+    allowed_signers: &[AllowedSigner],
+    signature: &str,
+    time: SystemTime,
+) -> Result<Vec<String>> {
+    let signature = parse_signature(signature)?;
+    let time = unix_timestamp(time);
+
+    Ok(allowed_signers
+        .iter()
+        .filter(|signer| signer.matches_key(signature.public_key()) && signer.is_valid_at(time))
+        .map(|signer| signer.principals.clone())
+        .collect())
+}
+
+/// Verify `signature` over `data` without checking who made it.
+pub fn check_novalidate(
+    namespace: &str,
+    data: &[u8],
+    signature: &str,
+) -> Result<SshKeyFingerprint> {
+    let signature = parse_signature(signature)?;
+    let public_key = verify_signature(namespace, data, &signature)?;
+    fingerprint_public_key(&public_key)
+}
+
+fn parse_signature(signature: &str) -> Result<SshSig> {
+    SshSig::from_pem(signature.trim()).map_err(|e| anyhow!("Invalid signature: {e}"))
+}
+
+fn verify_signature(namespace: &str, data: &[u8], signature: &SshSig) -> Result<PublicKey> {
+    if signature.namespace() != namespace {
+        return Err(anyhow!(
+            "Signature namespace {} does not match {namespace}",
+            signature.namespace()
+        ));
+    }
+
+    let public_key = PublicKey::from(signature.public_key().clone());
+    public_key
+        .verify(namespace, data, signature)
+        .map_err(|e| anyhow!("Signature verification failed: {e}"))?;
+    Ok(public_key)
+}
+
+fn unix_timestamp(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn parse_allowed_signer(line: &str) -> Result<AllowedSigner> {
+    let (principals, rest) = next_field(line);
+    let principals = unquote(principals);
+    if principals.is_empty() {
+        return Err(anyhow!("Missing principals"));
+    }
+
+    // Options are optional, so the second field is either the options or the key type
+    let (options, key) = match rest.split_whitespace().next() {
+        Some(field) if Algorithm::new(field).is_ok() => (None, rest),
+        Some(_) => {
+            let (options, key) = next_field(rest);
+            (Some(options), key)
+        }
+        None => return Err(anyhow!("Missing public key")),
+    };
+
+    let public_key =
+        PublicKey::from_openssh(key).map_err(|e| anyhow!("Invalid public key: {e}"))?;
+    let mut signer = AllowedSigner {
+        principals: principals.to_string(),
+        cert_authority: false,
+        namespaces: None,
+        valid_after: None,
+        valid_before: None,
+        public_key,
+    };
+
+    for option in options.map(split_options).unwrap_or_default() {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(unquote(value))),
+            None => (option, None),
+        };
+        match (name.to_ascii_lowercase().as_str(), value) {
+            ("cert-authority", None) => signer.cert_authority = true,
+            ("namespaces", Some(value)) => signer.namespaces = Some(value.to_string()),
+            ("valid-after", Some(value)) => signer.valid_after = Some(parse_time(value)?),
+            ("valid-before", Some(value)) => signer.valid_before = Some(parse_time(value)?),
+            _ => return Err(anyhow!("Unsupported option {option}")),
+        }
+    }
+
+    Ok(signer)
+}
+
+/// Split off the first whitespace separated field, which may contain quoted whitespace.
+fn next_field(line: &str) -> (&str, &str) {
+    let mut in_quotes = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                return (&line[..index], line[index..].trim_start())
+            }
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Split comma separated options, keeping commas inside quoted values.
+fn split_options(options: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (index, c) in options.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                result.push(&options[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(&options[start..]);
+    result
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Parse a `YYYYMMDD[HHMM[SS]][Z]` time. Times without the `Z` suffix are local times, as in ssh-keygen.
+/// If the local offset cannot be determined, they are interpreted as UTC.
+fn parse_time(value: &str) -> Result<i64> {
+    let invalid_time = || anyhow!("Invalid time {value}");
+    let (digits, utc) = match value.strip_suffix(['Z', 'z']) {
+        Some(digits) => (digits, true),
+        None => (value, false),
+    };
+    if ![8, 12, 14].contains(&digits.len()) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid_time());
+    }
+
+    // Missing hours, minutes and seconds default to zero
+    let field = |range: std::ops::Range<usize>| -> u8 {
+        digits.get(range).map_or(0, |field| {
+            field.parse().expect("Field only contains digits")
+        })
+    };
+    let year: i32 = digits[..4].parse().expect("Field only contains digits");
+    let month = Month::try_from(field(4..6)).map_err(|_| invalid_time())?;
+    let date = Date::from_calendar_date(year, month, field(6..8)).map_err(|_| invalid_time())?;
+    let time =
+        Time::from_hms(field(8..10), field(10..12), field(12..14)).map_err(|_| invalid_time())?;
+
+    let date_time = PrimitiveDateTime::new(date, time);
+    let offset = if utc {
+        UtcOffset::UTC
+    } else {
+        UtcOffset::local_offset_at(date_time.assume_utc()).unwrap_or(UtcOffset::UTC)
+    };
+    Ok(date_time.assume_offset(offset).unix_timestamp())
+}
+
+/// Match `value` against a comma separated list of patterns, like OpenSSH's `match_pattern_list`.
+///
+/// Patterns may use the `*` and `?` wildcards. A matching pattern prefixed with `!` rejects the value,
+/// regardless of the other patterns.
+fn match_pattern_list(value: &str, patterns: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',').map(str::trim) {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        if match_pattern(value.as_bytes(), pattern.as_bytes()) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn match_pattern(value: &[u8], pattern: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some((b'*', rest)) => (0..=value.len()).any(|index| match_pattern(&value[index..], rest)),
+        Some((b'?', rest)) => !value.is_empty() && match_pattern(&value[1..], rest),
+        Some((c, rest)) => value.first() == Some(c) && match_pattern(&value[1..], rest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::test_keys::{ED25519_PRIVATE_KEY, ED25519_PUBLIC_KEY};
+
+    const MESSAGE: &[u8] = b"hello bitwarden\n";
+
+    // Output of `ssh-keygen -Y sign -n git` for MESSAGE and ED25519_PRIVATE_KEY
+    const SIGNATURE: &str = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgFBdNnREnaaIk0nEUrd/I1/XdOM
+HCn621Na9C0lXu8TsAAAADZ2l0AAAAAAAAAAZzaGE1MTIAAABTAAAAC3NzaC1lZDI1NTE5
+AAAAQEoln80LnKeSVu9gJbEi9u6yWFd+LqrJz8NKK0r0Yzeskf82z3Fm2g1wW9AT41UlC+
+1xaPrOQhORBoMmMjDXQgA=
+-----END SSH SIGNATURE-----
+";
+
+    #[test]
+    fn test_sign_matches_ssh_keygen() {
+        let private_key = PrivateKey::from_openssh(ED25519_PRIVATE_KEY).unwrap();
+        assert_eq!(sign(&private_key, "git", MESSAGE).unwrap(), SIGNATURE);
+    }
+
+    #[test]
+    fn test_verify_with_allowed_signers() {
+        let allowed_signers = parse_allowed_signers(&format!(
+            "# Bitwarden signers\n\n\"*@bitwarden.com,!evil@bitwarden.com\" namespaces=\"git,file\",valid-after=\"20200101Z\" {ED25519_PUBLIC_KEY}\n"
+        ))
+        .unwrap();
+        let now = SystemTime::now();
+
+        let verified = verify(
+            &allowed_signers,
+            "test@bitwarden.com",
+            "git",
+            MESSAGE,
+            SIGNATURE,
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            verified.summary(),
+            "Good \"git\" signature for test@bitwarden.com with ED25519 key SHA256:r17nBbZtrV3L8okYgvj2eNVPsM8LBzkcWn4SSEDwIiM"
+        );
+
+        // Negated principal, namespace other than the signature's, tampered message and a time before the key is valid
+        assert!(verify(
+            &allowed_signers,
+            "evil@bitwarden.com",
+            "git",
+            MESSAGE,
+            SIGNATURE,
+            now
+        )
+        .is_err());
+        assert!(verify(
+            &allowed_signers,
+            "test@bitwarden.com",
+            "file",
+            MESSAGE,
+            SIGNATURE,
+            now
+        )
+        .is_err());
+        assert!(verify(
+            &allowed_signers,
+            "test@bitwarden.com",
+            "git",
+            b"hello",
+            SIGNATURE,
+            now
+        )
+        .is_err());
+        assert!(verify(
+            &allowed_signers,
+            "test@bitwarden.com",
+            "git",
+            MESSAGE,
+            SIGNATURE,
+            UNIX_EPOCH
+        )
+        .is_err());
+
+        // The signature namespace matches, but the key may not sign for it
+        let file_signers = parse_allowed_signers(&format!(
+            "test@bitwarden.com namespaces=\"file\" {ED25519_PUBLIC_KEY}\n"
+        ))
+        .unwrap();
+        assert!(verify(
+            &file_signers,
+            "test@bitwarden.com",
+            "git",
+            MESSAGE,
+            SIGNATURE,
+            now
+        )
+        .is_err());
+
+        assert_eq!(
+            find_principals(&allowed_signers, SIGNATURE, now).unwrap(),
+            vec!["*@bitwarden.com,!evil@bitwarden.com".to_string()]
+        );
+        assert!(find_principals(&allowed_signers, SIGNATURE, UNIX_EPOCH)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("20240102Z").unwrap(), 1704153600);
+        assert_eq!(parse_time("202401021530Z").unwrap(), 1704209400);
+        assert_eq!(parse_time("20240102153045Z").unwrap(), 1704209445);
+        assert!(parse_time("20241302Z").is_err());
+        assert!(parse_time("2024").is_err());
+    }
+}
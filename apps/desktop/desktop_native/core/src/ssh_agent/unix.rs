@@ -1,113 +1,456 @@
 use std::{
-    collections::HashMap,
     fs,
-    os::unix::fs::PermissionsExt,
-    sync::{
-        atomic::{AtomicBool, AtomicU32},
-        Arc, RwLock,
-    },
+    future::Future,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+    time::Duration,
 };
 
 use bitwarden_russh::ssh_agent;
+use futures::StreamExt;
 use homedir::my_home;
-use tokio::{net::UnixListener, sync::Mutex};
-use tokio_util::sync::CancellationToken;
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, Mutex},
+};
 
 use crate::ssh_agent::peercred_unix_listener_stream::PeercredUnixListenerStream;
 
-use super::{BitwardenDesktopAgent, BitwardenSshKey, SshAgentUIRequest};
+use super::{
+    gpg, peerinfo::models::PeerInfo, BitwardenDesktopAgent, BitwardenSshKey, SshAgentStatus,
+    SshAgentUIRequest,
+};
+
+/// How often the socket path is checked for having been deleted or replaced.
+const SOCKET_WATCH_INTERVAL: Duration = Duration::from_secs(5);
 
 impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Bind the agent socket and start serving it in the background.
+    ///
+    /// Only returns once the socket is listening. If the socket could not be set up, the error is returned
+    /// and the agent is not started.
+    ///
+    /// If another agent is already answering on the socket path, the agent refuses to start and reports
+    /// [`SshAgentStatus::Conflict`], unless `take_over` is set, in which case the socket is replaced.
     pub async fn start_server(
         auth_request_tx: tokio::sync::mpsc::Sender<SshAgentUIRequest>,
         auth_response_rx: Arc<Mutex<tokio::sync::broadcast::Receiver<(u32, bool)>>>,
+        take_over: bool,
     ) -> Result<Self, anyhow::Error> {
-        let agent = BitwardenDesktopAgent {
-            keystore: ssh_agent::KeyStore(Arc::new(RwLock::new(HashMap::new()))),
-            cancellation_token: CancellationToken::new(),
-            show_ui_request_tx: auth_request_tx,
-            get_ui_response_rx: auth_response_rx,
-            request_id: Arc::new(AtomicU32::new(0)),
-            needs_unlock: Arc::new(AtomicBool::new(true)),
-            is_running: Arc::new(AtomicBool::new(false)),
+        let agent = BitwardenDesktopAgent::new(auth_request_tx, auth_response_rx);
+
+        let ssh_path = match socket_path() {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("[SSH Agent Native Module] Error while starting agent server: {e}");
+                agent.set_status(SshAgentStatus::Failed {
+                    reason: e.to_string(),
+                });
+                return Err(e);
+            }
         };
-        let cloned_agent_state = agent.clone();
+
+        agent.listen(ssh_path, take_over).await?;
+        Ok(agent)
+    }
+
+    /// Bind the agent socket at `ssh_path` and start serving it, see [`BitwardenDesktopAgent::start_server`].
+    async fn listen(&self, ssh_path: String, take_over: bool) -> Result<(), anyhow::Error> {
+        if is_socket_live(Path::new(&ssh_path)).await {
+            if !take_over {
+                println!("[SSH Agent Native Module] Another SSH agent is already listening on {ssh_path:?}");
+                self.set_status(SshAgentStatus::Conflict {
+                    path: ssh_path.clone(),
+                });
+                return Err(anyhow::anyhow!(
+                    "Another SSH agent is already listening on {ssh_path}"
+                ));
+            }
+            println!("[SSH Agent Native Module] Taking over the socket of the SSH agent listening on {ssh_path:?}");
+        }
+
+        let (listener, socket_id) = match bind_socket(&ssh_path) {
+            Ok(bound) => bound,
+            Err(e) => {
+                eprintln!("[SSH Agent Native Module] Error while starting agent server: {e}");
+                self.set_status(SshAgentStatus::Failed {
+                    reason: e.to_string(),
+                });
+                return Err(e);
+            }
+        };
+        let (replacement_tx, replacement_rx) = mpsc::channel(1);
+        let connections = self.connections.clone();
+        let stream =
+            PeercredUnixListenerStream::new(listener, replacement_rx).map(move |accepted| {
+                accepted
+                    .map(|(stream, peer_info)| (connections.track(stream, &peer_info), peer_info))
+            });
+
+        self.is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        self.set_status(SshAgentStatus::Listening {
+            path: ssh_path.clone(),
+        });
+
+        tokio::spawn(watch_socket(
+            self.clone(),
+            ssh_path,
+            socket_id,
+            replacement_tx,
+            SOCKET_WATCH_INTERVAL,
+        ));
+
+        let cloned_agent_state = self.clone();
         tokio::spawn(async move {
-            let ssh_path = match std::env::var("BITWARDEN_SSH_AUTH_SOCK") {
-                Ok(path) => path,
-                Err(_) => {
-                    println!("[SSH Agent Native Module] BITWARDEN_SSH_AUTH_SOCK not set, using default path");
-
-                    let ssh_agent_directory = match my_home() {
-                        Ok(Some(home)) => home,
-                        _ => {
-                            println!(
-                                "[SSH Agent Native Module] Could not determine home directory"
-                            );
-                            return;
-                        }
-                    };
-
-                    let is_flatpak = std::env::var("container") == Ok("flatpak".to_string());
-                    if !is_flatpak {
-                        ssh_agent_directory
-                            .join(".bitwarden-ssh-agent.sock")
-                            .to_str()
-                            .expect("Path should be valid")
-                            .to_owned()
-                    } else {
-                        ssh_agent_directory
-                            .join(".var/app/com.bitwarden.desktop/data/.bitwarden-ssh-agent.sock")
-                            .to_str()
-                            .expect("Path should be valid")
-                            .to_owned()
-                    }
-                }
-            };
+            let cloned_keystore = cloned_agent_state.keystore.clone();
+            let cloned_cancellation_token = cloned_agent_state.cancellation_token.clone();
+            let _ = ssh_agent::serve(
+                stream,
+                cloned_agent_state.clone(),
+                cloned_keystore,
+                cloned_cancellation_token,
+            )
+            .await;
+            cloned_agent_state.set_exited();
+            println!("[SSH Agent Native Module] SSH Agent server exited");
+        });
+
+        Ok(())
+    }
+
+    /// Bind the gpg-agent socket and serve gpg requests in the background, with the keys set through
+    /// [`BitwardenDesktopAgent::set_gpg_keys`]. Returns the socket path.
+    ///
+    /// The socket is `BITWARDEN_GPG_AGENT_SOCK`, or the socket gpg connects to by default. If gpg-agent is already
+    /// listening on it, the listener is not started.
+    pub async fn start_gpg_server(&self) -> Result<String, anyhow::Error> {
+        if !self.is_running() {
+            return Err(anyhow::anyhow!(
+                "[BitwardenDesktopAgent] Tried to start the GPG agent while agent is not running"
+            ));
+        }
+
+        let gpg_path = gpg_socket_path().await?;
+        if is_socket_live(Path::new(&gpg_path)).await {
+            println!("[GPG Agent] Another gpg-agent is already listening on {gpg_path:?}");
+            return Err(anyhow::anyhow!(
+                "Another gpg-agent is already listening on {gpg_path}"
+            ));
+        }
+        if let Some(directory) = Path::new(&gpg_path).parent() {
+            // gpg creates its socket directory only readable by the current user
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(directory)?;
+        }
 
-            println!("[SSH Agent Native Module] Starting SSH Agent server on {ssh_path:?}");
-            let sockname = std::path::Path::new(&ssh_path);
-            if let Err(e) = std::fs::remove_file(sockname) {
-                println!("[SSH Agent Native Module] Could not remove existing socket file: {e}");
-                if e.kind() != std::io::ErrorKind::NotFound {
-                    return;
+        let (listener, _socket_id) = bind_socket(&gpg_path)?;
+        spawn_accept_loop(
+            self.clone(),
+            listener,
+            "[GPG Agent]",
+            gpg::session::serve_connection,
+        );
+
+        Ok(gpg_path)
+    }
+}
+
+/// Accept connections on `listener` until the agent is stopped, serving each with `serve_connection`.
+///
+/// Used for the sockets served next to the agent socket, which are not watched and never replaced.
+fn spawn_accept_loop<F, Fut>(
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    listener: UnixListener,
+    log_prefix: &'static str,
+    serve_connection: F,
+) where
+    F: Fn(BitwardenDesktopAgent<BitwardenSshKey>, UnixStream, PeerInfo) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (_replacement_tx, replacement_rx) = mpsc::channel(1);
+    let mut stream = PeercredUnixListenerStream::new(listener, replacement_rx);
+
+    tokio::spawn(async move {
+        loop {
+            let connection = tokio::select! {
+                _ = agent.cancellation_token.cancelled() => break,
+                connection = stream.next() => connection,
+            };
+            match connection {
+                Some(Ok((socket, peer_info))) => {
+                    tokio::spawn(serve_connection(agent.clone(), socket, peer_info));
                 }
+                Some(Err(e)) => {
+                    eprintln!("{log_prefix} Error while accepting connection: {e}");
+                }
+                None => break,
             }
+        }
+        println!("{log_prefix} server exited");
+    });
+}
 
-            match UnixListener::bind(sockname) {
-                Ok(listener) => {
-                    // Only the current user should be able to access the socket
-                    if let Err(e) = fs::set_permissions(sockname, fs::Permissions::from_mode(0o600))
-                    {
-                        println!("[SSH Agent Native Module] Could not set socket permissions: {e}");
-                        return;
-                    }
-
-                    let stream = PeercredUnixListenerStream::new(listener);
-
-                    let cloned_keystore = cloned_agent_state.keystore.clone();
-                    let cloned_cancellation_token = cloned_agent_state.cancellation_token.clone();
-                    cloned_agent_state
-                        .is_running
-                        .store(true, std::sync::atomic::Ordering::Relaxed);
-                    let _ = ssh_agent::serve(
-                        stream,
-                        cloned_agent_state.clone(),
-                        cloned_keystore,
-                        cloned_cancellation_token,
-                    )
-                    .await;
-                    cloned_agent_state
-                        .is_running
-                        .store(false, std::sync::atomic::Ordering::Relaxed);
-                    println!("[SSH Agent Native Module] SSH Agent server exited");
+/// Resolve the agent socket path, honoring `BITWARDEN_SSH_AUTH_SOCK` and the flatpak sandbox.
+fn socket_path() -> Result<String, anyhow::Error> {
+    match std::env::var("BITWARDEN_SSH_AUTH_SOCK") {
+        Ok(path) => Ok(path),
+        Err(_) => {
+            println!(
+                "[SSH Agent Native Module] BITWARDEN_SSH_AUTH_SOCK not set, using default path"
+            );
+
+            let ssh_agent_directory = match my_home() {
+                Ok(Some(home)) => home,
+                _ => {
+                    return Err(anyhow::anyhow!("Could not determine home directory"));
                 }
-                Err(e) => {
-                    eprintln!("[SSH Agent Native Module] Error while starting agent server: {e}");
+            };
+
+            let is_flatpak = std::env::var("container") == Ok("flatpak".to_string());
+            let path = if !is_flatpak {
+                ssh_agent_directory.join(".bitwarden-ssh-agent.sock")
+            } else {
+                ssh_agent_directory
+                    .join(".var/app/com.bitwarden.desktop/data/.bitwarden-ssh-agent.sock")
+            };
+            path.to_str()
+                .map(|path| path.to_owned())
+                .ok_or_else(|| anyhow::anyhow!("Socket path is not valid UTF-8"))
+        }
+    }
+}
+
+/// Resolve the gpg-agent socket path, honoring `BITWARDEN_GPG_AGENT_SOCK`, and otherwise asking gpgconf.
+async fn gpg_socket_path() -> Result<String, anyhow::Error> {
+    if let Ok(path) = std::env::var("BITWARDEN_GPG_AGENT_SOCK") {
+        return Ok(path);
+    }
+
+    let output = tokio::process::Command::new("gpgconf")
+        .args(["--list-dirs", "agent-socket"])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not run gpgconf: {e}"))?;
+    let path = String::from_utf8(output.stdout)?.trim().to_owned();
+    if !output.status.success() || path.is_empty() {
+        return Err(anyhow::anyhow!(
+            "gpgconf could not determine the gpg-agent socket"
+        ));
+    }
+    Ok(path)
+}
+
+/// Identifies the socket file bound by this agent, so that a replaced file can be told apart from it.
+///
+/// The inode number of a deleted file is often reused right away, so the change time is included as well.
+type SocketId = (u64, u64, i64, i64);
+
+fn socket_id(path: &Path) -> std::io::Result<SocketId> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok((
+        metadata.dev(),
+        metadata.ino(),
+        metadata.ctime(),
+        metadata.ctime_nsec(),
+    ))
+}
+
+/// Whether another process is accepting connections on the socket at `path`.
+async fn is_socket_live(path: &Path) -> bool {
+    UnixStream::connect(path).await.is_ok()
+}
+
+/// Bind the agent socket at `ssh_path`, replacing any stale socket file.
+fn bind_socket(ssh_path: &str) -> Result<(UnixListener, SocketId), anyhow::Error> {
+    println!("[SSH Agent Native Module] Starting SSH Agent server on {ssh_path:?}");
+    let sockname = Path::new(ssh_path);
+    if let Err(e) = std::fs::remove_file(sockname) {
+        println!("[SSH Agent Native Module] Could not remove existing socket file: {e}");
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+
+    let listener = UnixListener::bind(sockname)?;
+
+    // Only the current user should be able to access the socket
+    fs::set_permissions(sockname, fs::Permissions::from_mode(0o600))?;
+
+    Ok((listener, socket_id(sockname)?))
+}
+
+/// Periodically check that the socket file at `ssh_path` is still the one bound by this agent.
+///
+/// If the file was deleted (or replaced by something that is not listening), the socket is re-created and the
+/// new listener is handed to the running stream. If another agent now answers on the path, it is left alone,
+/// and the agent shuts down and reports [`SshAgentStatus::Conflict`], as it can no longer be reached.
+async fn watch_socket(
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    ssh_path: String,
+    mut bound_socket_id: SocketId,
+    replacement_tx: mpsc::Sender<UnixListener>,
+    period: Duration,
+) {
+    let sockname = Path::new(&ssh_path);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = agent.cancellation_token.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        match socket_id(sockname) {
+            Ok(id) if id == bound_socket_id => continue,
+            Ok(_) if is_socket_live(sockname).await => {
+                println!("[SSH Agent Native Module] Socket {ssh_path:?} was taken over by another SSH agent");
+                agent.shut_down(SshAgentStatus::Conflict { path: ssh_path });
+                break;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                println!("[SSH Agent Native Module] Could not check socket file: {e}");
+                continue;
+            }
+        }
+
+        println!("[SSH Agent Native Module] Socket {ssh_path:?} was removed, re-creating it");
+        match bind_socket(&ssh_path) {
+            Ok((listener, id)) => {
+                bound_socket_id = id;
+                if replacement_tx.send(listener).await.is_err() {
+                    break;
                 }
             }
-        });
+            Err(e) => {
+                eprintln!("[SSH Agent Native Module] Could not re-create socket: {e}");
+            }
+        }
+    }
+}
 
-        Ok(agent)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::tests::new_agent;
+
+    fn test_directory(name: &str) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("ssh-agent-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn socket_in(directory: &Path) -> String {
+        directory.join("agent.sock").to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_listen_status() {
+        let directory = test_directory("status");
+        let path = socket_in(&directory);
+
+        let agent = new_agent();
+        agent.listen(path.clone(), false).await.unwrap();
+        assert!(agent.is_running());
+        assert_eq!(agent.status(), SshAgentStatus::Listening { path });
+        agent.stop();
+        assert!(!agent.is_running());
+        assert_eq!(agent.status(), SshAgentStatus::Stopped);
+
+        // The bind error is returned instead of surfacing later in the serve loop
+        let agent = new_agent();
+        let missing = directory.join("missing").join("agent.sock");
+        assert!(agent
+            .listen(missing.to_str().unwrap().to_owned(), false)
+            .await
+            .is_err());
+        assert!(!agent.is_running());
+        assert!(matches!(agent.status(), SshAgentStatus::Failed { .. }));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_socket_and_conflict() {
+        let directory = test_directory("conflict");
+        let path = socket_in(&directory);
+
+        // A socket file that nobody listens on is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(!is_socket_live(Path::new(&path)).await);
+        let agent = new_agent();
+        agent.listen(path.clone(), false).await.unwrap();
+        assert!(is_socket_live(Path::new(&path)).await);
+
+        // A live socket is left alone, unless taking over is requested
+        let other = new_agent();
+        assert!(other.listen(path.clone(), false).await.is_err());
+        assert_eq!(
+            other.status(),
+            SshAgentStatus::Conflict { path: path.clone() }
+        );
+        assert!(!other.is_running());
+
+        let other = new_agent();
+        other.listen(path.clone(), true).await.unwrap();
+        assert_eq!(
+            other.status(),
+            SshAgentStatus::Listening { path: path.clone() }
+        );
+
+        agent.stop();
+        other.stop();
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_socket() {
+        let directory = test_directory("watch");
+        let path = socket_in(&directory);
+
+        let agent = new_agent();
+        agent
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let mut status = agent.subscribe_status();
+        let (_listener, id) = bind_socket(&path).unwrap();
+        let (replacement_tx, mut replacement_rx) = mpsc::channel(1);
+        tokio::spawn(watch_socket(
+            agent.clone(),
+            path.clone(),
+            id,
+            replacement_tx,
+            Duration::from_millis(10),
+        ));
+
+        // A deleted socket is re-created
+        fs::remove_file(&path).unwrap();
+        let replacement = tokio::time::timeout(Duration::from_secs(5), replacement_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        drop(replacement);
+
+        // Another agent taking the socket over stops this one. Its socket is renamed over the path, so that the
+        // watcher never finds the path missing and re-creates it in between.
+        let other_path = directory.join("other.sock");
+        let _other = UnixListener::bind(&other_path).unwrap();
+        fs::rename(&other_path, &path).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), status.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*status.borrow(), SshAgentStatus::Conflict { path });
+        assert!(!agent.is_running());
+        assert!(agent.cancellation_token.is_cancelled());
+
+        fs::remove_dir_all(directory).unwrap();
     }
 }
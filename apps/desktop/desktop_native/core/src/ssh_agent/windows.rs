@@ -1,42 +1,66 @@
 use bitwarden_russh::ssh_agent;
+use futures::StreamExt;
 pub mod named_pipe_listener_stream;
 
-use std::{
-    collections::HashMap,
-    sync::{
-        atomic::{AtomicBool, AtomicU32},
-        Arc, RwLock,
-    },
-};
+use std::sync::Arc;
 use tokio::sync::Mutex;
-use tokio_util::sync::CancellationToken;
 
-use super::{BitwardenDesktopAgent, BitwardenSshKey, SshAgentUIRequest};
+use super::{BitwardenDesktopAgent, BitwardenSshKey, SshAgentStatus, SshAgentUIRequest};
 
 impl BitwardenDesktopAgent<BitwardenSshKey> {
+    /// Create the agent named pipe and start serving it in the background.
+    ///
+    /// Only returns once the pipe is listening. If the pipe could not be created, the error is returned
+    /// and the agent is not started.
+    ///
+    /// If another agent already owns the pipe, the agent refuses to start and reports [`SshAgentStatus::Conflict`].
+    /// Named pipes cannot be taken over from another process, so `take_over` has no effect on Windows.
     pub async fn start_server(
         auth_request_tx: tokio::sync::mpsc::Sender<SshAgentUIRequest>,
         auth_response_rx: Arc<Mutex<tokio::sync::broadcast::Receiver<(u32, bool)>>>,
+        _take_over: bool,
     ) -> Result<Self, anyhow::Error> {
-        let agent_state = BitwardenDesktopAgent {
-            keystore: ssh_agent::KeyStore(Arc::new(RwLock::new(HashMap::new()))),
-            show_ui_request_tx: auth_request_tx,
-            get_ui_response_rx: auth_response_rx,
-            cancellation_token: CancellationToken::new(),
-            request_id: Arc::new(AtomicU32::new(0)),
-            needs_unlock: Arc::new(AtomicBool::new(true)),
-            is_running: Arc::new(AtomicBool::new(true)),
-        };
-        let stream = named_pipe_listener_stream::NamedPipeServerStream::new(
+        let agent_state = BitwardenDesktopAgent::new(auth_request_tx, auth_response_rx);
+        let stream = match named_pipe_listener_stream::NamedPipeServerStream::new(
             agent_state.cancellation_token.clone(),
             agent_state.is_running.clone(),
-        );
+            agent_state.status.clone(),
+        ) {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                agent_state.set_status(SshAgentStatus::Conflict {
+                    path: named_pipe_listener_stream::PIPE_NAME.to_string(),
+                });
+                return Err(anyhow::anyhow!(
+                    "Another SSH agent is already listening on {}",
+                    named_pipe_listener_stream::PIPE_NAME
+                ));
+            }
+            Err(e) => {
+                agent_state.set_status(SshAgentStatus::Failed {
+                    reason: e.to_string(),
+                });
+                return Err(anyhow::Error::from(e).context(format!(
+                    "Could not create named pipe {}",
+                    named_pipe_listener_stream::PIPE_NAME
+                )));
+            }
+        };
+
+        agent_state
+            .is_running
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        agent_state.set_status(SshAgentStatus::Listening {
+            path: named_pipe_listener_stream::PIPE_NAME.to_string(),
+        });
+
+        let connections = agent_state.connections.clone();
+        let stream = stream.map(move |accepted| {
+            accepted.map(|(stream, peer_info)| (connections.track(stream, &peer_info), peer_info))
+        });
 
         let cloned_agent_state = agent_state.clone();
         tokio::spawn(async move {
-            cloned_agent_state
-                .is_running
-                .store(true, std::sync::atomic::Ordering::Relaxed);
             let _ = ssh_agent::serve(
                 stream,
                 cloned_agent_state.clone(),
@@ -44,10 +68,14 @@ impl BitwardenDesktopAgent<BitwardenSshKey> {
                 cloned_agent_state.cancellation_token.clone(),
             )
             .await;
-            cloned_agent_state
-                .is_running
-                .store(false, std::sync::atomic::Ordering::Relaxed);
+            cloned_agent_state.set_exited();
         });
         Ok(agent_state)
     }
+
+    /// gpg on Windows connects to gpg-agent through a TCP port and nonce file instead of a socket, which is not
+    /// supported.
+    pub async fn start_gpg_server(&self) -> Result<String, anyhow::Error> {
+        Err(anyhow::anyhow!("The GPG agent is not supported on Windows"))
+    }
 }
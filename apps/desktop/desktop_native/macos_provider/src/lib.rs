@@ -1,14 +1,16 @@
 #![cfg(target_os = "macos")]
 
-use std::{
-    collections::HashMap,
-    sync::{atomic::AtomicU32, Arc, Mutex},
-    time::Instant,
-};
+use std::{sync::Arc, time::Duration};
 
+use desktop_core::ipc::{
+    client::ReconnectOptions,
+    protocol::Command,
+    rpc::{RpcClient, RpcError},
+    APP_ENDPOINT,
+};
 use futures::FutureExt;
 use log::{error, info};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 
 uniffi::setup_scaffolding!();
 
@@ -49,14 +51,12 @@ trait Callback: Send + Sync {
     fn error(&self, error: BitwardenError);
 }
 
+/// How long a request waits for the app, which includes the time the user takes to confirm it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 #[derive(uniffi::Object)]
 pub struct MacOSProviderClient {
-    to_server_send: tokio::sync::mpsc::Sender<String>,
-
-    // We need to keep track of the callbacks so we can call them when we receive a response
-    response_callbacks_counter: AtomicU32,
-    #[allow(clippy::type_complexity)]
-    response_callbacks_queue: Arc<Mutex<HashMap<u32, (Box<dyn Callback>, Instant)>>>,
+    rpc: Arc<RpcClient>,
 }
 
 #[uniffi::export]
@@ -70,63 +70,47 @@ impl MacOSProviderClient {
         let (from_server_send, mut from_server_recv) = tokio::sync::mpsc::channel(32);
         let (to_server_send, to_server_recv) = tokio::sync::mpsc::channel(32);
 
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Can't create runtime");
+
         let client = MacOSProviderClient {
-            to_server_send,
-            response_callbacks_counter: AtomicU32::new(0),
-            response_callbacks_queue: Arc::new(Mutex::new(HashMap::new())),
+            rpc: Arc::new(RpcClient::new(to_server_send, rt.handle().clone())),
         };
 
-        let path = desktop_core::ipc::path("autofill");
+        let path = desktop_core::ipc::path(APP_ENDPOINT);
 
-        let queue = client.response_callbacks_queue.clone();
+        let rpc = client.rpc.clone();
 
         std::thread::spawn(move || {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("Can't create runtime");
-
             rt.spawn(
-                desktop_core::ipc::client::connect(path, from_server_send, to_server_recv)
-                    .map(|r| r.map_err(|e| e.to_string())),
+                desktop_core::ipc::client::connect_reconnecting(
+                    path,
+                    from_server_send,
+                    to_server_recv,
+                    None,
+                    ReconnectOptions {
+                        channel: Some("autofill".to_owned()),
+                        ..Default::default()
+                    },
+                )
+                .map(|r| r.map_err(|e| e.to_string())),
             );
 
             rt.block_on(async move {
                 while let Some(message) = from_server_recv.recv().await {
-                    match serde_json::from_str::<SerializedMessage>(&message) {
-                        Ok(SerializedMessage::Command(CommandMessage::Connected)) => {
+                    match serde_json::from_str::<Command>(&message) {
+                        Ok(Command::Connected) => {
                             info!("Connected to server");
                         }
-                        Ok(SerializedMessage::Command(CommandMessage::Disconnected)) => {
-                            info!("Disconnected from server");
+                        Ok(Command::Disconnected { reason }) => {
+                            info!("Disconnected from server: {reason:?}");
                         }
-                        Ok(SerializedMessage::Message {
-                            sequence_number,
-                            value,
-                        }) => match queue.lock().unwrap().remove(&sequence_number) {
-                            Some((cb, request_start_time)) => {
-                                info!(
-                                    "Time to process request: {:?}",
-                                    request_start_time.elapsed()
-                                );
-                                match value {
-                                    Ok(value) => {
-                                        if let Err(e) = cb.complete(value) {
-                                            error!("Error deserializing message: {e}");
-                                        }
-                                    }
-                                    Err(e) => {
-                                        error!("Error processing message: {e:?}");
-                                        cb.error(e)
-                                    }
-                                }
-                            }
-                            None => {
-                                error!("No callback found for sequence number: {sequence_number}")
+                        Err(_) => {
+                            if let Err(e) = rpc.dispatch(&message) {
+                                error!("Error deserializing message: {e}");
                             }
-                        },
-                        Err(e) => {
-                            error!("Error deserializing message: {e}");
                         }
                     };
                 }
@@ -141,7 +125,7 @@ impl MacOSProviderClient {
         request: PasskeyRegistrationRequest,
         callback: Arc<dyn PreparePasskeyRegistrationCallback>,
     ) {
-        self.send_message(request, Box::new(callback));
+        self.send_message(Request::Registration(request), Box::new(callback));
     }
 
     pub fn prepare_passkey_assertion(
@@ -149,7 +133,7 @@ impl MacOSProviderClient {
         request: PasskeyAssertionRequest,
         callback: Arc<dyn PreparePasskeyAssertionCallback>,
     ) {
-        self.send_message(request, Box::new(callback));
+        self.send_message(Request::Assertion(request), Box::new(callback));
     }
 
     pub fn prepare_passkey_assertion_without_user_interface(
@@ -157,66 +141,40 @@ impl MacOSProviderClient {
         request: PasskeyAssertionWithoutUserInterfaceRequest,
         callback: Arc<dyn PreparePasskeyAssertionCallback>,
     ) {
-        self.send_message(request, Box::new(callback));
+        self.send_message(
+            Request::AssertionWithoutUserInterface(request),
+            Box::new(callback),
+        );
     }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "command", rename_all = "camelCase")]
-enum CommandMessage {
-    Connected,
-    Disconnected,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(untagged, rename_all = "camelCase")]
-enum SerializedMessage {
-    Command(CommandMessage),
-    Message {
-        sequence_number: u32,
-        value: Result<serde_json::Value, BitwardenError>,
-    },
+/// The requests sent to the app, tagged with their kind.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum Request {
+    Registration(PasskeyRegistrationRequest),
+    Assertion(PasskeyAssertionRequest),
+    AssertionWithoutUserInterface(PasskeyAssertionWithoutUserInterfaceRequest),
 }
 
 impl MacOSProviderClient {
-    fn add_callback(&self, callback: Box<dyn Callback>) -> u32 {
-        let sequence_number = self
-            .response_callbacks_counter
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-
-        self.response_callbacks_queue
-            .lock()
-            .unwrap()
-            .insert(sequence_number, (callback, Instant::now()));
-
-        sequence_number
-    }
-
-    fn send_message(
-        &self,
-        message: impl Serialize + DeserializeOwned,
-        callback: Box<dyn Callback>,
-    ) {
-        let sequence_number = self.add_callback(callback);
-
-        let message = serde_json::to_string(&SerializedMessage::Message {
-            sequence_number,
-            value: Ok(serde_json::to_value(message).unwrap()),
-        })
-        .expect("Can't serialize message");
-
-        if let Err(e) = self.to_server_send.blocking_send(message) {
-            // Make sure we remove the callback from the queue if we can't send the message
-            if let Some((cb, _)) = self
-                .response_callbacks_queue
-                .lock()
-                .unwrap()
-                .remove(&sequence_number)
-            {
-                cb.error(BitwardenError::Internal(format!(
-                    "Error sending message: {e}"
-                )));
-            }
-        }
+    /// Send a request to the app without blocking the calling thread. If too many requests are already waiting to
+    /// be sent, the callback gets an error right away saying so, and the extension can retry.
+    fn send_message(&self, message: Request, callback: Box<dyn Callback>) {
+        self.rpc.call(
+            message,
+            REQUEST_TIMEOUT,
+            move |response: Result<serde_json::Value, RpcError>| match response {
+                Ok(value) => {
+                    if let Err(e) = callback.complete(value) {
+                        error!("Error deserializing message: {e}");
+                    }
+                }
+                Err(e) => {
+                    error!("Error processing message: {e:?}");
+                    callback.error(BitwardenError::Internal(e.to_string()))
+                }
+            },
+        );
     }
 }
@@ -66,18 +66,313 @@ export declare namespace sshagent {
   }
   export interface SshUiRequest {
     cipherId?: string
+    /** The account the requested key belongs to, if the key was set through `setAccountKeys`. */
+    accountId?: string
     isList: boolean
     processName: string
     isForwarding: boolean
     namespace?: string
+    /** Set when the request is to issue a certificate with the CA key `cipherId`. */
+    certificate?: SshCertificateApproval
+    /** Set when the request comes from gpg, to use the OpenPGP key `cipherId`. */
+    gpgOperation?: GpgOperation
+    /** Set when the request is to decrypt an age file with the SSH key `cipherId`. */
+    isAgeDecryption: boolean
   }
-  export function serve(callback: (err: Error | null, arg: SshUiRequest) => any): Promise<SshAgentState>
+  export interface SshCertificateApproval {
+    keyId: string
+    principals: Array<string>
+    /** Unix timestamp in seconds. */
+    validAfter: number
+    /** Unix timestamp in seconds. */
+    validBefore: number
+    /** SHA256 fingerprint of the certified public key. */
+    publicKeyFingerprint: string
+    /** Critical options such as `force-command`, with their values. */
+    criticalOptions: Record<string, string>
+    /** Extensions such as `permit-pty`, with their values. Most extensions have an empty value. */
+    extensions: Record<string, string>
+  }
+  export interface SshAgentConnection {
+    id: number
+    processName: string
+    pid: number
+    /** Unix timestamp in seconds. */
+    connectedAt: number
+    /** The number of agent protocol requests received on the connection. */
+    requestsServed: number
+    isForwarding: boolean
+    /** SHA256 fingerprint of the host key the connection is bound to, if the client sent `session-bind`. */
+    boundHostKey?: string
+  }
+  export const enum GpgOperationKind {
+    Sign = 'sign',
+    Decrypt = 'decrypt'
+  }
+  export interface GpgOperation {
+    kind: GpgOperationKind
+    /** The hash algorithm of the signed digest, e.g. `sha512`. Only set for `sign`. */
+    hashAlgorithm?: string
+  }
+  export interface SshCertificateRequest {
+    /** The cipher of the CA key, which must be loaded in the agent. */
+    caCipherId: string
+    /** The OpenSSH public key to certify. */
+    publicKey: string
+    keyId: string
+    /** The users the certificate is valid for. At least one principal is required. */
+    principals: Array<string>
+    /** Unix timestamp in seconds. */
+    validAfter: number
+    /** Unix timestamp in seconds. */
+    validBefore: number
+    criticalOptions?: Record<string, string>
+    /** Defaults to the extensions `ssh-keygen` adds to user certificates. */
+    extensions?: Record<string, string>
+    serial?: number
+  }
+  export const enum SshAgentStatusKind {
+    Starting = 'starting',
+    Listening = 'listening',
+    Failed = 'failed',
+    Stopped = 'stopped',
+    Conflict = 'conflict'
+  }
+  export interface SshAgentStatus {
+    kind: SshAgentStatusKind
+    /** The socket or named pipe the agent is listening on, set when `kind` is `listening` or `conflict`. */
+    path?: string
+    /** The reason the agent failed, set when `kind` is `failed`. */
+    reason?: string
+  }
+  export interface SshKeyFingerprint {
+    /** The key type as shown by `ssh-keygen`, e.g. `ED25519` or `RSA`. */
+    keyType: string
+    bits: number
+    sha256: string
+    md5: string
+    comment: string
+    randomart: string
+    /** The summary line printed by `ssh-keygen -l`. */
+    summary: string
+  }
+  export interface SshSignatureVerification {
+    principal: string
+    namespace: string
+    fingerprint: SshKeyFingerprint
+    /** The message printed by `ssh-keygen -Y verify`. */
+    summary: string
+  }
+  export const enum SshPrivateKeyFormat {
+    OpenSsh = 'openssh',
+    Ppk = 'ppk',
+    Pkcs1 = 'pkcs1',
+    Sec1 = 'sec1',
+    Pkcs8 = 'pkcs8'
+  }
+  export interface DiscoveredSshKey {
+    path: string
+    format: SshPrivateKeyFormat
+    /**
+     * The key type as shown by `ssh-keygen`, e.g. `ED25519` or `RSA`. Unknown for encrypted PKCS#8 keys without
+     * a `.pub` file.
+     */
+    keyType?: string
+    /** Unknown for encrypted PEM keys without a `.pub` file. */
+    bits?: number
+    /** The SHA256 fingerprint, e.g. `SHA256:r17nBbZt...`. Unknown for encrypted PEM keys without a `.pub` file. */
+    fingerprint?: string
+    comment: string
+    encrypted: boolean
+    /**
+     * Whether a `.pub` file containing the same public key exists next to the private key. For encrypted PEM
+     * keys any `.pub` file counts.
+     */
+    hasPublicKey: boolean
+  }
+  export interface SshHostMapping {
+    /** An ssh_config `Host` pattern, such as `github.com` or `*.example.com`. */
+    hostPattern: string
+    cipherIds: Array<string>
+  }
+  export interface ExportedPublicKey {
+    cipherId: string
+    name: string
+    path: string
+  }
+  export interface PublicKeyExport {
+    keys: Array<ExportedPublicKey>
+    /** The exported keys in `authorized_keys` format, one per line. */
+    authorizedKeys: string
+    /** Path of the written ssh_config fragment, to be referenced with an `Include` directive. */
+    sshConfigPath?: string
+  }
+  /**
+   * Start the SSH agent. Resolves once the agent is listening, or rejects with the error that prevented it from starting.
+   *
+   * @param callback Called whenever a client request needs to be approved in the UI.
+   * @param statusCallback Called with the current status once the agent is listening, and again on every status change.
+   * @param takeOver Replace the socket of another agent that is already listening, instead of failing with a conflict.
+   */
+  export function serve(callback: (err: Error | null, arg: SshUiRequest) => any, statusCallback?: ((err: Error | null, arg: SshAgentStatus) => any) | undefined | null, takeOver?: boolean | undefined | null): Promise<SshAgentState>
   export function stop(agentState: SshAgentState): void
   export function isRunning(agentState: SshAgentState): boolean
+  export function status(agentState: SshAgentState): SshAgentStatus
   export function setKeys(agentState: SshAgentState, newKeys: Array<PrivateKey>): void
+  /** Compute the fingerprints and randomart of an OpenSSH public or private key, as shown by `ssh-keygen -lv`. */
+  export function fingerprint(key: string): SshKeyFingerprint
+  /**
+   * Create an armored SSHSIG signature over `data` with the key of the given cipher, like `ssh-keygen -Y sign`.
+   * The key must be unlocked.
+   */
+  export function signSshsig(agentState: SshAgentState, cipherId: string, namespace: string, data: Buffer): string
+  /**
+   * Issue an OpenSSH user certificate signed with a CA key in the agent, after the user approved it through the
+   * serve callback. Resolves to the certificate, to be saved as `<key>-cert.pub`.
+   */
+  export function issueCertificate(agentState: SshAgentState, request: SshCertificateRequest): Promise<string>
+  /**
+   * Verify an SSHSIG signature over `data` made by `principal`, against the contents of an `allowed_signers` file,
+   * like `ssh-keygen -Y verify`.
+   */
+  export function verifySshsig(allowedSigners: string, principal: string, namespace: string, data: Buffer, signature: string): SshSignatureVerification
+  /**
+   * Find the principals in the contents of an `allowed_signers` file that are allowed to use the key that made `signature`,
+   * like `ssh-keygen -Y find-principals`. The signature itself is not verified.
+   */
+  export function findSshsigPrincipals(allowedSigners: string, signature: string): Array<string>
+  /**
+   * Convert a PuTTY `.ppk` file (version 2 or 3) to an OpenSSH private key.
+   *
+   * @param passphrase Required if the PPK file is encrypted. The returned OpenSSH key is always unencrypted.
+   */
+  export function importPpk(ppk: string, passphrase?: string | undefined | null): string
+  /**
+   * Convert an unencrypted OpenSSH private key to a PuTTY `.ppk` file (version 3).
+   *
+   * @param passphrase When set, the PPK file is encrypted with it.
+   */
+  export function exportPpk(privateKey: string, passphrase?: string | undefined | null): string
+  /**
+   * Find the private keys in `~/.ssh` and the given directories that can be imported. Keys are never decrypted.
+   *
+   * @param additionalDirectories Directories to search in addition to `~/.ssh`. Subdirectories are not searched.
+   */
+  export function discoverKeys(additionalDirectories?: Array<string> | undefined | null): Array<DiscoveredSshKey>
+  /**
+   * Export the public keys of the given ciphers as `.pub` files, removing previously exported keys that are not selected.
+   *
+   * @param hosts When set, an ssh_config fragment mapping each host to its keys is written next to the public keys.
+   * @param directory The managed directory to export to. Defaults to `~/.ssh/bitwarden`. A directory holding files that
+   * were not exported, such as `~/.ssh`, is refused.
+   */
+  export function exportPublicKeys(agentState: SshAgentState, cipherIds: Array<string>, hosts?: Array<SshHostMapping> | undefined | null, directory?: string | undefined | null): PublicKeyExport
+  /** Replace the keys of a single account, keeping the keys of all other accounts loaded. */
+  export function setAccountKeys(agentState: SshAgentState, accountId: string, newKeys: Array<PrivateKey>): void
   export function lock(agentState: SshAgentState): void
+  /** Lock the keys of a single account, keeping the keys of all other accounts unlocked. */
+  export function lockAccount(agentState: SshAgentState, accountId: string): void
   export function clearKeys(agentState: SshAgentState): void
+  /** Remove the keys of a single account, keeping the keys of all other accounts loaded. */
+  export function clearAccountKeys(agentState: SshAgentState, accountId: string): void
+  /** List the connections to the agent that are currently open. */
+  export function connections(agentState: SshAgentState): Array<SshAgentConnection>
+  /** Close a connection immediately. Returns false if it is no longer open. */
+  export function terminateConnection(agentState: SshAgentState, id: number): boolean
+  /** Close all connections forwarded from other hosts immediately. Returns the number of connections closed. */
+  export function terminateForwardedConnections(agentState: SshAgentState): number
+  /**
+   * Start serving gpg on the gpg-agent socket, next to the SSH agent. Resolves to the socket path.
+   *
+   * Sign and decrypt requests are approved through the serve callback, with `gpgOperation` set. Rejects if gpg-agent
+   * is already running.
+   */
+  export function startGpgAgent(agentState: SshAgentState): Promise<string>
+  /**
+   * Start serving age file key requests on the `age` channel of the app's IPC server. Resolves to the socket path.
+   *
+   * Decryptions are approved through the serve callback, with `isAgeDecryption` set.
+   */
+  export function startAgeServer(agentState: SshAgentState): Promise<string>
+  /**
+   * Start serving the PKCS#11 module on the `pkcs11` channel of the app's IPC server. Resolves to the socket path.
+   *
+   * Signatures are approved through the serve callback, the same way as SSH sign requests.
+   */
+  export function startPkcs11Server(agentState: SshAgentState): Promise<string>
+  /** Replace all OpenPGP keys. `privateKey` is an armored secret key block exported without a passphrase. */
+  export function setGpgKeys(agentState: SshAgentState, newKeys: Array<PrivateKey>): void
+  /** Replace the OpenPGP keys of a single account, keeping the keys of all other accounts loaded. */
+  export function setAccountGpgKeys(agentState: SshAgentState, accountId: string, newKeys: Array<PrivateKey>): void
+  /**
+   * Generate the keys `bitwarden-ssh-agent` daemons connect with, unless they already exist. Call this before
+   * `SshAgentDaemonServer.listen`. Keys are never rotated afterwards.
+   */
+  export function provisionDaemonKeys(): void
+  export const enum SshAgentDaemonEventKind {
+    Ready = 'ready',
+    Disconnected = 'disconnected'
+  }
+  export interface SshAgentDaemonEvent {
+    /** `ready` once a daemon connected and should be sent the keys, `disconnected` once it is gone. */
+    kind: SshAgentDaemonEventKind
+    clientId: number
+    /** The socket the daemon serves the agent on. Only set for `ready`. */
+    socketPath?: string
+  }
+  export const enum AskpassEventKind {
+    Prompt = 'prompt',
+    Canceled = 'canceled'
+  }
+  export const enum AskpassPromptType {
+    Passphrase = 'passphrase',
+    Confirm = 'confirm',
+    Notify = 'notify'
+  }
+  export interface AskpassEvent {
+    /** `canceled` when the helper exited before the prompt was answered, and the prompt should be dismissed. */
+    kind: AskpassEventKind
+    requestId: string
+    /** Only set for `prompt`. */
+    promptType?: AskpassPromptType
+    /** The prompt text of OpenSSH. Only set for `prompt`. */
+    prompt?: string
+  }
   export class SshAgentState {   }
+  export class SshAgentDaemonServer {
+    /**
+     * Listen for `bitwarden-ssh-agent` daemons. The connections are encrypted with the keys provisioned with
+     * `provisionDaemonKeys`, which the daemons read from a file only the user can access.
+     *
+     * @param callback Called whenever a request of a daemon needs to be approved in the UI, like the callback of `serve`.
+     * @param eventCallback Called when a daemon connects or disconnects.
+     */
+    static listen(callback: (err: Error | null, arg: SshUiRequest) => Promise<boolean>, eventCallback: (err: Error | null, event: SshAgentDaemonEvent) => void): Promise<SshAgentDaemonServer>
+    /** Replace the keys of `accountId`, or all keys, in every daemon. Resolves to the number of daemons. */
+    setKeys(accountId: string | undefined | null, keys: Array<PrivateKey>): Promise<number>
+    /** Remove the private keys of `accountId`, or of all accounts, in every daemon. */
+    lock(accountId?: string | undefined | null): Promise<number>
+    /** Remove the keys of `accountId`, or all keys, in every daemon. */
+    clearKeys(accountId?: string | undefined | null): Promise<number>
+    /** Stop listening on the `ssh-agent` channel. The daemons no longer reach the app until it listens again. */
+    stop(): void
+  }
+  export class AskpassServer {
+    /**
+     * Listen for the prompts of the `bitwarden-askpass` helper, which OpenSSH runs as `SSH_ASKPASS`.
+     *
+     * @param callback This function will be called with every prompt, and when the helper of a prompt exits.
+     * @param allowedExecutables If set, only processes of the current user running one of these executables can
+     * show prompts, like the `allowedExecutables` of `IpcServer.listen`. Should be the `bitwarden-askpass` helper.
+     */
+    static listen(callback: (error: null | Error, event: AskpassEvent) => void, allowedExecutables?: Array<string> | undefined | null): Promise<AskpassServer>
+    /** Return the path of the socket. */
+    getPath(): string
+    /** Answer a prompt. The passphrase is only sent if `approved` is true. Rejects if the helper already exited. */
+    respond(requestId: string, approved: boolean, passphrase?: string | undefined | null): Promise<void>
+    /** Stop listening on the `askpass` channel. Helpers that are still waiting exit with an error. */
+    stop(): void
+  }
 }
 export declare namespace processisolations {
   export function disableCoredumps(): Promise<void>
@@ -96,32 +391,60 @@ export declare namespace ipc {
   export interface IpcMessage {
     clientId: number
     kind: IpcMessageType
+    /** The message on `Message` messages, or the reason on `Disconnected` and `Rejected` messages. */
     message?: string
+    /** The process that connected, set on `Connected` and `Rejected` messages. */
+    peer?: IpcPeer
+    /** The channel the client sent the message on, if it's not the default one. */
+    channel?: string
+  }
+  export interface IpcPeer {
+    pid?: number
+    uid?: number
+    executable?: string
   }
   export const enum IpcMessageType {
     Connected = 0,
     Disconnected = 1,
-    Message = 2
+    Message = 2,
+    Rejected = 3
   }
+  /**
+   * Generate the keys of both ends of the channel `name`, unless they already exist. Call this when installing the
+   * client of an encrypted channel, before listening with `encrypted`. Keys are never rotated afterwards.
+   */
+  export function provisionKeys(name: string): void
   export class IpcServer {
     /**
-     * Create and start the IPC server without blocking.
+     * Listen on a channel of the app's IPC server, which is started with the first channel and shared by all of
+     * them. Listening again on the same channel replaces the previous listener. The clients already on the
+     * channel are checked again, and the ones that are no longer allowed are disconnected from it.
      *
-     * @param name The endpoint name to listen on. This name uniquely identifies the IPC connection and must be the same for both the server and client.
-     * @param callback This function will be called whenever a message is received from a client.
+     * @param channel The name of the channel, which the clients have to use as well.
+     * @param callback This function will be called with a `Connected` message the first time a client sends on
+     * the channel, and then with its messages.
+     * @param allowedExecutables If set, only processes of the current user running one of these executables, unmodified
+     * since the server was started, can send on the channel. The messages of other processes are dropped.
+     * @param encrypted If true, clients must authenticate with the keys provisioned for this channel with `provisionKeys`,
+     * and all their messages are encrypted.
      */
-    static listen(name: string, callback: (error: null | Error, message: IpcMessage) => void): Promise<IpcServer>
+    static listen(channel: string, callback: (error: null | Error, message: IpcMessage) => void, allowedExecutables?: Array<string> | undefined | null, encrypted?: boolean | undefined | null): Promise<IpcServer>
     /** Return the path to the IPC server. */
     getPath(): string
-    /** Stop the IPC server. */
+    /** Stop listening on the channel. Clients sending on it get an error. */
     stop(): void
     /**
-     * Send a message over the IPC server to all the connected clients
+     * Send a message to all the clients on the channel
      *
      * @return The number of clients that the message was sent to. Note that the number of messages
      * actually received may be less, as some clients could disconnect before receiving the message.
      */
-    send(message: string): number
+    send(message: string): Promise<number>
+    /**
+     * Send a message to a single client on the channel.
+     * Rejects if the client is no longer connected, or is disconnected for not reading its messages.
+     */
+    sendTo(clientId: number, message: string): Promise<void>
   }
 }
 export declare namespace autostart {
@@ -180,19 +503,29 @@ export declare namespace autofill {
   }
   export class IpcServer {
     /**
-     * Create and start the IPC server without blocking.
+     * Listen on a channel of the app's IPC server, which is started with the first channel and shared by all of
+     * them.
      *
-     * @param name The endpoint name to listen on. This name uniquely identifies the IPC connection and must be the same for both the server and client.
+     * @param name The name of the channel, which the clients have to use as well.
      * @param callback This function will be called whenever a message is received from a client.
      */
     static listen(name: string, registrationCallback: (error: null | Error, clientId: number, sequenceNumber: number, message: PasskeyRegistrationRequest) => void, assertionCallback: (error: null | Error, clientId: number, sequenceNumber: number, message: PasskeyAssertionRequest) => void, assertionWithoutUserInterfaceCallback: (error: null | Error, clientId: number, sequenceNumber: number, message: PasskeyAssertionWithoutUserInterfaceRequest) => void): Promise<IpcServer>
     /** Return the path to the IPC server. */
     getPath(): string
-    /** Stop the IPC server. */
+    /** Stop listening on the channel. */
     stop(): void
-    completeRegistration(clientId: number, sequenceNumber: number, response: PasskeyRegistrationResponse): number
-    completeAssertion(clientId: number, sequenceNumber: number, response: PasskeyAssertionResponse): number
-    completeError(clientId: number, sequenceNumber: number, error: string): number
+    /**
+     * Send the response to a registration request to the client that made it only.
+     * Rejects if the client is no longer connected.
+     */
+    completeRegistration(clientId: number, sequenceNumber: number, response: PasskeyRegistrationResponse): Promise<void>
+    /**
+     * Send the response to an assertion request to the client that made it only.
+     * Rejects if the client is no longer connected.
+     */
+    completeAssertion(clientId: number, sequenceNumber: number, response: PasskeyAssertionResponse): Promise<void>
+    /** Fail a request of the client. Rejects if the client is no longer connected. */
+    completeError(clientId: number, sequenceNumber: number, error: string): Promise<void>
   }
 }
 export declare namespace passkey_authenticator {
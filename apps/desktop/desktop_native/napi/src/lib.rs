@@ -164,12 +164,21 @@ pub mod clipboards {
 
 #[napi]
 pub mod sshagent {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::Arc};
 
-    use desktop_core::ssh_agent::BitwardenSshKey;
+    use desktop_core::{
+        ipc::{
+            noise::{self, NoiseKeys, Role},
+            server,
+        },
+        ssh_agent::{askpass, daemon, BitwardenSshKey},
+    };
     use napi::{
         bindgen_prelude::Promise,
-        threadsafe_function::{ErrorStrategy::CalleeHandled, ThreadsafeFunction},
+        threadsafe_function::{
+            ErrorStrategy::CalleeHandled, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+        },
+        Env, JsObject,
     };
     use tokio::{self, sync::Mutex};
 
@@ -195,15 +204,302 @@ pub mod sshagent {
     #[napi(object)]
     pub struct SshUIRequest {
         pub cipher_id: Option<String>,
+        /// The account the requested key belongs to, if the key was set through `setAccountKeys`.
+        pub account_id: Option<String>,
         pub is_list: bool,
         pub process_name: String,
         pub is_forwarding: bool,
         pub namespace: Option<String>,
+        /// Set when the request is to issue a certificate with the CA key `cipherId`.
+        pub certificate: Option<SshCertificateApproval>,
+        /// Set when the request comes from gpg, to use the OpenPGP key `cipherId`.
+        pub gpg_operation: Option<GpgOperation>,
+        /// Set when the request is to decrypt an age file with the SSH key `cipherId`.
+        pub is_age_decryption: bool,
+    }
+
+    impl From<desktop_core::ssh_agent::SshAgentUIRequest> for SshUIRequest {
+        fn from(request: desktop_core::ssh_agent::SshAgentUIRequest) -> Self {
+            SshUIRequest {
+                cipher_id: request.cipher_id,
+                account_id: request.account_id,
+                is_list: request.is_list,
+                process_name: request.process_name,
+                is_forwarding: request.is_forwarding,
+                namespace: request.namespace,
+                certificate: request.certificate.map(SshCertificateApproval::from),
+                gpg_operation: request.gpg_operation.map(GpgOperation::from),
+                is_age_decryption: request.is_age_decryption,
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct SshCertificateApproval {
+        pub key_id: String,
+        pub principals: Vec<String>,
+        /// Unix timestamp in seconds.
+        pub valid_after: i64,
+        /// Unix timestamp in seconds.
+        pub valid_before: i64,
+        /// SHA256 fingerprint of the certified public key.
+        pub public_key_fingerprint: String,
+        /// Critical options such as `force-command`, with their values.
+        pub critical_options: HashMap<String, String>,
+        /// Extensions such as `permit-pty`, with their values. Most extensions have an empty value.
+        pub extensions: HashMap<String, String>,
+    }
+
+    impl From<desktop_core::ssh_agent::ca::CertificateApproval> for SshCertificateApproval {
+        fn from(approval: desktop_core::ssh_agent::ca::CertificateApproval) -> Self {
+            SshCertificateApproval {
+                key_id: approval.key_id,
+                principals: approval.principals,
+                valid_after: approval.valid_after as i64,
+                valid_before: approval.valid_before as i64,
+                public_key_fingerprint: approval.public_key_fingerprint,
+                critical_options: approval.critical_options.into_iter().collect(),
+                extensions: approval.extensions.into_iter().collect(),
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct SshAgentConnection {
+        pub id: u32,
+        pub process_name: String,
+        pub pid: u32,
+        /// Unix timestamp in seconds.
+        pub connected_at: i64,
+        /// The number of agent protocol requests received on the connection.
+        pub requests_served: i64,
+        pub is_forwarding: bool,
+        /// SHA256 fingerprint of the host key the connection is bound to, if the client sent `session-bind`.
+        pub bound_host_key: Option<String>,
+    }
+
+    impl From<desktop_core::ssh_agent::connections::AgentConnection> for SshAgentConnection {
+        fn from(connection: desktop_core::ssh_agent::connections::AgentConnection) -> Self {
+            SshAgentConnection {
+                id: connection.id,
+                process_name: connection.process_name,
+                pid: connection.pid,
+                connected_at: connection.connected_at as i64,
+                requests_served: connection.requests_served as i64,
+                is_forwarding: connection.is_forwarding,
+                bound_host_key: connection.bound_host_key,
+            }
+        }
+    }
+
+    #[napi(string_enum)]
+    pub enum GpgOperationKind {
+        #[napi(value = "sign")]
+        Sign,
+        #[napi(value = "decrypt")]
+        Decrypt,
+    }
+
+    #[napi(object)]
+    pub struct GpgOperation {
+        pub kind: GpgOperationKind,
+        /// The hash algorithm of the signed digest, e.g. `sha512`. Only set for `sign`.
+        pub hash_algorithm: Option<String>,
+    }
+
+    impl From<desktop_core::ssh_agent::gpg::GpgOperation> for GpgOperation {
+        fn from(operation: desktop_core::ssh_agent::gpg::GpgOperation) -> Self {
+            use desktop_core::ssh_agent::gpg::GpgOperation as CoreGpgOperation;
+
+            match operation {
+                CoreGpgOperation::Sign { hash_algorithm } => GpgOperation {
+                    kind: GpgOperationKind::Sign,
+                    hash_algorithm: Some(hash_algorithm),
+                },
+                CoreGpgOperation::Decrypt => GpgOperation {
+                    kind: GpgOperationKind::Decrypt,
+                    hash_algorithm: None,
+                },
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct SshCertificateRequest {
+        /// The cipher of the CA key, which must be loaded in the agent.
+        pub ca_cipher_id: String,
+        /// The OpenSSH public key to certify.
+        pub public_key: String,
+        pub key_id: String,
+        /// The users the certificate is valid for. At least one principal is required.
+        pub principals: Vec<String>,
+        /// Unix timestamp in seconds.
+        pub valid_after: i64,
+        /// Unix timestamp in seconds.
+        pub valid_before: i64,
+        pub critical_options: Option<HashMap<String, String>>,
+        /// Defaults to the extensions `ssh-keygen` adds to user certificates.
+        pub extensions: Option<HashMap<String, String>>,
+        pub serial: Option<i64>,
+    }
+
+    #[napi(string_enum)]
+    pub enum SshAgentStatusKind {
+        #[napi(value = "starting")]
+        Starting,
+        #[napi(value = "listening")]
+        Listening,
+        #[napi(value = "failed")]
+        Failed,
+        #[napi(value = "stopped")]
+        Stopped,
+        #[napi(value = "conflict")]
+        Conflict,
+    }
+
+    #[napi(object)]
+    pub struct SshAgentStatus {
+        pub kind: SshAgentStatusKind,
+        /// The socket or named pipe the agent is listening on, set when `kind` is `listening` or `conflict`.
+        pub path: Option<String>,
+        /// The reason the agent failed, set when `kind` is `failed`.
+        pub reason: Option<String>,
+    }
+
+    impl From<desktop_core::ssh_agent::SshAgentStatus> for SshAgentStatus {
+        fn from(status: desktop_core::ssh_agent::SshAgentStatus) -> Self {
+            use desktop_core::ssh_agent::SshAgentStatus as CoreStatus;
+            match status {
+                CoreStatus::Starting => SshAgentStatus {
+                    kind: SshAgentStatusKind::Starting,
+                    path: None,
+                    reason: None,
+                },
+                CoreStatus::Listening { path } => SshAgentStatus {
+                    kind: SshAgentStatusKind::Listening,
+                    path: Some(path),
+                    reason: None,
+                },
+                CoreStatus::Failed { reason } => SshAgentStatus {
+                    kind: SshAgentStatusKind::Failed,
+                    path: None,
+                    reason: Some(reason),
+                },
+                CoreStatus::Stopped => SshAgentStatus {
+                    kind: SshAgentStatusKind::Stopped,
+                    path: None,
+                    reason: None,
+                },
+                CoreStatus::Conflict { path } => SshAgentStatus {
+                    kind: SshAgentStatusKind::Conflict,
+                    path: Some(path),
+                    reason: None,
+                },
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct SshKeyFingerprint {
+        /// The key type as shown by `ssh-keygen`, e.g. `ED25519` or `RSA`.
+        pub key_type: String,
+        pub bits: u32,
+        pub sha256: String,
+        pub md5: String,
+        pub comment: String,
+        pub randomart: String,
+        /// The summary line printed by `ssh-keygen -l`.
+        pub summary: String,
+    }
+
+    impl From<desktop_core::ssh_agent::fingerprint::SshKeyFingerprint> for SshKeyFingerprint {
+        fn from(fingerprint: desktop_core::ssh_agent::fingerprint::SshKeyFingerprint) -> Self {
+            SshKeyFingerprint {
+                summary: fingerprint.summary(),
+                key_type: fingerprint.key_type,
+                bits: fingerprint.bits,
+                sha256: fingerprint.sha256,
+                md5: fingerprint.md5,
+                comment: fingerprint.comment,
+                randomart: fingerprint.randomart,
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct SshSignatureVerification {
+        pub principal: String,
+        pub namespace: String,
+        pub fingerprint: SshKeyFingerprint,
+        /// The message printed by `ssh-keygen -Y verify`.
+        pub summary: String,
+    }
+
+    #[napi(string_enum)]
+    pub enum SshPrivateKeyFormat {
+        #[napi(value = "openssh")]
+        OpenSsh,
+        #[napi(value = "ppk")]
+        Ppk,
+        #[napi(value = "pkcs1")]
+        Pkcs1,
+        #[napi(value = "sec1")]
+        Sec1,
+        #[napi(value = "pkcs8")]
+        Pkcs8,
+    }
+
+    #[napi(object)]
+    pub struct DiscoveredSshKey {
+        pub path: String,
+        pub format: SshPrivateKeyFormat,
+        /// The key type as shown by `ssh-keygen`, e.g. `ED25519` or `RSA`. Unknown for encrypted PKCS#8 keys without
+        /// a `.pub` file.
+        pub key_type: Option<String>,
+        /// Unknown for encrypted PEM keys without a `.pub` file.
+        pub bits: Option<u32>,
+        /// The SHA256 fingerprint, e.g. `SHA256:r17nBbZt...`. Unknown for encrypted PEM keys without a `.pub` file.
+        pub fingerprint: Option<String>,
+        pub comment: String,
+        pub encrypted: bool,
+        /// Whether a `.pub` file containing the same public key exists next to the private key. For encrypted PEM
+        /// keys any `.pub` file counts.
+        pub has_public_key: bool,
+    }
+
+    #[napi(object)]
+    pub struct SshHostMapping {
+        /// An ssh_config `Host` pattern, such as `github.com` or `*.example.com`.
+        pub host_pattern: String,
+        pub cipher_ids: Vec<String>,
+    }
+
+    #[napi(object)]
+    pub struct ExportedPublicKey {
+        pub cipher_id: String,
+        pub name: String,
+        pub path: String,
+    }
+
+    #[napi(object)]
+    pub struct PublicKeyExport {
+        pub keys: Vec<ExportedPublicKey>,
+        /// The exported keys in `authorized_keys` format, one per line.
+        pub authorized_keys: String,
+        /// Path of the written ssh_config fragment, to be referenced with an `Include` directive.
+        pub ssh_config_path: Option<String>,
     }
 
+    /// Start the SSH agent. Resolves once the agent is listening, or rejects with the error that prevented it from starting.
+    ///
+    /// @param callback Called whenever a client request needs to be approved in the UI.
+    /// @param statusCallback Called with the current status once the agent is listening, and again on every status change.
+    /// @param takeOver Replace the socket of another agent that is already listening, instead of failing with a conflict.
     #[napi]
     pub async fn serve(
         callback: ThreadsafeFunction<SshUIRequest, CalleeHandled>,
+        status_callback: Option<ThreadsafeFunction<SshAgentStatus, CalleeHandled>>,
+        take_over: Option<bool>,
     ) -> napi::Result<SshAgentState> {
         let (auth_request_tx, mut auth_request_rx) =
             tokio::sync::mpsc::channel::<desktop_core::ssh_agent::SshAgentUIRequest>(32);
@@ -220,13 +516,7 @@ pub mod sshagent {
                     let auth_response_tx_arc = cloned_response_tx_arc;
                     let callback = cloned_callback;
                     let promise_result: Result<Promise<bool>, napi::Error> = callback
-                        .call_async(Ok(SshUIRequest {
-                            cipher_id: request.cipher_id,
-                            is_list: request.is_list,
-                            process_name: request.process_name,
-                            is_forwarding: request.is_forwarding,
-                            namespace: request.namespace,
-                        }))
+                        .call_async(Ok(SshUIRequest::from(request.clone())))
                         .await;
                     match promise_result {
                         Ok(promise_result) => match promise_result.await {
@@ -262,10 +552,26 @@ pub mod sshagent {
         match desktop_core::ssh_agent::BitwardenDesktopAgent::start_server(
             auth_request_tx,
             Arc::new(Mutex::new(auth_response_rx)),
+            take_over.unwrap_or(false),
         )
         .await
         {
-            Ok(state) => Ok(SshAgentState { state }),
+            Ok(state) => {
+                if let Some(status_callback) = status_callback {
+                    let mut status_rx = state.subscribe_status();
+                    tokio::spawn(async move {
+                        loop {
+                            let status = status_rx.borrow_and_update().clone();
+                            status_callback
+                                .call(Ok(status.into()), ThreadsafeFunctionCallMode::NonBlocking);
+                            if status_rx.changed().await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+                Ok(SshAgentState { state })
+            }
             Err(e) => Err(napi::Error::from_reason(e.to_string())),
         }
     }
@@ -283,6 +589,11 @@ pub mod sshagent {
         bitwarden_agent_state.is_running()
     }
 
+    #[napi]
+    pub fn status(agent_state: &mut SshAgentState) -> SshAgentStatus {
+        agent_state.state.status().into()
+    }
+
     #[napi]
     pub fn set_keys(
         agent_state: &mut SshAgentState,
@@ -300,6 +611,248 @@ pub mod sshagent {
         Ok(())
     }
 
+    /// Compute the fingerprints and randomart of an OpenSSH public or private key, as shown by `ssh-keygen -lv`.
+    #[napi]
+    pub fn fingerprint(key: String) -> napi::Result<SshKeyFingerprint> {
+        desktop_core::ssh_agent::fingerprint::fingerprint(&key)
+            .map(SshKeyFingerprint::from)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Create an armored SSHSIG signature over `data` with the key of the given cipher, like `ssh-keygen -Y sign`.
+    /// The key must be unlocked.
+    #[napi]
+    pub fn sign_sshsig(
+        agent_state: &mut SshAgentState,
+        cipher_id: String,
+        namespace: String,
+        data: napi::bindgen_prelude::Buffer,
+    ) -> napi::Result<String> {
+        agent_state
+            .state
+            .sign_sshsig(&cipher_id, &namespace, &data)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Issue an OpenSSH user certificate signed with a CA key in the agent, after the user approved it through the
+    /// serve callback. Resolves to the certificate, to be saved as `<key>-cert.pub`.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn issue_certificate(
+        env: Env,
+        agent_state: &SshAgentState,
+        request: SshCertificateRequest,
+    ) -> napi::Result<JsObject> {
+        use desktop_core::ssh_agent::ca;
+
+        let timestamp = |value: i64| {
+            u64::try_from(value).map_err(|_| napi::Error::from_reason("Invalid timestamp"))
+        };
+        let request = ca::CertificateRequest {
+            ca_cipher_id: request.ca_cipher_id,
+            public_key: request.public_key,
+            key_id: request.key_id,
+            principals: request.principals,
+            valid_after: timestamp(request.valid_after)?,
+            valid_before: timestamp(request.valid_before)?,
+            critical_options: request
+                .critical_options
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            extensions: match request.extensions {
+                Some(extensions) => extensions.into_iter().collect(),
+                None => ca::DEFAULT_USER_EXTENSIONS
+                    .iter()
+                    .map(|extension| (extension.to_string(), String::new()))
+                    .collect(),
+            },
+            serial: u64::try_from(request.serial.unwrap_or_default())
+                .map_err(|_| napi::Error::from_reason("Invalid serial"))?,
+        };
+
+        // The approval can take as long as the user needs, so the future owns a handle to the agent instead of
+        // borrowing the JS object
+        let state = agent_state.state.clone();
+        env.spawn_future(async move {
+            state
+                .issue_certificate(request)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))
+        })
+    }
+
+    /// Verify an SSHSIG signature over `data` made by `principal`, against the contents of an `allowed_signers` file,
+    /// like `ssh-keygen -Y verify`.
+    #[napi]
+    pub fn verify_sshsig(
+        allowed_signers: String,
+        principal: String,
+        namespace: String,
+        data: napi::bindgen_prelude::Buffer,
+        signature: String,
+    ) -> napi::Result<SshSignatureVerification> {
+        use desktop_core::ssh_agent::sshsig;
+
+        let allowed_signers = sshsig::parse_allowed_signers(&allowed_signers)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        let verified = sshsig::verify(
+            &allowed_signers,
+            &principal,
+            &namespace,
+            &data,
+            &signature,
+            std::time::SystemTime::now(),
+        )
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        Ok(SshSignatureVerification {
+            summary: verified.summary(),
+            principal: verified.principal,
+            namespace: verified.namespace,
+            fingerprint: verified.fingerprint.into(),
+        })
+    }
+
+    /// Find the principals in the contents of an `allowed_signers` file that are allowed to use the key that made `signature`,
+    /// like `ssh-keygen -Y find-principals`. The signature itself is not verified.
+    #[napi]
+    pub fn find_sshsig_principals(
+        allowed_signers: String,
+        signature: String,
+    ) -> napi::Result<Vec<String>> {
+        use desktop_core::ssh_agent::sshsig;
+
+        let allowed_signers = sshsig::parse_allowed_signers(&allowed_signers)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        sshsig::find_principals(&allowed_signers, &signature, std::time::SystemTime::now())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Convert a PuTTY `.ppk` file (version 2 or 3) to an OpenSSH private key.
+    ///
+    /// @param passphrase Required if the PPK file is encrypted. The returned OpenSSH key is always unencrypted.
+    #[napi]
+    pub fn import_ppk(ppk: String, passphrase: Option<String>) -> napi::Result<String> {
+        desktop_core::ssh_agent::ppk::ppk_to_openssh(&ppk, passphrase.as_deref())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Convert an unencrypted OpenSSH private key to a PuTTY `.ppk` file (version 3).
+    ///
+    /// @param passphrase When set, the PPK file is encrypted with it.
+    #[napi]
+    pub fn export_ppk(private_key: String, passphrase: Option<String>) -> napi::Result<String> {
+        desktop_core::ssh_agent::ppk::openssh_to_ppk(&private_key, passphrase.as_deref())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Find the private keys in `~/.ssh` and the given directories that can be imported. Keys are never decrypted.
+    ///
+    /// @param additionalDirectories Directories to search in addition to `~/.ssh`. Subdirectories are not searched.
+    #[napi]
+    pub fn discover_keys(
+        additional_directories: Option<Vec<String>>,
+    ) -> napi::Result<Vec<DiscoveredSshKey>> {
+        use desktop_core::ssh_agent::discovery::{self, PrivateKeyFormat};
+
+        let additional_directories: Vec<std::path::PathBuf> = additional_directories
+            .unwrap_or_default()
+            .into_iter()
+            .map(std::path::PathBuf::from)
+            .collect();
+        let keys = discovery::discover_keys(&additional_directories)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        Ok(keys
+            .into_iter()
+            .map(|key| DiscoveredSshKey {
+                path: key.path.to_string_lossy().to_string(),
+                format: match key.format {
+                    PrivateKeyFormat::OpenSsh => SshPrivateKeyFormat::OpenSsh,
+                    PrivateKeyFormat::Ppk => SshPrivateKeyFormat::Ppk,
+                    PrivateKeyFormat::Pkcs1 => SshPrivateKeyFormat::Pkcs1,
+                    PrivateKeyFormat::Sec1 => SshPrivateKeyFormat::Sec1,
+                    PrivateKeyFormat::Pkcs8 => SshPrivateKeyFormat::Pkcs8,
+                },
+                key_type: key.key_type,
+                bits: key.bits,
+                fingerprint: key.fingerprint,
+                comment: key.comment,
+                encrypted: key.encrypted,
+                has_public_key: key.has_public_key,
+            })
+            .collect())
+    }
+
+    /// Export the public keys of the given ciphers as `.pub` files, removing previously exported keys that are not selected.
+    ///
+    /// @param hosts When set, an ssh_config fragment mapping each host to its keys is written next to the public keys.
+    /// @param directory The managed directory to export to. Defaults to `~/.ssh/bitwarden`. A directory holding files that
+    /// were not exported, such as `~/.ssh`, is refused.
+    #[napi]
+    pub fn export_public_keys(
+        agent_state: &mut SshAgentState,
+        cipher_ids: Vec<String>,
+        hosts: Option<Vec<SshHostMapping>>,
+        directory: Option<String>,
+    ) -> napi::Result<PublicKeyExport> {
+        let directory = match directory {
+            Some(directory) => std::path::PathBuf::from(directory),
+            None => desktop_core::ssh_agent::export::default_export_directory()
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?,
+        };
+        let hosts: Option<Vec<desktop_core::ssh_agent::export::HostMapping>> = hosts.map(|hosts| {
+            hosts
+                .into_iter()
+                .map(|host| desktop_core::ssh_agent::export::HostMapping {
+                    host_pattern: host.host_pattern,
+                    cipher_ids: host.cipher_ids,
+                })
+                .collect()
+        });
+
+        let export = agent_state
+            .state
+            .export_public_keys(&directory, &cipher_ids, hosts.as_deref())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        Ok(PublicKeyExport {
+            keys: export
+                .keys
+                .into_iter()
+                .map(|key| ExportedPublicKey {
+                    cipher_id: key.cipher_id,
+                    name: key.name,
+                    path: key.path.to_string_lossy().to_string(),
+                })
+                .collect(),
+            authorized_keys: export.authorized_keys,
+            ssh_config_path: export
+                .ssh_config_path
+                .map(|path| path.to_string_lossy().to_string()),
+        })
+    }
+
+    /// Replace the keys of a single account, keeping the keys of all other accounts loaded.
+    #[napi]
+    pub fn set_account_keys(
+        agent_state: &mut SshAgentState,
+        account_id: String,
+        new_keys: Vec<PrivateKey>,
+    ) -> napi::Result<()> {
+        let bitwarden_agent_state = &mut agent_state.state;
+        bitwarden_agent_state
+            .set_account_keys(
+                &account_id,
+                new_keys
+                    .iter()
+                    .map(|k| (k.private_key.clone(), k.name.clone(), k.cipher_id.clone()))
+                    .collect(),
+            )
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        Ok(())
+    }
+
     #[napi]
     pub fn lock(agent_state: &mut SshAgentState) -> napi::Result<()> {
         let bitwarden_agent_state = &mut agent_state.state;
@@ -308,6 +861,15 @@ pub mod sshagent {
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
+    /// Lock the keys of a single account, keeping the keys of all other accounts unlocked.
+    #[napi]
+    pub fn lock_account(agent_state: &mut SshAgentState, account_id: String) -> napi::Result<()> {
+        let bitwarden_agent_state = &mut agent_state.state;
+        bitwarden_agent_state
+            .lock_account(&account_id)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
     #[napi]
     pub fn clear_keys(agent_state: &mut SshAgentState) -> napi::Result<()> {
         let bitwarden_agent_state = &mut agent_state.state;
@@ -315,6 +877,402 @@ pub mod sshagent {
             .clear_keys()
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
+
+    /// Remove the keys of a single account, keeping the keys of all other accounts loaded.
+    #[napi]
+    pub fn clear_account_keys(
+        agent_state: &mut SshAgentState,
+        account_id: String,
+    ) -> napi::Result<()> {
+        let bitwarden_agent_state = &mut agent_state.state;
+        bitwarden_agent_state
+            .clear_account_keys(&account_id)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// List the connections to the agent that are currently open.
+    #[napi]
+    pub fn connections(agent_state: &SshAgentState) -> Vec<SshAgentConnection> {
+        agent_state
+            .state
+            .connections()
+            .into_iter()
+            .map(SshAgentConnection::from)
+            .collect()
+    }
+
+    /// Close a connection immediately. Returns false if it is no longer open.
+    #[napi]
+    pub fn terminate_connection(agent_state: &SshAgentState, id: u32) -> bool {
+        agent_state.state.terminate_connection(id)
+    }
+
+    /// Close all connections forwarded from other hosts immediately. Returns the number of connections closed.
+    #[napi]
+    pub fn terminate_forwarded_connections(agent_state: &SshAgentState) -> u32 {
+        agent_state.state.terminate_forwarded_connections() as u32
+    }
+
+    /// Start serving gpg on the gpg-agent socket, next to the SSH agent. Resolves to the socket path.
+    ///
+    /// Sign and decrypt requests are approved through the serve callback, with `gpgOperation` set. Rejects if gpg-agent
+    /// is already running.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn start_gpg_agent(env: Env, agent_state: &SshAgentState) -> napi::Result<JsObject> {
+        let state = agent_state.state.clone();
+        env.spawn_future(async move {
+            state
+                .start_gpg_server()
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))
+        })
+    }
+
+    /// Start serving age file key requests on the `age` channel of the app's IPC server. Resolves to the socket path.
+    ///
+    /// Decryptions are approved through the serve callback, with `isAgeDecryption` set.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn start_age_server(env: Env, agent_state: &SshAgentState) -> napi::Result<JsObject> {
+        let state = agent_state.state.clone();
+        env.spawn_future(async move {
+            let server = server::shared().map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            state
+                .start_age_server(server)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))
+        })
+    }
+
+    /// Start serving the PKCS#11 module on the `pkcs11` channel of the app's IPC server. Resolves to the socket path.
+    ///
+    /// Signatures are approved through the serve callback, the same way as SSH sign requests.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn start_pkcs11_server(env: Env, agent_state: &SshAgentState) -> napi::Result<JsObject> {
+        let state = agent_state.state.clone();
+        env.spawn_future(async move {
+            let server = server::shared().map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            state
+                .start_pkcs11_server(server)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))
+        })
+    }
+
+    /// Replace all OpenPGP keys. `privateKey` is an armored secret key block exported without a passphrase.
+    #[napi]
+    pub fn set_gpg_keys(
+        agent_state: &mut SshAgentState,
+        new_keys: Vec<PrivateKey>,
+    ) -> napi::Result<()> {
+        agent_state
+            .state
+            .set_gpg_keys(
+                new_keys
+                    .iter()
+                    .map(|k| (k.private_key.clone(), k.name.clone(), k.cipher_id.clone()))
+                    .collect(),
+            )
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Replace the OpenPGP keys of a single account, keeping the keys of all other accounts loaded.
+    #[napi]
+    pub fn set_account_gpg_keys(
+        agent_state: &mut SshAgentState,
+        account_id: String,
+        new_keys: Vec<PrivateKey>,
+    ) -> napi::Result<()> {
+        agent_state
+            .state
+            .set_account_gpg_keys(
+                &account_id,
+                new_keys
+                    .iter()
+                    .map(|k| (k.private_key.clone(), k.name.clone(), k.cipher_id.clone()))
+                    .collect(),
+            )
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Generate the keys `bitwarden-ssh-agent` daemons connect with, unless they already exist. Call this before
+    /// `SshAgentDaemonServer.listen`. Keys are never rotated afterwards.
+    #[napi]
+    pub fn provision_daemon_keys() -> napi::Result<()> {
+        noise::provision_keys(daemon::IPC_NAME).map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    #[napi(string_enum)]
+    pub enum SshAgentDaemonEventKind {
+        #[napi(value = "ready")]
+        Ready,
+        #[napi(value = "disconnected")]
+        Disconnected,
+    }
+
+    #[napi(object)]
+    pub struct SshAgentDaemonEvent {
+        /// `ready` once a daemon connected and should be sent the keys, `disconnected` once it is gone.
+        pub kind: SshAgentDaemonEventKind,
+        pub client_id: u32,
+        /// The socket the daemon serves the agent on. Only set for `ready`.
+        pub socket_path: Option<String>,
+    }
+
+    fn daemon_keys(keys: Vec<PrivateKey>) -> Vec<daemon::DaemonKey> {
+        keys.into_iter()
+            .map(|key| daemon::DaemonKey {
+                private_key: key.private_key,
+                name: key.name,
+                cipher_id: key.cipher_id,
+            })
+            .collect()
+    }
+
+    #[napi]
+    pub struct SshAgentDaemonServer {
+        server: Arc<daemon::DaemonServer>,
+    }
+
+    #[napi]
+    impl SshAgentDaemonServer {
+        /// Listen for `bitwarden-ssh-agent` daemons. The connections are encrypted with the keys provisioned with
+        /// `provisionDaemonKeys`, which the daemons read from a file only the user can access.
+        ///
+        /// @param callback Called whenever a request of a daemon needs to be approved in the UI, like the callback of `serve`.
+        /// @param eventCallback Called when a daemon connects or disconnects.
+        #[napi(factory)]
+        pub async fn listen(
+            #[napi(ts_arg_type = "(err: Error | null, arg: SshUiRequest) => Promise<boolean>")]
+            callback: ThreadsafeFunction<SshUIRequest, CalleeHandled>,
+            #[napi(ts_arg_type = "(err: Error | null, event: SshAgentDaemonEvent) => void")]
+            event_callback: ThreadsafeFunction<SshAgentDaemonEvent, CalleeHandled>,
+        ) -> napi::Result<Self> {
+            let keys = NoiseKeys::load(&noise::key_path(daemon::IPC_NAME, Role::App))
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            let (send, mut recv) = tokio::sync::mpsc::channel(32);
+            let server = server::shared().map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            let server = daemon::DaemonServer::start(server, keys, send)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            let server = Arc::new(server);
+
+            let task_server = server.clone();
+            tokio::spawn(async move {
+                while let Some(event) = recv.recv().await {
+                    let (client_id, request) = match event {
+                        daemon::DaemonServerEvent::Ready {
+                            client_id,
+                            socket_path,
+                        } => {
+                            let event = SshAgentDaemonEvent {
+                                kind: SshAgentDaemonEventKind::Ready,
+                                client_id,
+                                socket_path: Some(socket_path),
+                            };
+                            event_callback.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+                            continue;
+                        }
+                        daemon::DaemonServerEvent::Disconnected { client_id } => {
+                            let event = SshAgentDaemonEvent {
+                                kind: SshAgentDaemonEventKind::Disconnected,
+                                client_id,
+                                socket_path: None,
+                            };
+                            event_callback.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+                            continue;
+                        }
+                        daemon::DaemonServerEvent::ApprovalRequest { client_id, request } => {
+                            (client_id, request)
+                        }
+                    };
+
+                    let server = task_server.clone();
+                    let callback = callback.clone();
+                    tokio::spawn(async move {
+                        let request_id = request.request_id;
+                        let approved = match callback
+                            .call_async::<Promise<bool>>(Ok(SshUIRequest::from(request)))
+                            .await
+                        {
+                            Ok(promise) => promise.await.unwrap_or(false),
+                            Err(e) => {
+                                println!("[SSH Agent Native Module] calling UI callback could not create promise: {e}");
+                                false
+                            }
+                        };
+                        let response = daemon::DaemonCommand::ApprovalResponse {
+                            request_id,
+                            approved,
+                        };
+                        if let Err(e) = server.send_to(client_id, &response).await {
+                            println!("[SSH Agent Native Module] Could not answer daemon {client_id}: {e}");
+                        }
+                    });
+                }
+            });
+
+            Ok(SshAgentDaemonServer { server })
+        }
+
+        /// Replace the keys of `accountId`, or all keys, in every daemon. Resolves to the number of daemons.
+        #[napi]
+        pub async fn set_keys(
+            &self,
+            account_id: Option<String>,
+            keys: Vec<PrivateKey>,
+        ) -> napi::Result<u32> {
+            self.send(daemon::DaemonCommand::SetKeys {
+                account_id,
+                keys: daemon_keys(keys),
+            })
+            .await
+        }
+
+        /// Remove the private keys of `accountId`, or of all accounts, in every daemon.
+        #[napi]
+        pub async fn lock(&self, account_id: Option<String>) -> napi::Result<u32> {
+            self.send(daemon::DaemonCommand::Lock { account_id }).await
+        }
+
+        /// Remove the keys of `accountId`, or all keys, in every daemon.
+        #[napi]
+        pub async fn clear_keys(&self, account_id: Option<String>) -> napi::Result<u32> {
+            self.send(daemon::DaemonCommand::ClearKeys { account_id })
+                .await
+        }
+
+        /// Stop listening on the `ssh-agent` channel. The daemons no longer reach the app until it listens again.
+        #[napi]
+        pub fn stop(&self) {
+            self.server.stop();
+        }
+
+        async fn send(&self, command: daemon::DaemonCommand) -> napi::Result<u32> {
+            self.server
+                .send(&command)
+                .await
+                // NAPI doesn't support u64 or usize, so we need to convert to u32
+                .map(|sent| u32::try_from(sent).unwrap_or_default())
+                .map_err(|e| napi::Error::from_reason(e.to_string()))
+        }
+    }
+
+    #[napi(string_enum)]
+    pub enum AskpassEventKind {
+        #[napi(value = "prompt")]
+        Prompt,
+        #[napi(value = "canceled")]
+        Canceled,
+    }
+
+    #[napi(string_enum)]
+    pub enum AskpassPromptType {
+        #[napi(value = "passphrase")]
+        Passphrase,
+        #[napi(value = "confirm")]
+        Confirm,
+        #[napi(value = "notify")]
+        Notify,
+    }
+
+    impl From<askpass::AskpassPromptType> for AskpassPromptType {
+        fn from(prompt_type: askpass::AskpassPromptType) -> Self {
+            match prompt_type {
+                askpass::AskpassPromptType::Passphrase => AskpassPromptType::Passphrase,
+                askpass::AskpassPromptType::Confirm => AskpassPromptType::Confirm,
+                askpass::AskpassPromptType::Notify => AskpassPromptType::Notify,
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct AskpassEvent {
+        /// `canceled` when the helper exited before the prompt was answered, and the prompt should be dismissed.
+        pub kind: AskpassEventKind,
+        pub request_id: String,
+        /// Only set for `prompt`.
+        pub prompt_type: Option<AskpassPromptType>,
+        /// The prompt text of OpenSSH. Only set for `prompt`.
+        pub prompt: Option<String>,
+    }
+
+    impl From<askpass::AskpassEvent> for AskpassEvent {
+        fn from(event: askpass::AskpassEvent) -> Self {
+            match event {
+                askpass::AskpassEvent::Prompt(request) => AskpassEvent {
+                    kind: AskpassEventKind::Prompt,
+                    request_id: request.request_id,
+                    prompt_type: Some(request.prompt_type.into()),
+                    prompt: Some(request.prompt),
+                },
+                askpass::AskpassEvent::Canceled { request_id } => AskpassEvent {
+                    kind: AskpassEventKind::Canceled,
+                    request_id,
+                    prompt_type: None,
+                    prompt: None,
+                },
+            }
+        }
+    }
+
+    #[napi]
+    pub struct AskpassServer {
+        server: askpass::AskpassServer,
+    }
+
+    #[napi]
+    impl AskpassServer {
+        /// Listen for the prompts of the `bitwarden-askpass` helper, which OpenSSH runs as `SSH_ASKPASS`.
+        ///
+        /// @param callback This function will be called with every prompt, and when the helper of a prompt exits.
+        /// @param allowedExecutables If set, only processes of the current user running one of these executables can
+        /// show prompts, like the `allowedExecutables` of `IpcServer.listen`. Should be the `bitwarden-askpass` helper.
+        #[napi(factory)]
+        pub async fn listen(
+            #[napi(ts_arg_type = "(error: null | Error, event: AskpassEvent) => void")]
+            callback: ThreadsafeFunction<AskpassEvent, CalleeHandled>,
+            allowed_executables: Option<Vec<String>>,
+        ) -> napi::Result<Self> {
+            let policy = crate::ipc::peer_policy(allowed_executables)?;
+            let (send, mut recv) = tokio::sync::mpsc::channel(32);
+            let server = server::shared().map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            let server = askpass::AskpassServer::start(server, policy, send)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            tokio::spawn(async move {
+                while let Some(event) = recv.recv().await {
+                    callback.call(Ok(event.into()), ThreadsafeFunctionCallMode::NonBlocking);
+                }
+            });
+            Ok(AskpassServer { server })
+        }
+
+        /// Return the path of the socket.
+        #[napi]
+        pub fn get_path(&self) -> String {
+            self.server.path().to_string_lossy().to_string()
+        }
+
+        /// Answer a prompt. The passphrase is only sent if `approved` is true. Rejects if the helper already exited.
+        #[napi]
+        pub async fn respond(
+            &self,
+            request_id: String,
+            approved: bool,
+            passphrase: Option<String>,
+        ) -> napi::Result<()> {
+            self.server
+                .respond(&request_id, approved, passphrase)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))
+        }
+
+        /// Stop listening on the `askpass` channel. Helpers that are still waiting exit with an error.
+        #[napi]
+        pub fn stop(&self) {
+            self.server.stop();
+        }
+    }
 }
 
 #[napi]
@@ -382,7 +1340,13 @@ pub mod windows_registry {
 
 #[napi]
 pub mod ipc {
-    use desktop_core::ipc::server::{Message, MessageType};
+    use std::{path::Path, sync::Arc};
+
+    use desktop_core::ipc::{
+        noise::{self, NoiseKeys, Role},
+        peer::{AllowedExecutable, PeerIdentity, PeerPolicy},
+        server::{self, Message, MessageType, Server},
+    };
     use napi::threadsafe_function::{
         ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode,
     };
@@ -391,7 +1355,12 @@ pub mod ipc {
     pub struct IpcMessage {
         pub client_id: u32,
         pub kind: IpcMessageType,
+        /// The message on `Message` messages, or the reason on `Disconnected` and `Rejected` messages.
         pub message: Option<String>,
+        /// The process that connected, set on `Connected` and `Rejected` messages.
+        pub peer: Option<IpcPeer>,
+        /// The channel the client sent the message on, if it's not the default one.
+        pub channel: Option<String>,
     }
 
     impl From<Message> for IpcMessage {
@@ -400,6 +1369,27 @@ pub mod ipc {
                 client_id: message.client_id,
                 kind: message.kind.into(),
                 message: message.message,
+                peer: message.peer.map(|peer| peer.into()),
+                channel: message.channel,
+            }
+        }
+    }
+
+    #[napi(object)]
+    pub struct IpcPeer {
+        pub pid: Option<u32>,
+        pub uid: Option<u32>,
+        pub executable: Option<String>,
+    }
+
+    impl From<PeerIdentity> for IpcPeer {
+        fn from(peer: PeerIdentity) -> Self {
+            IpcPeer {
+                pid: peer.pid,
+                uid: peer.uid,
+                executable: peer
+                    .executable
+                    .map(|executable| executable.to_string_lossy().to_string()),
             }
         }
     }
@@ -409,6 +1399,7 @@ pub mod ipc {
         Connected,
         Disconnected,
         Message,
+        Rejected,
     }
 
     impl From<MessageType> for IpcMessageType {
@@ -417,43 +1408,90 @@ pub mod ipc {
                 MessageType::Connected => IpcMessageType::Connected,
                 MessageType::Disconnected => IpcMessageType::Disconnected,
                 MessageType::Message => IpcMessageType::Message,
+                MessageType::Rejected => IpcMessageType::Rejected,
             }
         }
     }
 
+    pub(crate) fn peer_policy(
+        allowed_executables: Option<Vec<String>>,
+    ) -> napi::Result<PeerPolicy> {
+        Ok(match allowed_executables {
+            Some(paths) => PeerPolicy::Allowlist(
+                paths
+                    .iter()
+                    .map(|path| AllowedExecutable::new(Path::new(path)))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| napi::Error::from_reason(e.to_string()))?,
+            ),
+            None => PeerPolicy::AllowAll,
+        })
+    }
+
+    fn forward(
+        mut recv: tokio::sync::mpsc::Receiver<Message>,
+        callback: ThreadsafeFunction<IpcMessage, ErrorStrategy::CalleeHandled>,
+    ) {
+        tokio::spawn(async move {
+            while let Some(message) = recv.recv().await {
+                callback.call(Ok(message.into()), ThreadsafeFunctionCallMode::NonBlocking);
+            }
+        });
+    }
+
+    /// Generate the keys of both ends of the channel `name`, unless they already exist. Call this when installing the
+    /// client of an encrypted channel, before listening with `encrypted`. Keys are never rotated afterwards.
+    #[napi]
+    pub fn provision_keys(name: String) -> napi::Result<()> {
+        noise::provision_keys(&name).map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
     #[napi]
     pub struct IpcServer {
-        server: desktop_core::ipc::server::Server,
+        server: Arc<Server>,
+        channel: String,
     }
 
     #[napi]
     impl IpcServer {
-        /// Create and start the IPC server without blocking.
+        /// Listen on a channel of the app's IPC server, which is started with the first channel and shared by all of
+        /// them. Listening again on the same channel replaces the previous listener. The clients already on the
+        /// channel are checked again, and the ones that are no longer allowed are disconnected from it.
         ///
-        /// @param name The endpoint name to listen on. This name uniquely identifies the IPC connection and must be the same for both the server and client.
-        /// @param callback This function will be called whenever a message is received from a client.
+        /// @param channel The name of the channel, which the clients have to use as well.
+        /// @param callback This function will be called with a `Connected` message the first time a client sends on
+        /// the channel, and then with its messages.
+        /// @param allowedExecutables If set, only processes of the current user running one of these executables, unmodified
+        /// since the server was started, can send on the channel. The messages of other processes are dropped.
+        /// @param encrypted If true, clients must authenticate with the keys provisioned for this channel with `provisionKeys`,
+        /// and all their messages are encrypted.
         #[napi(factory)]
         pub async fn listen(
-            name: String,
+            channel: String,
             #[napi(ts_arg_type = "(error: null | Error, message: IpcMessage) => void")]
             callback: ThreadsafeFunction<IpcMessage, ErrorStrategy::CalleeHandled>,
+            allowed_executables: Option<Vec<String>>,
+            encrypted: Option<bool>,
         ) -> napi::Result<Self> {
-            let (send, mut recv) = tokio::sync::mpsc::channel::<Message>(32);
-            tokio::spawn(async move {
-                while let Some(message) = recv.recv().await {
-                    callback.call(Ok(message.into()), ThreadsafeFunctionCallMode::NonBlocking);
-                }
-            });
-
-            let path = desktop_core::ipc::path(&name);
+            let policy = peer_policy(allowed_executables)?;
+            let keys = match encrypted {
+                Some(true) => Some(
+                    NoiseKeys::load(&noise::key_path(&channel, Role::App))
+                        .map_err(|e| napi::Error::from_reason(e.to_string()))?,
+                ),
+                _ => None,
+            };
 
-            let server = desktop_core::ipc::server::Server::start(&path, send).map_err(|e| {
-                napi::Error::from_reason(format!(
-                    "Error listening to server - Path: {path:?} - Error: {e} - {e:?}"
-                ))
+            let server = server::shared().map_err(|e| {
+                napi::Error::from_reason(format!("Error listening to server - Error: {e} - {e:?}"))
             })?;
+            let recv = server
+                .subscribe_with_keys(&channel, policy, keys)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            forward(recv, callback);
 
-            Ok(IpcServer { server })
+            Ok(IpcServer { server, channel })
         }
 
         /// Return the path to the IPC server.
@@ -462,27 +1500,40 @@ pub mod ipc {
             self.server.path.to_string_lossy().to_string()
         }
 
-        /// Stop the IPC server.
+        /// Stop listening on the channel. Clients sending on it get an error.
         #[napi]
         pub fn stop(&self) -> napi::Result<()> {
-            self.server.stop();
+            self.server.unsubscribe(&self.channel);
             Ok(())
         }
 
-        /// Send a message over the IPC server to all the connected clients
+        /// Send a message to all the clients on the channel
         ///
         /// @return The number of clients that the message was sent to. Note that the number of messages
         /// actually received may be less, as some clients could disconnect before receiving the message.
         #[napi]
-        pub fn send(&self, message: String) -> napi::Result<u32> {
+        pub async fn send(&self, message: String) -> napi::Result<u32> {
             self.server
-                .send(message)
+                .broadcast_on(&self.channel, message)
+                .await
                 .map_err(|e| {
                     napi::Error::from_reason(format!("Error sending message - Error: {e} - {e:?}"))
                 })
                 // NAPI doesn't support u64 or usize, so we need to convert to u32
                 .map(|u| u32::try_from(u).unwrap_or_default())
         }
+
+        /// Send a message to a single client on the channel.
+        /// Rejects if the client is no longer connected, or is disconnected for not reading its messages.
+        #[napi]
+        pub async fn send_to(&self, client_id: u32, message: String) -> napi::Result<()> {
+            self.server
+                .send_on(client_id, &self.channel, message)
+                .await
+                .map_err(|e| {
+                    napi::Error::from_reason(format!("Error sending message - Error: {e} - {e:?}"))
+                })
+        }
     }
 }
 
@@ -498,11 +1549,17 @@ pub mod autostart {
 
 #[napi]
 pub mod autofill {
-    use desktop_core::ipc::server::{Message, MessageType};
+    use std::sync::Arc;
+
+    use desktop_core::ipc::{
+        peer::PeerPolicy,
+        rpc::{RpcError, RpcMessage},
+        server::{self, Message, MessageType, Server},
+    };
     use napi::threadsafe_function::{
         ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode,
     };
-    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use serde::{Deserialize, Serialize};
 
     #[napi]
     pub async fn run_command(value: String) -> napi::Result<String> {
@@ -511,11 +1568,6 @@ pub mod autofill {
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
-    #[derive(Debug, serde::Serialize, serde:: Deserialize)]
-    pub enum BitwardenError {
-        Internal(String),
-    }
-
     #[napi(string_enum)]
     #[derive(Debug, Serialize, Deserialize)]
     #[serde(rename_all = "camelCase")]
@@ -528,11 +1580,13 @@ pub mod autofill {
         Discouraged,
     }
 
+    /// The requests of the autofill extension, tagged with their kind.
     #[derive(Serialize, Deserialize)]
-    #[serde(bound = "T: Serialize + DeserializeOwned")]
-    pub struct PasskeyMessage<T: Serialize + DeserializeOwned> {
-        pub sequence_number: u32,
-        pub value: Result<T, BitwardenError>,
+    #[serde(tag = "kind", rename_all = "camelCase")]
+    pub enum PasskeyRequest {
+        Registration(PasskeyRegistrationRequest),
+        Assertion(PasskeyAssertionRequest),
+        AssertionWithoutUserInterface(PasskeyAssertionWithoutUserInterfaceRequest),
     }
 
     #[napi(object)]
@@ -606,14 +1660,16 @@ pub mod autofill {
 
     #[napi]
     pub struct IpcServer {
-        server: desktop_core::ipc::server::Server,
+        server: Arc<Server>,
+        channel: String,
     }
 
     #[napi]
     impl IpcServer {
-        /// Create and start the IPC server without blocking.
+        /// Listen on a channel of the app's IPC server, which is started with the first channel and shared by all of
+        /// them.
         ///
-        /// @param name The endpoint name to listen on. This name uniquely identifies the IPC connection and must be the same for both the server and client.
+        /// @param name The name of the channel, which the clients have to use as well.
         /// @param callback This function will be called whenever a message is received from a client.
         #[napi(factory)]
         pub async fn listen(
@@ -642,92 +1698,71 @@ pub mod autofill {
                 ErrorStrategy::CalleeHandled,
             >,
         ) -> napi::Result<Self> {
-            let (send, mut recv) = tokio::sync::mpsc::channel::<Message>(32);
+            let server = server::shared().map_err(|e| {
+                napi::Error::from_reason(format!("Error listening to server - Error: {e} - {e:?}"))
+            })?;
+            let mut recv = server
+                .subscribe(&name, PeerPolicy::AllowAll)
+                .await
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?;
             tokio::spawn(async move {
                 while let Some(Message {
                     client_id,
                     kind,
                     message,
+                    ..
                 }) = recv.recv().await
                 {
                     match kind {
                         // TODO: We're ignoring the connection and disconnection messages for now
-                        MessageType::Connected | MessageType::Disconnected => continue,
+                        MessageType::Connected
+                        | MessageType::Disconnected
+                        | MessageType::Rejected => continue,
                         MessageType::Message => {
                             let Some(message) = message else {
                                 println!("[ERROR] Message is empty");
                                 continue;
                             };
 
-                            match serde_json::from_str::<PasskeyMessage<PasskeyAssertionRequest>>(
-                                &message,
-                            ) {
-                                Ok(msg) => {
-                                    let value = msg
-                                        .value
-                                        .map(|value| (client_id, msg.sequence_number, value))
-                                        .map_err(|e| napi::Error::from_reason(format!("{e:?}")));
-
-                                    assertion_callback
-                                        .call(value, ThreadsafeFunctionCallMode::NonBlocking);
-                                    continue;
-                                }
+                            let msg = match RpcMessage::<PasskeyRequest>::from_json(&message) {
+                                Ok(msg) => msg,
                                 Err(e) => {
-                                    println!("[ERROR] Error deserializing message1: {e}");
-                                }
-                            }
-
-                            match serde_json::from_str::<
-                                PasskeyMessage<PasskeyAssertionWithoutUserInterfaceRequest>,
-                            >(&message)
-                            {
-                                Ok(msg) => {
-                                    let value = msg
-                                        .value
-                                        .map(|value| (client_id, msg.sequence_number, value))
-                                        .map_err(|e| napi::Error::from_reason(format!("{e:?}")));
-
-                                    assertion_without_user_interface_callback
-                                        .call(value, ThreadsafeFunctionCallMode::NonBlocking);
+                                    println!("[ERROR] Received an unknown message: {e}");
                                     continue;
                                 }
-                                Err(e) => {
-                                    println!("[ERROR] Error deserializing message1: {e}");
-                                }
-                            }
+                            };
 
-                            match serde_json::from_str::<PasskeyMessage<PasskeyRegistrationRequest>>(
-                                &message,
-                            ) {
-                                Ok(msg) => {
-                                    let value = msg
-                                        .value
-                                        .map(|value| (client_id, msg.sequence_number, value))
-                                        .map_err(|e| napi::Error::from_reason(format!("{e:?}")));
-                                    registration_callback
-                                        .call(value, ThreadsafeFunctionCallMode::NonBlocking);
-                                    continue;
+                            let sequence_number = msg.sequence_number;
+                            match msg.value {
+                                Ok(PasskeyRequest::Registration(request)) => registration_callback
+                                    .call(
+                                        Ok((client_id, sequence_number, request)),
+                                        ThreadsafeFunctionCallMode::NonBlocking,
+                                    ),
+                                Ok(PasskeyRequest::Assertion(request)) => assertion_callback.call(
+                                    Ok((client_id, sequence_number, request)),
+                                    ThreadsafeFunctionCallMode::NonBlocking,
+                                ),
+                                Ok(PasskeyRequest::AssertionWithoutUserInterface(request)) => {
+                                    assertion_without_user_interface_callback.call(
+                                        Ok((client_id, sequence_number, request)),
+                                        ThreadsafeFunctionCallMode::NonBlocking,
+                                    )
                                 }
-                                Err(e) => {
-                                    println!("[ERROR] Error deserializing message2: {e}");
-                                }
-                            }
-
-                            println!("[ERROR] Received an unknown message2: {message:?}");
+                                Err(e) => assertion_callback.call(
+                                    Err(napi::Error::from_reason(format!("{e:?}"))),
+                                    ThreadsafeFunctionCallMode::NonBlocking,
+                                ),
+                            };
                         }
                     }
                 }
             });
 
-            let path = desktop_core::ipc::path(&name);
-
-            let server = desktop_core::ipc::server::Server::start(&path, send).map_err(|e| {
-                napi::Error::from_reason(format!(
-                    "Error listening to server - Path: {path:?} - Error: {e} - {e:?}"
-                ))
-            })?;
-
-            Ok(IpcServer { server })
+            Ok(IpcServer {
+                server,
+                channel: name,
+            })
         }
 
         /// Return the path to the IPC server.
@@ -736,64 +1771,67 @@ pub mod autofill {
             self.server.path.to_string_lossy().to_string()
         }
 
-        /// Stop the IPC server.
+        /// Stop listening on the channel.
         #[napi]
         pub fn stop(&self) -> napi::Result<()> {
-            self.server.stop();
+            self.server.unsubscribe(&self.channel);
             Ok(())
         }
 
+        /// Send the response to a registration request to the client that made it only.
+        /// Rejects if the client is no longer connected.
         #[napi]
-        pub fn complete_registration(
+        pub async fn complete_registration(
             &self,
             client_id: u32,
             sequence_number: u32,
             response: PasskeyRegistrationResponse,
-        ) -> napi::Result<u32> {
-            let message = PasskeyMessage {
+        ) -> napi::Result<()> {
+            let message = RpcMessage {
                 sequence_number,
                 value: Ok(response),
             };
-            self.send(client_id, serde_json::to_string(&message).unwrap())
+            self.send(client_id, message.to_json().unwrap()).await
         }
 
+        /// Send the response to an assertion request to the client that made it only.
+        /// Rejects if the client is no longer connected.
         #[napi]
-        pub fn complete_assertion(
+        pub async fn complete_assertion(
             &self,
             client_id: u32,
             sequence_number: u32,
             response: PasskeyAssertionResponse,
-        ) -> napi::Result<u32> {
-            let message = PasskeyMessage {
+        ) -> napi::Result<()> {
+            let message = RpcMessage {
                 sequence_number,
                 value: Ok(response),
             };
-            self.send(client_id, serde_json::to_string(&message).unwrap())
+            self.send(client_id, message.to_json().unwrap()).await
         }
 
+        /// Fail a request of the client. Rejects if the client is no longer connected.
         #[napi]
-        pub fn complete_error(
+        pub async fn complete_error(
             &self,
             client_id: u32,
             sequence_number: u32,
             error: String,
-        ) -> napi::Result<u32> {
-            let message: PasskeyMessage<()> = PasskeyMessage {
+        ) -> napi::Result<()> {
+            let message: RpcMessage<()> = RpcMessage {
                 sequence_number,
-                value: Err(BitwardenError::Internal(error)),
+                value: Err(RpcError::Internal(error)),
             };
-            self.send(client_id, serde_json::to_string(&message).unwrap())
+            self.send(client_id, message.to_json().unwrap()).await
         }
 
-        // TODO: Add a way to send a message to a specific client?
-        fn send(&self, _client_id: u32, message: String) -> napi::Result<u32> {
+        async fn send(&self, client_id: u32, message: String) -> napi::Result<()> {
             self.server
-                .send(message)
+                .send_on(client_id, &self.channel, message)
+                .await
                 .map_err(|e| {
                     napi::Error::from_reason(format!("Error sending message - Error: {e} - {e:?}"))
                 })
-                // NAPI doesn't support u64 or usize, so we need to convert to u32
-                .map(|u| u32::try_from(u).unwrap_or_default())
         }
     }
 }
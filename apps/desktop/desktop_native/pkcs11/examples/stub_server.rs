@@ -0,0 +1,96 @@
+//! A stand-in for the desktop app, to test the module without it. Serves the keys in the given OpenSSH private key
+//! files on the `pkcs11` channel of the app's IPC socket and approves every signature.
+//!
+//! Usage: cargo run -p desktop_pkcs11 --example stub_server -- KEY_FILE...
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use desktop_core::{
+    ipc::{
+        peer::PeerPolicy,
+        server::{self, MessageType},
+    },
+    ssh_agent::pkcs11::{self, Pkcs11Key, Pkcs11Request, Pkcs11Response, IPC_NAME},
+};
+use ssh_key::PrivateKey;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let keys = std::env::args()
+        .skip(1)
+        .map(|path| {
+            let key = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Could not read {path}: {e}"))
+                .and_then(|key| {
+                    PrivateKey::from_openssh(key).map_err(|e| anyhow!("Invalid key {path}: {e}"))
+                })?;
+            Ok((path, key))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        return Err(anyhow!("Usage: stub_server KEY_FILE..."));
+    }
+
+    let server = server::shared()?;
+    let mut recv = server.subscribe(IPC_NAME, PeerPolicy::AllowAll).await?;
+    println!("Listening on {}", server.path.display());
+
+    while let Some(message) = recv.recv().await {
+        let client_id = message.client_id;
+        let (MessageType::Message, Some(message)) = (message.kind, message.message) else {
+            continue;
+        };
+        let request: Pkcs11Request = match serde_json::from_str(&message) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("Invalid request: {e}");
+                continue;
+            }
+        };
+        println!("{request:?}");
+
+        let request_id = request.request_id().to_owned();
+        let response = handle(&keys, request).unwrap_or_else(|e| Pkcs11Response::Error {
+            request_id,
+            message: e.to_string(),
+        });
+        server
+            .send_on(client_id, IPC_NAME, serde_json::to_string(&response)?)
+            .await?;
+    }
+    Ok(())
+}
+
+fn handle(keys: &[(String, PrivateKey)], request: Pkcs11Request) -> Result<Pkcs11Response> {
+    match request {
+        Pkcs11Request::ListKeys { request_id, .. } => Ok(Pkcs11Response::Keys {
+            request_id,
+            keys: keys
+                .iter()
+                .map(|(name, key)| {
+                    Ok(Pkcs11Key {
+                        name: name.clone(),
+                        public_key: key.public_key().to_openssh().map_err(|e| anyhow!("{e}"))?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        }),
+        Pkcs11Request::Sign {
+            request_id,
+            public_key,
+            mechanism,
+            data,
+            ..
+        } => {
+            let (_, key) = keys
+                .iter()
+                .find(|(_, key)| key.public_key().to_openssh().ok().as_deref() == Some(&public_key))
+                .ok_or_else(|| anyhow!("Unknown key"))?;
+            let signature = pkcs11::sign(key, mechanism, &STANDARD.decode(data)?)?;
+            Ok(Pkcs11Response::Signature {
+                request_id,
+                signature: STANDARD.encode(signature),
+            })
+        }
+    }
+}
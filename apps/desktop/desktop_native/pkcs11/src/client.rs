@@ -0,0 +1,66 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use desktop_core::{
+    ipc,
+    ssh_agent::pkcs11::{Pkcs11Key, Pkcs11Mechanism, Pkcs11Request, Pkcs11Response, IPC_NAME},
+};
+
+/// How long to wait for the app. Both listing and signing can wait for the user to unlock or approve.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Ask the app for the keys in the agent.
+pub fn list_keys() -> Result<Vec<Pkcs11Key>> {
+    match request(Pkcs11Request::ListKeys {
+        request_id: next_request_id(),
+    })? {
+        Pkcs11Response::Keys { keys, .. } => Ok(keys),
+        Pkcs11Response::Error { message, .. } => Err(anyhow!(message)),
+        response => Err(anyhow!("Unexpected response {response:?}")),
+    }
+}
+
+/// Ask the app to sign `data` with the key of `public_key`, which shows the same approval as an SSH signature.
+pub fn sign(public_key: &str, mechanism: Pkcs11Mechanism, data: &[u8]) -> Result<Vec<u8>> {
+    match request(Pkcs11Request::Sign {
+        request_id: next_request_id(),
+        public_key: public_key.to_string(),
+        mechanism,
+        data: STANDARD.encode(data),
+    })? {
+        Pkcs11Response::Signature { signature, .. } => Ok(STANDARD.decode(signature)?),
+        Pkcs11Response::Error { message, .. } => Err(anyhow!(message)),
+        response => Err(anyhow!("Unexpected response {response:?}")),
+    }
+}
+
+fn next_request_id() -> String {
+    format!(
+        "{}-{}",
+        std::process::id(),
+        REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Send `request` over a new connection and wait for its response.
+///
+/// The module is loaded into applications that may or may not run an async runtime, so every request gets its own
+/// single threaded runtime instead of sharing one with the host.
+fn request(request: Pkcs11Request) -> Result<Pkcs11Response> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(ipc::client::request_on_channel(
+        ipc::path(ipc::APP_ENDPOINT),
+        IPC_NAME,
+        &request,
+        |response: &Pkcs11Response| response.request_id() == request.request_id(),
+        REQUEST_TIMEOUT,
+    ))
+}
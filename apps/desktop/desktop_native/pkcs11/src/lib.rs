@@ -0,0 +1,677 @@
+//! Bitwarden PKCS#11 module.
+//!
+//! Exposes the SSH keys of the desktop app's agent as a token to applications that use PKCS#11 instead of the agent
+//! protocol, for example `ssh -I libbitwarden_pkcs11.so`. The module holds no key material: object searches list the
+//! keys in the app, and signatures are made by the app after the user approved them, just as with SSH signatures.
+//! Both go over the `pkcs11` channel of the app's IPC socket, see `desktop_core::ssh_agent::pkcs11`.
+//!
+//! The token has a single slot without a PIN. Every key is a public and a private key object sharing the same
+//! `CKA_ID`, and supports `CKM_RSA_PKCS`, `CKM_ECDSA` (P-256 and P-384) or `CKM_EDDSA`.
+//!
+//! To test without the app, run the stub server example with a key file and point `pkcs11-tool` at the module:
+//!
+//! ```sh
+//! cargo run -p desktop_pkcs11 --example stub_server -- ~/.ssh/id_ed25519
+//! pkcs11-tool --module target/debug/libbitwarden_pkcs11.so --list-objects
+//! ```
+
+// The functions and structure fields keep the names of the specification
+#![allow(non_snake_case)]
+
+use std::{
+    collections::HashMap,
+    os::raw::c_void,
+    sync::{Mutex, MutexGuard},
+};
+
+use types::*;
+
+mod client;
+mod object;
+mod types;
+
+use object::KeyObject;
+
+const SLOT_ID: CK_SLOT_ID = 1;
+
+const MANUFACTURER: &str = "Bitwarden Inc.";
+
+static MODULE: Mutex<Option<Module>> = Mutex::new(None);
+
+static FUNCTION_LIST: CK_FUNCTION_LIST = CK_FUNCTION_LIST {
+    version: CK_VERSION {
+        major: 2,
+        minor: 40,
+    },
+    C_Initialize,
+    C_Finalize,
+    C_GetInfo,
+    C_GetFunctionList,
+    C_GetSlotList,
+    C_GetSlotInfo,
+    C_GetTokenInfo,
+    C_GetMechanismList,
+    C_GetMechanismInfo,
+    C_InitToken: not_supported,
+    C_InitPIN: not_supported,
+    C_SetPIN: not_supported,
+    C_OpenSession,
+    C_CloseSession,
+    C_CloseAllSessions,
+    C_GetSessionInfo,
+    C_GetOperationState: not_supported,
+    C_SetOperationState: not_supported,
+    C_Login,
+    C_Logout,
+    C_CreateObject: not_supported,
+    C_CopyObject: not_supported,
+    C_DestroyObject: not_supported,
+    C_GetObjectSize: not_supported,
+    C_GetAttributeValue,
+    C_SetAttributeValue: not_supported,
+    C_FindObjectsInit,
+    C_FindObjects,
+    C_FindObjectsFinal,
+    C_EncryptInit: not_supported,
+    C_Encrypt: not_supported,
+    C_EncryptUpdate: not_supported,
+    C_EncryptFinal: not_supported,
+    C_DecryptInit: not_supported,
+    C_Decrypt: not_supported,
+    C_DecryptUpdate: not_supported,
+    C_DecryptFinal: not_supported,
+    C_DigestInit: not_supported,
+    C_Digest: not_supported,
+    C_DigestUpdate: not_supported,
+    C_DigestKey: not_supported,
+    C_DigestFinal: not_supported,
+    C_SignInit,
+    C_Sign,
+    C_SignUpdate: not_supported,
+    C_SignFinal: not_supported,
+    C_SignRecoverInit: not_supported,
+    C_SignRecover: not_supported,
+    C_VerifyInit: not_supported,
+    C_Verify: not_supported,
+    C_VerifyUpdate: not_supported,
+    C_VerifyFinal: not_supported,
+    C_VerifyRecoverInit: not_supported,
+    C_VerifyRecover: not_supported,
+    C_DigestEncryptUpdate: not_supported,
+    C_DecryptDigestUpdate: not_supported,
+    C_SignEncryptUpdate: not_supported,
+    C_DecryptVerifyUpdate: not_supported,
+    C_GenerateKey: not_supported,
+    C_GenerateKeyPair: not_supported,
+    C_WrapKey: not_supported,
+    C_UnwrapKey: not_supported,
+    C_DeriveKey: not_supported,
+    C_SeedRandom: not_supported,
+    C_GenerateRandom: not_supported,
+    C_GetFunctionStatus: not_supported,
+    C_CancelFunction: not_supported,
+    C_WaitForSlotEvent: not_supported,
+};
+
+#[derive(Default)]
+struct Module {
+    /// The keys listed so far. Keys are never removed, so object handles stay valid for the lifetime of the module.
+    keys: Vec<KeyObject>,
+    sessions: HashMap<CK_SESSION_HANDLE, Session>,
+    next_session: CK_SESSION_HANDLE,
+}
+
+#[derive(Default)]
+struct Session {
+    /// The remaining results of the active search.
+    found: Option<Vec<CK_OBJECT_HANDLE>>,
+    /// The private key object of the active signing operation.
+    signing_key: Option<CK_OBJECT_HANDLE>,
+}
+
+impl Module {
+    /// The key of an object handle, and whether the handle is its private key object.
+    fn object(&self, handle: CK_OBJECT_HANDLE) -> Option<(&KeyObject, bool)> {
+        let index = (handle as usize).checked_sub(1)?;
+        self.keys.get(index / 2).map(|key| (key, index % 2 == 1))
+    }
+
+    fn session(&mut self, handle: CK_SESSION_HANDLE) -> Result<&mut Session, CK_RV> {
+        self.sessions
+            .get_mut(&handle)
+            .ok_or(CKR_SESSION_HANDLE_INVALID)
+    }
+}
+
+fn object_handle(index: usize, private: bool) -> CK_OBJECT_HANDLE {
+    (index * 2 + 1 + private as usize) as CK_OBJECT_HANDLE
+}
+
+fn lock() -> MutexGuard<'static, Option<Module>> {
+    MODULE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `f` with the initialized module, and convert its result to a return value.
+fn with_module(f: impl FnOnce(&mut Module) -> Result<(), CK_RV>) -> CK_RV {
+    match lock().as_mut() {
+        Some(module) => f(module).err().unwrap_or(CKR_OK),
+        None => CKR_CRYPTOKI_NOT_INITIALIZED,
+    }
+}
+
+/// Copy `value` into a fixed size PKCS#11 string field, padded with spaces.
+fn padded<const N: usize>(value: &str) -> [CK_BYTE; N] {
+    let mut field = [b' '; N];
+    let len = value.len().min(N);
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    field
+}
+
+/// The shared entry for all functions of the list the module does not implement.
+unsafe extern "C" fn not_supported() -> CK_RV {
+    CKR_FUNCTION_NOT_SUPPORTED
+}
+
+/// # Safety
+///
+/// `function_list` must be null or point to writable memory for a pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn C_GetFunctionList(function_list: *mut *const CK_FUNCTION_LIST) -> CK_RV {
+    if function_list.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    *function_list = &FUNCTION_LIST;
+    CKR_OK
+}
+
+unsafe extern "C" fn C_Initialize(_init_args: *mut c_void) -> CK_RV {
+    // The module only uses its own mutex, so the locking callbacks of the arguments are not needed
+    let mut module = lock();
+    if module.is_some() {
+        return CKR_CRYPTOKI_ALREADY_INITIALIZED;
+    }
+    *module = Some(Module::default());
+    CKR_OK
+}
+
+unsafe extern "C" fn C_Finalize(reserved: *mut c_void) -> CK_RV {
+    if !reserved.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    match lock().take() {
+        Some(_) => CKR_OK,
+        None => CKR_CRYPTOKI_NOT_INITIALIZED,
+    }
+}
+
+unsafe extern "C" fn C_GetInfo(info: *mut CK_INFO) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|_| {
+        *info = CK_INFO {
+            cryptokiVersion: FUNCTION_LIST.version,
+            manufacturerID: padded(MANUFACTURER),
+            flags: 0,
+            libraryDescription: padded("Bitwarden SSH agent keys"),
+            libraryVersion: CK_VERSION { major: 1, minor: 0 },
+        };
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_GetSlotList(
+    _token_present: CK_BBOOL,
+    slot_list: *mut CK_SLOT_ID,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if count.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|_| {
+        if !slot_list.is_null() {
+            if *count < 1 {
+                *count = 1;
+                return Err(CKR_BUFFER_TOO_SMALL);
+            }
+            *slot_list = SLOT_ID;
+        }
+        *count = 1;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_GetSlotInfo(slot_id: CK_SLOT_ID, info: *mut CK_SLOT_INFO) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|_| {
+        if slot_id != SLOT_ID {
+            return Err(CKR_SLOT_ID_INVALID);
+        }
+        *info = CK_SLOT_INFO {
+            slotDescription: padded("Bitwarden desktop app"),
+            manufacturerID: padded(MANUFACTURER),
+            flags: CKF_TOKEN_PRESENT,
+            hardwareVersion: CK_VERSION::default(),
+            firmwareVersion: CK_VERSION::default(),
+        };
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_GetTokenInfo(slot_id: CK_SLOT_ID, info: *mut CK_TOKEN_INFO) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|module| {
+        if slot_id != SLOT_ID {
+            return Err(CKR_SLOT_ID_INVALID);
+        }
+        *info = CK_TOKEN_INFO {
+            label: padded("Bitwarden"),
+            manufacturerID: padded(MANUFACTURER),
+            model: padded("SSH agent"),
+            serialNumber: padded("1"),
+            flags: CKF_TOKEN_INITIALIZED,
+            ulMaxSessionCount: CK_UNAVAILABLE_INFORMATION,
+            ulSessionCount: module.sessions.len() as CK_ULONG,
+            ulMaxRwSessionCount: 0,
+            ulRwSessionCount: 0,
+            ulMaxPinLen: 0,
+            ulMinPinLen: 0,
+            ulTotalPublicMemory: CK_UNAVAILABLE_INFORMATION,
+            ulFreePublicMemory: CK_UNAVAILABLE_INFORMATION,
+            ulTotalPrivateMemory: CK_UNAVAILABLE_INFORMATION,
+            ulFreePrivateMemory: CK_UNAVAILABLE_INFORMATION,
+            hardwareVersion: CK_VERSION::default(),
+            firmwareVersion: CK_VERSION::default(),
+            utcTime: [b' '; 16],
+        };
+        Ok(())
+    })
+}
+
+const MECHANISMS: [(CK_MECHANISM_TYPE, CK_ULONG, CK_ULONG); 3] = [
+    (CKM_RSA_PKCS, 1024, 16384),
+    (CKM_ECDSA, 256, 384),
+    (CKM_EDDSA, 256, 256),
+];
+
+unsafe extern "C" fn C_GetMechanismList(
+    slot_id: CK_SLOT_ID,
+    mechanism_list: *mut CK_MECHANISM_TYPE,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if count.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|_| {
+        if slot_id != SLOT_ID {
+            return Err(CKR_SLOT_ID_INVALID);
+        }
+        if !mechanism_list.is_null() {
+            if (*count as usize) < MECHANISMS.len() {
+                *count = MECHANISMS.len() as CK_ULONG;
+                return Err(CKR_BUFFER_TOO_SMALL);
+            }
+            for (i, (mechanism, _, _)) in MECHANISMS.iter().enumerate() {
+                *mechanism_list.add(i) = *mechanism;
+            }
+        }
+        *count = MECHANISMS.len() as CK_ULONG;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_GetMechanismInfo(
+    slot_id: CK_SLOT_ID,
+    mechanism_type: CK_MECHANISM_TYPE,
+    info: *mut CK_MECHANISM_INFO,
+) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|_| {
+        if slot_id != SLOT_ID {
+            return Err(CKR_SLOT_ID_INVALID);
+        }
+        let (_, min, max) = MECHANISMS
+            .iter()
+            .find(|(mechanism, _, _)| *mechanism == mechanism_type)
+            .ok_or(CKR_MECHANISM_INVALID)?;
+        *info = CK_MECHANISM_INFO {
+            ulMinKeySize: *min,
+            ulMaxKeySize: *max,
+            flags: CKF_SIGN,
+        };
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_OpenSession(
+    slot_id: CK_SLOT_ID,
+    flags: CK_FLAGS,
+    _application: *mut c_void,
+    _notify: CK_NOTIFY,
+    session: *mut CK_SESSION_HANDLE,
+) -> CK_RV {
+    if session.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|module| {
+        if slot_id != SLOT_ID {
+            return Err(CKR_SLOT_ID_INVALID);
+        }
+        if flags & CKF_SERIAL_SESSION == 0 {
+            return Err(CKR_SESSION_PARALLEL_NOT_SUPPORTED);
+        }
+        module.next_session += 1;
+        module
+            .sessions
+            .insert(module.next_session, Session::default());
+        *session = module.next_session;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_CloseSession(session: CK_SESSION_HANDLE) -> CK_RV {
+    with_module(|module| {
+        module
+            .sessions
+            .remove(&session)
+            .map(|_| ())
+            .ok_or(CKR_SESSION_HANDLE_INVALID)
+    })
+}
+
+unsafe extern "C" fn C_CloseAllSessions(slot_id: CK_SLOT_ID) -> CK_RV {
+    with_module(|module| {
+        if slot_id != SLOT_ID {
+            return Err(CKR_SLOT_ID_INVALID);
+        }
+        module.sessions.clear();
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_GetSessionInfo(
+    session: CK_SESSION_HANDLE,
+    info: *mut CK_SESSION_INFO,
+) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|module| {
+        module.session(session)?;
+        *info = CK_SESSION_INFO {
+            slotID: SLOT_ID,
+            state: CKS_RO_PUBLIC_SESSION,
+            flags: CKF_SERIAL_SESSION,
+            ulDeviceError: 0,
+        };
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_Login(
+    session: CK_SESSION_HANDLE,
+    _user_type: CK_USER_TYPE,
+    _pin: *mut CK_BYTE,
+    _pin_len: CK_ULONG,
+) -> CK_RV {
+    // There is no PIN, the app asks the user to unlock and approve instead
+    with_module(|module| module.session(session).map(|_| ()))
+}
+
+unsafe extern "C" fn C_Logout(session: CK_SESSION_HANDLE) -> CK_RV {
+    with_module(|module| module.session(session).map(|_| ()))
+}
+
+unsafe extern "C" fn C_GetAttributeValue(
+    session: CK_SESSION_HANDLE,
+    object: CK_OBJECT_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> CK_RV {
+    if template.is_null() && count > 0 {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|module| {
+        module.session(session)?;
+        let (key, private) = module.object(object).ok_or(CKR_OBJECT_HANDLE_INVALID)?;
+
+        // Every attribute is processed even if an earlier one fails, as the specification requires
+        let mut result = Ok(());
+        for i in 0..count as usize {
+            let attribute = &mut *template.add(i);
+            match key.attribute(private, attribute.r#type) {
+                None => {
+                    attribute.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+                    result = Err(CKR_ATTRIBUTE_TYPE_INVALID);
+                }
+                Some(value) if attribute.pValue.is_null() => {
+                    attribute.ulValueLen = value.len() as CK_ULONG;
+                }
+                Some(value) if (attribute.ulValueLen as usize) < value.len() => {
+                    attribute.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+                    result = Err(CKR_BUFFER_TOO_SMALL);
+                }
+                Some(value) => {
+                    std::ptr::copy_nonoverlapping(
+                        value.as_ptr(),
+                        attribute.pValue as *mut u8,
+                        value.len(),
+                    );
+                    attribute.ulValueLen = value.len() as CK_ULONG;
+                }
+            }
+        }
+        result
+    })
+}
+
+unsafe extern "C" fn C_FindObjectsInit(
+    session: CK_SESSION_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> CK_RV {
+    if template.is_null() && count > 0 {
+        return CKR_ARGUMENTS_BAD;
+    }
+    let template = (0..count as usize)
+        .map(|i| {
+            let attribute = &*template.add(i);
+            let value = if attribute.pValue.is_null() {
+                vec![]
+            } else {
+                std::slice::from_raw_parts(
+                    attribute.pValue as *const u8,
+                    attribute.ulValueLen as usize,
+                )
+                .to_vec()
+            };
+            (attribute.r#type, value)
+        })
+        .collect::<Vec<_>>();
+
+    let rv = with_module(|module| match module.session(session)?.found {
+        Some(_) => Err(CKR_OPERATION_ACTIVE),
+        None => Ok(()),
+    });
+    if rv != CKR_OK {
+        return rv;
+    }
+
+    // The module lock is not held while waiting for the app, which may ask the user to unlock first
+    let keys = match client::list_keys() {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("[Bitwarden PKCS#11] Could not list keys: {e}");
+            return CKR_DEVICE_ERROR;
+        }
+    };
+
+    with_module(|module| {
+        for key in keys {
+            if module.keys.iter().any(|k| k.public_key == key.public_key) {
+                continue;
+            }
+            match KeyObject::new(&key) {
+                Ok(object) => module.keys.push(object),
+                // Keys the token can not represent are left out
+                Err(_) => continue,
+            }
+        }
+
+        let found = module
+            .keys
+            .iter()
+            .enumerate()
+            .flat_map(|(index, key)| {
+                [false, true]
+                    .into_iter()
+                    .filter(|private| key.matches(*private, &template))
+                    .map(move |private| object_handle(index, private))
+            })
+            .rev()
+            .collect();
+        module.session(session)?.found = Some(found);
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_FindObjects(
+    session: CK_SESSION_HANDLE,
+    objects: *mut CK_OBJECT_HANDLE,
+    max_count: CK_ULONG,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if objects.is_null() || count.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_module(|module| {
+        let found = module
+            .session(session)?
+            .found
+            .as_mut()
+            .ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+        let mut n = 0;
+        // The results are stored in reverse, so they are returned in order
+        while n < max_count as usize {
+            let Some(handle) = found.pop() else {
+                break;
+            };
+            *objects.add(n) = handle;
+            n += 1;
+        }
+        *count = n as CK_ULONG;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_FindObjectsFinal(session: CK_SESSION_HANDLE) -> CK_RV {
+    with_module(|module| {
+        module
+            .session(session)?
+            .found
+            .take()
+            .map(|_| ())
+            .ok_or(CKR_OPERATION_NOT_INITIALIZED)
+    })
+}
+
+unsafe extern "C" fn C_SignInit(
+    session: CK_SESSION_HANDLE,
+    mechanism: *mut CK_MECHANISM,
+    key: CK_OBJECT_HANDLE,
+) -> CK_RV {
+    if mechanism.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    let mechanism = (*mechanism).mechanism;
+    with_module(|module| {
+        let (object, private) = module.object(key).ok_or(CKR_KEY_HANDLE_INVALID)?;
+        if !private {
+            return Err(CKR_KEY_TYPE_INCONSISTENT);
+        }
+        if object.mechanism != mechanism {
+            return Err(CKR_MECHANISM_INVALID);
+        }
+        let session = module.session(session)?;
+        if session.signing_key.is_some() {
+            return Err(CKR_OPERATION_ACTIVE);
+        }
+        session.signing_key = Some(key);
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn C_Sign(
+    session: CK_SESSION_HANDLE,
+    data: *mut CK_BYTE,
+    data_len: CK_ULONG,
+    signature: *mut CK_BYTE,
+    signature_len: *mut CK_ULONG,
+) -> CK_RV {
+    if (data.is_null() && data_len > 0) || signature_len.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+
+    let mut request = None;
+    let rv = with_module(|module| {
+        let handle = module
+            .session(session)?
+            .signing_key
+            .ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+        let (key, _) = module.object(handle).ok_or(CKR_KEY_HANDLE_INVALID)?;
+        // Querying the length must not sign, or the user would be asked to approve twice
+        let length = key.signature_length as CK_ULONG;
+        if signature.is_null() {
+            *signature_len = length;
+            return Ok(());
+        }
+        if *signature_len < length {
+            *signature_len = length;
+            return Err(CKR_BUFFER_TOO_SMALL);
+        }
+        request = Some((key.public_key.clone(), key.pkcs11_mechanism()));
+        module.session(session)?.signing_key = None;
+        Ok(())
+    });
+    let Some((public_key, mechanism)) = request else {
+        return rv;
+    };
+
+    let data = if data.is_null() {
+        &[][..]
+    } else {
+        std::slice::from_raw_parts(data, data_len as usize)
+    };
+    match client::sign(&public_key, mechanism, data) {
+        Ok(result) if result.len() as CK_ULONG <= *signature_len => {
+            std::ptr::copy_nonoverlapping(result.as_ptr(), signature, result.len());
+            *signature_len = result.len() as CK_ULONG;
+            CKR_OK
+        }
+        Ok(_) => CKR_GENERAL_ERROR,
+        Err(e) => {
+            eprintln!("[Bitwarden PKCS#11] Could not sign: {e}");
+            CKR_FUNCTION_REJECTED
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_handles() {
+        let module = Module::default();
+        assert!(module.object(0).is_none());
+        assert_eq!(object_handle(0, false), 1);
+        assert_eq!(object_handle(0, true), 2);
+        assert_eq!(object_handle(3, true), 8);
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use desktop_core::ssh_agent::pkcs11::{Pkcs11Key, Pkcs11Mechanism};
+use sha2::{Digest, Sha256};
+use ssh_key::{public::EcdsaPublicKey, public::KeyData, PublicKey};
+
+use crate::types::*;
+
+/// DER encoded OIDs of the curves, as `CKA_EC_PARAMS` holds them.
+const OID_P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_ED25519: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+/// A key of the agent, which the token exposes as a public and a private key object with the same `CKA_ID`.
+pub struct KeyObject {
+    /// The OpenSSH public key, which identifies the key towards the app.
+    pub public_key: String,
+    pub mechanism: CK_MECHANISM_TYPE,
+    pub signature_length: usize,
+    public_attributes: Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)>,
+    private_attributes: Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)>,
+}
+
+impl KeyObject {
+    pub fn new(key: &Pkcs11Key) -> Result<Self> {
+        let public_key = PublicKey::from_openssh(&key.public_key)
+            .map_err(|e| anyhow!("Invalid public key: {e}"))?;
+        let id = Sha256::digest(
+            public_key
+                .to_bytes()
+                .map_err(|e| anyhow!("Invalid public key: {e}"))?,
+        )
+        .to_vec();
+
+        let (key_type, mechanism, signature_length, key_attributes, public_only) =
+            match public_key.key_data() {
+                KeyData::Rsa(rsa) => {
+                    let modulus = rsa
+                        .n
+                        .as_positive_bytes()
+                        .ok_or_else(|| anyhow!("Invalid RSA modulus"))?;
+                    let exponent = rsa
+                        .e
+                        .as_positive_bytes()
+                        .ok_or_else(|| anyhow!("Invalid RSA exponent"))?;
+                    let bits = modulus.len() * 8 - modulus[0].leading_zeros() as usize;
+                    (
+                        CKK_RSA,
+                        CKM_RSA_PKCS,
+                        modulus.len(),
+                        vec![
+                            (CKA_MODULUS, modulus.to_vec()),
+                            (CKA_PUBLIC_EXPONENT, exponent.to_vec()),
+                            (CKA_MODULUS_BITS, ulong(bits as CK_ULONG)),
+                        ],
+                        vec![],
+                    )
+                }
+                KeyData::Ecdsa(EcdsaPublicKey::NistP256(point)) => (
+                    CKK_EC,
+                    CKM_ECDSA,
+                    64,
+                    vec![(CKA_EC_PARAMS, OID_P256.to_vec())],
+                    vec![(CKA_EC_POINT, der_octet_string(point.as_bytes()))],
+                ),
+                KeyData::Ecdsa(EcdsaPublicKey::NistP384(point)) => (
+                    CKK_EC,
+                    CKM_ECDSA,
+                    96,
+                    vec![(CKA_EC_PARAMS, OID_P384.to_vec())],
+                    vec![(CKA_EC_POINT, der_octet_string(point.as_bytes()))],
+                ),
+                KeyData::Ed25519(point) => (
+                    CKK_EC_EDWARDS,
+                    CKM_EDDSA,
+                    64,
+                    vec![(CKA_EC_PARAMS, OID_ED25519.to_vec())],
+                    vec![(CKA_EC_POINT, der_octet_string(&point.0))],
+                ),
+                _ => return Err(anyhow!("{} keys are not supported", public_key.algorithm())),
+            };
+
+        let common = [
+            (CKA_TOKEN, vec![CK_TRUE]),
+            (CKA_PRIVATE, vec![CK_FALSE]),
+            (CKA_MODIFIABLE, vec![CK_FALSE]),
+            (CKA_LABEL, key.name.as_bytes().to_vec()),
+            (CKA_ID, id),
+            (CKA_KEY_TYPE, ulong(key_type)),
+            (CKA_LOCAL, vec![CK_FALSE]),
+            (CKA_DERIVE, vec![CK_FALSE]),
+        ];
+
+        let mut public_attributes = vec![
+            (CKA_CLASS, ulong(CKO_PUBLIC_KEY)),
+            (CKA_VERIFY, vec![CK_TRUE]),
+            (CKA_ENCRYPT, vec![CK_FALSE]),
+            (CKA_WRAP, vec![CK_FALSE]),
+        ];
+        public_attributes.extend(common.iter().cloned());
+        public_attributes.extend(key_attributes.iter().cloned());
+        public_attributes.extend(public_only);
+
+        let mut private_attributes = vec![
+            (CKA_CLASS, ulong(CKO_PRIVATE_KEY)),
+            (CKA_SIGN, vec![CK_TRUE]),
+            (CKA_SIGN_RECOVER, vec![CK_FALSE]),
+            (CKA_DECRYPT, vec![CK_FALSE]),
+            (CKA_UNWRAP, vec![CK_FALSE]),
+            (CKA_SENSITIVE, vec![CK_TRUE]),
+            (CKA_ALWAYS_SENSITIVE, vec![CK_TRUE]),
+            (CKA_EXTRACTABLE, vec![CK_FALSE]),
+            (CKA_NEVER_EXTRACTABLE, vec![CK_TRUE]),
+            (CKA_ALWAYS_AUTHENTICATE, vec![CK_FALSE]),
+        ];
+        private_attributes.extend(common);
+        private_attributes.extend(key_attributes);
+
+        Ok(KeyObject {
+            public_key: key.public_key.clone(),
+            mechanism,
+            signature_length,
+            public_attributes,
+            private_attributes,
+        })
+    }
+
+    pub fn attributes(&self, private: bool) -> &[(CK_ATTRIBUTE_TYPE, Vec<u8>)] {
+        if private {
+            &self.private_attributes
+        } else {
+            &self.public_attributes
+        }
+    }
+
+    pub fn attribute(&self, private: bool, attribute_type: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+        self.attributes(private)
+            .iter()
+            .find(|(t, _)| *t == attribute_type)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Whether the object has all attributes of a `C_FindObjectsInit` template, with the same values.
+    pub fn matches(&self, private: bool, template: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
+        template
+            .iter()
+            .all(|(t, value)| self.attribute(private, *t) == Some(value.as_slice()))
+    }
+
+    pub fn pkcs11_mechanism(&self) -> Pkcs11Mechanism {
+        match self.mechanism {
+            CKM_RSA_PKCS => Pkcs11Mechanism::RsaPkcs,
+            CKM_ECDSA => Pkcs11Mechanism::Ecdsa,
+            _ => Pkcs11Mechanism::Eddsa,
+        }
+    }
+}
+
+/// A `CK_ULONG` attribute value, in native byte order as callers pass them in templates.
+pub fn ulong(value: CK_ULONG) -> Vec<u8> {
+    value.to_ne_bytes().to_vec()
+}
+
+fn der_octet_string(data: &[u8]) -> Vec<u8> {
+    // The points of the supported curves are shorter than 128 bytes, so the short length form suffices
+    [&[0x04, data.len() as u8][..], data].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_object() {
+        let key = Pkcs11Key {
+            name: "work".to_string(),
+            public_key:
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILtogkJa9JaNdBnZXdDCt2K34JQgxvYMitRvRKo6/q9F"
+                    .to_string(),
+        };
+        let object = KeyObject::new(&key).unwrap();
+        assert_eq!(object.mechanism, CKM_EDDSA);
+        assert_eq!(object.attribute(false, CKA_LABEL), Some(&b"work"[..]));
+        assert_eq!(object.attribute(true, CKA_EC_POINT), None);
+        assert_eq!(
+            object.attribute(false, CKA_EC_POINT).unwrap()[..2],
+            [0x04, 32]
+        );
+        assert_eq!(
+            object.attribute(false, CKA_ID),
+            object.attribute(true, CKA_ID)
+        );
+
+        assert!(object.matches(
+            true,
+            &[
+                (CKA_CLASS, ulong(CKO_PRIVATE_KEY)),
+                (CKA_SIGN, vec![CK_TRUE])
+            ]
+        ));
+        assert!(!object.matches(false, &[(CKA_CLASS, ulong(CKO_PRIVATE_KEY))]));
+    }
+}
@@ -0,0 +1,286 @@
+//! The subset of the PKCS#11 v2.40 types and constants the module uses, named as in `pkcs11t.h` and `pkcs11f.h`.
+//! The structures are packed on Windows, as the specification requires there.
+
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use std::os::raw::{c_ulong, c_void};
+
+pub type CK_BYTE = u8;
+pub type CK_BBOOL = u8;
+pub type CK_ULONG = c_ulong;
+pub type CK_FLAGS = CK_ULONG;
+pub type CK_RV = CK_ULONG;
+pub type CK_SLOT_ID = CK_ULONG;
+pub type CK_SESSION_HANDLE = CK_ULONG;
+pub type CK_OBJECT_HANDLE = CK_ULONG;
+pub type CK_OBJECT_CLASS = CK_ULONG;
+pub type CK_KEY_TYPE = CK_ULONG;
+pub type CK_ATTRIBUTE_TYPE = CK_ULONG;
+pub type CK_MECHANISM_TYPE = CK_ULONG;
+pub type CK_USER_TYPE = CK_ULONG;
+pub type CK_STATE = CK_ULONG;
+pub type CK_NOTIFY =
+    Option<unsafe extern "C" fn(CK_SESSION_HANDLE, CK_ULONG, *mut c_void) -> CK_RV>;
+
+pub const CK_TRUE: CK_BBOOL = 1;
+pub const CK_FALSE: CK_BBOOL = 0;
+pub const CK_UNAVAILABLE_INFORMATION: CK_ULONG = !0;
+
+pub const CKR_OK: CK_RV = 0x0;
+pub const CKR_HOST_MEMORY: CK_RV = 0x2;
+pub const CKR_SLOT_ID_INVALID: CK_RV = 0x3;
+pub const CKR_GENERAL_ERROR: CK_RV = 0x5;
+pub const CKR_FUNCTION_FAILED: CK_RV = 0x6;
+pub const CKR_ARGUMENTS_BAD: CK_RV = 0x7;
+pub const CKR_ATTRIBUTE_TYPE_INVALID: CK_RV = 0x12;
+pub const CKR_DEVICE_ERROR: CK_RV = 0x30;
+pub const CKR_FUNCTION_NOT_SUPPORTED: CK_RV = 0x54;
+pub const CKR_KEY_HANDLE_INVALID: CK_RV = 0x60;
+pub const CKR_KEY_TYPE_INCONSISTENT: CK_RV = 0x63;
+pub const CKR_MECHANISM_INVALID: CK_RV = 0x70;
+pub const CKR_OBJECT_HANDLE_INVALID: CK_RV = 0x82;
+pub const CKR_OPERATION_ACTIVE: CK_RV = 0x90;
+pub const CKR_OPERATION_NOT_INITIALIZED: CK_RV = 0x91;
+pub const CKR_SESSION_HANDLE_INVALID: CK_RV = 0xB3;
+pub const CKR_SESSION_PARALLEL_NOT_SUPPORTED: CK_RV = 0xB4;
+pub const CKR_USER_ALREADY_LOGGED_IN: CK_RV = 0x100;
+pub const CKR_USER_NOT_LOGGED_IN: CK_RV = 0x101;
+pub const CKR_BUFFER_TOO_SMALL: CK_RV = 0x150;
+pub const CKR_CRYPTOKI_NOT_INITIALIZED: CK_RV = 0x190;
+pub const CKR_CRYPTOKI_ALREADY_INITIALIZED: CK_RV = 0x191;
+pub const CKR_FUNCTION_REJECTED: CK_RV = 0x200;
+
+pub const CKF_TOKEN_PRESENT: CK_FLAGS = 0x1;
+pub const CKF_RW_SESSION: CK_FLAGS = 0x2;
+pub const CKF_SERIAL_SESSION: CK_FLAGS = 0x4;
+pub const CKF_TOKEN_INITIALIZED: CK_FLAGS = 0x400;
+pub const CKF_SIGN: CK_FLAGS = 0x800;
+
+pub const CKS_RO_PUBLIC_SESSION: CK_STATE = 0;
+
+pub const CKO_PUBLIC_KEY: CK_OBJECT_CLASS = 2;
+pub const CKO_PRIVATE_KEY: CK_OBJECT_CLASS = 3;
+
+pub const CKK_RSA: CK_KEY_TYPE = 0x0;
+pub const CKK_EC: CK_KEY_TYPE = 0x3;
+pub const CKK_EC_EDWARDS: CK_KEY_TYPE = 0x40;
+
+pub const CKA_CLASS: CK_ATTRIBUTE_TYPE = 0x0;
+pub const CKA_TOKEN: CK_ATTRIBUTE_TYPE = 0x1;
+pub const CKA_PRIVATE: CK_ATTRIBUTE_TYPE = 0x2;
+pub const CKA_LABEL: CK_ATTRIBUTE_TYPE = 0x3;
+pub const CKA_KEY_TYPE: CK_ATTRIBUTE_TYPE = 0x100;
+pub const CKA_ID: CK_ATTRIBUTE_TYPE = 0x102;
+pub const CKA_SENSITIVE: CK_ATTRIBUTE_TYPE = 0x103;
+pub const CKA_ENCRYPT: CK_ATTRIBUTE_TYPE = 0x104;
+pub const CKA_DECRYPT: CK_ATTRIBUTE_TYPE = 0x105;
+pub const CKA_WRAP: CK_ATTRIBUTE_TYPE = 0x106;
+pub const CKA_UNWRAP: CK_ATTRIBUTE_TYPE = 0x107;
+pub const CKA_SIGN: CK_ATTRIBUTE_TYPE = 0x108;
+pub const CKA_SIGN_RECOVER: CK_ATTRIBUTE_TYPE = 0x109;
+pub const CKA_VERIFY: CK_ATTRIBUTE_TYPE = 0x10A;
+pub const CKA_DERIVE: CK_ATTRIBUTE_TYPE = 0x10C;
+pub const CKA_MODULUS: CK_ATTRIBUTE_TYPE = 0x120;
+pub const CKA_MODULUS_BITS: CK_ATTRIBUTE_TYPE = 0x121;
+pub const CKA_PUBLIC_EXPONENT: CK_ATTRIBUTE_TYPE = 0x122;
+pub const CKA_EXTRACTABLE: CK_ATTRIBUTE_TYPE = 0x162;
+pub const CKA_LOCAL: CK_ATTRIBUTE_TYPE = 0x163;
+pub const CKA_NEVER_EXTRACTABLE: CK_ATTRIBUTE_TYPE = 0x164;
+pub const CKA_ALWAYS_SENSITIVE: CK_ATTRIBUTE_TYPE = 0x165;
+pub const CKA_MODIFIABLE: CK_ATTRIBUTE_TYPE = 0x170;
+pub const CKA_EC_PARAMS: CK_ATTRIBUTE_TYPE = 0x180;
+pub const CKA_EC_POINT: CK_ATTRIBUTE_TYPE = 0x181;
+pub const CKA_ALWAYS_AUTHENTICATE: CK_ATTRIBUTE_TYPE = 0x202;
+
+pub const CKM_RSA_PKCS: CK_MECHANISM_TYPE = 0x1;
+pub const CKM_ECDSA: CK_MECHANISM_TYPE = 0x1041;
+pub const CKM_EDDSA: CK_MECHANISM_TYPE = 0x1057;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CK_VERSION {
+    pub major: CK_BYTE,
+    pub minor: CK_BYTE,
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_INFO {
+    pub cryptokiVersion: CK_VERSION,
+    pub manufacturerID: [CK_BYTE; 32],
+    pub flags: CK_FLAGS,
+    pub libraryDescription: [CK_BYTE; 32],
+    pub libraryVersion: CK_VERSION,
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_SLOT_INFO {
+    pub slotDescription: [CK_BYTE; 64],
+    pub manufacturerID: [CK_BYTE; 32],
+    pub flags: CK_FLAGS,
+    pub hardwareVersion: CK_VERSION,
+    pub firmwareVersion: CK_VERSION,
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_TOKEN_INFO {
+    pub label: [CK_BYTE; 32],
+    pub manufacturerID: [CK_BYTE; 32],
+    pub model: [CK_BYTE; 16],
+    pub serialNumber: [CK_BYTE; 16],
+    pub flags: CK_FLAGS,
+    pub ulMaxSessionCount: CK_ULONG,
+    pub ulSessionCount: CK_ULONG,
+    pub ulMaxRwSessionCount: CK_ULONG,
+    pub ulRwSessionCount: CK_ULONG,
+    pub ulMaxPinLen: CK_ULONG,
+    pub ulMinPinLen: CK_ULONG,
+    pub ulTotalPublicMemory: CK_ULONG,
+    pub ulFreePublicMemory: CK_ULONG,
+    pub ulTotalPrivateMemory: CK_ULONG,
+    pub ulFreePrivateMemory: CK_ULONG,
+    pub hardwareVersion: CK_VERSION,
+    pub firmwareVersion: CK_VERSION,
+    pub utcTime: [CK_BYTE; 16],
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_SESSION_INFO {
+    pub slotID: CK_SLOT_ID,
+    pub state: CK_STATE,
+    pub flags: CK_FLAGS,
+    pub ulDeviceError: CK_ULONG,
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_ATTRIBUTE {
+    pub r#type: CK_ATTRIBUTE_TYPE,
+    pub pValue: *mut c_void,
+    pub ulValueLen: CK_ULONG,
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_MECHANISM {
+    pub mechanism: CK_MECHANISM_TYPE,
+    pub pParameter: *mut c_void,
+    pub ulParameterLen: CK_ULONG,
+}
+
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_MECHANISM_INFO {
+    pub ulMinKeySize: CK_ULONG,
+    pub ulMaxKeySize: CK_ULONG,
+    pub flags: CK_FLAGS,
+}
+
+/// A function of the list the module does not implement. They all return `CKR_FUNCTION_NOT_SUPPORTED` without
+/// touching their arguments, so a single function without parameters stands in for all of them.
+pub type CK_UNSUPPORTED = unsafe extern "C" fn() -> CK_RV;
+
+/// `CK_FUNCTION_LIST`, with the slots in the order of `pkcs11f.h`.
+#[repr(C)]
+#[cfg_attr(windows, repr(packed))]
+pub struct CK_FUNCTION_LIST {
+    pub version: CK_VERSION,
+    pub C_Initialize: unsafe extern "C" fn(*mut c_void) -> CK_RV,
+    pub C_Finalize: unsafe extern "C" fn(*mut c_void) -> CK_RV,
+    pub C_GetInfo: unsafe extern "C" fn(*mut CK_INFO) -> CK_RV,
+    pub C_GetFunctionList: unsafe extern "C" fn(*mut *const CK_FUNCTION_LIST) -> CK_RV,
+    pub C_GetSlotList: unsafe extern "C" fn(CK_BBOOL, *mut CK_SLOT_ID, *mut CK_ULONG) -> CK_RV,
+    pub C_GetSlotInfo: unsafe extern "C" fn(CK_SLOT_ID, *mut CK_SLOT_INFO) -> CK_RV,
+    pub C_GetTokenInfo: unsafe extern "C" fn(CK_SLOT_ID, *mut CK_TOKEN_INFO) -> CK_RV,
+    pub C_GetMechanismList:
+        unsafe extern "C" fn(CK_SLOT_ID, *mut CK_MECHANISM_TYPE, *mut CK_ULONG) -> CK_RV,
+    pub C_GetMechanismInfo:
+        unsafe extern "C" fn(CK_SLOT_ID, CK_MECHANISM_TYPE, *mut CK_MECHANISM_INFO) -> CK_RV,
+    pub C_InitToken: CK_UNSUPPORTED,
+    pub C_InitPIN: CK_UNSUPPORTED,
+    pub C_SetPIN: CK_UNSUPPORTED,
+    pub C_OpenSession: unsafe extern "C" fn(
+        CK_SLOT_ID,
+        CK_FLAGS,
+        *mut c_void,
+        CK_NOTIFY,
+        *mut CK_SESSION_HANDLE,
+    ) -> CK_RV,
+    pub C_CloseSession: unsafe extern "C" fn(CK_SESSION_HANDLE) -> CK_RV,
+    pub C_CloseAllSessions: unsafe extern "C" fn(CK_SLOT_ID) -> CK_RV,
+    pub C_GetSessionInfo: unsafe extern "C" fn(CK_SESSION_HANDLE, *mut CK_SESSION_INFO) -> CK_RV,
+    pub C_GetOperationState: CK_UNSUPPORTED,
+    pub C_SetOperationState: CK_UNSUPPORTED,
+    pub C_Login:
+        unsafe extern "C" fn(CK_SESSION_HANDLE, CK_USER_TYPE, *mut CK_BYTE, CK_ULONG) -> CK_RV,
+    pub C_Logout: unsafe extern "C" fn(CK_SESSION_HANDLE) -> CK_RV,
+    pub C_CreateObject: CK_UNSUPPORTED,
+    pub C_CopyObject: CK_UNSUPPORTED,
+    pub C_DestroyObject: CK_UNSUPPORTED,
+    pub C_GetObjectSize: CK_UNSUPPORTED,
+    pub C_GetAttributeValue: unsafe extern "C" fn(
+        CK_SESSION_HANDLE,
+        CK_OBJECT_HANDLE,
+        *mut CK_ATTRIBUTE,
+        CK_ULONG,
+    ) -> CK_RV,
+    pub C_SetAttributeValue: CK_UNSUPPORTED,
+    pub C_FindObjectsInit:
+        unsafe extern "C" fn(CK_SESSION_HANDLE, *mut CK_ATTRIBUTE, CK_ULONG) -> CK_RV,
+    pub C_FindObjects: unsafe extern "C" fn(
+        CK_SESSION_HANDLE,
+        *mut CK_OBJECT_HANDLE,
+        CK_ULONG,
+        *mut CK_ULONG,
+    ) -> CK_RV,
+    pub C_FindObjectsFinal: unsafe extern "C" fn(CK_SESSION_HANDLE) -> CK_RV,
+    pub C_EncryptInit: CK_UNSUPPORTED,
+    pub C_Encrypt: CK_UNSUPPORTED,
+    pub C_EncryptUpdate: CK_UNSUPPORTED,
+    pub C_EncryptFinal: CK_UNSUPPORTED,
+    pub C_DecryptInit: CK_UNSUPPORTED,
+    pub C_Decrypt: CK_UNSUPPORTED,
+    pub C_DecryptUpdate: CK_UNSUPPORTED,
+    pub C_DecryptFinal: CK_UNSUPPORTED,
+    pub C_DigestInit: CK_UNSUPPORTED,
+    pub C_Digest: CK_UNSUPPORTED,
+    pub C_DigestUpdate: CK_UNSUPPORTED,
+    pub C_DigestKey: CK_UNSUPPORTED,
+    pub C_DigestFinal: CK_UNSUPPORTED,
+    pub C_SignInit:
+        unsafe extern "C" fn(CK_SESSION_HANDLE, *mut CK_MECHANISM, CK_OBJECT_HANDLE) -> CK_RV,
+    pub C_Sign: unsafe extern "C" fn(
+        CK_SESSION_HANDLE,
+        *mut CK_BYTE,
+        CK_ULONG,
+        *mut CK_BYTE,
+        *mut CK_ULONG,
+    ) -> CK_RV,
+    pub C_SignUpdate: CK_UNSUPPORTED,
+    pub C_SignFinal: CK_UNSUPPORTED,
+    pub C_SignRecoverInit: CK_UNSUPPORTED,
+    pub C_SignRecover: CK_UNSUPPORTED,
+    pub C_VerifyInit: CK_UNSUPPORTED,
+    pub C_Verify: CK_UNSUPPORTED,
+    pub C_VerifyUpdate: CK_UNSUPPORTED,
+    pub C_VerifyFinal: CK_UNSUPPORTED,
+    pub C_VerifyRecoverInit: CK_UNSUPPORTED,
+    pub C_VerifyRecover: CK_UNSUPPORTED,
+    pub C_DigestEncryptUpdate: CK_UNSUPPORTED,
+    pub C_DecryptDigestUpdate: CK_UNSUPPORTED,
+    pub C_SignEncryptUpdate: CK_UNSUPPORTED,
+    pub C_DecryptVerifyUpdate: CK_UNSUPPORTED,
+    pub C_GenerateKey: CK_UNSUPPORTED,
+    pub C_GenerateKeyPair: CK_UNSUPPORTED,
+    pub C_WrapKey: CK_UNSUPPORTED,
+    pub C_UnwrapKey: CK_UNSUPPORTED,
+    pub C_DeriveKey: CK_UNSUPPORTED,
+    pub C_SeedRandom: CK_UNSUPPORTED,
+    pub C_GenerateRandom: CK_UNSUPPORTED,
+    pub C_GetFunctionStatus: CK_UNSUPPORTED,
+    pub C_CancelFunction: CK_UNSUPPORTED,
+    pub C_WaitForSlotEvent: CK_UNSUPPORTED,
+}
@@ -1,9 +1,16 @@
 use std::path::Path;
 
-use desktop_core::ipc::{MESSAGE_CHANNEL_BUFFER, NATIVE_MESSAGING_BUFFER_SIZE};
+use desktop_core::ipc::{
+    client::ReconnectOptions,
+    noise::{self, NoiseKeys, Role},
+    protocol::Command,
+    APP_ENDPOINT, BROWSER_CHANNEL, MAX_MESSAGE_SIZE, MESSAGE_CHANNEL_BUFFER,
+    NATIVE_MESSAGING_BUFFER_SIZE,
+};
 use futures::{FutureExt, SinkExt, StreamExt};
 use log::*;
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio::io::AsyncWrite;
+use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -38,6 +45,38 @@ fn init_logging(log_path: &Path, console_level: LevelFilter, file_level: LevelFi
     }
 }
 
+/// The codec writing the messages to the browser, which only accepts messages up to NATIVE_MESSAGING_BUFFER_SIZE.
+fn browser_writer<W: AsyncWrite>(writer: W) -> FramedWrite<W, LengthDelimitedCodec> {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(NATIVE_MESSAGING_BUFFER_SIZE)
+        .native_endian()
+        .new_write(writer)
+}
+
+/// The message to pass on to the browser for `msg` from the desktop app. A message too large for the browser can't
+/// be split, as the browser has no way to put it back together. It is replaced with a `messageTooLarge` command
+/// carrying the `appId` and `messageId` of the message, so that the request it answers fails instead of timing out.
+fn browser_message(msg: String) -> String {
+    if msg.len() <= NATIVE_MESSAGING_BUFFER_SIZE {
+        return msg;
+    }
+
+    error!(
+        "Dropped a message of {} bytes, the browser accepts at most {} bytes",
+        msg.len(),
+        NATIVE_MESSAGING_BUFFER_SIZE
+    );
+    let mut reply = serde_json::json!({ "command": "messageTooLarge" });
+    if let Ok(serde_json::Value::Object(message)) = serde_json::from_str(&msg) {
+        for key in ["appId", "messageId"] {
+            if let Some(value) = message.get(key) {
+                reply[key] = value.clone();
+            }
+        }
+    }
+    reply.to_string()
+}
+
 /// Bitwarden IPC Proxy.
 ///
 /// This proxy allows browser extensions to communicate with a desktop application using Native
@@ -55,7 +94,7 @@ async fn main() {
     #[cfg(target_os = "windows")]
     let should_foreground = windows::allow_foreground();
 
-    let sock_path = desktop_core::ipc::path("bitwarden");
+    let sock_path = desktop_core::ipc::path(APP_ENDPOINT);
 
     let log_path = {
         let mut path = sock_path.clone();
@@ -92,20 +131,40 @@ async fn main() {
     let (in_send, in_recv) = tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
     let (out_send, mut out_recv) = tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
 
+    // The app provisions the key file when it installs the manifests that point the browsers to the proxy
+    let key_path = noise::key_path(BROWSER_CHANNEL, Role::Proxy);
+    let keys = match key_path.exists() {
+        true => match NoiseKeys::load(&key_path) {
+            Ok(keys) => Some(keys),
+            Err(e) => {
+                error!("Could not load the IPC keys: {}", e);
+                std::process::exit(1);
+            }
+        },
+        false => None,
+    };
+
+    // Give the app a few seconds to start listening, or to restart, before letting the browser know it's not running
+    let options = ReconnectOptions {
+        channel: Some(BROWSER_CHANNEL.to_owned()),
+        max_attempts: Some(6),
+        ..Default::default()
+    };
     let mut handle = tokio::spawn(
-        desktop_core::ipc::client::connect(sock_path, out_send, in_recv)
-            .map(|r| r.map_err(|e| e.to_string())),
+        desktop_core::ipc::client::connect_reconnecting(
+            sock_path, out_send, in_recv, keys, options,
+        )
+        .map(|r| r.map_err(|e| e.to_string())),
     );
 
     // Create a new codec for reading and writing messages from stdin/stdout.
+    // The browser sends messages larger than a single IPC frame, which are split into fragments on the way to the app,
+    // but only accepts messages up to NATIVE_MESSAGING_BUFFER_SIZE in return, see `browser_message`.
     let mut stdin = LengthDelimitedCodec::builder()
-        .max_frame_length(NATIVE_MESSAGING_BUFFER_SIZE)
+        .max_frame_length(MAX_MESSAGE_SIZE)
         .native_endian()
         .new_read(tokio::io::stdin());
-    let mut stdout = LengthDelimitedCodec::builder()
-        .max_frame_length(NATIVE_MESSAGING_BUFFER_SIZE)
-        .native_endian()
-        .new_write(tokio::io::stdout());
+    let mut stdout = browser_writer(tokio::io::stdout());
 
     loop {
         tokio::select! {
@@ -137,7 +196,14 @@ async fn main() {
                 match msg {
                     Some(msg) => {
                         debug!("OUT: {}", msg);
-                        stdout.send(msg.into()).await.unwrap();
+                        // A desktop app that is only slow to answer still answers the heartbeats, and is not disconnected
+                        if let Ok(Command::Disconnected { reason: Some(reason) }) = Command::from_json(&msg) {
+                            warn!("Lost the connection to the desktop app: {}", reason);
+                        }
+                        if let Err(e) = stdout.send(browser_message(msg).into()).await {
+                            error!("Error writing to the browser: {}", e);
+                            std::process::exit(1);
+                        }
                     }
                     None => {
                         info!("Channel closed, exiting.");
@@ -171,3 +237,74 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use desktop_core::ipc::server::{MessageType, Server};
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+
+    async fn read(
+        browser: &mut FramedRead<DuplexStream, LengthDelimitedCodec>,
+    ) -> serde_json::Value {
+        let frame = browser.next().await.unwrap().unwrap();
+        serde_json::from_slice(&frame).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_large_message() {
+        let path = std::env::temp_dir().join(format!("proxy-large-{}.sock", std::process::id()));
+        let (server_send, mut server_recv) = tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let server = Server::start(&path, server_send).unwrap();
+
+        let (out_send, mut out_recv) = tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (_in_send, in_recv) = tokio::sync::mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let client_path = path.clone();
+        tokio::spawn(async move {
+            desktop_core::ipc::client::connect(client_path, out_send, in_recv)
+                .await
+                .is_ok()
+        });
+        assert!(matches!(
+            server_recv.recv().await.unwrap().kind,
+            MessageType::Connected
+        ));
+
+        let large = serde_json::json!({
+            "appId": "app",
+            "messageId": 7,
+            "message": "a".repeat(2 * NATIVE_MESSAGING_BUFFER_SIZE),
+        });
+        let small = r#"{"appId":"app","messageId":8,"message":"small"}"#;
+        server.broadcast(large.to_string()).await.unwrap();
+        server.broadcast(small.to_owned()).await.unwrap();
+
+        // The proxy writes to the browser, which reads the frames on the other end of stdout
+        let (proxy_end, browser_end) = tokio::io::duplex(64 * 1024);
+        let mut stdout = browser_writer(proxy_end);
+        let mut browser = LengthDelimitedCodec::builder()
+            .native_endian()
+            .new_read(browser_end);
+        let forward = tokio::spawn(async move {
+            for _ in 0..3 {
+                let msg = out_recv.recv().await.unwrap();
+                stdout.send(browser_message(msg).into()).await.unwrap();
+            }
+        });
+
+        assert_eq!(
+            read(&mut browser).await,
+            serde_json::json!({ "command": "connected" })
+        );
+        assert_eq!(
+            read(&mut browser).await,
+            serde_json::json!({ "command": "messageTooLarge", "appId": "app", "messageId": 7 })
+        );
+        assert_eq!(read(&mut browser).await["message"], "small");
+        forward.await.unwrap();
+
+        server.stop();
+    }
+}
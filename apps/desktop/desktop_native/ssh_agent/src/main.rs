@@ -0,0 +1,438 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use desktop_core::{
+    ipc::{
+        self,
+        noise::{self, NoiseKeys, Role},
+        MESSAGE_CHANNEL_BUFFER,
+    },
+    ssh_agent::{
+        daemon::{DaemonCommand, DaemonEvent, IPC_NAME},
+        gpg::GpgOperation,
+        BitwardenDesktopAgent, BitwardenSshKey, SshAgentStatus, SshAgentUIRequest,
+    },
+};
+use tokio::sync::{broadcast, mpsc};
+
+mod prompt;
+
+const USAGE: &str =
+    "Usage: bitwarden-ssh-agent [-D | --foreground] [--log-file <path>] [--take-over]";
+
+/// How long to wait before connecting to the app again, after it was closed or was not running.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bitwarden headless SSH agent.
+///
+/// Runs the SSH agent outside of the desktop app, for example on a jump host or in a window manager session that
+/// should keep the agent across app restarts. The keys and the approvals come from the desktop app whenever it is
+/// running, over the `ssh-agent` channel of its IPC socket. While the app is not running, the daemon keeps the keys it
+/// was last sent, and asks for approval in the terminal it runs in, or with polkit on Linux when it runs in the
+/// background.
+///
+/// Like `ssh-agent`, the daemon detaches from the terminal once it is listening, and only prints the socket path in
+/// the format of `ssh-agent` to stdout, so `eval $(bitwarden-ssh-agent)` sets `SSH_AUTH_SOCK`. Its log is discarded,
+/// or written to the `--log-file`. With `--foreground` it stays attached, logs to stderr, and can prompt in the
+/// terminal. SIGINT and SIGTERM stop it.
+fn main() {
+    let result = Options::parse(std::env::args().skip(1)).and_then(|options| {
+        let Some(options) = options else {
+            println!("{USAGE}");
+            return Ok(());
+        };
+        if options.foreground {
+            run(options)
+        } else {
+            detach(options)
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("bitwarden-ssh-agent: {e}");
+        std::process::exit(1);
+    }
+}
+
+struct Options {
+    take_over: bool,
+    foreground: bool,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    log_file: Option<PathBuf>,
+}
+
+impl Options {
+    /// Returns `None` if the usage was requested.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>> {
+        let mut options = Options {
+            take_over: false,
+            foreground: false,
+            log_file: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--take-over" => options.take_over = true,
+                "-D" | "--foreground" => options.foreground = true,
+                "--log-file" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--log-file requires a path\n{USAGE}"))?;
+                    options.log_file = Some(PathBuf::from(path));
+                }
+                "-h" | "--help" => return Ok(None),
+                _ => return Err(anyhow!("Unexpected argument {arg}\n{USAGE}")),
+            }
+        }
+        Ok(Some(options))
+    }
+}
+
+/// Run the daemon again in a new session without a terminal, and print what it prints to stdout once it is
+/// listening.
+#[cfg(unix)]
+fn detach(options: Options) -> Result<()> {
+    use std::{io::Read, os::unix::process::CommandExt, process::Stdio};
+
+    let log = match &options.log_file {
+        Some(path) => Stdio::from(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow!("Could not open {}: {e}", path.display()))?,
+        ),
+        None => Stdio::null(),
+    };
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .arg("--foreground")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(log);
+    if options.take_over {
+        command.arg("--take-over");
+    }
+    // SAFETY: setsid is async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+
+    // The daemon closes stdout once it printed the socket path
+    let mut output = String::new();
+    child
+        .stdout
+        .take()
+        .expect("stdout is piped")
+        .read_to_string(&mut output)?;
+    if output.is_empty() {
+        let status = child.wait()?;
+        return Err(anyhow!("The agent did not start: {status}"));
+    }
+    print!("{output}");
+    Ok(())
+}
+
+/// There is no `eval $(...)` to detach for outside of Unix shells, so the daemon stays in the foreground.
+#[cfg(not(unix))]
+fn detach(options: Options) -> Result<()> {
+    run(options)
+}
+
+/// Keep stdout for what `eval` should run, and send everything else that is printed to stdout, such as the logs of
+/// the agent, to stderr instead.
+#[cfg(unix)]
+fn take_stdout() -> Result<Box<dyn Write>> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: The duplicate is owned by the returned file only, and both descriptors stay open
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd == -1 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Box::new(std::fs::File::from_raw_fd(fd)))
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> Result<Box<dyn Write>> {
+    Ok(Box::new(std::io::stdout()))
+}
+
+/// Resolves once the daemon is asked to stop, with SIGINT or SIGTERM.
+async fn terminated() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run(options: Options) -> Result<()> {
+    let take_over = options.take_over;
+    let mut stdout = take_stdout()?;
+    let (request_tx, request_rx) = mpsc::channel::<SshAgentUIRequest>(32);
+    let (response_tx, response_rx) = broadcast::channel::<(u32, bool)>(32);
+    let agent = BitwardenDesktopAgent::start_server(
+        request_tx,
+        Arc::new(tokio::sync::Mutex::new(response_rx)),
+        take_over,
+    )
+    .await?;
+    let SshAgentStatus::Listening { path } = agent.status() else {
+        return Err(anyhow!("The agent is not listening"));
+    };
+    writeln!(stdout, "SSH_AUTH_SOCK={path}; export SSH_AUTH_SOCK;")?;
+    // Closing it lets `eval` and a detaching parent stop waiting
+    drop(stdout);
+
+    let app = Arc::new(App::default());
+    tokio::select! {
+        result = terminated() => result?,
+        _ = connect_to_app(agent.clone(), app.clone(), response_tx.clone(), path) => {}
+        _ = serve_approvals(agent.clone(), app, response_tx, request_rx) => {}
+    }
+    agent.stop();
+    Ok(())
+}
+
+/// The connection to the desktop app.
+#[derive(Default)]
+struct App {
+    /// Forwards messages to the app while it is connected.
+    sender: Mutex<Option<mpsc::Sender<String>>>,
+    /// The approval requests forwarded to the app that were not answered yet.
+    pending: Mutex<HashSet<u32>>,
+}
+
+impl App {
+    fn sender(&self) -> Option<mpsc::Sender<String>> {
+        self.sender.lock().expect("Mutex is not poisoned").clone()
+    }
+}
+
+/// Keep connecting to the app, and apply the commands it sends while it is connected.
+async fn connect_to_app(
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    app: Arc<App>,
+    responses: broadcast::Sender<(u32, bool)>,
+    socket_path: String,
+) {
+    // Only the first failed attempt is logged, the app not running is the normal case
+    let mut log_failure = true;
+    loop {
+        let (to_app_send, to_app_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        let (from_app_send, mut from_app_recv) = mpsc::channel(MESSAGE_CHANNEL_BUFFER);
+        // The app provisions the keys when the SSH agent is enabled. Only the app holds the other half, so no other
+        // process can pose as it.
+        let keys = match NoiseKeys::load(&noise::key_path(IPC_NAME, Role::Proxy)) {
+            Ok(keys) => keys,
+            Err(e) => {
+                if log_failure {
+                    eprintln!(
+                        "[SSH Agent Daemon] Could not load the keys to connect to the app: {e}"
+                    );
+                    log_failure = false;
+                }
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+                continue;
+            }
+        };
+        let connection = ipc::client::connect_on_channel(
+            ipc::path(ipc::APP_ENDPOINT),
+            IPC_NAME,
+            from_app_send,
+            to_app_recv,
+            Some(keys),
+        );
+        let commands = async {
+            while let Some(message) = from_app_recv.recv().await {
+                let command = match serde_json::from_str::<DaemonCommand>(&message) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        eprintln!("[SSH Agent Daemon] Invalid message from the app: {e}");
+                        continue;
+                    }
+                };
+                if command == DaemonCommand::Connected {
+                    eprintln!("[SSH Agent Daemon] Connected to the app");
+                    log_failure = true;
+                    *app.sender.lock().expect("Mutex is not poisoned") = Some(to_app_send.clone());
+                    let ready = DaemonEvent::Ready {
+                        socket_path: socket_path.clone(),
+                    };
+                    let _ = to_app_send
+                        .send(serde_json::to_string(&ready).expect("Events can be serialized"))
+                        .await;
+                    continue;
+                }
+                if let Err(e) = handle_command(&agent, &app, &responses, command) {
+                    eprintln!("[SSH Agent Daemon] Could not apply command from the app: {e}");
+                }
+            }
+        };
+
+        tokio::select! {
+            result = connection => {
+                if let Err(e) = result {
+                    if log_failure {
+                        eprintln!("[SSH Agent Daemon] Could not connect to the app: {e}");
+                        log_failure = false;
+                    }
+                }
+            }
+            _ = commands => {}
+        }
+
+        if app
+            .sender
+            .lock()
+            .expect("Mutex is not poisoned")
+            .take()
+            .is_some()
+        {
+            eprintln!("[SSH Agent Daemon] Disconnected from the app");
+        }
+        // Nobody is left to answer the requests that were shown in the app
+        for request_id in app.pending.lock().expect("Mutex is not poisoned").drain() {
+            let _ = responses.send((request_id, false));
+        }
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+fn handle_command(
+    agent: &BitwardenDesktopAgent<BitwardenSshKey>,
+    app: &App,
+    responses: &broadcast::Sender<(u32, bool)>,
+    command: DaemonCommand,
+) -> Result<()> {
+    let mut agent = agent.clone();
+    match command {
+        DaemonCommand::Connected | DaemonCommand::Disconnected => Ok(()),
+        DaemonCommand::SetKeys { account_id, keys } => {
+            let keys = keys
+                .into_iter()
+                .map(|key| (key.private_key, key.name, key.cipher_id))
+                .collect();
+            match account_id {
+                Some(account_id) => agent.set_account_keys(&account_id, keys),
+                None => agent.set_keys(keys),
+            }
+        }
+        DaemonCommand::Lock { account_id } => match account_id {
+            Some(account_id) => agent.lock_account(&account_id),
+            None => agent.lock(),
+        },
+        DaemonCommand::ClearKeys { account_id } => match account_id {
+            Some(account_id) => agent.clear_account_keys(&account_id),
+            None => agent.clear_keys(),
+        },
+        DaemonCommand::ApprovalResponse {
+            request_id,
+            approved,
+        } => {
+            // Only requests that were shown in the app can be answered by it
+            if app
+                .pending
+                .lock()
+                .expect("Mutex is not poisoned")
+                .remove(&request_id)
+            {
+                let _ = responses.send((request_id, approved));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Forward the approval requests of the agent to the app, or prompt locally while it is not connected.
+async fn serve_approvals(
+    agent: BitwardenDesktopAgent<BitwardenSshKey>,
+    app: Arc<App>,
+    responses: broadcast::Sender<(u32, bool)>,
+    mut requests: mpsc::Receiver<SshAgentUIRequest>,
+) {
+    while let Some(request) = requests.recv().await {
+        let request_id = request.request_id;
+        let description = describe(&agent, &request);
+
+        if let Some(sender) = app.sender() {
+            app.pending
+                .lock()
+                .expect("Mutex is not poisoned")
+                .insert(request_id);
+            let event = DaemonEvent::ApprovalRequest(request);
+            if sender
+                .send(serde_json::to_string(&event).expect("Events can be serialized"))
+                .await
+                .is_ok()
+            {
+                continue;
+            }
+            app.pending
+                .lock()
+                .expect("Mutex is not poisoned")
+                .remove(&request_id);
+        }
+
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            let approved = prompt::ask(description).await;
+            let _ = responses.send((request_id, approved));
+        });
+    }
+}
+
+/// Describe a request for the fallback prompts, which unlike the app have nothing but text.
+fn describe(agent: &BitwardenDesktopAgent<BitwardenSshKey>, request: &SshAgentUIRequest) -> String {
+    let key = request
+        .cipher_id
+        .as_deref()
+        .map(|cipher_id| agent.key_name(cipher_id).unwrap_or(cipher_id.to_string()))
+        .unwrap_or_default();
+    let action = if request.is_list {
+        "list the SSH keys".to_string()
+    } else if let Some(certificate) = &request.certificate {
+        format!("issue {} with the CA key {key}", certificate.describe())
+    } else if let Some(operation) = &request.gpg_operation {
+        match operation {
+            GpgOperation::Sign { .. } => format!("sign with the OpenPGP key {key}"),
+            GpgOperation::Decrypt => format!("decrypt with the OpenPGP key {key}"),
+        }
+    } else if request.is_age_decryption {
+        format!("decrypt an age file with the SSH key {key}")
+    } else if let Some(namespace) = &request.namespace {
+        format!("sign a {namespace} signature with the SSH key {key}")
+    } else {
+        format!("sign with the SSH key {key}")
+    };
+    let forwarded = if request.is_forwarding {
+        " over a forwarded connection"
+    } else {
+        ""
+    };
+    format!("{} wants to {action}{forwarded}", request.process_name)
+}
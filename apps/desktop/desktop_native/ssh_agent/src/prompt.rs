@@ -0,0 +1,53 @@
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    sync::Mutex,
+};
+
+/// Only one terminal prompt is shown at a time, so concurrent requests do not interleave.
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+/// Ask the user to approve a request while the app is not connected.
+///
+/// Prompts in the terminal if the daemon runs in one. Otherwise, on Linux, polkit asks the user to authenticate.
+/// Requests are denied if neither is available.
+pub async fn ask(description: String) -> bool {
+    eprintln!("[SSH Agent Daemon] Approval request: {description}");
+
+    if std::io::stdin().is_terminal() {
+        return tokio::task::spawn_blocking(move || ask_terminal(&description))
+            .await
+            .unwrap_or(false);
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use desktop_core::biometric::{Biometric, BiometricTrait};
+
+        match Biometric::prompt(vec![], description).await {
+            Ok(approved) => approved,
+            Err(e) => {
+                eprintln!("[SSH Agent Daemon] polkit prompt failed: {e}");
+                false
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        eprintln!("[SSH Agent Daemon] No prompt is available, denying the request");
+        false
+    }
+}
+
+fn ask_terminal(description: &str) -> bool {
+    let _guard = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stderr = std::io::stderr();
+    let _ = write!(stderr, "{description}. Allow? [y/N] ");
+    let _ = stderr.flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
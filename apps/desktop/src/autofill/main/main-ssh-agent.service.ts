@@ -1,12 +1,16 @@
 // FIXME: Update this file to be type safe and remove this and next line
 // @ts-strict-ignore
-import { ipcMain } from "electron";
+import * as path from "path";
+
+import { app, ipcMain } from "electron";
 import { concatMap, delay, filter, firstValueFrom, from, race, take, timer } from "rxjs";
 
 import { LogService } from "@bitwarden/common/platform/abstractions/log.service";
 import { MessagingService } from "@bitwarden/common/platform/abstractions/messaging.service";
 import { sshagent } from "@bitwarden/desktop-napi";
 
+import { isDev } from "../../utils";
+
 class AgentResponse {
   requestId: number;
   accepted: boolean;
@@ -20,6 +24,11 @@ export class MainSshAgentService {
   private requestResponses: AgentResponse[] = [];
   private request_id = 0;
   private agentState: sshagent.SshAgentState;
+  private askpassServer: sshagent.AskpassServer;
+  private daemonServer: sshagent.SshAgentDaemonServer;
+  // What the daemons are sent when they connect
+  private daemonKeys: { name: string; privateKey: string; cipherId: string }[] | null = null;
+  private daemonLocked = false;
 
   constructor(
     private logService: LogService,
@@ -37,65 +46,137 @@ export class MainSshAgentService {
   init() {
     // handle sign request passing to UI
     sshagent
-      .serve(async (err: Error, sshUiRequest: sshagent.SshUiRequest) => {
-        // clear all old (> SIGN_TIMEOUT) requests
-        this.requestResponses = this.requestResponses.filter(
-          (response) => response.timestamp > new Date(Date.now() - this.SIGN_TIMEOUT),
-        );
-
-        this.request_id += 1;
-        const id_for_this_request = this.request_id;
-        this.messagingService.send("sshagent.signrequest", {
-          cipherId: sshUiRequest.cipherId,
-          isListRequest: sshUiRequest.isList,
-          requestId: id_for_this_request,
-          processName: sshUiRequest.processName,
-          isAgentForwarding: sshUiRequest.isForwarding,
-          namespace: sshUiRequest.namespace,
-        });
+      .serve(async (err: Error, sshUiRequest: sshagent.SshUiRequest) =>
+        this.requestApproval(sshUiRequest),
+      )
+      .then((agentState: sshagent.SshAgentState) => {
+        this.agentState = agentState;
+        this.logService.info("SSH agent started");
 
-        const result = await firstValueFrom(
-          race(
-            from([false]).pipe(delay(this.SIGN_TIMEOUT)),
-
-            //poll for response
-            timer(0, this.REQUEST_POLL_INTERVAL).pipe(
-              concatMap(() => from(this.requestResponses)),
-              filter((response) => response.requestId == id_for_this_request),
-              take(1),
-              concatMap(() => from([true])),
-            ),
-          ),
-        );
-
-        if (!result) {
-          return false;
-        }
+        // serve gpg with the OpenPGP keys of the vault, unless the user runs their own gpg-agent
+        sshagent
+          .startGpgAgent(agentState)
+          .then((socketPath: string) => {
+            this.logService.info("GPG agent started on " + socketPath);
+          })
+          .catch((e) => {
+            this.logService.warning("GPG agent not started: ", e);
+          });
 
-        const response = this.requestResponses.find(
-          (response) => response.requestId == id_for_this_request,
-        );
+        // serve the bitwarden-age helper, which decrypts age files with the SSH keys of the vault
+        sshagent
+          .startAgeServer(agentState)
+          .then(() => {
+            this.logService.info("SSH agent age server started");
+          })
+          .catch((e) => {
+            this.logService.error("SSH agent age server encountered an error: ", e);
+          });
 
-        this.requestResponses = this.requestResponses.filter(
-          (response) => response.requestId != id_for_this_request,
-        );
+        // serve the bitwarden_pkcs11 module loaded by applications that speak PKCS#11
+        sshagent
+          .startPkcs11Server(agentState)
+          .then(() => {
+            this.logService.info("SSH agent PKCS#11 server started");
+          })
+          .catch((e) => {
+            this.logService.error("SSH agent PKCS#11 server encountered an error: ", e);
+          });
+      })
+      .catch((e) => {
+        this.logService.error("SSH agent encountered an error: ", e);
+      });
 
-        return response.accepted;
+    // feed the keys and approvals to the headless agents started with bitwarden-ssh-agent
+    try {
+      sshagent.provisionDaemonKeys();
+    } catch (e) {
+      this.logService.error("Failed to provision the SSH agent daemon keys: ", e);
+    }
+    sshagent.SshAgentDaemonServer.listen(
+      async (err: Error, sshUiRequest: sshagent.SshUiRequest) => this.requestApproval(sshUiRequest),
+      (err: Error, event: sshagent.SshAgentDaemonEvent) => {
+        if (err != null || event.kind !== sshagent.SshAgentDaemonEventKind.Ready) {
+          return;
+        }
+
+        this.logService.info("SSH agent daemon connected, serving on " + event.socketPath);
+        this.updateDaemons().catch((e) => {
+          this.logService.error("Failed to send the SSH keys to the daemon: ", e);
+        });
+      },
+    )
+      .then((daemonServer: sshagent.SshAgentDaemonServer) => {
+        this.daemonServer = daemonServer;
+        this.logService.info("SSH agent daemon server started");
       })
-      .then((agentState: sshagent.SshAgentState) => {
-        this.agentState = agentState;
-        this.logService.info("SSH agent started");
+      .catch((e) => {
+        this.logService.error("SSH agent daemon server encountered an error: ", e);
+      });
+
+    // handle the prompts of the askpass helper, answering only the helper that asked. Only the shipped helper may
+    // show prompts, as their answers can be passphrases. Dev builds don't ship it, so any process is accepted there.
+    const ext = process.platform === "win32" ? ".exe" : "";
+    const askpassExecutables = isDev()
+      ? null
+      : [path.join(path.dirname(app.getPath("exe")), `bitwarden-askpass${ext}`)];
+    sshagent.AskpassServer.listen(
+      (err: Error, event: sshagent.AskpassEvent) => {
+        if (err != null) {
+          this.logService.error("Askpass server error: ", err);
+          return;
+        }
+
+        this.messagingService.send("sshagent.askpass", {
+          requestId: event.requestId,
+          kind: event.kind,
+          promptType: event.promptType,
+          prompt: event.prompt,
+        });
+      },
+      askpassExecutables,
+    )
+      .then((askpassServer: sshagent.AskpassServer) => {
+        this.askpassServer = askpassServer;
+        this.logService.info("SSH askpass server started");
       })
       .catch((e) => {
-        this.logService.error("SSH agent encountered an error: ", e);
+        this.logService.error("SSH askpass server encountered an error: ", e);
       });
 
+    ipcMain.handle(
+      "sshagent.askpassresponse",
+      async (
+        event: any,
+        {
+          requestId,
+          approved,
+          passphrase,
+        }: { requestId: string; approved: boolean; passphrase: string | null },
+      ) => {
+        if (this.askpassServer != null) {
+          await this.askpassServer.respond(requestId, approved, passphrase);
+        }
+      },
+    );
+
     ipcMain.handle(
       "sshagent.setkeys",
       async (event: any, keys: { name: string; privateKey: string; cipherId: string }[]) => {
         if (this.agentState != null && (await sshagent.isRunning(this.agentState))) {
           sshagent.setKeys(this.agentState, keys);
         }
+        this.daemonKeys = keys;
+        this.daemonLocked = false;
+        await this.updateDaemons();
+      },
+    );
+    ipcMain.handle(
+      "sshagent.setgpgkeys",
+      async (event: any, keys: { name: string; privateKey: string; cipherId: string }[]) => {
+        if (this.agentState != null && (await sshagent.isRunning(this.agentState))) {
+          sshagent.setGpgKeys(this.agentState, keys);
+        }
       },
     );
     ipcMain.handle(
@@ -109,12 +190,78 @@ export class MainSshAgentService {
       if (this.agentState != null && (await sshagent.isRunning(this.agentState))) {
         sshagent.lock(this.agentState);
       }
+      this.daemonLocked = true;
+      await this.updateDaemons();
     });
 
     ipcMain.handle("sshagent.clearkeys", async (event: any) => {
       if (this.agentState != null) {
         sshagent.clearKeys(this.agentState);
       }
+      this.daemonKeys = null;
+      this.daemonLocked = false;
+      await this.daemonServer?.clearKeys(null);
     });
   }
+
+  private async updateDaemons() {
+    if (this.daemonServer == null || this.daemonKeys == null) {
+      return;
+    }
+
+    await this.daemonServer.setKeys(null, this.daemonKeys);
+    if (this.daemonLocked) {
+      await this.daemonServer.lock(null);
+    }
+  }
+
+  private async requestApproval(sshUiRequest: sshagent.SshUiRequest): Promise<boolean> {
+    // clear all old (> SIGN_TIMEOUT) requests
+    this.requestResponses = this.requestResponses.filter(
+      (response) => response.timestamp > new Date(Date.now() - this.SIGN_TIMEOUT),
+    );
+
+    this.request_id += 1;
+    const id_for_this_request = this.request_id;
+    this.messagingService.send("sshagent.signrequest", {
+      cipherId: sshUiRequest.cipherId,
+      isListRequest: sshUiRequest.isList,
+      requestId: id_for_this_request,
+      processName: sshUiRequest.processName,
+      isAgentForwarding: sshUiRequest.isForwarding,
+      namespace: sshUiRequest.namespace,
+      accountId: sshUiRequest.accountId,
+      certificate: sshUiRequest.certificate,
+      gpgOperation: sshUiRequest.gpgOperation,
+      isAgeDecryption: sshUiRequest.isAgeDecryption,
+    });
+
+    const result = await firstValueFrom(
+      race(
+        from([false]).pipe(delay(this.SIGN_TIMEOUT)),
+
+        //poll for response
+        timer(0, this.REQUEST_POLL_INTERVAL).pipe(
+          concatMap(() => from(this.requestResponses)),
+          filter((response) => response.requestId == id_for_this_request),
+          take(1),
+          concatMap(() => from([true])),
+        ),
+      ),
+    );
+
+    if (!result) {
+      return false;
+    }
+
+    const response = this.requestResponses.find(
+      (response) => response.requestId == id_for_this_request,
+    );
+
+    this.requestResponses = this.requestResponses.filter(
+      (response) => response.requestId != id_for_this_request,
+    );
+
+    return response.accepted;
+  }
 }
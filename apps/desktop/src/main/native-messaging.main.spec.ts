@@ -0,0 +1,75 @@
+import { mock } from "jest-mock-extended";
+
+import { LogService } from "@bitwarden/common/platform/abstractions/log.service";
+import { ipc } from "@bitwarden/desktop-napi";
+
+import { NativeMessagingMain } from "./native-messaging.main";
+import { WindowMain } from "./window.main";
+
+jest.mock("electron", () => ({
+  ipcMain: { handle: jest.fn(), on: jest.fn() },
+}));
+
+jest.mock("@bitwarden/desktop-napi", () => ({
+  ipc: {
+    provisionKeys: jest.fn(),
+    IpcServer: { listen: jest.fn() },
+    IpcMessageType: {},
+  },
+  windows_registry: {},
+}));
+
+jest.mock("../utils", () => ({
+  isDev: () => true,
+}));
+
+describe("NativeMessagingMain", () => {
+  let nativeMessagingMain: NativeMessagingMain;
+
+  beforeEach(() => {
+    (ipc.IpcServer.listen as jest.Mock).mockResolvedValue({
+      getPath: () => "/tmp/bitwarden.sock",
+      stop: jest.fn(),
+    });
+    nativeMessagingMain = new NativeMessagingMain(
+      mock<LogService>(),
+      mock<WindowMain>(),
+      "/userData",
+      "/app/bitwarden",
+      "/app",
+    );
+  });
+
+  afterEach(() => {
+    jest.clearAllMocks();
+  });
+
+  describe("listen", () => {
+    it("provisions the browser keys before listening on the encrypted channel", async () => {
+      // As on the first start after upgrading with browser integration enabled, without a toggle
+      await nativeMessagingMain.listen();
+
+      expect(ipc.provisionKeys).toHaveBeenCalledWith("browser");
+      expect(ipc.IpcServer.listen).toHaveBeenCalledWith(
+        "browser",
+        expect.any(Function),
+        null,
+        true,
+      );
+      expect((ipc.provisionKeys as jest.Mock).mock.invocationCallOrder[0]).toBeLessThan(
+        (ipc.IpcServer.listen as jest.Mock).mock.invocationCallOrder[0],
+      );
+    });
+
+    it("does not listen if the keys can't be provisioned", async () => {
+      (ipc.provisionKeys as jest.Mock).mockImplementationOnce(() => {
+        throw new Error("Could not create the key directory");
+      });
+
+      await expect(nativeMessagingMain.listen()).rejects.toThrow(
+        "Could not create the key directory",
+      );
+      expect(ipc.IpcServer.listen).not.toHaveBeenCalled();
+    });
+  });
+});
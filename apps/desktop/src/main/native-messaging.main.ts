@@ -78,37 +78,52 @@ export class NativeMessagingMain {
       this.ipcServer.stop();
     }
 
-    this.ipcServer = await ipc.IpcServer.listen("bitwarden", (error, msg) => {
-      switch (msg.kind) {
-        case ipc.IpcMessageType.Connected: {
-          this.connected.push(msg.clientId);
-          this.logService.info("Native messaging client " + msg.clientId + " has connected");
-          break;
-        }
-        case ipc.IpcMessageType.Disconnected: {
-          const index = this.connected.indexOf(msg.clientId);
-          if (index > -1) {
-            this.connected.splice(index, 1);
+    // The proxy connects with the keys of the channel. They are kept once generated, so this only creates them on
+    // the first start, including the first start after upgrading with browser integration already enabled.
+    ipc.provisionKeys("browser");
+
+    // Only the shipped proxy may connect. Dev builds rebuild it while the app runs, so any client is accepted there.
+    const allowedExecutables = isDev() ? null : [this.binaryPath()];
+
+    this.ipcServer = await ipc.IpcServer.listen(
+      "browser",
+      (error, msg) => {
+        switch (msg.kind) {
+          case ipc.IpcMessageType.Connected: {
+            this.connected.push(msg.clientId);
+            this.logService.info(
+              "Native messaging client " + msg.clientId + " has connected:",
+              msg.peer?.executable,
+            );
+            break;
           }
+          case ipc.IpcMessageType.Disconnected: {
+            const index = this.connected.indexOf(msg.clientId);
+            if (index > -1) {
+              this.connected.splice(index, 1);
+            }
 
-          this.logService.info("Native messaging client " + msg.clientId + " has disconnected");
-          break;
-        }
-        case ipc.IpcMessageType.Message:
-          try {
-            const msgJson = JSON.parse(msg.message);
-            this.logService.debug("Native messaging message:", msgJson);
-            this.windowMain.win?.webContents.send("nativeMessaging", msgJson);
-          } catch (e) {
-            this.logService.warning("Error processing message:", e, msg.message);
+            this.logService.info("Native messaging client " + msg.clientId + " has disconnected");
+            break;
           }
-          break;
+          case ipc.IpcMessageType.Message:
+            try {
+              const msgJson = JSON.parse(msg.message);
+              this.logService.debug("Native messaging message:", msgJson);
+              this.windowMain.win?.webContents.send("nativeMessaging", msgJson);
+            } catch (e) {
+              this.logService.warning("Error processing message:", e, msg.message);
+            }
+            break;
 
-        default:
-          this.logService.warning("Unknown message type:", msg.kind, msg.message);
-          break;
-      }
-    });
+          default:
+            this.logService.warning("Unknown message type:", msg.kind, msg.message);
+            break;
+        }
+      },
+      allowedExecutables,
+      true,
+    );
 
     this.logService.info("Native messaging server started at:", this.ipcServer.getPath());
 
@@ -125,7 +140,9 @@ export class NativeMessagingMain {
 
   send(message: object) {
     this.logService.debug("Native messaging reply:", message);
-    this.ipcServer?.send(JSON.stringify(message));
+    this.ipcServer
+      ?.send(JSON.stringify(message))
+      .catch((e) => this.logService.error("Native messaging reply failed:", e));
   }
 
   async generateManifests() {
@@ -14,11 +14,40 @@ import {
   DialogService,
 } from "@bitwarden/components";
 
+/** The certificate a CA key is asked to sign, as sent by the agent. */
+export interface SshCertificateApproval {
+  keyId: string;
+  principals: string[];
+  /** Unix timestamp in seconds. */
+  validAfter: number;
+  /** Unix timestamp in seconds. */
+  validBefore: number;
+  publicKeyFingerprint: string;
+  criticalOptions: Record<string, string>;
+  extensions: Record<string, string>;
+}
+
+/** What the key is used for, when it's not an SSH login or signature. */
+export interface SshRequestUsage {
+  certificate?: SshCertificateApproval;
+  gpgOperation?: { kind: "sign" | "decrypt" };
+  isAgeDecryption?: boolean;
+}
+
 export interface ApproveSshRequestParams {
   cipherName: string;
   applicationName: string;
   isAgentForwarding: boolean;
   action: string;
+  certificate?: SshCertificateApproval & {
+    /** The critical options and extensions as `name=value`, or just `name`, like `ssh-keygen -L`. */
+    criticalOptionList: string[];
+    extensionList: string[];
+  };
+}
+
+function formatOptions(options: Record<string, string>): string[] {
+  return Object.entries(options).map(([name, value]) => (value === "" ? name : `${name}=${value}`));
 }
 
 @Component({
@@ -50,9 +79,17 @@ export class ApproveSshRequestComponent {
     applicationName: string,
     isAgentForwarding: boolean,
     namespace: string,
+    usage: SshRequestUsage = {},
   ) {
     let actioni18nKey = "sshActionLogin";
-    if (namespace === "git") {
+    if (usage.certificate != null) {
+      actioni18nKey = "sshActionIssueCertificate";
+    } else if (usage.gpgOperation != null) {
+      actioni18nKey =
+        usage.gpgOperation.kind === "decrypt" ? "sshActionGpgDecrypt" : "sshActionGpgSign";
+    } else if (usage.isAgeDecryption) {
+      actioni18nKey = "sshActionAgeDecrypt";
+    } else if (namespace === "git") {
       actioni18nKey = "sshActionGitSign";
     } else if (namespace != null && namespace != "") {
       actioni18nKey = "sshActionSign";
@@ -64,6 +101,14 @@ export class ApproveSshRequestComponent {
         applicationName,
         isAgentForwarding,
         action: actioni18nKey,
+        certificate:
+          usage.certificate == null
+            ? undefined
+            : {
+                ...usage.certificate,
+                criticalOptionList: formatOptions(usage.certificate.criticalOptions),
+                extensionList: formatOptions(usage.certificate.extensions),
+              },
       },
     });
   }
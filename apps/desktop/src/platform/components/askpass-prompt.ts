@@ -0,0 +1,65 @@
+import { CommonModule } from "@angular/common";
+import { Component, Inject } from "@angular/core";
+import { FormBuilder, ReactiveFormsModule } from "@angular/forms";
+
+import { JslibModule } from "@bitwarden/angular/jslib.module";
+import {
+  DIALOG_DATA,
+  DialogRef,
+  AsyncActionsModule,
+  ButtonModule,
+  DialogModule,
+  FormFieldModule,
+  IconButtonModule,
+  DialogService,
+} from "@bitwarden/components";
+
+export interface AskpassPromptParams {
+  promptType: "passphrase" | "confirm" | "notify";
+  prompt: string;
+}
+
+export interface AskpassPromptResult {
+  approved: boolean;
+  passphrase: string | null;
+}
+
+@Component({
+  selector: "app-askpass-prompt",
+  templateUrl: "askpass-prompt.html",
+  imports: [
+    DialogModule,
+    CommonModule,
+    JslibModule,
+    ButtonModule,
+    IconButtonModule,
+    ReactiveFormsModule,
+    AsyncActionsModule,
+    FormFieldModule,
+  ],
+})
+export class AskpassPromptComponent {
+  askpassPromptForm = this.formBuilder.group({
+    passphrase: [""],
+  });
+
+  constructor(
+    @Inject(DIALOG_DATA) protected params: AskpassPromptParams,
+    private dialogRef: DialogRef<AskpassPromptResult>,
+    private formBuilder: FormBuilder,
+  ) {}
+
+  static open(dialogService: DialogService, params: AskpassPromptParams) {
+    return dialogService.open<AskpassPromptResult, AskpassPromptParams>(AskpassPromptComponent, {
+      data: params,
+    });
+  }
+
+  submit = async () => {
+    this.dialogRef.close({
+      approved: true,
+      passphrase:
+        this.params.promptType === "passphrase" ? this.askpassPromptForm.value.passphrase : null,
+    });
+  };
+}
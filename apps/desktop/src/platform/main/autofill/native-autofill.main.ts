@@ -40,7 +40,9 @@ export class NativeAutofillMain {
       (error, clientId, sequenceNumber, request) => {
         if (error) {
           this.logService.error("autofill.IpcServer.registration", error);
-          this.ipcServer.completeError(clientId, sequenceNumber, String(error));
+          this.ipcServer
+            .completeError(clientId, sequenceNumber, String(error))
+            .catch((e) => this.logService.error("autofill.IpcServer.send", e));
           return;
         }
         this.windowMain.win.webContents.send("autofill.passkeyRegistration", {
@@ -53,7 +55,9 @@ export class NativeAutofillMain {
       (error, clientId, sequenceNumber, request) => {
         if (error) {
           this.logService.error("autofill.IpcServer.assertion", error);
-          this.ipcServer.completeError(clientId, sequenceNumber, String(error));
+          this.ipcServer
+            .completeError(clientId, sequenceNumber, String(error))
+            .catch((e) => this.logService.error("autofill.IpcServer.send", e));
           return;
         }
         this.windowMain.win.webContents.send("autofill.passkeyAssertion", {
@@ -66,7 +70,9 @@ export class NativeAutofillMain {
       (error, clientId, sequenceNumber, request) => {
         if (error) {
           this.logService.error("autofill.IpcServer.assertion", error);
-          this.ipcServer.completeError(clientId, sequenceNumber, String(error));
+          this.ipcServer
+            .completeError(clientId, sequenceNumber, String(error))
+            .catch((e) => this.logService.error("autofill.IpcServer.send", e));
           return;
         }
         this.windowMain.win.webContents.send("autofill.passkeyAssertionWithoutUserInterface", {
@@ -80,19 +86,25 @@ export class NativeAutofillMain {
     ipcMain.on("autofill.completePasskeyRegistration", (event, data) => {
       this.logService.warning("autofill.completePasskeyRegistration", data);
       const { clientId, sequenceNumber, response } = data;
-      this.ipcServer.completeRegistration(clientId, sequenceNumber, response);
+      this.ipcServer
+        .completeRegistration(clientId, sequenceNumber, response)
+        .catch((e) => this.logService.error("autofill.IpcServer.send", e));
     });
 
     ipcMain.on("autofill.completePasskeyAssertion", (event, data) => {
       this.logService.warning("autofill.completePasskeyAssertion", data);
       const { clientId, sequenceNumber, response } = data;
-      this.ipcServer.completeAssertion(clientId, sequenceNumber, response);
+      this.ipcServer
+        .completeAssertion(clientId, sequenceNumber, response)
+        .catch((e) => this.logService.error("autofill.IpcServer.send", e));
     });
 
     ipcMain.on("autofill.completeError", (event, data) => {
       this.logService.warning("autofill.completeError", data);
       const { clientId, sequenceNumber, error } = data;
-      this.ipcServer.completeError(clientId, sequenceNumber, String(error));
+      this.ipcServer
+        .completeError(clientId, sequenceNumber, String(error))
+        .catch((e) => this.logService.error("autofill.IpcServer.send", e));
     });
   }
 
@@ -54,9 +54,14 @@ const sshAgent = {
   },
   setKeys: (keys: { name: string; privateKey: string; cipherId: string }[]): Promise<void> =>
     ipcRenderer.invoke("sshagent.setkeys", keys),
+  setGpgKeys: (keys: { name: string; privateKey: string; cipherId: string }[]): Promise<void> =>
+    ipcRenderer.invoke("sshagent.setgpgkeys", keys),
   signRequestResponse: async (requestId: number, accepted: boolean) => {
     await ipcRenderer.invoke("sshagent.signrequestresponse", { requestId, accepted });
   },
+  askpassResponse: async (requestId: string, approved: boolean, passphrase: string | null) => {
+    await ipcRenderer.invoke("sshagent.askpassresponse", { requestId, approved, passphrase });
+  },
   lock: async () => {
     return await ipcRenderer.invoke("sshagent.lock");
   },